@@ -1,155 +1,822 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::Local;
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use regex::Regex;
+use std::{
+    cell::RefCell,
+    fs,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::parse::TieResolution;
 
 /// A struct that contains and initiates all input arguments
+#[derive(Clone)]
 pub struct Args {
     pub fastq: String,                           // fastq file path
-    pub format: String,                          // format scheme file path
+    pub fastq_samples: Vec<(String, String)>,    // sample name and fastq file path pairs when each sample has its own fastq.  Empty otherwise
+    pub merge_pairs_option: Option<String>, // Read 2 fastq file whose reads are merged with the fastq reads by their overlap before matching.  Optional
+    pub min_overlap: usize, // Fewest overlapping bases for a read pair to be merged
+    pub format: String,                          // format scheme file path, or the format scheme itself when format_from_string.  Comma separated for a pool of library designs
+    pub format_from_string: bool,                // Whether or not the format scheme was given with --sequence-format-string instead of a file
     pub sample_barcodes_option: Option<String>,  // sample barcode file path.  Optional
-    pub counted_barcodes_option: Option<String>, // building block barcode file path. Optional
+    pub illumina_sample_sheet: bool, // Whether or not the sample barcode file is an Illumina SampleSheet.csv
+    pub pool_sample_names: bool, // Whether or not sample barcodes with the same sample name are counted together instead of under names with their barcode added
+    pub counted_barcodes_option: Option<String>, // building block barcode file path, or one comma separated for each sequence format. Optional
     pub output_dir: String,                      // output directory.  Deafaults to './'
     pub threads: u16, // Number of threads to use.  Defaults to number of threads on the machine
     pub prefix: String, // Prefix string for the output files
+    pub verbosity: Verbosity, // How much of the routine run information is printed to stdout
     pub merge_output: bool, // Whether or not to create an additional output file that merges all samples
-    pub barcodes_errors_option: Option<u16>, // Optional input of how many errors are allowed in each building block barcode.  Defaults to 20% of the length
+    pub merge_only: bool,   // Whether or not to only write the merged output file and skip the individual sample files
+    pub emit_empty_samples: bool, // Whether or not to write a header only counts file for each sample from the sample barcode file without counts
+    pub merge_chunk_rows_option: Option<usize>, // Most rows within each part of the merged output file, which is split into parts.  Optional
+    pub barcodes_errors_option: Option<Vec<u16>>, // Optional input of how many errors are allowed in each building block barcode, either one value for all or one for each.  Defaults to 20% of the length
     pub sample_errors_option: Option<u16>, // Optional input of how many errors are allowed in each sample barcode.  Defaults to 20% of the length
+    pub max_error_fraction_option: Option<f32>, // Fraction of each sample and counted barcode's length allowed as errors when their max errors are not set.  Optional
     pub constant_errors_option: Option<u16>, // Optional input of how many errors are allowed in each constant region barcode.  Defaults to 20% of the length
     pub min_average_quality_score: f32,
+    pub max_expected_errors_option: Option<f32>, // Maximum summed error probability allowed within each barcode.  Optional
+    pub quality_offset: u8, // The ascii offset of the quality scores.  33 for Phred+33 and 64 for Phred+64
+    pub skip_format_check: bool, // Whether or not to skip the FASTQ format check of the first record
     pub enrich: bool,
+    pub reference_sample_option: Option<String>, // Sample name that the other samples' counts are compared with for enrichment ratios.  Optional
+    pub pseudocount: f64, // Added to each count before the enrichment ratios so that barcodes missing from a sample still have a ratio
+    pub log_base: f64, // Base of the logarithm of the enrichment ratios
+    pub per_cycle_counts: bool, // Whether or not to write the counts of each barcode at each counted barcode position, ignoring the other positions
+    pub pairwise_matrix_option: Option<(usize, usize)>, // The two counted barcode numbers written as a matrix of counts for each sample.  Optional
+    pub strict_n: bool, // Whether or not 'N's within the sequenced read count as mismatches instead of wildcards
+    pub cluster_unknown: bool, // Whether or not to merge counted barcodes within one mismatch when there is no counted barcode file
+    pub quality_weighted: bool, // Whether or not each read adds its probability of correct barcode bases to the counts instead of 1
+    pub checkpoint_interval: u32, // Number of reads between saving checkpoints.  0 turns off checkpoints
+    pub resume: bool, // Whether or not to resume from the checkpoint file within the output directory
+    pub umi_bloom_bits: Option<usize>, // Bits of the Bloom filter used for each counted barcode's random barcodes.  None stores every random barcode
+    pub report_reads: bool, // Whether or not to write the reads of each counted barcode beside its unique random barcodes
+    pub umi_composition: bool, // Whether or not to write the base composition at each random barcode position
+    pub umi_hll: bool, // Whether or not to estimate the unique random barcodes of each counted barcode with a HyperLogLog sketch instead of storing them
+    pub dedup_name_regex_option: Option<Regex>, // Regex that finds the molecule key within each read name, which is used in place of a random barcode.  Optional
+    pub sample_progress_seconds: u64, // Seconds between printing matched reads per sample to stderr.  0 turns it off
+    pub show_matches: usize, // Number of matched reads printed with the region codes of the sequence format beneath them.  0 turns it off
+    pub preview: usize, // Number of matched reads of the run whose barcodes are printed to stderr as they are parsed.  0 turns it off
+    pub progress_interval: usize, // Number of barcodes written between printing the written count.  0 turns it off
+    pub write_uncorrected: bool, // Whether or not to write the counted barcodes that could not be fixed to a separate file
+    pub count_unmatched_sample: bool, // Whether or not to write the sample barcodes that could not be fixed to a separate file
+    pub match_unknown_samples: bool, // Whether or not reads with a sample barcode that could not be fixed are counted under an 'unknown' sample
+    pub delimiter: char, // Delimiter for the output files along with the sample and counted barcode files
+    pub seq_column_option: Option<String>, // Header name of the sequence column within the sample and counted barcode files.  Optional
+    pub id_column_option: Option<String>, // Header name of the ID column within the sample and counted barcode files.  Optional
+    pub index_column_option: Option<String>, // Header name of the barcode number column, or the sample group column.  Optional
+    pub anchor_end: bool, // Whether or not to prefer the format match closest to the end of the read instead of the start
+    pub sort_by: SortBy, // The order of the rows within the counts files
+    pub trim_start: usize, // Bases removed from the start of each read, along with their quality scores, before matching
+    pub trim_end: usize, // Bases removed from the end of each read, along with their quality scores, before matching
+    pub trim_adapter_option: Option<String>, // Adapter sequence removed, along with everything after it, from each read before matching.  Optional
+    pub adapter_errors: usize, // Mismatches allowed when finding the adapter
+    pub max_read_length_option: Option<usize>, // Reads longer than this are truncated to it, or skipped, before matching.  Optional
+    pub skip_long_reads: bool, // Whether or not reads longer than the maximum read length are skipped instead of truncated
+    pub unordered_barcodes: bool, // Whether or not to count the counted barcodes regardless of their order within the read
+    pub check_barcode_distances: bool, // Whether or not to warn about counted barcodes too close to each other to be fixed.  Sample barcodes are always checked
+    pub output_dna: bool, // Whether or not to write the error corrected DNA barcodes instead of converting them to their IDs
+    pub output_gzip: bool, // Whether or not to gzip the counts and diagnostic output files
+    pub min_correction_margin: u16, // How many more mismatches the second closest known barcode needs than the closest for a fix to be kept
+    pub corrector: String, // Name of the built-in corrector that fixes the sample and counted barcodes.  Either 'hamming' or 'edit'
+    pub tie_resolution: TieResolution, // Whether sample and counted barcodes tied between known barcodes are discarded or fixed to one of them
+    pub top_option: Option<usize>, // The number of the most abundant barcodes to print for each sample after counting
+    pub min_umi_option: Option<usize>, // The fewest random barcodes a counted barcode needs within a sample to be written.  Optional
+    pub downsample_to_option: Option<usize>, // Reads each sample's counts are downsampled to before writing.  Optional
+    pub downsample_seed: u64, // Seed of the downsampling draw so that the same seed gives the same counts
+    pub min_match_rate_option: Option<f32>, // The minimum fraction of matched reads, checked after check_after_reads reads.  Optional
+    pub min_match_rate_exit_option: Option<f32>, // The minimum fraction of matched reads of the finished run, below which the exit code is nonzero.  Optional
+    pub fail_on_empty: bool, // Whether or not to exit with a nonzero code when no reads matched or any sample has no counts
+    pub check_after_reads: u32, // The number of reads processed before the match rate is checked
+    pub dump_index_option: Option<String>, // File to write the counted barcode correction indexes to for later runs.  Optional
+    pub load_index_option: Option<String>, // File to load the counted barcode correction indexes from instead of creating them.  Optional
+    pub rarefaction: bool, // Whether or not to estimate the barcodes not yet sequenced within each sample for the stats file
+    pub pool_unknown_samples: bool, // Whether or not to count every read under one 'unknown' sample when there is a sample barcode but no sample barcode file
+    pub verify: bool, // Whether or not to read back the written files and check their rows and counts
+    pub sample_written_command_option: Option<String>, // Command run with the sample name and file path after each sample's counts file is written.  Optional
+    pub check_reverse_complement: bool, // Whether or not to try the reverse complement of reads where the constant region is not found
+    pub fold_revcomp: bool, // Whether or not to count each counted barcode together with its reverse complement
+    pub per_read_output_option: Option<String>, // File with one line for each read and how it was assigned.  Optional
+    pub name_map_option: Option<String>, // File of sample ID and output file basename pairs used in place of the default counts file names.  Optional
+    pub exclude_barcodes_option: Option<String>, // File of counted barcodes whose counts are left out of the counts files.  Optional
+    pub exclude_samples_option: Option<String>, // File of samples that are left out of the counts files.  Optional
+    pub valid_combinations_option: Option<String>, // File of the expected counted barcode combinations.  Other combinations are written to separate files.  Optional
+    pub write_from_checkpoint_option: Option<String>, // Results checkpoint of a finished parse to write the counts files from without reading the fastq.  Optional
 }
 
-impl Args {
+/// How much of the routine run information, such as the format, stats and files written, is printed to stdout.  Warnings, errors and
+/// the reading progress go to stderr at every level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,   // only the warnings, errors and progress
+    Normal,  // the routine run information
+    Verbose, // also each checkpoint saved
+}
+
+/// The verbosity of the run, set once the arguments are parsed.  Kept globally so that info_println and verbose_println can be used
+/// anywhere without passing the arguments through
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+impl Verbosity {
+    /// Sets the verbosity of the run, which info_println and verbose_println check before printing
+    pub fn set(self) {
+        VERBOSITY.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Whether or not lines of this verbosity are printed with the verbosity of the run
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::arguments::Verbosity;
+    ///
+    /// Verbosity::Quiet.set();
+    /// assert!(!Verbosity::Normal.enabled());
+    ///
+    /// Verbosity::Verbose.set();
+    /// assert!(Verbosity::Normal.enabled());
+    /// assert!(Verbosity::Verbose.enabled());
+    /// ```
+    pub fn enabled(self) -> bool {
+        VERBOSITY.load(Ordering::Relaxed) >= self as u8
+    }
+}
+
+/// The order of the rows within the counts files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    Barcode, // by the barcode columns in order
+    Count,   // by the highest count first
+}
+
+/// Arguments for the merge-counts subcommand, which sums the counts files of separately completed runs
+pub struct MergeCountsArgs {
+    pub counts_files: Vec<String>, // counts files to sum.  Need to have the same barcode columns
+    pub output: String,            // merged counts file path
+    pub delimiter: char,           // delimiter of the counts files and the merged counts file
+}
+
+/// Arguments for the self-test subcommand, which counts synthetic reads generated from a sequence format and barcode set and checks the
+/// counts against the known truth
+pub struct SelfTestArgs {
+    pub format: String,                     // sequence format file used to generate the reads
+    pub counted_barcodes: String,           // counted barcodes file.  Each read uses one of these for each counted barcode
+    pub sample_barcodes_option: Option<String>, // sample barcodes file, needed when the format has a sample barcode
+    pub output_dir: String,                 // directory for the synthetic fastq and the counts files
+    pub reads: u32,                         // number of synthetic reads
+    pub error_rate: f64,                    // chance of a substitution at each base
+    pub seed: u64,                          // seed for the random reads so that runs can be repeated
+    pub tolerance: f64,                     // allowed relative difference between the counted and true counts
+    pub threads: u16,                       // threads used for counting
+}
+
+/// The command to run.  Either counting barcodes within a fastq file, merging the counts files of completed runs, or checking the input
+/// files before a run
+pub enum Command {
+    Count(Box<Args>),
+    MergeCounts(MergeCountsArgs),
+    Validate(Box<Args>),
+    SelfTest(SelfTestArgs),
+}
+
+impl Command {
     pub fn new() -> Result<Self> {
-        let total_cpus = num_cpus::get().to_string();
+        // One thread reads the fastq while the others parse, so at least two are needed
+        let total_cpus = num_cpus::get().max(2).to_string();
         let today = Local::now().format("%Y-%m-%d").to_string();
         // parse arguments
-        let args = App::new("NGS-Barcode-Count")
+        let matches = App::new("NGS-Barcode-Count")
         .version(crate_version!())
         .author("Rory Coffey <coffeyrt@gmail.com>")
         .about("Counts barcodes located in sequencing data")
-        .arg(
-            Arg::with_name("fastq")
-                .short("f")
-                .long("fastq")
-                .takes_value(true)
-                .required(true)
-                .help("FastQ file"),
-        )
-        .arg(
-            Arg::with_name("format_file")
-                .short("q")
-                .long("sequence-format")
-                .takes_value(true)
-                .required(true)
-                .help("Sequence format file"),
-        )
-        .arg(
-            Arg::with_name("sample_file")
-                .short("s")
-                .long("sample-barcodes")
-                .takes_value(true)
-                .help("Sample barcodes file"),
-        )
-        .arg(
-            Arg::with_name("barcode_file")
-                .short("c")
-                .long("counted-barcodes")
-                .takes_value(true)
-                .help("Counted barcodes file"),
-        )
-        .arg(
-            Arg::with_name("threads")
-                .short("t")
-                .long("threads")
-                .takes_value(true)
-                .default_value(&total_cpus)
-                .help("Number of threads"),
-        )
-        .arg(
-            Arg::with_name("dir")
-                .short("o")
-                .long("output-dir")
-                .takes_value(true)
-                .default_value("./")
-                .help("Directory to output the counts to"),
-        )
-        .arg(
-            Arg::with_name("prefix")
-                .short("p")
-                .long("prefix")
-                .takes_value(true)
-                .default_value(&today)
-                .help("File prefix name.  THe output will end with '_<sample_name>_counts.csv'"),
-        )
-        .arg(
-            Arg::with_name("merge-output")
-                .short("m")
-                .long("merge-output")
-                .takes_value(false)
-                .help("Merge sample output counts into a single file.  Not necessary when there is only one sample"),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("merge-counts")
+                .about("Sums the counts files from separate runs into a single counts file without reparsing the fastq files")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Merged counts file path"),
+                )
+                .arg(
+                    Arg::with_name("delimiter")
+                        .long("delimiter")
+                        .takes_value(true)
+                        .default_value(",")
+                        .help("Delimiter of the counts files.  Use 'tab' for tab separated files"),
+                )
+                .arg(
+                    Arg::with_name("counts_files")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                        .min_values(2)
+                        .help("Counts files to merge.  Need to have the same barcode columns"),
+                ),
         )
-        .arg(
-            Arg::with_name("enrich")
-                .long("enrich")
-                .short("e")
-                .takes_value(false)
-                .help("Create output files of enrichment for single and double synthons/barcodes"),
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Checks the sequence format, barcode files and the first fastq record, then reports a pass or fail summary without counting")
+                .args(&count_args(&total_cpus, &today)),
         )
-        .arg(
-            Arg::with_name("max_barcode")
-                .long("max-errors-counted-barcode")
-                .takes_value(true)
-                .help("Maximimum number of sequence errors allowed within each counted barcode. Defaults to 20% of the total."),
-        )
-        .arg(
-            Arg::with_name("max_sample")
-                .long("max-errors-sample")
-                .takes_value(true)
-                .help("Maximimum number of sequence errors allowed within sample barcode. Defaults to 20% of the total."),
-        )
-        .arg(
-            Arg::with_name("max_constant")
-                .long("max-errors-constant")
-                .takes_value(true)
-                .help("Maximimum number of sequence errors allowed within constant region. Defaults to 20% of the total."),
-        )
-        .arg(
-            Arg::with_name("min")
-                .long("min-quality")
-                .takes_value(true)
-                .default_value("0")
-                .help("Minimum average read quality score per barcode"),
+        .subcommand(
+            SubCommand::with_name("self-test")
+                .about("Generates synthetic reads with sequencing errors from a sequence format and barcode files, counts them, and checks the counts against the truth")
+                .arg(
+                    Arg::with_name("format_file")
+                        .short("q")
+                        .long("sequence-format")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Sequence format file used to generate the reads"),
+                )
+                .arg(
+                    Arg::with_name("barcode_file")
+                        .short("c")
+                        .long("counted-barcodes")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Counted barcodes file.  Each read uses a random barcode from this file for each counted barcode"),
+                )
+                .arg(
+                    Arg::with_name("sample_file")
+                        .short("s")
+                        .long("sample-barcodes")
+                        .takes_value(true)
+                        .help("Sample barcodes file.  Needed when the sequence format has a sample barcode"),
+                )
+                .arg(
+                    Arg::with_name("output_dir")
+                        .short("o")
+                        .long("output-dir")
+                        .takes_value(true)
+                        .help("Directory for the synthetic fastq and counts files.  Defaults to a new directory within the system temporary directory"),
+                )
+                .arg(
+                    Arg::with_name("reads")
+                        .long("reads")
+                        .takes_value(true)
+                        .default_value("100000")
+                        .help("Number of synthetic reads"),
+                )
+                .arg(
+                    Arg::with_name("error_rate")
+                        .long("error-rate")
+                        .takes_value(true)
+                        .default_value("0.01")
+                        .help("Chance of a substitution at each base of a read"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Seed for generating the reads.  The same seed gives the same reads"),
+                )
+                .arg(
+                    Arg::with_name("tolerance")
+                        .long("tolerance")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .help("Allowed relative difference between each counted and true count"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .takes_value(true)
+                        .default_value(&total_cpus)
+                        .help("Number of threads used for counting"),
+                ),
         )
+        .args(&count_args(&total_cpus, &today))
         .get_matches();
 
-        let sample_barcodes_option;
-        if let Some(sample) = args.value_of("sample_file") {
-            sample_barcodes_option = Some(sample.to_string())
-        } else {
-            sample_barcodes_option = None
+        if let Some(merge_matches) = matches.subcommand_matches("merge-counts") {
+            return Ok(Command::MergeCounts(MergeCountsArgs {
+                counts_files: merge_matches
+                    .values_of("counts_files")
+                    .unwrap()
+                    .map(|counts_file| counts_file.to_string())
+                    .collect(),
+                output: merge_matches.value_of("output").unwrap().to_string(),
+                delimiter: parse_delimiter(merge_matches.value_of("delimiter").unwrap())?,
+            }));
+        }
+        if let Some(self_test_matches) = matches.subcommand_matches("self-test") {
+            let error_rate = self_test_matches
+                .value_of("error_rate")
+                .unwrap()
+                .parse::<f64>()
+                .context("Unable to convert error rate to a float")?;
+            if !(0.0..1.0).contains(&error_rate) {
+                bail!("--error-rate needs to be at least 0 and less than 1")
+            }
+            let output_dir = match self_test_matches.value_of("output_dir") {
+                Some(output_dir) => output_dir.to_string(),
+                None => std::env::temp_dir()
+                    .join(format!("barcode_count_self_test_{}", std::process::id()))
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            return Ok(Command::SelfTest(SelfTestArgs {
+                format: self_test_matches.value_of("format_file").unwrap().to_string(),
+                counted_barcodes: self_test_matches.value_of("barcode_file").unwrap().to_string(),
+                sample_barcodes_option: self_test_matches.value_of("sample_file").map(|samples| samples.to_string()),
+                output_dir,
+                reads: self_test_matches
+                    .value_of("reads")
+                    .unwrap()
+                    .parse::<u32>()
+                    .context("Unable to convert reads to an integer")?,
+                error_rate,
+                seed: self_test_matches
+                    .value_of("seed")
+                    .unwrap()
+                    .parse::<u64>()
+                    .context("Unable to convert seed to an integer")?,
+                tolerance: self_test_matches
+                    .value_of("tolerance")
+                    .unwrap()
+                    .parse::<f64>()
+                    .context("Unable to convert tolerance to a float")?,
+                threads: self_test_matches
+                    .value_of("threads")
+                    .unwrap()
+                    .parse::<u16>()
+                    .context("Unable to convert threads to an integer")?,
+            }));
+        }
+        if let Some(validate_matches) = matches.subcommand_matches("validate") {
+            return Ok(Command::Validate(Box::new(Args::from_matches(validate_matches.clone())?)));
         }
+        Ok(Command::Count(Box::new(Args::from_matches(matches)?)))
+    }
+}
 
-        let counted_barcodes_option;
-        if let Some(barcodes) = args.value_of("barcode_file") {
-            counted_barcodes_option = Some(barcodes.to_string())
-        } else {
-            counted_barcodes_option = None
+/// The arguments for counting.  Used by the main command and by the validate subcommand, which checks the same input files
+fn count_args<'a>(total_cpus: &'a str, today: &'a str) -> Vec<Arg<'a, 'a>> {
+    vec![
+        Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .help("TOML config file with any of the run parameters, using the long argument names as keys.  Arguments given on the command line override the config values"),
+        Arg::with_name("fastq")
+            .short("f")
+            .long("fastq")
+            .takes_value(true)
+            .required_unless_one(&["config", "write_from_checkpoint"])
+            .help("FastQ file.  Already demultiplexed fastq files can be given per sample as sample_name=fastq_file,sample_name=fastq_file"),
+        Arg::with_name("merge_pairs")
+            .long("merge-pairs")
+            .takes_value(true)
+            .help("Read 2 fastq file of paired-end reads.  Each read pair is merged by the overlap of the --fastq read with the reverse complement of its read 2, keeping the higher quality base where they disagree, and the merged read is matched.  Pairs that do not overlap are matched with the --fastq read alone"),
+        Arg::with_name("min_overlap")
+            .long("min-overlap")
+            .takes_value(true)
+            .default_value("10")
+            .help("Fewest overlapping bases for --merge-pairs to merge a read pair"),
+        Arg::with_name("format_file")
+            .short("q")
+            .long("sequence-format")
+            .takes_value(true)
+            .required_unless_one(&["config", "format_string"])
+            .help("Sequence format file.  Comma separated files for a pool of library designs, where each read is counted under the first format that matches"),
+        Arg::with_name("format_string")
+            .long("sequence-format-string")
+            .takes_value(true)
+            .conflicts_with("format_file")
+            .help("Sequence format given directly, such as '[8]AGCT{6}', in place of a sequence format file"),
+        Arg::with_name("sample_file")
+            .short("s")
+            .long("sample-barcodes")
+            .takes_value(true)
+            .help("Sample barcodes file"),
+        Arg::with_name("illumina_sample_sheet")
+            .long("illumina-sample-sheet")
+            .takes_value(true)
+            .conflicts_with("sample_file")
+            .help("Illumina SampleSheet.csv to read the sample barcodes from in place of --sample-barcodes.  Uses the Sample_ID, index, and index2 columns of the [Data] section, with index2 added to the end of index for dual-index designs"),
+        Arg::with_name("pool_sample_names")
+            .long("pool-sample-names")
+            .takes_value(false)
+            .help("Count sample barcodes that have the same sample name together under that name.  Without this flag, each of these sample barcodes is written under the sample name with its barcode added"),
+        Arg::with_name("barcode_file")
+            .short("c")
+            .long("counted-barcodes")
+            .takes_value(true)
+            .help("Counted barcodes file.  With more than one sequence format, either one file for all or comma separated files, one for each format"),
+        Arg::with_name("threads")
+            .short("t")
+            .long("threads")
+            .takes_value(true)
+            .default_value(total_cpus)
+            .help("Number of threads, which is at least 2.  One reads the fastq and the others parse the reads.  No more than this are used"),
+        Arg::with_name("dir")
+            .short("o")
+            .long("output-dir")
+            .takes_value(true)
+            .default_value("./")
+            .help("Directory to output the counts to"),
+        Arg::with_name("prefix")
+            .short("p")
+            .long("prefix")
+            .takes_value(true)
+            .default_value(today)
+            .help("File prefix name.  THe output will end with '_<sample_name>_counts.csv'"),
+        Arg::with_name("quiet")
+            .long("quiet")
+            .takes_value(false)
+            .conflicts_with("verbose")
+            .help("Only print warnings, errors and progress, leaving out the format, stats and files written"),
+        Arg::with_name("verbose")
+            .long("verbose")
+            .takes_value(false)
+            .help("Also print when each checkpoint is saved"),
+        Arg::with_name("merge-output")
+            .short("m")
+            .long("merge-output")
+            .takes_value(false)
+            .help("Merge sample output counts into a single file.  Not necessary when there is only one sample"),
+        Arg::with_name("merge_only")
+            .long("merge-only")
+            .takes_value(false)
+            .help("Only write the merged output file, which has each sample as a column, instead of a file per sample"),
+        Arg::with_name("emit_empty_samples")
+            .long("emit-empty-samples")
+            .takes_value(true)
+            .possible_values(&["yes", "no"])
+            .default_value("yes")
+            .help("Whether samples from the sample barcode file without any counts get a header only counts file or are only listed"),
+        Arg::with_name("merge_chunk_rows")
+            .long("merge-chunk-rows")
+            .takes_value(true)
+            .help("Split the merged output file into <prefix>_counts.all.part<k>.csv files of at most this many rows each, with the header repeated in each"),
+        Arg::with_name("enrich")
+            .long("enrich")
+            .short("e")
+            .takes_value(false)
+            .help("Create output files of enrichment for single and double synthons/barcodes"),
+        Arg::with_name("reference_sample")
+            .long("reference-sample")
+            .takes_value(true)
+            .help("Sample name to compare every other sample with.  Writes the log2 ratio of each barcode's normalized count against the reference sample to <prefix>_enrichment_ratios.csv"),
+        Arg::with_name("pseudocount")
+            .long("pseudocount")
+            .takes_value(true)
+            .default_value("1")
+            .help("Pseudocount added to each count before the --reference-sample enrichment ratios"),
+        Arg::with_name("log_base")
+            .long("log-base")
+            .takes_value(true)
+            .default_value("2")
+            .help("Base of the logarithm of the --reference-sample enrichment ratios.  Use 'e' for the natural log"),
+        Arg::with_name("pairwise_matrix")
+            .long("pairwise-matrix")
+            .takes_value(true)
+            .help("Two counted barcode numbers, such as 1,2, to write a matrix of counts for each sample with the first barcode as rows and the second as columns, adding together the counts over the other barcodes"),
+        Arg::with_name("per_cycle_counts")
+            .long("per-cycle-counts")
+            .takes_value(false)
+            .help("Write a counts file for each counted barcode position, or cycle, with the count of each barcode at that position"),
+        Arg::with_name("max_barcode")
+            .long("max-errors-counted-barcode")
+            .takes_value(true)
+            .help("Maximimum number of sequence errors allowed within each counted barcode. Either one value for all counted barcodes or a comma separated value for each, such as 1,2,2. Defaults to 20% of the total."),
+        Arg::with_name("max_sample")
+            .long("max-errors-sample")
+            .takes_value(true)
+            .help("Maximimum number of sequence errors allowed within sample barcode. Defaults to 20% of the total."),
+        Arg::with_name("max_error_fraction")
+            .long("max-error-fraction")
+            .takes_value(true)
+            .help("Fraction of each sample and counted barcode's length allowed as errors, rounded down, in place of the 20% default.  Used for the barcodes without --max-errors-sample or --max-errors-counted-barcode.  Meant for --corrector edit"),
+        Arg::with_name("max_constant")
+            .long("max-errors-constant")
+            .takes_value(true)
+            .help("Maximimum number of sequence errors allowed within constant region. Defaults to 20% of the total."),
+        Arg::with_name("min")
+            .long("min-quality")
+            .takes_value(true)
+            .default_value("0")
+            .help("Minimum average read quality score per barcode"),
+        Arg::with_name("max_expected_errors")
+            .long("max-expected-errors")
+            .takes_value(true)
+            .help("Maximum expected errors per barcode, which is the sum of the error probability of each base from its quality score"),
+        Arg::with_name("quality_offset")
+            .long("quality-offset")
+            .takes_value(true)
+            .default_value("33")
+            .help("Quality score ascii offset.  33 for Phred+33, 64 for legacy Phred+64, or 'auto' to detect from the first reads"),
+        Arg::with_name("skip_format_check")
+            .long("skip-format-check")
+            .takes_value(false)
+            .help("Skip the FASTQ format check of the first record, which can fail for valid files with unusual sequence ID lines"),
+        Arg::with_name("strict_n")
+            .long("strict-n")
+            .takes_value(false)
+            .help("Count 'N's within the sequenced read as mismatches during error correction instead of as any nucleotide"),
+        Arg::with_name("cluster_unknown")
+            .long("cluster-unknown")
+            .takes_value(false)
+            .help("Without a counted barcodes file, merge counted barcodes within one mismatch into the highest count barcode"),
+        Arg::with_name("quality_weighted")
+            .long("quality-weighted")
+            .takes_value(false)
+            .help("Each read adds the probability that its barcode bases are correct, from their quality scores, to the counts instead of 1.  The counts files have fractional counts.  Not supported with a random barcode"),
+        Arg::with_name("umi_bloom")
+            .long("umi-bloom")
+            .takes_value(true)
+            .help("Track the random barcodes of each counted barcode with a Bloom filter of this many bits instead of storing each random barcode.  Uses much less memory, but a few new random barcodes are counted as duplicates"),
+        Arg::with_name("report_reads")
+            .long("report-reads")
+            .takes_value(false)
+            .help("With a random barcode, write the reads of each counted barcode, including duplicates, as a Reads column beside the unique random barcodes, which are the UMIs column"),
+        Arg::with_name("umi_composition")
+            .long("umi-composition")
+            .takes_value(false)
+            .help("Write the base counts and fractions at each random barcode position, over the random barcodes of every matched read, to <prefix>_umi_composition.csv to check for biased random barcode synthesis"),
+        Arg::with_name("umi_hll")
+            .long("umi-hll")
+            .takes_value(false)
+            .help("Estimate the unique random barcodes of each counted barcode with a HyperLogLog sketch, which uses at most 4KB, instead of storing each random barcode.  The counts have about a 1.6% relative error"),
+        Arg::with_name("dedup_name_regex")
+            .long("dedup-by-name-regex")
+            .takes_value(true)
+            .help("Regex that finds a molecule key within each read name, the first line of the FASTQ record, which is used like a random barcode to count duplicates once.  The first capture group is used if there is one, otherwise the whole match"),
+        Arg::with_name("checkpoint_interval")
+            .long("checkpoint-interval")
+            .takes_value(true)
+            .default_value("0")
+            .help("Save a checkpoint of the counts every this many reads so that an interrupted run can be resumed.  0 turns off checkpoints"),
+        Arg::with_name("resume")
+            .long("resume")
+            .takes_value(false)
+            .help("Resume from the checkpoint file saved within the output directory with the same prefix"),
+        Arg::with_name("sample_progress")
+            .long("sample-progress")
+            .takes_value(true)
+            .default_value("0")
+            .help("Print the matched reads per sample to stderr every this many seconds while parsing.  0 turns it off"),
+        Arg::with_name("show_matches")
+            .long("show-matches")
+            .takes_value(true)
+            .default_value("0")
+            .help("Print the first this many matched reads of each fastq file with the region codes of the sequence format beneath them and the IDs of their barcodes, to check where the format finds the barcodes.  0 turns it off"),
+        Arg::with_name("preview")
+            .long("preview")
+            .takes_value(true)
+            .default_value("0")
+            .help("Print the sample, counted and random barcodes found within the first this many matched reads of the run to stderr as they are parsed, then continue counting.  0 turns it off"),
+        Arg::with_name("progress_interval")
+            .long("progress-interval")
+            .takes_value(true)
+            .default_value("50000")
+            .help("Print the number of barcodes written so far every this many barcodes while writing the counts files.  0 turns it off, which is useful when the output is captured to a log"),
+        Arg::with_name("write_uncorrected")
+            .long("write-uncorrected")
+            .takes_value(false)
+            .help("Write the sequenced counted barcodes that could not be fixed, and how often they occur, to <prefix>_uncorrected.csv"),
+        Arg::with_name("count_unmatched_sample")
+            .long("count-unmatched-sample")
+            .takes_value(false)
+            .help("Write the sequenced sample barcodes that could not be fixed, and how often they occur, to <prefix>_unknown_samples.csv"),
+        Arg::with_name("match_unknown_samples")
+            .long("match-unknown-samples")
+            .takes_value(false)
+            .help("Find the counted barcodes of reads with a sample barcode that could not be fixed, and count them under an 'unknown' sample instead of discarding the reads.  Useful for finding index hopping"),
+        Arg::with_name("trim_start")
+            .long("trim-start")
+            .takes_value(true)
+            .default_value("0")
+            .help("Number of bases, along with their quality scores, to remove from the start of each read before matching"),
+        Arg::with_name("trim_end")
+            .long("trim-end")
+            .takes_value(true)
+            .default_value("0")
+            .help("Number of bases, along with their quality scores, to remove from the end of each read before matching"),
+        Arg::with_name("trim_adapter")
+            .long("trim-adapter")
+            .takes_value(true)
+            .help("Adapter sequence to find within each read and remove, along with everything after it and the quality scores, before matching"),
+        Arg::with_name("adapter_errors")
+            .long("adapter-errors")
+            .takes_value(true)
+            .default_value("2")
+            .help("Number of mismatches allowed when finding the --trim-adapter sequence"),
+        Arg::with_name("max_read_length")
+            .long("max-read-length")
+            .takes_value(true)
+            .help("Reads longer than this, after trimming, are truncated to this length or skipped, set by --long-reads, before matching"),
+        Arg::with_name("long_reads")
+            .long("long-reads")
+            .takes_value(true)
+            .possible_values(&["truncate", "skip"])
+            .default_value("truncate")
+            .help("Whether reads longer than --max-read-length are truncated to it or skipped and tallied as long reads"),
+        Arg::with_name("unordered_barcodes")
+            .long("unordered-barcodes")
+            .takes_value(false)
+            .help("Count the counted barcodes regardless of their order within the read.  Each row has the barcodes sorted, so that every order of the same barcodes is counted together"),
+        Arg::with_name("check_barcode_distances")
+            .long("check-barcode-distances")
+            .takes_value(false)
+            .help("Warn about counted barcodes within twice the allowed errors of each other, which cannot always be fixed.  Sample barcodes are always checked"),
+        Arg::with_name("fold_revcomp")
+            .long("fold-revcomp")
+            .takes_value(false)
+            .help("Count each counted barcode together with its reverse complement, as the lexicographically smaller of the two.  For symmetric assays"),
+        Arg::with_name("check_reverse_complement")
+            .long("check-reverse-complement")
+            .takes_value(false)
+            .help("When the constant region is not found within a read, look for it within the reverse complement of the read before counting a constant region mismatch.  For single reads sequenced in both orientations"),
+        Arg::with_name("sample_written_command")
+            .long("sample-written-command")
+            .takes_value(true)
+            .help("Command run with the sample name and counts file path as its arguments as soon as each sample's counts file is written, to start downstream processing"),
+        Arg::with_name("name_map")
+            .long("name-map")
+            .takes_value(true)
+            .help("File with a header, then the sample ID and output file basename of each sample.  The counts files of these samples are named <basename>.csv in place of <prefix>_<sample>_counts.csv"),
+        Arg::with_name("exclude_barcodes")
+            .long("exclude-barcodes")
+            .takes_value(true)
+            .help("File with one counted barcode sequence or ID on each line.  Counted barcodes with any of these barcodes, such as spike-in controls, are left out of the counts files and their counts are reported as excluded"),
+        Arg::with_name("exclude_samples")
+            .long("exclude-samples")
+            .takes_value(true)
+            .help("File with one sample barcode or sample name on each line.  These samples are left out of the counts files and their counts are reported as excluded"),
+        Arg::with_name("valid_combinations")
+            .long("valid-combinations")
+            .takes_value(true)
+            .help("File with one expected combination of counted barcode sequences or IDs on each line, separated by the delimiter.  Counts of other combinations, such as synthesis errors or crossovers, are written to <prefix>_<sample>_unexpected_counts.csv instead of the counts files"),
+        Arg::with_name("write_from_checkpoint")
+            .long("write-from-checkpoint")
+            .takes_value(true)
+            .help("Results checkpoint, saved as <prefix>_results.checkpoint within the output directory right before the counts files are written, to write the counts files from without reparsing the fastq.  Use the same arguments as the run that saved it"),
+        Arg::with_name("per_read_output")
+            .long("per-read-output")
+            .takes_value(true)
+            .help("Tab separated file with one line for each read: read ID, sample, counted barcode IDs, random barcode and whether it was counted or why not.  Gzipped when the file ends with .gz.  Very large, with a line for every read"),
+        Arg::with_name("verify")
+            .long("verify")
+            .takes_value(false)
+            .help("Read back each written file and check that its rows and Count column total are the same as what was written.  The run fails for any difference"),
+        Arg::with_name("pool_unknown_samples")
+            .long("pool-unknown-samples")
+            .takes_value(false)
+            .help("When the format has a sample barcode but --sample-barcodes is not used, count every read under one 'unknown' sample instead of a sample for each sequenced sample barcode"),
+        Arg::with_name("rarefaction")
+            .long("rarefaction")
+            .takes_value(false)
+            .help("Add an estimate of how many barcodes within each sample were not sequenced to the stats file, using the Chao1 and Good-Turing estimators"),
+        Arg::with_name("dump_index")
+            .long("dump-index")
+            .takes_value(true)
+            .help("Write the counted barcode correction indexes to this file, so that later runs with the same counted barcodes file can load them with --load-index"),
+        Arg::with_name("load_index")
+            .long("load-index")
+            .takes_value(true)
+            .conflicts_with("dump_index")
+            .help("Load the counted barcode correction indexes from a file written with --dump-index instead of creating them"),
+        Arg::with_name("min_match_rate")
+            .long("min-match-rate")
+            .takes_value(true)
+            .help("Stop the run if the fraction of matched reads is below this, between 0 and 1, once --check-after-reads reads are processed.  A few unmatched reads are shown to help find the misconfiguration"),
+        Arg::with_name("min_match_rate_exit")
+            .long("min-match-rate-exit")
+            .takes_value(true)
+            .help("Exit with code 3 after writing the counts if the fraction of matched reads of the whole run is below this, between 0 and 1"),
+        Arg::with_name("fail_on_empty")
+            .long("fail-on-empty")
+            .takes_value(false)
+            .help("Exit with code 2 after writing the counts if no reads matched, or code 4 if any sample has no counts"),
+        Arg::with_name("check_after_reads")
+            .long("check-after-reads")
+            .takes_value(true)
+            .default_value("100000")
+            .help("Number of reads processed before the match rate is checked with --min-match-rate"),
+        Arg::with_name("min_umi")
+            .long("min-umi")
+            .takes_value(true)
+            .help("Drop counted barcodes with fewer than this many unique random barcodes within a sample before writing the counts.  Only used when there is a random barcode"),
+        Arg::with_name("downsample_to")
+            .long("downsample-to")
+            .takes_value(true)
+            .help("Downsample each sample's counts to this many reads with a multinomial draw before writing, so that samples are compared at the same depth.  Samples with fewer reads are written unchanged.  Only used when there is not a random barcode"),
+        Arg::with_name("downsample_seed")
+            .long("downsample-seed")
+            .takes_value(true)
+            .default_value("1")
+            .help("Seed for --downsample-to.  The same seed gives the same counts"),
+        Arg::with_name("top")
+            .long("top")
+            .takes_value(true)
+            .help("Print this many of the most abundant barcodes for each sample, along with their counts, after counting"),
+        Arg::with_name("min_correction_margin")
+            .long("min-correction-margin")
+            .takes_value(true)
+            .default_value("0")
+            .help("How many more mismatches the second closest sample or counted barcode needs than the closest for a sequencing error to be fixed.  Default is 0, which only requires the closest barcode to not be tied"),
+        Arg::with_name("corrector")
+            .long("corrector")
+            .takes_value(true)
+            .possible_values(&["hamming", "edit"])
+            .default_value("hamming")
+            .help("Fix the sample and counted barcodes with the fewest mismatches, 'hamming', or the fewest insertions, deletions and substitutions, 'edit'"),
+        Arg::with_name("tie_break")
+            .long("tie-break")
+            .takes_value(true)
+            .possible_values(&["discard", "first", "random"])
+            .default_value("discard")
+            .help("Whether a sample or counted barcode tied between known barcodes is discarded, fixed to the 'first' in sorted order, or fixed to a 'random' one seeded by --tie-break-seed"),
+        Arg::with_name("tie_break_seed")
+            .long("tie-break-seed")
+            .takes_value(true)
+            .default_value("1")
+            .help("Seed for --tie-break random.  The same seed fixes the same tied barcodes to the same known barcodes"),
+        Arg::with_name("output_gzip")
+            .long("output-gzip")
+            .takes_value(false)
+            .help("Gzip the counts files along with the other diagnostic output files.  The stats file is not gzipped"),
+        Arg::with_name("output_dna")
+            .long("output-dna")
+            .takes_value(false)
+            .help("Write the error corrected DNA barcodes instead of their IDs from the counted barcode conversion file"),
+        Arg::with_name("delimiter")
+            .long("delimiter")
+            .takes_value(true)
+            .default_value(",")
+            .help("Delimiter for the output files along with the sample and counted barcode files.  Use 'tab' for tab separated files"),
+        Arg::with_name("seq_column")
+            .long("seq-column")
+            .takes_value(true)
+            .help("Header name of the barcode sequence column within the sample and counted barcode files.  Default is the first column"),
+        Arg::with_name("id_column")
+            .long("id-column")
+            .takes_value(true)
+            .help("Header name of the ID column within the sample and counted barcode files.  Default is the second column"),
+        Arg::with_name("index_column")
+            .long("index-column")
+            .takes_value(true)
+            .help("Header name of the barcode number column within the counted barcode file, and of the group column within the sample barcode file.  Default is the third column"),
+        Arg::with_name("anchor")
+            .long("anchor")
+            .takes_value(true)
+            .possible_values(&["start", "end"])
+            .default_value("start")
+            .help("Prefer the format match closest to the 'start' or the 'end' of the read when it is found more than once"),
+        Arg::with_name("sort_by")
+            .long("sort-by")
+            .takes_value(true)
+            .possible_values(&["barcode", "count"])
+            .default_value("barcode")
+            .help("Sort the rows of the counts files by the 'barcode' columns or by the highest 'count' first"),
+    ]
+}
+
+impl Args {
+    /// Creates the arguments for counting from the command line matches, along with the config file if it was included
+    fn from_matches(matches: ArgMatches) -> Result<Self> {
+        // Combine the command line arguments with the config file, if it was included
+        let args = ArgValues::new(matches)?;
+
+        let mut sample_barcodes_option = args.value_of("sample_file", "sample-barcodes")?;
+        let illumina_sample_sheet_option = args.value_of("illumina_sample_sheet", "illumina-sample-sheet")?;
+        let illumina_sample_sheet = illumina_sample_sheet_option.is_some();
+        if illumina_sample_sheet {
+            if sample_barcodes_option.is_some() {
+                bail!("--sample-barcodes and --illumina-sample-sheet cannot be used together")
+            }
+            sample_barcodes_option = illumina_sample_sheet_option;
         }
+        let pool_sample_names = args.is_present("pool_sample_names", "pool-sample-names")?;
+
+        let counted_barcodes_option = args.value_of("barcode_file", "counted-barcodes")?;
 
         let barcodes_errors_option;
-        if let Some(barcodes) = args.value_of("max_barcode") {
+        if let Some(barcodes) = args.value_of("max_barcode", "max-errors-counted-barcode")? {
             barcodes_errors_option = Some(
                 barcodes
-                    .parse::<u16>()
-                    .context("Unable to convert maximum barcode errors to an integer")?,
+                    .split(',')
+                    .map(|barcode_errors| {
+                        barcode_errors
+                            .trim()
+                            .parse::<u16>()
+                            .context("Unable to convert maximum barcode errors to an integer")
+                    })
+                    .collect::<Result<Vec<u16>>>()?,
             )
         } else {
             barcodes_errors_option = None
         }
 
         let sample_errors_option;
-        if let Some(sample) = args.value_of("max_sample") {
+        if let Some(sample) = args.value_of("max_sample", "max-errors-sample")? {
             sample_errors_option = Some(
                 sample
                     .parse::<u16>()
@@ -158,9 +825,20 @@ impl Args {
         } else {
             sample_errors_option = None
         }
+        let max_error_fraction_option = args
+            .value_of("max_error_fraction", "max-error-fraction")?
+            .map(|max_error_fraction| {
+                max_error_fraction
+                    .parse::<f32>()
+                    .context("Unable to convert max error fraction to a float")
+            })
+            .transpose()?;
+        if max_error_fraction_option.is_some_and(|max_error_fraction| !(0.0..1.0).contains(&max_error_fraction)) {
+            bail!("--max-error-fraction needs to be at least 0 and below 1")
+        }
 
         let constant_errors_option;
-        if let Some(constant) = args.value_of("max_constant") {
+        if let Some(constant) = args.value_of("max_constant", "max-errors-constant")? {
             constant_errors_option = Some(
                 constant
                     .parse::<u16>()
@@ -170,37 +848,584 @@ impl Args {
             constant_errors_option = None
         }
 
-        let merge_output = args.is_present("merge-output");
-        let enrich = args.is_present("enrich");
-        let fastq = args.value_of("fastq").unwrap().to_string();
-        let format = args.value_of("format_file").unwrap().to_string();
-        let output_dir = args.value_of("dir").unwrap().to_string();
+        let merge_only = args.is_present("merge_only", "merge-only")?;
+        let emit_empty_samples = match args.value_of("emit_empty_samples", "emit-empty-samples")?.unwrap().as_str() {
+            "yes" => true,
+            "no" => false,
+            other => bail!("Emit empty samples must be either 'yes' or 'no', not '{}'", other),
+        };
+        let merge_chunk_rows_option = args
+            .value_of("merge_chunk_rows", "merge-chunk-rows")?
+            .map(|merge_chunk_rows| {
+                merge_chunk_rows
+                    .parse::<usize>()
+                    .context("Unable to convert merge chunk rows to an integer")
+            })
+            .transpose()?;
+        if merge_chunk_rows_option == Some(0) {
+            bail!("--merge-chunk-rows needs to be above 0")
+        }
+        // Only writing the merged file, or splitting it, requires the merged file
+        let merge_output =
+            args.is_present("merge-output", "merge-output")? || merge_only || merge_chunk_rows_option.is_some();
+        let enrich = args.is_present("enrich", "enrich")?;
+        let reference_sample_option = args.value_of("reference_sample", "reference-sample")?;
+        let pseudocount = args
+            .value_of("pseudocount", "pseudocount")?
+            .unwrap()
+            .parse::<f64>()
+            .context("Unable to convert pseudocount to a float")?;
+        // A pseudocount of 0 gives barcodes missing from a sample an infinite ratio
+        if pseudocount <= 0.0 || !pseudocount.is_finite() {
+            bail!("--pseudocount must be greater than 0: {}", pseudocount)
+        }
+        let log_base_text = args.value_of("log_base", "log-base")?.unwrap();
+        let log_base = if log_base_text.eq_ignore_ascii_case("e") {
+            std::f64::consts::E
+        } else {
+            log_base_text
+                .parse::<f64>()
+                .context("Unable to convert log base to a float")?
+        };
+        if log_base <= 0.0 || log_base == 1.0 || !log_base.is_finite() {
+            bail!("--log-base must be greater than 0 and not 1: {}", log_base_text)
+        }
+        let per_cycle_counts = args.is_present("per_cycle_counts", "per-cycle-counts")?;
+        let pairwise_matrix_option = args
+            .value_of("pairwise_matrix", "pairwise-matrix")?
+            .map(|pairwise_matrix| -> Result<(usize, usize)> {
+                let barcode_numbers = pairwise_matrix
+                    .split(',')
+                    .map(|barcode_number| {
+                        barcode_number
+                            .trim()
+                            .parse::<usize>()
+                            .context("Unable to convert pairwise matrix barcode numbers to integers")
+                    })
+                    .collect::<Result<Vec<usize>>>()?;
+                match barcode_numbers[..] {
+                    [row_barcode, column_barcode] if row_barcode != column_barcode && row_barcode > 0 && column_barcode > 0 => {
+                        Ok((row_barcode, column_barcode))
+                    }
+                    _ => bail!("--pairwise-matrix needs two different counted barcode numbers, such as 1,2: {}", pairwise_matrix),
+                }
+            })
+            .transpose()?;
+        let strict_n = args.is_present("strict_n", "strict-n")?;
+        let cluster_unknown = args.is_present("cluster_unknown", "cluster-unknown")?;
+        let resume = args.is_present("resume", "resume")?;
+        let checkpoint_interval = args
+            .value_of("checkpoint_interval", "checkpoint-interval")?
+            .unwrap()
+            .parse::<u32>()
+            .context("Unable to convert checkpoint interval to an integer")?;
+        let umi_bloom_bits = args
+            .value_of("umi_bloom", "umi-bloom")?
+            .map(|bits| {
+                bits.parse::<usize>()
+                    .context("Unable to convert umi bloom bits to an integer")
+            })
+            .transpose()?;
+        // The random barcodes within a Bloom filter cannot be listed within a checkpoint
+        if umi_bloom_bits.is_some() && (resume || checkpoint_interval > 0) {
+            bail!("Checkpoints and --resume are not supported with --umi-bloom")
+        }
+        let umi_hll = args.is_present("umi_hll", "umi-hll")?;
+        if umi_hll && umi_bloom_bits.is_some() {
+            bail!("Only one of --umi-hll and --umi-bloom can be used")
+        }
+        if umi_hll && (resume || checkpoint_interval > 0) {
+            bail!("Checkpoints and --resume are not supported with --umi-hll")
+        }
+        let quality_weighted = args.is_present("quality_weighted", "quality-weighted")?;
+        // The weighted counts are kept beside the read counts, which are the only counts within checkpoints and clustering
+        if quality_weighted && (resume || checkpoint_interval > 0 || cluster_unknown) {
+            bail!("Checkpoints, --resume and --cluster-unknown are not supported with --quality-weighted")
+        }
+        // The read counts are kept beside the random barcodes, which are the only counts within checkpoints and clustering
+        let report_reads = args.is_present("report_reads", "report-reads")?;
+        if report_reads && (resume || checkpoint_interval > 0 || cluster_unknown) {
+            bail!("Checkpoints, --resume and --cluster-unknown are not supported with --report-reads")
+        }
+        let umi_composition = args.is_present("umi_composition", "umi-composition")?;
+        let dedup_name_regex_option = args
+            .value_of("dedup_name_regex", "dedup-by-name-regex")?
+            .map(|name_regex| {
+                Regex::new(&name_regex)
+                    .context(format!("Unable to create a regex from --dedup-by-name-regex: {}", name_regex))
+            })
+            .transpose()?;
+        let sample_progress_seconds = args
+            .value_of("sample_progress", "sample-progress")?
+            .unwrap()
+            .parse::<u64>()
+            .context("Unable to convert sample progress seconds to an integer")?;
+        let show_matches = args
+            .value_of("show_matches", "show-matches")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert show matches to an integer")?;
+        let preview = args
+            .value_of("preview", "preview")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert preview to an integer")?;
+        let progress_interval = args
+            .value_of("progress_interval", "progress-interval")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert progress interval to an integer")?;
+        let write_uncorrected = args.is_present("write_uncorrected", "write-uncorrected")?;
+        let count_unmatched_sample =
+            args.is_present("count_unmatched_sample", "count-unmatched-sample")?;
+        let match_unknown_samples = args.is_present("match_unknown_samples", "match-unknown-samples")?;
+        let trim_start = args
+            .value_of("trim_start", "trim-start")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert trim start to an integer")?;
+        let trim_end = args
+            .value_of("trim_end", "trim-end")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert trim end to an integer")?;
+        let trim_adapter_option = args
+            .value_of("trim_adapter", "trim-adapter")?
+            .map(|adapter| adapter.to_uppercase());
+        if trim_adapter_option.as_deref() == Some("") {
+            bail!("--trim-adapter needs an adapter sequence")
+        }
+        let adapter_errors = args
+            .value_of("adapter_errors", "adapter-errors")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert adapter errors to an integer")?;
+        let max_read_length_option = args
+            .value_of("max_read_length", "max-read-length")?
+            .map(|max_read_length| {
+                max_read_length
+                    .parse::<usize>()
+                    .context("Unable to convert max read length to an integer")
+            })
+            .transpose()?;
+        if max_read_length_option == Some(0) {
+            bail!("--max-read-length needs to be above 0")
+        }
+        let skip_long_reads = match args.value_of("long_reads", "long-reads")?.unwrap().as_str() {
+            "truncate" => false,
+            "skip" => true,
+            other => bail!("Long reads must be either 'truncate' or 'skip', not '{}'", other),
+        };
+        let unordered_barcodes = args.is_present("unordered_barcodes", "unordered-barcodes")?;
+        let check_barcode_distances =
+            args.is_present("check_barcode_distances", "check-barcode-distances")?;
+        let output_dna = args.is_present("output_dna", "output-dna")?;
+        let output_gzip = args.is_present("output_gzip", "output-gzip")?;
+        let verify = args.is_present("verify", "verify")?;
+        let sample_written_command_option =
+            args.value_of("sample_written_command", "sample-written-command")?;
+        let fold_revcomp = args.is_present("fold_revcomp", "fold-revcomp")?;
+        let per_read_output_option = args.value_of("per_read_output", "per-read-output")?;
+        let name_map_option = args.value_of("name_map", "name-map")?;
+        let exclude_barcodes_option = args.value_of("exclude_barcodes", "exclude-barcodes")?;
+        let exclude_samples_option = args.value_of("exclude_samples", "exclude-samples")?;
+        let valid_combinations_option = args.value_of("valid_combinations", "valid-combinations")?;
+        let check_reverse_complement =
+            args.is_present("check_reverse_complement", "check-reverse-complement")?;
+        let pool_unknown_samples = args.is_present("pool_unknown_samples", "pool-unknown-samples")?;
+        let rarefaction = args.is_present("rarefaction", "rarefaction")?;
+        let dump_index_option = args.value_of("dump_index", "dump-index")?;
+        let load_index_option = args.value_of("load_index", "load-index")?;
+        if dump_index_option.is_some() && load_index_option.is_some() {
+            bail!("--dump-index and --load-index cannot be used together")
+        }
+        let min_match_rate_option = args
+            .value_of("min_match_rate", "min-match-rate")?
+            .map(|min_match_rate| {
+                min_match_rate
+                    .parse::<f32>()
+                    .context("Unable to convert min match rate to a float")
+            })
+            .transpose()?;
+        if let Some(min_match_rate) = min_match_rate_option {
+            if !(0.0..=1.0).contains(&min_match_rate) {
+                bail!("--min-match-rate must be between 0 and 1: {}", min_match_rate)
+            }
+        }
+        let min_match_rate_exit_option = args
+            .value_of("min_match_rate_exit", "min-match-rate-exit")?
+            .map(|min_match_rate| {
+                min_match_rate
+                    .parse::<f32>()
+                    .context("Unable to convert min match rate exit to a float")
+            })
+            .transpose()?;
+        if let Some(min_match_rate) = min_match_rate_exit_option {
+            if !(0.0..=1.0).contains(&min_match_rate) {
+                bail!("--min-match-rate-exit must be between 0 and 1: {}", min_match_rate)
+            }
+        }
+        let fail_on_empty = args.is_present("fail_on_empty", "fail-on-empty")?;
+        let check_after_reads = args
+            .value_of("check_after_reads", "check-after-reads")?
+            .unwrap()
+            .parse::<u32>()
+            .context("Unable to convert check after reads to an integer")?;
+        let top_option = args
+            .value_of("top", "top")?
+            .map(|top| top.parse::<usize>().context("Unable to convert top to an integer"))
+            .transpose()?;
+        let min_umi_option = args
+            .value_of("min_umi", "min-umi")?
+            .map(|min_umi| min_umi.parse::<usize>().context("Unable to convert min umi to an integer"))
+            .transpose()?;
+        let downsample_to_option = args
+            .value_of("downsample_to", "downsample-to")?
+            .map(|downsample_to| {
+                downsample_to
+                    .parse::<usize>()
+                    .context("Unable to convert downsample to reads to an integer")
+            })
+            .transpose()?;
+        let downsample_seed = args
+            .value_of("downsample_seed", "downsample-seed")?
+            .unwrap()
+            .parse::<u64>()
+            .context("Unable to convert downsample seed to an integer")?;
+        // The weighted counts are kept beside the read counts, which are the only counts downsampled
+        if downsample_to_option.is_some() && quality_weighted {
+            bail!("--downsample-to is not supported with --quality-weighted")
+        }
+        let min_correction_margin = args
+            .value_of("min_correction_margin", "min-correction-margin")?
+            .unwrap()
+            .parse::<u16>()
+            .context("Unable to convert min correction margin to an integer")?;
+        let corrector = args.value_of("corrector", "corrector")?.unwrap();
+        let tie_break_seed = args
+            .value_of("tie_break_seed", "tie-break-seed")?
+            .unwrap()
+            .parse::<u64>()
+            .context("Unable to convert tie break seed to an integer")?;
+        let tie_resolution = match args.value_of("tie_break", "tie-break")?.unwrap().as_str() {
+            "discard" => TieResolution::Discard,
+            "first" => TieResolution::KeepLowest,
+            "random" => TieResolution::KeepRandom(tie_break_seed),
+            other => bail!("Tie break must be either 'discard', 'first' or 'random', not '{}'", other),
+        };
+        let delimiter = parse_delimiter(&args.value_of("delimiter", "delimiter")?.unwrap())?;
+        let seq_column_option = args.value_of("seq_column", "seq-column")?;
+        let id_column_option = args.value_of("id_column", "id-column")?;
+        let index_column_option = args.value_of("index_column", "index-column")?;
+        let anchor_end = match args.value_of("anchor", "anchor")?.unwrap().as_str() {
+            "start" => false,
+            "end" => true,
+            other => bail!("Anchor must be either 'start' or 'end', not '{}'", other),
+        };
+        let sort_by = match args.value_of("sort_by", "sort-by")?.unwrap().as_str() {
+            "barcode" => SortBy::Barcode,
+            "count" => SortBy::Count,
+            other => bail!("Sort by must be either 'barcode' or 'count', not '{}'", other),
+        };
+        let write_from_checkpoint_option = args.value_of("write_from_checkpoint", "write-from-checkpoint")?;
+        // Writing from a results checkpoint does not read the fastq
+        let fastq = match args.value_of("fastq", "fastq")? {
+            Some(fastq) => fastq,
+            None if write_from_checkpoint_option.is_some() => String::new(),
+            None => bail!("A fastq file is required with --fastq or within the config file"),
+        };
+        if write_from_checkpoint_option.is_some()
+            && (resume || checkpoint_interval > 0 || per_read_output_option.is_some() || sample_progress_seconds > 0)
+        {
+            bail!("Checkpoints, --resume, --per-read-output and --sample-progress are not supported with --write-from-checkpoint")
+        }
+        // Already demultiplexed fastq files can be given as comma separated sample_name=fastq_file, which labels each file's reads with the sample name
+        let mut fastq_samples = Vec::new();
+        if fastq.contains('=') {
+            for fastq_sample in fastq.split(',') {
+                let (sample_name, fastq_file) = fastq_sample.split_once('=').ok_or_else(|| {
+                    anyhow!("Each fastq needs a sample name as sample_name=fastq_file when any are named: {}", fastq_sample)
+                })?;
+                if sample_name.is_empty() || fastq_file.is_empty() {
+                    bail!("Both the sample name and fastq file are needed for {}", fastq_sample)
+                }
+                fastq_samples.push((sample_name.to_string(), fastq_file.to_string()));
+            }
+            if resume || checkpoint_interval > 0 {
+                bail!("Checkpoints and --resume are not supported with a fastq file per sample")
+            }
+        }
+        let merge_pairs_option = args.value_of("merge_pairs", "merge-pairs")?;
+        let min_overlap = args
+            .value_of("min_overlap", "min-overlap")?
+            .unwrap()
+            .parse::<usize>()
+            .context("Unable to convert min overlap to an integer")?;
+        if merge_pairs_option.is_some() {
+            if !fastq_samples.is_empty() {
+                bail!("--merge-pairs is not supported with a fastq file per sample")
+            }
+            // The checkpoint offset is only within the read 1 fastq
+            if resume || checkpoint_interval > 0 {
+                bail!("Checkpoints and --resume are not supported with --merge-pairs")
+            }
+            if min_overlap == 0 {
+                bail!("--min-overlap needs to be at least 1")
+            }
+        }
+        // The format is either read from a file or given directly as a string, but not both
+        let (format, format_from_string) = match (
+            args.value_of("format_file", "sequence-format")?,
+            args.value_of("format_string", "sequence-format-string")?,
+        ) {
+            (Some(format_file), None) => (format_file, false),
+            (None, Some(format_string)) => (format_string, true),
+            (Some(_), Some(_)) => {
+                bail!("Only one of --sequence-format and --sequence-format-string can be used")
+            }
+            (None, None) => bail!(
+                "A sequence format is required with --sequence-format, --sequence-format-string, or within the config file"
+            ),
+        };
+        let output_dir = args.value_of("dir", "output-dir")?.unwrap();
         let threads = args
-            .value_of("threads")
+            .value_of("threads", "threads")?
             .unwrap()
             .parse::<u16>()
             .context("Unable to convert threads to an integer")?;
-        let prefix = args.value_of("prefix").unwrap().to_string();
+        if threads < 2 {
+            bail!("At least 2 threads are needed, one to read the fastq and one to parse the reads")
+        }
+        let prefix = args.value_of("prefix", "prefix")?.unwrap();
+        let verbosity = if args.is_present("quiet", "quiet")? {
+            Verbosity::Quiet
+        } else if args.is_present("verbose", "verbose")? {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        };
         let min_average_quality_score = args
-            .value_of("min")
+            .value_of("min", "min-quality")?
             .unwrap()
             .parse::<f32>()
             .context("Unable to convert min score to a float")?;
+        let max_expected_errors_option = args
+            .value_of("max_expected_errors", "max-expected-errors")?
+            .map(|max_expected_errors| {
+                max_expected_errors
+                    .parse::<f32>()
+                    .context("Unable to convert max expected errors to a float")
+            })
+            .transpose()?;
+        let quality_offset_value = args.value_of("quality_offset", "quality-offset")?.unwrap();
+        let quality_offset = if quality_offset_value == "auto" && write_from_checkpoint_option.is_some() {
+            // The quality scores were already used by the parse that saved the checkpoint
+            33
+        } else if quality_offset_value == "auto" {
+            let first_fastq = fastq_samples.first().map_or(&fastq, |(_, fastq_file)| fastq_file);
+            let detected_offset = crate::input::detect_quality_offset(first_fastq)?;
+            println!("Detected quality offset: {}\n", detected_offset);
+            detected_offset
+        } else {
+            quality_offset_value
+                .parse::<u8>()
+                .context("Unable to convert quality offset to an integer or 'auto'")?
+        };
+        let skip_format_check = args.is_present("skip_format_check", "skip-format-check")?;
+        args.check_unused()?;
 
         Ok(Args {
             fastq,
+            fastq_samples,
+            merge_pairs_option,
+            min_overlap,
             format,
+            format_from_string,
             sample_barcodes_option,
+            illumina_sample_sheet,
+            pool_sample_names,
             counted_barcodes_option,
             output_dir,
             threads,
             prefix,
+            verbosity,
             merge_output,
+            merge_only,
+            emit_empty_samples,
+            merge_chunk_rows_option,
             barcodes_errors_option,
             sample_errors_option,
+            max_error_fraction_option,
             constant_errors_option,
             min_average_quality_score,
+            max_expected_errors_option,
+            quality_offset,
+            skip_format_check,
             enrich,
+            reference_sample_option,
+            pseudocount,
+            log_base,
+            per_cycle_counts,
+            pairwise_matrix_option,
+            strict_n,
+            cluster_unknown,
+            quality_weighted,
+            checkpoint_interval,
+            resume,
+            umi_bloom_bits,
+            report_reads,
+            umi_composition,
+            umi_hll,
+            dedup_name_regex_option,
+            sample_progress_seconds,
+            show_matches,
+            preview,
+            progress_interval,
+            write_uncorrected,
+            count_unmatched_sample,
+            match_unknown_samples,
+            delimiter,
+            seq_column_option,
+            id_column_option,
+            index_column_option,
+            anchor_end,
+            sort_by,
+            trim_start,
+            trim_end,
+            trim_adapter_option,
+            adapter_errors,
+            max_read_length_option,
+            skip_long_reads,
+            unordered_barcodes,
+            check_barcode_distances,
+            output_dna,
+            output_gzip,
+            min_correction_margin,
+            corrector,
+            tie_resolution,
+            top_option,
+            min_umi_option,
+            downsample_to_option,
+            downsample_seed,
+            min_match_rate_option,
+            min_match_rate_exit_option,
+            fail_on_empty,
+            check_after_reads,
+            dump_index_option,
+            load_index_option,
+            rarefaction,
+            pool_unknown_samples,
+            verify,
+            sample_written_command_option,
+            check_reverse_complement,
+            fold_revcomp,
+            per_read_output_option,
+            name_map_option,
+            exclude_barcodes_option,
+            exclude_samples_option,
+            valid_combinations_option,
+            write_from_checkpoint_option,
+        })
+    }
+}
+
+/// Converts the delimiter argument into the delimiter character.  'tab' or '\t' are converted to a tab
+fn parse_delimiter(delimiter_value: &str) -> Result<char> {
+    if delimiter_value == "tab" || delimiter_value == "\\t" {
+        return Ok('\t');
+    }
+    let mut delimiter_chars = delimiter_value.chars();
+    match (delimiter_chars.next(), delimiter_chars.next()) {
+        (Some(delimiter_char), None) => Ok(delimiter_char),
+        _ => bail!(
+            "Delimiter needs to be a single character or 'tab', not '{}'",
+            delimiter_value
+        ),
+    }
+}
+
+/// Holds the command line argument matches along with the values from the config file, if one was included.  Values
+/// explicitly given on the command line take priority, then the config file values, then the argument defaults
+struct ArgValues<'a> {
+    matches: ArgMatches<'a>,
+    config: toml::value::Table,
+    used_keys: RefCell<Vec<String>>, // config keys that have been looked up.  Used to find misspelled keys
+}
+
+impl<'a> ArgValues<'a> {
+    /// Reads in the config file from the --config argument if it exists
+    fn new(matches: ArgMatches<'a>) -> Result<Self> {
+        let mut config = toml::value::Table::new();
+        if let Some(config_path) = matches.value_of("config") {
+            let config_data = fs::read_to_string(config_path)
+                .context(format!("Failed to open {}", config_path))?;
+            config = config_data
+                .parse::<toml::Value>()
+                .context(format!("Failed to parse config file {}", config_path))?
+                .as_table()
+                .cloned()
+                .ok_or_else(|| anyhow!("Config file {} is not a TOML table", config_path))?;
+        }
+        Ok(ArgValues {
+            matches,
+            config,
+            used_keys: RefCell::new(Vec::new()),
         })
     }
+
+    /// Returns an error if the config file contains any keys that were not used by the arguments, which is likely a misspelling
+    fn check_unused(&self) -> Result<()> {
+        let used_keys = self.used_keys.borrow();
+        let unused_keys = self
+            .config
+            .keys()
+            .filter(|key| !used_keys.contains(key))
+            .cloned()
+            .collect::<Vec<String>>();
+        if !unused_keys.is_empty() {
+            return Err(anyhow!(
+                "Config file contains unknown keys: {}",
+                unused_keys.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the value for the argument name, or config key, as a String
+    fn value_of(&self, name: &str, config_key: &str) -> Result<Option<String>> {
+        self.used_keys.borrow_mut().push(config_key.to_string());
+        if self.matches.occurrences_of(name) == 0 {
+            if let Some(config_value) = self.config.get(config_key) {
+                let value = match config_value {
+                    toml::Value::String(value) => value.to_string(),
+                    toml::Value::Integer(value) => value.to_string(),
+                    toml::Value::Float(value) => value.to_string(),
+                    _ => {
+                        return Err(anyhow!(
+                            "Config value for {} needs to be a string or number",
+                            config_key
+                        ))
+                    }
+                };
+                return Ok(Some(value));
+            }
+        }
+        Ok(self.matches.value_of(name).map(|value| value.to_string()))
+    }
+
+    /// Returns whether the argument flag, or config key set to true, is present
+    fn is_present(&self, name: &str, config_key: &str) -> Result<bool> {
+        self.used_keys.borrow_mut().push(config_key.to_string());
+        if self.matches.is_present(name) {
+            return Ok(true);
+        }
+        match self.config.get(config_key) {
+            Some(toml::Value::Boolean(present)) => Ok(*present),
+            Some(_) => Err(anyhow!(
+                "Config value for {} needs to be true or false",
+                config_key
+            )),
+            None => Ok(false),
+        }
+    }
 }
@@ -1,16 +1,23 @@
-use ahash::{AHashSet, HashMap, HashMapExt};
-use anyhow::{anyhow, Context, Result};
+use ahash::{AHashMap, AHashSet, HashMap, HashMapExt};
+use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
 use num_format::{Locale, ToFormattedString};
-use regex::Regex;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 use std::{
     fmt, fs,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
     sync::{
         atomic::{AtomicU32, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
+use crate::parse::{canonical_barcode, expand_iupac, has_iupac, iupac_bases, reverse_complement, KmerIndex, MismatchIndex};
+
 // Struct to keep track of sequencing errors and correct matches.  This is displayed at the end of the algorithm for QC measures
 #[derive(Debug, Clone)]
 pub struct SequenceErrors {
@@ -20,6 +27,12 @@ pub struct SequenceErrors {
     matched: Arc<AtomicU32>,         // total matched
     duplicates: Arc<AtomicU32>,      // total random barcode duplicates
     low_quality: Arc<AtomicU32>,     // total random barcode duplicates
+    reverse_complement: Arc<AtomicU32>, // matched reads, including duplicates, that were found within the reverse complement of the read
+    malformed: Arc<AtomicU32>,       // malformed reads that were skipped
+    short: Arc<AtomicU32>,           // reads shorter than the sequence format
+    adapter_trimmed: Arc<AtomicU32>, // reads with adapter sequence removed before matching
+    long: Arc<AtomicU32>,            // reads longer than the maximum read length that were skipped
+    tied: Arc<AtomicU32>,            // sample and counted barcodes with more than one closest known barcode, whether or not the tie was kept
 }
 
 impl Default for SequenceErrors {
@@ -45,6 +58,12 @@ impl SequenceErrors {
             matched: Arc::new(AtomicU32::new(0)),
             duplicates: Arc::new(AtomicU32::new(0)),
             low_quality: Arc::new(AtomicU32::new(0)),
+            reverse_complement: Arc::new(AtomicU32::new(0)),
+            malformed: Arc::new(AtomicU32::new(0)),
+            short: Arc::new(AtomicU32::new(0)),
+            adapter_trimmed: Arc::new(AtomicU32::new(0)),
+            long: Arc::new(AtomicU32::new(0)),
+            tied: Arc::new(AtomicU32::new(0)),
         }
     }
 
@@ -113,6 +132,24 @@ impl SequenceErrors {
         self.duplicates.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Moves reads from correct matches to duplicates.  Used when merging a thread's counts finds random barcodes that another thread
+    /// already counted
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.correct_match();
+    /// sequence_errors.correct_match();
+    /// sequence_errors.recount_duplicates(1);
+    /// assert_eq!(sequence_errors.matched_reads(), 2);
+    /// ```
+    pub fn recount_duplicates(&mut self, duplicates: u32) {
+        self.matched.fetch_sub(duplicates, Ordering::Relaxed);
+        self.duplicates.fetch_add(duplicates, Ordering::Relaxed);
+    }
+
     /// Add one to low_quality
     ///
     /// # Example
@@ -126,6 +163,170 @@ impl SequenceErrors {
         self.low_quality.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Add one to the matched reads found within the reverse complement of the read
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.reverse_complement_match();
+    /// ```
+    pub fn reverse_complement_match(&mut self) {
+        self.reverse_complement.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add one to the malformed reads that were skipped
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.malformed_read();
+    /// ```
+    pub fn malformed_read(&mut self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add one to the reads shorter than the sequence format
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.short_read();
+    /// ```
+    pub fn short_read(&mut self) {
+        self.short.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add one to the reads with adapter sequence removed
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.adapter_trimmed_read();
+    /// ```
+    pub fn adapter_trimmed_read(&mut self) {
+        self.adapter_trimmed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add one to the reads longer than the maximum read length that were skipped
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.long_read();
+    /// ```
+    pub fn long_read(&mut self) {
+        self.long.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add the sample and counted barcodes of a read that were tied between more than one closest known barcode
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.tied_barcodes(2);
+    /// ```
+    pub fn tied_barcodes(&mut self, tied_barcodes: u32) {
+        if tied_barcodes > 0 {
+            self.tied.fetch_add(tied_barcodes, Ordering::Relaxed);
+        }
+    }
+
+    /// Packs the error counts into a tab separated string.  Used to save checkpoints
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.correct_match();
+    /// sequence_errors.barcode_error();
+    /// let resumed_errors = SequenceErrors::new();
+    /// resumed_errors.unpack(&sequence_errors.pack()).unwrap();
+    /// assert_eq!(resumed_errors.pack(), sequence_errors.pack());
+    /// ```
+    pub fn pack(&self) -> String {
+        [
+            &self.constant_region,
+            &self.sample_barcode,
+            &self.barcode,
+            &self.matched,
+            &self.duplicates,
+            &self.low_quality,
+            &self.reverse_complement,
+            &self.malformed,
+            &self.short,
+            &self.adapter_trimmed,
+            &self.long,
+            &self.tied,
+        ]
+        .iter()
+        .map(|count| count.load(Ordering::Relaxed).to_string())
+        .join("\t")
+    }
+
+    /// Returns the number of reads that matched the format with fixed barcodes, including random barcode duplicates
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceErrors;
+    ///
+    /// let mut sequence_errors = SequenceErrors::new();
+    /// sequence_errors.correct_match();
+    /// sequence_errors.duplicated();
+    /// sequence_errors.barcode_error();
+    /// assert_eq!(sequence_errors.matched_reads(), 2);
+    /// ```
+    pub fn matched_reads(&self) -> u32 {
+        self.matched.load(Ordering::Relaxed) + self.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Sets the error counts from a string created with pack.  Used to resume from checkpoints
+    pub fn unpack(&self, packed: &str) -> Result<()> {
+        let counts = packed
+            .split('\t')
+            .map(|count| count.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .context(format!("Unable to convert sequence errors to integers: {}", packed))?;
+        let fields = [
+            &self.constant_region,
+            &self.sample_barcode,
+            &self.barcode,
+            &self.matched,
+            &self.duplicates,
+            &self.low_quality,
+            &self.reverse_complement,
+            &self.malformed,
+            &self.short,
+            &self.adapter_trimmed,
+            &self.long,
+            &self.tied,
+        ];
+        if counts.len() != fields.len() {
+            return Err(anyhow!(
+                "Expected {} sequence error counts but found {}: {}",
+                fields.len(),
+                counts.len(),
+                packed
+            ));
+        }
+        for (field, count) in fields.iter().zip(counts) {
+            field.store(count, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
     pub fn arc_clone(&self) -> SequenceErrors {
         SequenceErrors {
             constant_region: Arc::clone(&self.constant_region),
@@ -134,6 +335,12 @@ impl SequenceErrors {
             matched: Arc::clone(&self.matched),
             duplicates: Arc::clone(&self.duplicates),
             low_quality: Arc::clone(&self.low_quality),
+            reverse_complement: Arc::clone(&self.reverse_complement),
+            malformed: Arc::clone(&self.malformed),
+            short: Arc::clone(&self.short),
+            adapter_trimmed: Arc::clone(&self.adapter_trimmed),
+            long: Arc::clone(&self.long),
+            tied: Arc::clone(&self.tied),
         }
     }
 }
@@ -148,7 +355,9 @@ impl fmt::Display for SequenceErrors {
             Sample barcode mismatches:   {}\n\
             Counted barcode mismatches:  {}\n\
             Duplicates:                  {}\n\
-            Low quality barcodes:        {}",
+            Low quality barcodes:        {}\n\
+            Reads shorter than format:   {}\n\
+            Malformed reads skipped:     {}",
             self.matched
                 .load(Ordering::Relaxed)
                 .to_formatted_string(&Locale::en),
@@ -165,12 +374,110 @@ impl fmt::Display for SequenceErrors {
                 .load(Ordering::Relaxed)
                 .to_formatted_string(&Locale::en),
             self.low_quality
+                .load(Ordering::Relaxed)
+                .to_formatted_string(&Locale::en),
+            self.short
+                .load(Ordering::Relaxed)
+                .to_formatted_string(&Locale::en),
+            self.malformed
                 .load(Ordering::Relaxed)
                 .to_formatted_string(&Locale::en)
-        )
+        )?;
+        // Only shown when an adapter was given and found within some reads
+        let adapter_trimmed = self.adapter_trimmed.load(Ordering::Relaxed);
+        if adapter_trimmed > 0 {
+            write!(
+                f,
+                "\n\
+                Adapter trimmed reads:       {}",
+                adapter_trimmed.to_formatted_string(&Locale::en)
+            )?;
+        }
+        // Only shown when long reads are skipped and some were longer than the maximum read length
+        let long = self.long.load(Ordering::Relaxed);
+        if long > 0 {
+            write!(
+                f,
+                "\n\
+                Reads longer than maximum:   {}",
+                long.to_formatted_string(&Locale::en)
+            )?;
+        }
+        // Only shown when some barcodes were tied between known barcodes
+        let tied = self.tied.load(Ordering::Relaxed);
+        if tied > 0 {
+            write!(
+                f,
+                "\n\
+                Tied barcode fixes:          {}",
+                tied.to_formatted_string(&Locale::en)
+            )?;
+        }
+        // Only shown when reads were checked in both orientations and some were found in the reverse complement
+        let reverse_complement = self.reverse_complement.load(Ordering::Relaxed);
+        if reverse_complement > 0 {
+            write!(
+                f,
+                "\n\
+                Forward matches:             {}\n\
+                Reverse complement matches:  {}",
+                self.matched_reads().saturating_sub(reverse_complement).to_formatted_string(&Locale::en),
+                reverse_complement.to_formatted_string(&Locale::en)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem found with a finished run, checked after the counts files are written.  Each has its own exit code so that a pipeline can
+/// branch on the exit status of the run
+#[derive(Debug, PartialEq)]
+pub enum RunCheckFailure {
+    NoMatchedReads,
+    LowMatchRate { match_rate: f64, min_match_rate: f32 },
+    EmptySamples(Vec<String>), // the samples without any counts
+}
+
+impl RunCheckFailure {
+    /// Returns the exit code of the failure.  1 is left for all other errors
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::RunCheckFailure;
+    ///
+    /// assert_eq!(RunCheckFailure::NoMatchedReads.exit_code(), 2);
+    /// assert_eq!(RunCheckFailure::EmptySamples(vec!["S1".to_string()]).exit_code(), 4);
+    /// ```
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunCheckFailure::NoMatchedReads => 2,
+            RunCheckFailure::LowMatchRate { .. } => 3,
+            RunCheckFailure::EmptySamples(_) => 4,
+        }
+    }
+}
+
+impl fmt::Display for RunCheckFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunCheckFailure::NoMatchedReads => write!(f, "No reads matched"),
+            RunCheckFailure::LowMatchRate {
+                match_rate,
+                min_match_rate,
+            } => write!(
+                f,
+                "The match rate of {:.4} is below the --min-match-rate-exit of {}",
+                match_rate, min_match_rate
+            ),
+            RunCheckFailure::EmptySamples(samples) => {
+                write!(f, "Samples without any counts: {}", samples.join(", "))
+            }
+        }
     }
 }
 
+impl std::error::Error for RunCheckFailure {}
+
 // Struct to keep the format information for the sequencing, ie barcodes, regex search etc.
 #[derive(Debug, Clone)]
 pub struct SequenceFormat {
@@ -183,7 +490,13 @@ pub struct SequenceFormat {
     pub barcode_lengths: Vec<u16>,   // The length of each counted barcode
     pub sample_length_option: Option<u16>, // Sample barcode length
     pub random_barcode: bool,        // Whether a random barcode is included
+    pub random_barcode_num: usize,   // Number of random barcode regions, which are joined in order into one random barcode
     pub sample_barcode: bool,        // Whether a sammple barcode is included
+    pub spacer_num: usize,           // Number of variable length spacers.  These are left out of the format and regions strings
+    pub variant_num: usize,          // Number of reported constant regions, whose sequences are counted as extra columns after the counted barcodes
+    pub variant_alternatives: Vec<Vec<String>>, // The alternative sequences of each reported constant region.  Empty for a '<>' region
+    pub variant_starts: Vec<usize>,  // Where each reported constant region starts within the format string
+    pub match_example: String,       // The format string with an 'A' for each 'N' and the first alternative of each alternative constant region, which the regex matches
 }
 
 impl SequenceFormat {
@@ -207,16 +520,58 @@ impl SequenceFormat {
             barcode_lengths: Vec::new(),
             sample_length_option: None,
             random_barcode: false,
+            random_barcode_num: 0,
             sample_barcode: false,
+            spacer_num: 0,
+            variant_num: 0,
+            variant_alternatives: Vec::new(),
+            variant_starts: Vec::new(),
+            match_example: String::new(),
         })
     }
     /// Parses the format file into all fields of the SequenceFormat struct, including the regex
     /// search, barcode sizes, and sequence format strings.
     pub fn parse_format_file(format_path: &str) -> Result<Self> {
-        let mut sequence_format = SequenceFormat::new()?;
         // Read sequence format file to string
         let format_data = fs::read_to_string(format_path)
-            .context(format!("Failed to open {}", format_path))?
+            .context(format!("Failed to open {}", format_path))?;
+        SequenceFormat::parse_format_str(&format_data)
+    }
+
+    /// Parses the format data, which is the contents of a format file or a format given on the command line, into all fields of the
+    /// SequenceFormat struct.  Lines that start with '#' are ignored
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceFormat;
+    ///
+    /// let sequence_format = SequenceFormat::parse_format_str("[8]AGCT{6}TGGA{6}").unwrap();
+    /// assert_eq!(sequence_format.format_string, "NNNNNNNNAGCTNNNNNNTGGANNNNNN");
+    /// assert_eq!(sequence_format.barcode_num, 2);
+    /// assert_eq!(sequence_format.sample_length_option, Some(8));
+    ///
+    /// // A random barcode split across a constant region is joined into one random barcode
+    /// let split_random_format = SequenceFormat::parse_format_str("(4)AGCT(4){6}").unwrap();
+    /// assert_eq!(split_random_format.random_barcode_num, 2);
+    ///
+    /// // A variable spacer of up to 3 of any base is left out of the format string, which is the shortest format
+    /// let spacer_format = SequenceFormat::parse_format_str("[8]AGCT~3{6}").unwrap();
+    /// assert_eq!(spacer_format.format_string, "NNNNNNNNAGCTNNNNNN");
+    /// assert_eq!(spacer_format.spacer_num, 1);
+    ///
+    /// // A reported constant region, within '<>', can have 'N's and its sequence is counted with the barcodes
+    /// let variant_format = SequenceFormat::parse_format_str("[8]<AGNN>{6}").unwrap();
+    /// assert_eq!(variant_format.format_string, "NNNNNNNNAGNNNNNNNN");
+    /// assert_eq!(variant_format.variant_num, 1);
+    ///
+    /// // Alternative constant regions separated by '|' match any of the sequences, and the one matched is counted with the barcodes
+    /// let construct_format = SequenceFormat::parse_format_str("[8]AGCT|TTGA{6}").unwrap();
+    /// assert_eq!(construct_format.format_string, "NNNNNNNNNNNNNNNNNN");
+    /// assert_eq!(construct_format.variant_alternatives, vec![vec!["AGCT".to_string(), "TTGA".to_string()]]);
+    /// ```
+    pub fn parse_format_str(format_data: &str) -> Result<Self> {
+        let mut sequence_format = SequenceFormat::new()?;
+        let format_data = format_data
             .lines() // split into lines
             .filter(|line| !line.starts_with('#')) // remove any line that starts with '#'
             .collect::<String>(); // collect into a String
@@ -227,9 +582,10 @@ impl SequenceFormat {
         let digit_search = Regex::new(r"\d+")?;
         // Search groups separated by '|' or statements in order to iterate through each group
         // within the format data from the format file and create the regex search string, along
-        // with add the other needed information.  Uses the {#}, [#], (#), [ATGC], and 'N's as
+        // with add the other needed information.  Uses the {#}, [#], (#), ~#, <[ATGCN]>, [ATGC]|[ATGC], [ATGC], and 'N's as
         // groups
-        let barcode_search = Regex::new(r"(?i)(\{\d+\})|(\[\d+\])|(\(\d+\))|N+|[ATGC]+")?;
+        let barcode_search =
+            Regex::new(r"(?i)(\{\d+\})|(\[\d+\])|(\(\d+\))|(~\d+)|(<[ATGCN]+>)|([ATGC]+(\|[ATGC]+)+)|N+|[ATGC]+")?;
         for group in barcode_search.find_iter(&format_data) {
             let group_str = group.as_str();
             // Holds the capture group name.  Is non-barcode regions
@@ -244,7 +600,8 @@ impl SequenceFormat {
                 sequence_format.barcode_num += 1;
                 group_name_option = Some(format!("barcode{}", sequence_format.barcode_num));
             } else if group_str.contains('(') {
-                group_name_option = Some("random".to_string());
+                sequence_format.random_barcode_num += 1;
+                group_name_option = Some(format!("random{}", sequence_format.random_barcode_num));
                 sequence_format.random_barcode = true;
             }
 
@@ -275,7 +632,7 @@ impl SequenceFormat {
                 } else if group_name.contains("barcode") {
                     sequence_format.barcode_lengths.push(digits);
                     push_char = 'B'
-                } else if group_name == "random" {
+                } else if group_name.contains("random") {
                     push_char = 'R'
                 }
                 // For the number of nucleotides of the barcode add 'N's to format string and the
@@ -284,6 +641,56 @@ impl SequenceFormat {
                     sequence_format.regions_string.push(push_char);
                     sequence_format.format_string.push('N')
                 }
+            } else if group_str.starts_with('~') {
+                // A variable spacer of 0 up to the given number of any nucleotide.  It is captured so that the region codes of each
+                // read can be found from where the spacer landed, but it is not added to the format or regions strings
+                sequence_format.spacer_num += 1;
+                let max_spacer_length = digit_search.find(group_str).unwrap().as_str();
+                regex_string.push_str(&format!(
+                    "(?P<spacer{}>.{{0,{}}})",
+                    sequence_format.spacer_num, max_spacer_length
+                ));
+            } else if group_str.starts_with('<') {
+                // A reported constant region, which is captured so that its sequence can be counted along with the counted barcodes.
+                // Any 'N's within it match any nucleotide
+                sequence_format.variant_num += 1;
+                let variant_bases = group_str.trim_matches(|bracket| bracket == '<' || bracket == '>').to_uppercase();
+                regex_string.push_str(&format!(
+                    "(?P<variant{}>{})",
+                    sequence_format.variant_num,
+                    variant_bases.replace('N', "[AGCT]")
+                ));
+                sequence_format.variant_starts.push(sequence_format.format_string.len());
+                sequence_format.format_string.push_str(&variant_bases);
+                for _ in 0..variant_bases.len() {
+                    sequence_format.regions_string.push('C');
+                }
+                sequence_format.constant_region_length += variant_bases.matches(|base| base != 'N').count() as u16;
+                sequence_format.variant_alternatives.push(Vec::new());
+            } else if group_str.contains('|') {
+                // Alternative constant regions, such as one of several constructs.  The alternative matched is counted along with the
+                // counted barcodes, the same as a reported constant region.  They are 'N's within the format string so that a constant
+                // region fix never changes one alternative into another
+                let alternatives = group_str
+                    .split('|')
+                    .map(|alternative| alternative.to_uppercase())
+                    .collect::<Vec<String>>();
+                let alternative_length = alternatives[0].len();
+                if alternatives.iter().any(|alternative| alternative.len() != alternative_length) {
+                    bail!("The alternative constant regions {} need to be the same length", group_str)
+                }
+                sequence_format.variant_num += 1;
+                regex_string.push_str(&format!(
+                    "(?P<variant{}>{})",
+                    sequence_format.variant_num,
+                    alternatives.join("|")
+                ));
+                sequence_format.variant_starts.push(sequence_format.format_string.len());
+                for _ in 0..alternative_length {
+                    sequence_format.regions_string.push('C');
+                    sequence_format.format_string.push('N');
+                }
+                sequence_format.variant_alternatives.push(alternatives);
             } else if group_str.contains('N') {
                 // Used to handle if 'N's are added to the format file.  These will be treated as
                 // 'any' nucleotide for error handling and matching
@@ -292,11 +699,15 @@ impl SequenceFormat {
                 n_group.push_str(&num_of_ns.to_string());
                 n_group.push('}');
                 regex_string.push_str(&n_group);
-                sequence_format.format_string.push_str(group_str);
+                sequence_format
+                    .format_string
+                    .push_str(&group_str.to_uppercase());
             } else {
                 // Any A,G,C, or T is treated as constant region here
                 regex_string.push_str(&group_str.to_uppercase());
-                sequence_format.format_string.push_str(group_str);
+                sequence_format
+                    .format_string
+                    .push_str(&group_str.to_uppercase());
                 let constant_group_length = group_str.chars().count();
                 for _ in 0..constant_group_length {
                     sequence_format.regions_string.push('C');
@@ -305,9 +716,101 @@ impl SequenceFormat {
             }
         }
         sequence_format.length = sequence_format.format_string.chars().count();
+        sequence_format.match_example = sequence_format.format_string.replace('N', "A");
+        for (variant_start, alternatives) in sequence_format
+            .variant_starts
+            .iter()
+            .zip(&sequence_format.variant_alternatives)
+        {
+            if let Some(first_alternative) = alternatives.first() {
+                sequence_format.match_example.replace_range(
+                    *variant_start..variant_start + first_alternative.len(),
+                    first_alternative,
+                );
+            }
+        }
         sequence_format.format_regex = Regex::new(&regex_string)?;
+        sequence_format.check_capture_groups()?;
         Ok(sequence_format)
     }
+
+    /// Checks that the regex search finds every barcode group expected from the format file by matching against the match example, which
+    /// is the format string with an 'A' in place of each 'N' and the first of any alternative constant regions.  This fails at startup
+    /// instead of within a processing thread when the format file is malformed
+    pub fn check_capture_groups(&self) -> Result<()> {
+        let captures = self.format_regex.captures(&self.match_example).ok_or_else(|| {
+            anyhow!(
+                "The sequence format regex {} does not match the format {}.  Check the sequence format file",
+                self.format_regex,
+                self.format_string
+            )
+        })?;
+        let mut expected_groups = (1..=self.barcode_num)
+            .map(|barcode_index| format!("barcode{}", barcode_index))
+            .collect::<Vec<String>>();
+        if self.sample_barcode {
+            expected_groups.push("sample".to_string());
+        }
+        for random_index in 1..=self.random_barcode_num {
+            expected_groups.push(format!("random{}", random_index));
+        }
+        for variant_index in 1..=self.variant_num {
+            expected_groups.push(format!("variant{}", variant_index));
+        }
+        let missing_groups = expected_groups
+            .iter()
+            .filter(|group_name| captures.name(group_name).is_none())
+            .cloned()
+            .collect::<Vec<String>>();
+        if !missing_groups.is_empty() {
+            bail!(
+                "The sequence format regex {} is missing the barcode groups {}.  Check the sequence format file",
+                self.format_regex,
+                missing_groups.join(", ")
+            )
+        }
+        Ok(())
+    }
+
+    /// Creates the regions string for one read's match, which is needed when the format has variable spacers since the barcodes are no
+    /// longer at fixed positions.  Each barcode is coded as within the regions string, the spacers are coded with 'V' and everything
+    /// else within the match, including reported constant regions, is 'C'
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::SequenceFormat;
+    ///
+    /// let sequence_format = SequenceFormat::parse_format_str("[2]AG~3{2}").unwrap();
+    /// let barcodes = sequence_format.format_regex.captures("TTAGCCAC").unwrap();
+    /// assert_eq!(sequence_format.match_regions(&barcodes), "SSCCVVBB");
+    /// ```
+    pub fn match_regions(&self, barcodes: &Captures) -> String {
+        let whole_match = barcodes.get(0).unwrap();
+        let mut regions = vec!['C'; whole_match.as_str().chars().count()];
+        for group_name in self.format_regex.capture_names().flatten() {
+            let region_code = if group_name == "sample" {
+                'S'
+            } else if group_name.starts_with("barcode") {
+                'B'
+            } else if group_name.starts_with("random") {
+                'R'
+            } else if group_name.starts_with("spacer") {
+                'V'
+            } else {
+                'C'
+            };
+            if let Some(group_match) = barcodes.name(group_name) {
+                for region in regions
+                    .iter_mut()
+                    .take(group_match.end() - whole_match.start())
+                    .skip(group_match.start() - whole_match.start())
+                {
+                    *region = region_code;
+                }
+            }
+        }
+        regions.into_iter().collect()
+    }
 }
 
 impl fmt::Display for SequenceFormat {
@@ -326,6 +829,12 @@ impl fmt::Display for SequenceFormat {
                 key.push_str(key_info);
             }
         }
+        if self.spacer_num > 0 {
+            key.push_str(&format!(
+                "\n{} variable spacer(s) left out of the format above",
+                self.spacer_num
+            ));
+        }
         write!(
             f,
             "-FORMAT-\n{}\n{}{}",
@@ -334,12 +843,226 @@ impl fmt::Display for SequenceFormat {
     }
 }
 
+/// The known sequences of a counted barcode, along with a precomputed index for fixing a single mismatch when only one is allowed
+#[derive(Clone)]
+pub struct CountedBarcodeSeqs {
+    pub seqs: AHashSet<String>,
+    pub iupac_barcodes: AHashMap<String, String>, // each sequence expanded from a barcode with IUPAC codes: the barcode as listed.  Empty without IUPAC codes
+    pub mismatch_index: Option<Arc<MismatchIndex>>, // shared between the processing threads since it can be large
+    pub kmer_index: Option<Arc<KmerIndex>>, // finds the known barcodes close enough to compare when more mismatches or a margin are allowed
+}
+
+/// Parses the [Data] section of an Illumina sample sheet into (sample barcode, Sample_ID) pairs.  The sample barcode is the index column
+/// followed by the index2 column when it is included.  The other sections, and rows without an index, are skipped
+///
+/// # Example
+/// ```
+/// use barcode_count::info::parse_illumina_sample_sheet;
+///
+/// let sample_sheet = "[Header]\nIEMFileVersion,4\n\n[Data]\nSample_ID,Sample_Name,index,index2\nS1,treated,agcatac,TTGACA\nS2,control,AACTTAC,GGCATA\n,,,\n";
+/// let samples = parse_illumina_sample_sheet(sample_sheet).unwrap();
+/// assert_eq!(samples, vec![("AGCATACTTGACA".to_string(), "S1".to_string()), ("AACTTACGGCATA".to_string(), "S2".to_string())]);
+/// ```
+pub fn parse_illumina_sample_sheet(sample_sheet: &str) -> Result<Vec<(String, String)>> {
+    let mut lines = sample_sheet
+        .lines()
+        .map(|line| line.trim())
+        .skip_while(|line| !line.to_lowercase().starts_with("[data]"))
+        .skip(1);
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow!("No [Data] section with a header was found"))?
+        .split(',')
+        .map(|column| column.trim().to_lowercase())
+        .collect::<Vec<String>>();
+    let column_index = |column_name: &str| header.iter().position(|column| column == column_name);
+    let sample_id_index =
+        column_index("sample_id").ok_or_else(|| anyhow!("The [Data] section does not have a Sample_ID column"))?;
+    let index_index =
+        column_index("index").ok_or_else(|| anyhow!("The [Data] section does not have an index column"))?;
+    let index2_index_option = column_index("index2");
+
+    let mut samples = Vec::new();
+    // The data continues until the next section or the end of the file
+    for line in lines.take_while(|line| !line.starts_with('[')) {
+        let values = line.split(',').map(|value| value.trim()).collect::<Vec<&str>>();
+        let index = values.get(index_index).copied().unwrap_or_default();
+        if index.is_empty() {
+            continue;
+        }
+        let mut barcode = index.to_uppercase();
+        if let Some(index2) = index2_index_option.and_then(|index2_index| values.get(index2_index)) {
+            barcode.push_str(&index2.to_uppercase());
+        }
+        let sample_id = values.get(sample_id_index).copied().unwrap_or_default();
+        samples.push((barcode, sample_id.to_string()));
+    }
+    Ok(samples)
+}
+
+/// Parses a name map of sample ID and output file basename pairs, separated by the delimiter and after a header line.  Used to name the
+/// counts files of the samples for downstream tools.  Each basename needs to be different and cannot contain a path separator
+///
+/// # Example
+/// ```
+/// use barcode_count::info::parse_name_map;
+///
+/// let name_map = parse_name_map("Sample_ID,Basename\nS1,plate1_A01\nS2,plate1_A02\n", ',').unwrap();
+/// assert_eq!(name_map["S1"], "plate1_A01");
+/// assert!(parse_name_map("Sample_ID,Basename\nS1,plate1\nS2,plate1\n", ',').is_err());
+/// ```
+pub fn parse_name_map(name_map_data: &str, delimiter: char) -> Result<HashMap<String, String>> {
+    let mut name_map = HashMap::new();
+    let mut basenames = AHashSet::new();
+    for line in name_map_data.lines().skip(1).filter(|line| !line.trim().is_empty()) {
+        let (sample_id, basename) = line
+            .split(delimiter)
+            .map(|value| value.trim())
+            .collect_tuple()
+            .ok_or_else(|| anyhow!("Each name map row needs a sample ID and a basename: {}", line))?;
+        if basename.is_empty() || basename.contains(['/', '\\']) {
+            bail!("The name map basename for {} needs to be a file name without a directory: '{}'", sample_id, basename)
+        }
+        if !basenames.insert(basename.to_string()) {
+            bail!("The name map basename {} is used for more than one sample", basename)
+        }
+        if name_map.insert(sample_id.to_string(), basename.to_string()).is_some() {
+            bail!("The name map has more than one basename for {}", sample_id)
+        }
+    }
+    Ok(name_map)
+}
+
+/// Parses a list of barcodes or samples to exclude from the counts, with one on each line and no header.  Only the first value of each
+/// line, before the delimiter, is used so that rows of the barcode files can be copied into the list
+///
+/// # Example
+/// ```
+/// use barcode_count::info::parse_exclusion_list;
+///
+/// let excluded = parse_exclusion_list("spike_in\nACGTAC,control,1\n\n", ',');
+/// assert!(excluded.contains("spike_in"));
+/// assert!(excluded.contains("ACGTAC"));
+/// assert_eq!(excluded.len(), 2);
+/// ```
+pub fn parse_exclusion_list(exclusion_data: &str, delimiter: char) -> AHashSet<String> {
+    exclusion_data
+        .lines()
+        .filter_map(|line| line.split(delimiter).next())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .collect()
+}
+
+/// Parses a list of the expected combinations of counted barcodes, with one combination on each line and no header.  The counted barcodes
+/// of each combination are separated by the delimiter, in the order of the sequence format, and are either all sequences or all IDs.  Each
+/// combination is kept as its combination_key
+///
+/// # Example
+/// ```
+/// use barcode_count::info::parse_combinations_list;
+///
+/// let combinations = parse_combinations_list("a1,b1,c1\nCAGAGA,TTGACC,GGATCC\n\n", ',', false);
+/// assert!(combinations.contains("a1,b1,c1"));
+/// assert!(combinations.contains("CAGAGA,TTGACC,GGATCC"));
+/// assert_eq!(combinations.len(), 2);
+///
+/// let unordered_combinations = parse_combinations_list("b1,a1\n", ',', true);
+/// assert!(unordered_combinations.contains("a1,b1"));
+/// ```
+pub fn parse_combinations_list(combinations_data: &str, delimiter: char, unordered: bool) -> AHashSet<String> {
+    combinations_data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| combination_key(line.split(delimiter), unordered))
+        .collect()
+}
+
+/// Joins the counted barcodes of a combination with commas so that combinations from files and from the counts can be compared.  The
+/// barcodes are sorted when they are unordered, since then their order within the read does not matter
+pub fn combination_key<'a, I>(barcodes: I, unordered: bool) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut barcodes = barcodes.into_iter().map(|barcode| barcode.trim()).collect::<Vec<&str>>();
+    if unordered {
+        barcodes.sort_unstable();
+    }
+    barcodes.join(",")
+}
+
+/// The header names of the sequence, ID and index columns of the sample and counted barcode files.  A column without a name is found by
+/// its position, which is first for the sequence, second for the ID and third for the index
+#[derive(Clone, Default)]
+pub struct BarcodeColumns {
+    pub seq_column_option: Option<String>,
+    pub id_column_option: Option<String>,
+    pub index_column_option: Option<String>, // the counted barcode number column, or the group column of the sample barcode file
+}
+
+impl BarcodeColumns {
+    /// Finds the positions of the sequence, ID and index columns within the delimiter separated header.  The names are matched ignoring
+    /// case and surrounding whitespace.  A name can be comma separated names, where the first found is used, for when the sample and
+    /// counted barcode files have different headers.  The index column is optional within the sample barcode file, so it is None when
+    /// its name is not found
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::info::BarcodeColumns;
+    ///
+    /// let default_columns = BarcodeColumns::default();
+    /// let named_columns = BarcodeColumns {
+    ///     seq_column_option: Some("Sequence".to_string()),
+    ///     id_column_option: Some("Sample_ID,name".to_string()),
+    ///     index_column_option: None,
+    /// };
+    ///
+    /// assert_eq!(default_columns.positions("notes,name,sequence", ',').unwrap(), (0, 1, Some(2)));
+    /// assert_eq!(named_columns.positions("notes,name,sequence", ',').unwrap(), (2, 1, Some(2)));
+    /// assert!(named_columns.positions("notes,id,sequence", ',').is_err());
+    /// ```
+    pub fn positions(&self, header: &str, delimiter: char) -> Result<(usize, usize, Option<usize>)> {
+        let header_columns = header
+            .split(delimiter)
+            .map(|column| column.trim().to_lowercase())
+            .collect::<Vec<String>>();
+        let position = |column_option: &Option<String>, default_position: usize| -> Option<usize> {
+            match column_option {
+                Some(column_names) => column_names.split(',').find_map(|column_name| {
+                    header_columns
+                        .iter()
+                        .position(|column| *column == column_name.trim().to_lowercase())
+                }),
+                None => Some(default_position),
+            }
+        };
+        let required_position = |column_option: &Option<String>, default_position: usize| -> Result<usize> {
+            position(column_option, default_position).ok_or_else(|| {
+                anyhow!(
+                    "Column {} is not within the header: {}",
+                    column_option.as_deref().unwrap_or_default(),
+                    header
+                )
+            })
+        };
+        Ok((
+            required_position(&self.seq_column_option, 0)?,
+            required_position(&self.id_column_option, 1)?,
+            position(&self.index_column_option, 2),
+        ))
+    }
+}
+
 /// Contains all possible barcode sequences for error handling and barcode to ID conversion
+#[derive(Clone)]
 pub struct BarcodeConversions {
     pub samples_barcode_hash: HashMap<String, String>,
     pub sample_seqs: AHashSet<String>,
+    pub sample_groups: HashMap<String, String>, // sample barcode -> group name, for technical replicates counted together.  Empty without a group column
     pub counted_barcodes_hash: Vec<HashMap<String, String>>,
-    pub counted_barcode_seqs: Vec<AHashSet<String>>,
+    pub counted_barcode_seqs: Vec<CountedBarcodeSeqs>,
 }
 
 impl Default for BarcodeConversions {
@@ -349,39 +1072,182 @@ impl Default for BarcodeConversions {
 }
 
 impl BarcodeConversions {
+    const MAX_IUPAC_EXPANSIONS: usize = 4096; // sequences that one IUPAC barcode can stand for, so that a barcode such as all 'R's is not expanded
+
     /// Creates an empty BarcodeConversions struct
     pub fn new() -> Self {
         BarcodeConversions {
             samples_barcode_hash: HashMap::new(),
             sample_seqs: AHashSet::new(),
+            sample_groups: HashMap::new(),
             counted_barcodes_hash: Vec::new(),
             counted_barcode_seqs: Vec::new(),
         }
     }
 
-    /// Reads in comma separated barcode file (CSV).  The columns need to have headers.  The first column needs to be the nucleotide barcode
-    /// and the second needs to be the ID
-    pub fn sample_barcode_file_conversion(&mut self, barcode_path: &str) -> Result<()> {
+    /// Reads in delimiter separated barcode file, comma separated (CSV) by default.  The columns need to have headers.  By default the first
+    /// column needs to be the nucleotide barcode and the second needs to be the ID.  An optional third column is the group of technical
+    /// replicates the barcode belongs to.  Columns named within barcode_columns are found by their header instead
+    pub fn sample_barcode_file_conversion(
+        &mut self,
+        barcode_path: &str,
+        delimiter: char,
+        barcode_columns: &BarcodeColumns,
+    ) -> Result<()> {
         // read in the sample barcode file
-        for (barcode, sample_id) in fs::read_to_string(barcode_path)
-            .context(format!("Failed to open {}", barcode_path))?
-            .lines() // split the lines
-            .skip(1) // skip the first line which should be the header
-            .map(|line| {
-                line.split(',')
-                    .take(2) // take only the first two values, or columns
-                    .map(|value| value.to_string())
-                    .collect_tuple()
-                    .unwrap_or(("".to_string(), "".to_string()))
-            })
+        let barcode_file = fs::read_to_string(barcode_path).context(format!("Failed to open {}", barcode_path))?;
+        let mut lines = barcode_file.lines();
+        let (seq_position, id_position, group_position) = barcode_columns
+            .positions(lines.next().unwrap_or_default(), delimiter)
+            .context(format!("Unable to find the columns of {}", barcode_path))?;
+        for line in lines {
+            let values = line.split(delimiter).collect::<Vec<&str>>();
+            let barcode = values.get(seq_position).copied().unwrap_or_default().to_uppercase();
+            let sample_id = values.get(id_position).copied().unwrap_or_default().to_string();
+            if let Some(group) = group_position
+                .and_then(|group_position| values.get(group_position))
+                .filter(|group| !group.is_empty())
+            {
+                self.sample_groups.insert(barcode.clone(), group.to_string());
+            }
+            self.samples_barcode_hash.insert(barcode, sample_id);
+        }
+        Ok(())
+    }
+
+    /// Reads the sample barcodes from the [Data] section of an Illumina SampleSheet.csv.  The Sample_ID column is the sample ID and the
+    /// index column is the sample barcode.  For dual-index designs, index2 is added to the end of index
+    pub fn illumina_sample_sheet_conversion(&mut self, sample_sheet_path: &str) -> Result<()> {
+        let sample_sheet = fs::read_to_string(sample_sheet_path)
+            .context(format!("Failed to open {}", sample_sheet_path))?;
+        for (barcode, sample_id) in parse_illumina_sample_sheet(&sample_sheet)
+            .context(format!("Unable to read the Illumina sample sheet {}", sample_sheet_path))?
         {
             self.samples_barcode_hash.insert(barcode, sample_id);
         }
         Ok(())
     }
 
-    /// Reads in comma separated barcode file (CSV).  The columns need to have headers.  The first column needs to be the nucleotide barcode
-    /// the second needs to be the ID, and the third needs to be the barcode index location
+    /// Finds sample barcodes that have the same sample name, which would otherwise write their counts to the same file.  With pool, these
+    /// sample barcodes are grouped under the sample name so that group_samples counts them together.  Otherwise each sample name has its
+    /// sample barcode added, as sample-name_barcode.  Sample barcodes already within a group are written under the group name and are skipped
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::BarcodeConversions;
+    ///
+    /// let mut barcode_conversions = BarcodeConversions::new();
+    /// barcode_conversions.samples_barcode_hash.insert("AGCT".to_string(), "S1".to_string());
+    /// barcode_conversions.samples_barcode_hash.insert("TTGA".to_string(), "S1".to_string());
+    /// barcode_conversions.samples_barcode_hash.insert("CCAG".to_string(), "S2".to_string());
+    /// let mut pooled_conversions = barcode_conversions.clone();
+    /// barcode_conversions.resolve_sample_name_collisions(false);
+    ///
+    /// assert_eq!(barcode_conversions.samples_barcode_hash["AGCT"], "S1_AGCT");
+    /// assert_eq!(barcode_conversions.samples_barcode_hash["CCAG"], "S2");
+    ///
+    /// pooled_conversions.resolve_sample_name_collisions(true);
+    /// pooled_conversions.get_sample_seqs();
+    /// pooled_conversions.group_samples().unwrap();
+    ///
+    /// assert_eq!(pooled_conversions.samples_barcode_hash.len(), 2);
+    /// assert_eq!(pooled_conversions.sample_groups["TTGA"], "S1");
+    /// ```
+    pub fn resolve_sample_name_collisions(&mut self, pool: bool) {
+        let mut name_barcodes: HashMap<String, Vec<String>> = HashMap::new();
+        for (sample_barcode, sample_id) in &self.samples_barcode_hash {
+            if !self.sample_groups.contains_key(sample_barcode) {
+                name_barcodes
+                    .entry(sample_id.to_string())
+                    .or_default()
+                    .push(sample_barcode.to_string());
+            }
+        }
+        for (sample_id, mut sample_barcodes) in name_barcodes.into_iter().sorted() {
+            if sample_barcodes.len() < 2 {
+                continue;
+            }
+            sample_barcodes.sort();
+            if pool {
+                println!(
+                    "Counting sample barcodes {} together as sample {}",
+                    sample_barcodes.join(", "),
+                    sample_id
+                );
+                for sample_barcode in sample_barcodes {
+                    self.sample_groups.insert(sample_barcode, sample_id.to_string());
+                }
+            } else {
+                println!(
+                    "WARNING: Sample barcodes {} have the same sample name, {}.  Their counts are written under the sample name with the barcode added.  Use --pool-sample-names to count them together",
+                    sample_barcodes.join(", "),
+                    sample_id
+                );
+                for sample_barcode in sample_barcodes {
+                    self.samples_barcode_hash
+                        .insert(sample_barcode.to_string(), format!("{}_{}", sample_id, sample_barcode));
+                }
+            }
+        }
+    }
+
+    /// Counts the sample barcodes of each group together under the group name.  The sample barcodes are still used to fix sequencing
+    /// errors, so this is called after get_sample_seqs.  A sample barcode without a group is its own group with its sample ID as the name.
+    /// The samples barcode hash is replaced with the group names, which are the keys the results are counted under.  Errors if the sample
+    /// ID of a sample barcode without a group is the name of a group, since its counts would be added to the group
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::BarcodeConversions;
+    ///
+    /// let mut barcode_conversions = BarcodeConversions::new();
+    /// barcode_conversions.samples_barcode_hash.insert("AGCT".to_string(), "rep_1".to_string());
+    /// barcode_conversions.samples_barcode_hash.insert("TTGA".to_string(), "rep_2".to_string());
+    /// barcode_conversions.samples_barcode_hash.insert("CCAG".to_string(), "control".to_string());
+    /// barcode_conversions.sample_groups.insert("AGCT".to_string(), "treated".to_string());
+    /// barcode_conversions.sample_groups.insert("TTGA".to_string(), "treated".to_string());
+    /// let mut clashing_conversions = barcode_conversions.clone();
+    /// barcode_conversions.get_sample_seqs();
+    /// barcode_conversions.group_samples().unwrap();
+    ///
+    /// assert_eq!(barcode_conversions.sample_groups["CCAG"], "control");
+    /// assert_eq!(barcode_conversions.samples_barcode_hash.len(), 2);
+    /// assert_eq!(barcode_conversions.sample_seqs.len(), 3);
+    ///
+    /// // A sample named after a group is not counted within it
+    /// clashing_conversions.samples_barcode_hash.insert("GGTA".to_string(), "treated".to_string());
+    /// assert!(clashing_conversions.group_samples().is_err());
+    /// ```
+    pub fn group_samples(&mut self) -> Result<()> {
+        if self.sample_groups.is_empty() {
+            return Ok(());
+        }
+        let group_names = self.sample_groups.values().cloned().collect::<AHashSet<String>>();
+        for (sample_barcode, sample_id) in self.samples_barcode_hash.iter().sorted() {
+            if self.sample_groups.contains_key(sample_barcode) {
+                continue;
+            }
+            if group_names.contains(sample_id) {
+                bail!(
+                    "Sample {} with barcode {} has the name of a sample group, so its counts would be added to the group.  Rename the sample or the group",
+                    sample_id,
+                    sample_barcode
+                )
+            }
+            self.sample_groups.insert(sample_barcode.to_string(), sample_id.to_string());
+        }
+        self.samples_barcode_hash = self
+            .sample_groups
+            .values()
+            .map(|group| (group.to_string(), group.to_string()))
+            .collect();
+        Ok(())
+    }
+
+    /// Reads in delimiter separated barcode file, comma separated (CSV) by default.  The columns need to have headers.  By default the first column needs to be the
+    /// nucleotide barcode the second needs to be the ID, and the third needs to be the barcode index location.  Columns named within barcode_columns are found by
+    /// their header instead.  If a nucleotide barcode is listed more than once for the same barcode index, the first ID is kept and a warning is printed for each
+    /// different ID skipped
     ///
     /// # Panics
     ///
@@ -391,19 +1257,30 @@ impl BarcodeConversions {
         &mut self,
         barcode_path: &str,
         barcode_num: usize,
+        delimiter: char,
+        barcode_columns: &BarcodeColumns,
     ) -> Result<()> {
         // read in the sample barcode file
-        let barcode_vecs = fs::read_to_string(barcode_path)
-            .context(format!("Failed to read {}", barcode_path))?
-            .lines() // split the lines
-            .skip(1) // skip the first line which should be the header
+        let barcode_file = fs::read_to_string(barcode_path).context(format!("Failed to read {}", barcode_path))?;
+        let mut lines = barcode_file.lines();
+        let header = lines.next().unwrap_or_default();
+        let (seq_position, id_position, index_position_option) = barcode_columns
+            .positions(header, delimiter)
+            .context(format!("Unable to find the columns of {}", barcode_path))?;
+        let index_position = index_position_option.ok_or_else(|| {
+            anyhow!(
+                "Column {} is not within the header of {}: {}",
+                barcode_columns.index_column_option.as_deref().unwrap_or_default(),
+                barcode_path,
+                header
+            )
+        })?;
+        let barcode_vecs = lines
             .map(|line| {
-                line.split(',')
-                    .take(3) // take only the first three values, or columns
-                    .map(|value| value.to_string())
-                    .collect_tuple()
-                    .unwrap_or(("".to_string(), "".to_string(), "".to_string()))
-            }) // comma split the line into a tuple with the first being the key and the last the value
+                let values = line.split(delimiter).collect::<Vec<&str>>();
+                let value = |position: usize| values.get(position).copied().unwrap_or_default().to_string();
+                (value(seq_position), value(id_position), value(index_position))
+            }) // split the line into a tuple of the sequence, ID and barcode number
             .collect::<Vec<(String, String, String)>>();
         for _ in 0..barcode_num {
             self.counted_barcodes_hash.push(HashMap::new());
@@ -414,8 +1291,33 @@ impl BarcodeConversions {
                 "Third column of barcode file contains something other than an integer: {}",
                 barcode_num
             ))? - 1;
+            // With a pool of library designs, the same counted barcodes file can be used with a format that has fewer counted barcodes
+            if barcode_num_usize >= self.counted_barcodes_hash.len() {
+                bail!(
+                    "Barcode number {} within the barcode file is more than the {} counted barcodes within the sequence format",
+                    barcode_num_usize + 1,
+                    self.counted_barcodes_hash.len()
+                )
+            }
             barcode_num_contained.insert(barcode_num_usize);
-            self.counted_barcodes_hash[barcode_num_usize].insert(barcode, id);
+            // If a sequence is listed more than once for the same barcode number, the first ID is kept and the others are skipped
+            match self.counted_barcodes_hash[barcode_num_usize].entry(barcode.to_uppercase()) {
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(id);
+                }
+                std::collections::hash_map::Entry::Occupied(entry) => {
+                    if entry.get() != &id {
+                        eprintln!(
+                            "WARNING: Barcode {} is listed more than once for barcode number {} within {}.  Keeping the first ID, {}, and skipping {}",
+                            entry.key(),
+                            barcode_num_usize + 1,
+                            barcode_path,
+                            entry.get(),
+                            id
+                        );
+                    }
+                }
+            }
         }
         let mut missing_barcode_num = Vec::new();
         for x in 0..barcode_num {
@@ -440,20 +1342,340 @@ impl BarcodeConversions {
         }
     }
 
-    /// Creates a hashmap of all counted barcode sequences in order to compare for sequencing errors
-    pub fn get_barcode_seqs(&mut self) {
-        if !self.counted_barcodes_hash.is_empty() {
-            self.counted_barcode_seqs = self
-                .counted_barcodes_hash
-                .iter()
-                .map(|hash| {
-                    hash.keys()
-                        .map(|key| key.to_string())
-                        .collect::<AHashSet<String>>()
+    /// Creates a hashmap of all counted barcode sequences in order to compare for sequencing errors.  Barcodes with IUPAC codes are expanded
+    /// into every sequence they stand for, and each expanded sequence is kept with the barcode so that it is counted as the barcode.  Errors
+    /// if an expanded sequence is another barcode or is within the expansion of another IUPAC barcode, since it could not be counted as
+    /// only one of them, or if a barcode stands for too many sequences
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::BarcodeConversions;
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut barcode_conversions = BarcodeConversions::new();
+    /// let mut barcode_hash = HashMap::new();
+    /// barcode_hash.insert("ACRT".to_string(), "b1".to_string());
+    /// barcode_conversions.counted_barcodes_hash = vec![barcode_hash.clone()];
+    /// barcode_conversions.get_barcode_seqs().unwrap();
+    ///
+    /// assert!(barcode_conversions.counted_barcode_seqs[0].seqs.contains("ACGT"));
+    /// assert_eq!(barcode_conversions.counted_barcode_seqs[0].iupac_barcodes["ACAT"], "ACRT");
+    ///
+    /// // ACGT is within the expansion of ACRT
+    /// barcode_hash.insert("ACGT".to_string(), "b2".to_string());
+    /// barcode_conversions.counted_barcodes_hash = vec![barcode_hash];
+    /// assert!(barcode_conversions.get_barcode_seqs().is_err());
+    /// ```
+    pub fn get_barcode_seqs(&mut self) -> Result<()> {
+        if !self.counted_barcodes_hash.is_empty() {
+            self.counted_barcode_seqs = self
+                .counted_barcodes_hash
+                .iter()
+                .map(|hash| {
+                    let mut seqs = hash
+                        .keys()
+                        .filter(|barcode| !has_iupac(barcode))
+                        .cloned()
+                        .collect::<AHashSet<String>>();
+                    let mut iupac_barcodes = AHashMap::new();
+                    // Sorted so that the same barcodes are named within an error each run
+                    for barcode in hash.keys().filter(|barcode| has_iupac(barcode)).sorted() {
+                        let expansions = barcode
+                            .chars()
+                            .map(|code| iupac_bases(code).map_or(1, |bases| bases.len()))
+                            .product::<usize>();
+                        if expansions > Self::MAX_IUPAC_EXPANSIONS {
+                            bail!(
+                                "Counted barcode {} stands for {} sequences, which is above the {} allowed.  Use 'N' for positions that can be any base",
+                                barcode,
+                                expansions,
+                                Self::MAX_IUPAC_EXPANSIONS
+                            )
+                        }
+                        for expanded_barcode in expand_iupac(barcode) {
+                            if seqs.contains(&expanded_barcode) {
+                                let other_barcode = iupac_barcodes.get(&expanded_barcode).unwrap_or(&expanded_barcode);
+                                bail!(
+                                    "Counted barcodes {} and {} both match {}, so reads with it can not be counted as only one of them",
+                                    other_barcode,
+                                    barcode,
+                                    expanded_barcode
+                                )
+                            }
+                            seqs.insert(expanded_barcode.clone());
+                            iupac_barcodes.insert(expanded_barcode, barcode.to_string());
+                        }
+                    }
+                    Ok(CountedBarcodeSeqs {
+                        seqs,
+                        iupac_barcodes,
+                        mismatch_index: None,
+                        kmer_index: None,
+                    })
                 }) // creates a hashset for each sequential barcode, then collects into a vector with the index being each sequential counted barcode
-                .collect::<Vec<AHashSet<String>>>();
+                .collect::<Result<Vec<CountedBarcodeSeqs>>>()?;
+        }
+        Ok(())
+    }
+
+    /// Adds the reverse complement of each known counted barcode so that reads with either orientation are fixed, then keys the barcode
+    /// conversions by the lexicographically smaller orientation, which is what the counted barcodes are folded into.  If both orientations
+    /// of a barcode are listed with different IDs, the first listed is kept
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::BarcodeConversions;
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut barcode_conversions = BarcodeConversions::new();
+    /// let mut barcode_hash = HashMap::new();
+    /// barcode_hash.insert("TTGC".to_string(), "b1".to_string());
+    /// barcode_conversions.counted_barcodes_hash = vec![barcode_hash];
+    /// barcode_conversions.get_barcode_seqs().unwrap();
+    /// barcode_conversions.fold_reverse_complements();
+    ///
+    /// assert!(barcode_conversions.counted_barcode_seqs[0].seqs.contains("GCAA"));
+    /// assert_eq!(barcode_conversions.counted_barcodes_hash[0]["GCAA"], "b1");
+    /// ```
+    pub fn fold_reverse_complements(&mut self) {
+        for counted_barcode_seqs in self.counted_barcode_seqs.iter_mut() {
+            let reverse_complements = counted_barcode_seqs
+                .seqs
+                .iter()
+                .map(|barcode| reverse_complement(barcode))
+                .collect::<Vec<String>>();
+            counted_barcode_seqs.seqs.extend(reverse_complements);
+        }
+        for barcode_hash in self.counted_barcodes_hash.iter_mut() {
+            let mut folded_hash = HashMap::new();
+            // Sorted so that which ID is kept does not depend on the hashmap order
+            for (barcode, id) in barcode_hash.drain().sorted() {
+                folded_hash.entry(canonical_barcode(&barcode)).or_insert(id);
+            }
+            *barcode_hash = folded_hash;
+        }
+    }
+
+    /// Errors if a counted barcode sequence is listed at more than one barcode number with different IDs.  With --unordered-barcodes,
+    /// the barcodes of a read are sorted, so a sequence is converted to its ID whatever position it is at, and could not be converted to
+    /// only one of them
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::BarcodeConversions;
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut barcode_conversions = BarcodeConversions::new();
+    /// let mut first_hash = HashMap::new();
+    /// first_hash.insert("ACGT".to_string(), "b1".to_string());
+    /// let mut second_hash = HashMap::new();
+    /// second_hash.insert("ACGT".to_string(), "b1".to_string());
+    /// barcode_conversions.counted_barcodes_hash = vec![first_hash, second_hash.clone()];
+    /// assert!(barcode_conversions.check_unordered_ids().is_ok());
+    ///
+    /// second_hash.insert("ACGT".to_string(), "b2".to_string());
+    /// barcode_conversions.counted_barcodes_hash[1] = second_hash;
+    /// assert!(barcode_conversions.check_unordered_ids().is_err());
+    /// ```
+    pub fn check_unordered_ids(&self) -> Result<()> {
+        let mut barcode_ids: AHashMap<&str, &str> = AHashMap::new();
+        for barcode_hash in &self.counted_barcodes_hash {
+            for (barcode, id) in barcode_hash.iter().sorted() {
+                match barcode_ids.get(barcode.as_str()) {
+                    Some(other_id) if other_id != id => bail!(
+                        "Counted barcode {} has the IDs {} and {} at different barcode numbers, which --unordered-barcodes can not tell apart",
+                        barcode,
+                        other_id,
+                        id
+                    ),
+                    Some(_) => (),
+                    None => {
+                        barcode_ids.insert(barcode, id);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the single mismatch index for each counted barcode that allows only one mismatch.  Fixing with the index is a hash lookup
+    /// per barcode position instead of a comparison with every known barcode, which is much faster with large barcode sets
+    pub fn get_barcode_indexes(&mut self, max_barcode_errors: &[u16]) {
+        for (counted_barcode_seqs, max_errors) in
+            self.counted_barcode_seqs.iter_mut().zip(max_barcode_errors)
+        {
+            if *max_errors == 1 {
+                counted_barcode_seqs.mismatch_index =
+                    MismatchIndex::new(&counted_barcode_seqs.seqs).map(Arc::new);
+            }
+        }
+    }
+
+    /// Creates the k-mer index for each counted barcode that allows mismatches, other than the ones fixed with the single mismatch index,
+    /// so that a sequenced barcode is only compared with the known barcodes that share enough k-mers with it.  Only created for large
+    /// barcode sets, where comparing with every known barcode is slow
+    pub fn get_kmer_indexes(&mut self, max_barcode_errors: &[u16], min_correction_margin: u16) {
+        for (counted_barcode_seqs, max_errors) in
+            self.counted_barcode_seqs.iter_mut().zip(max_barcode_errors)
+        {
+            if *max_errors > 1 || (*max_errors == 1 && min_correction_margin > 1) {
+                counted_barcode_seqs.kmer_index =
+                    KmerIndex::new(&counted_barcode_seqs.seqs, *max_errors, min_correction_margin).map(Arc::new);
+            }
+        }
+    }
+
+    /// Writes the single mismatch indexes of the counted barcodes with bincode, so that later runs over the same counted barcodes file can
+    /// load them with load_barcode_indexes instead of creating them again
+    pub fn dump_barcode_indexes(&self, index_path: &str) -> Result<()> {
+        let indexes = self
+            .counted_barcode_seqs
+            .iter()
+            .map(|counted_barcode_seqs| counted_barcode_seqs.mismatch_index.as_deref())
+            .collect::<Vec<Option<&MismatchIndex>>>();
+        let index_file =
+            File::create(index_path).context(format!("Unable to create index file: {}", index_path))?;
+        bincode::serialize_into(BufWriter::new(index_file), &indexes)
+            .context(format!("Unable to write index file: {}", index_path))?;
+        Ok(())
+    }
+
+    /// Loads the single mismatch indexes written by dump_barcode_indexes in place of get_barcode_indexes.  Each loaded index is checked to be
+    /// from the same counted barcodes, and is only used for counted barcodes that allow one mismatch, the same as get_barcode_indexes
+    pub fn load_barcode_indexes(&mut self, index_path: &str, max_barcode_errors: &[u16]) -> Result<()> {
+        let index_file =
+            File::open(index_path).context(format!("Unable to open index file: {}", index_path))?;
+        let indexes: Vec<Option<MismatchIndex>> = bincode::deserialize_from(BufReader::new(index_file))
+            .context(format!("Unable to read index file: {}", index_path))?;
+        if indexes.len() != self.counted_barcode_seqs.len() {
+            bail!(
+                "Index file {} has {} counted barcodes but the counted barcodes file has {}",
+                index_path,
+                indexes.len(),
+                self.counted_barcode_seqs.len()
+            )
+        }
+        for (barcode_index, ((counted_barcode_seqs, max_errors), index_option)) in self
+            .counted_barcode_seqs
+            .iter_mut()
+            .zip(max_barcode_errors)
+            .zip(indexes)
+            .enumerate()
+        {
+            if let Some(ref index) = index_option {
+                if !index.matches_barcodes(&counted_barcode_seqs.seqs) {
+                    bail!(
+                        "Index file {} was created from different barcodes for counted barcode {}.  Create it again with --dump-index",
+                        index_path,
+                        barcode_index + 1
+                    )
+                }
+            }
+            if *max_errors == 1 {
+                counted_barcode_seqs.mismatch_index = index_option.map(Arc::new);
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns about barcodes that are within twice the allowed errors of each other.  A read between two such barcodes can be equally close
+    /// to both, so it is not fixed and is lost as a mismatch.  Sample barcodes are always checked.  Counted barcodes are only checked
+    /// when check_counted is true, since the comparison of every pair can be slow with large barcode sets
+    pub fn check_correctable(&self, max_errors: &MaxSeqErrors, check_counted: bool) {
+        warn_close_barcodes(
+            &self.sample_seqs,
+            &AHashMap::new(),
+            max_errors.max_sample_errors(),
+            "sample barcodes",
+        );
+        if check_counted {
+            for (barcode_index, (counted_barcode_seqs, barcode_errors)) in self
+                .counted_barcode_seqs
+                .iter()
+                .zip(max_errors.max_barcode_errors())
+                .enumerate()
+            {
+                warn_close_barcodes(
+                    &counted_barcode_seqs.seqs,
+                    &counted_barcode_seqs.iupac_barcodes,
+                    *barcode_errors,
+                    &format!("counted barcodes for barcode number {}", barcode_index + 1),
+                );
+            }
+        }
+    }
+}
+
+/// Prints a warning listing the barcode pairs that are within twice the max errors of each other
+fn warn_close_barcodes(
+    barcode_seqs: &AHashSet<String>,
+    iupac_barcodes: &AHashMap<String, String>,
+    max_errors: u16,
+    barcode_type: &str,
+) {
+    // Sequences expanded from the same IUPAC barcode are counted as one, so they are not a problem when close
+    let mut close_pairs = close_barcode_pairs(barcode_seqs, max_errors);
+    close_pairs.retain(|(first_barcode, second_barcode, _)| {
+        match (iupac_barcodes.get(first_barcode), iupac_barcodes.get(second_barcode)) {
+            (Some(first_iupac), Some(second_iupac)) => first_iupac != second_iupac,
+            _ => true,
+        }
+    });
+    if close_pairs.is_empty() {
+        return;
+    }
+    let min_distance = close_pairs.iter().map(|(_, _, distance)| *distance).min().unwrap();
+    eprintln!(
+        "WARNING: {} pairs of {} are within twice the {} allowed errors of each other, with a minimum distance of {}.  Reads with errors equally close to both are not counted without --tie-break",
+        close_pairs.len().to_formatted_string(&Locale::en),
+        barcode_type,
+        max_errors,
+        min_distance
+    );
+    // Only list the closest pairs so that large barcode sets do not flood the output
+    for (first_barcode, second_barcode, distance) in close_pairs.iter().take(20) {
+        eprintln!("{}\t{}\tDistance: {}", first_barcode, second_barcode, distance);
+    }
+    if close_pairs.len() > 20 {
+        eprintln!("...");
+    }
+    eprintln!();
+}
+
+/// Returns each pair of barcodes whose Hamming distance is at most twice the max errors, sorted by distance then barcode.  Barcodes of
+/// different lengths are compared over the shorter length, which is how fix_error_with_margin compares them.  Nothing is returned when no errors are allowed
+///
+/// # Example
+///
+/// ```
+/// use barcode_count::info::close_barcode_pairs;
+///
+/// let barcodes: ahash::AHashSet<String> = ["AAAAAA".to_string(), "AAAATT".to_string(), "CCCCCC".to_string()].iter().cloned().collect();
+///
+/// assert_eq!(close_barcode_pairs(&barcodes, 1), vec![("AAAAAA".to_string(), "AAAATT".to_string(), 2)]);
+/// assert!(close_barcode_pairs(&barcodes, 0).is_empty());
+/// ```
+pub fn close_barcode_pairs(barcode_seqs: &AHashSet<String>, max_errors: u16) -> Vec<(String, String, usize)> {
+    let mut close_pairs = Vec::new();
+    if max_errors == 0 {
+        return close_pairs;
+    }
+    let max_distance = 2 * max_errors as usize;
+    let sorted_barcodes = barcode_seqs.iter().sorted().collect::<Vec<&String>>();
+    for (first_index, first_barcode) in sorted_barcodes.iter().enumerate() {
+        for second_barcode in &sorted_barcodes[(first_index + 1)..] {
+            let distance = first_barcode
+                .bytes()
+                .zip(second_barcode.bytes())
+                .filter(|(first_base, second_base)| first_base != second_base)
+                .count();
+            if distance <= max_distance {
+                close_pairs.push((first_barcode.to_string(), second_barcode.to_string(), distance));
+            }
         }
     }
+    close_pairs.sort_by_key(|(_, _, distance)| *distance);
+    close_pairs
 }
 
 /// Struct of how many sequencing errrors are allowed
@@ -490,7 +1712,7 @@ impl MaxSeqErrors {
     pub fn new(
         sample_errors_option: Option<u16>,
         sample_barcode_size_option: Option<u16>,
-        barcode_errors_option: Option<u16>,
+        barcode_errors_option: Option<Vec<u16>>,
         barcode_sizes: Vec<u16>,
         constant_errors_option: Option<u16>,
         constant_region_size: u16,
@@ -513,10 +1735,15 @@ impl MaxSeqErrors {
         }
 
         let mut max_barcode_errors = Vec::new();
-        // If max error was set by input arguments, use that value, otherwise calculate 20% of barcode size for max error
-        for barcode_size in &barcode_sizes {
-            if let Some(barcode_errors) = barcode_errors_option {
-                max_barcode_errors.push(barcode_errors);
+        // If max error was set by input arguments, use that value, otherwise calculate 20% of barcode size for max error.  A single value
+        // is used for every barcode, otherwise there is one value for each barcode position
+        for (barcode_index, barcode_size) in barcode_sizes.iter().enumerate() {
+            if let Some(ref barcode_errors) = barcode_errors_option {
+                if barcode_errors.len() == 1 {
+                    max_barcode_errors.push(barcode_errors[0]);
+                } else {
+                    max_barcode_errors.push(barcode_errors[barcode_index]);
+                }
             } else {
                 max_barcode_errors.push(barcode_size / 5);
             }
@@ -542,6 +1769,22 @@ impl MaxSeqErrors {
         }
     }
 
+    /// Returns the errors allowed within a barcode of the length when they are a fraction of it, rounded down, so that barcodes of
+    /// different lengths get the same tolerance.  Used with --max-error-fraction for the barcodes without set max errors
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::MaxSeqErrors;
+    ///
+    /// assert_eq!(MaxSeqErrors::fraction_errors(6, 0.2), 1);
+    /// assert_eq!(MaxSeqErrors::fraction_errors(10, 0.2), 2);
+    /// assert_eq!(MaxSeqErrors::fraction_errors(10, 0.3), 3);
+    /// ```
+    pub fn fraction_errors(length: u16, fraction: f32) -> u16 {
+        // The small addition keeps products such as 10 * 0.3 from rounding down below the whole number they are
+        (length as f32 * fraction + 1e-4).floor() as u16
+    }
+
     /// Returns the maximum allowed constant region errors
     ///
     /// # Example
@@ -555,7 +1798,7 @@ impl MaxSeqErrors {
     /// let constant_errors_option = None;
     /// let constant_region_size = 30;
     /// let min_quality = 0.0;
-    /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option, barcode_sizes, constant_errors_option, constant_region_size, min_quality);
+    /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option.clone(), barcode_sizes, constant_errors_option, constant_region_size, min_quality);
     /// assert_eq!(max_sequence_errors.max_constant_errors(), 6);
     /// let barcode_sizes = vec![8,8,8];
     /// let constant_errors_option = Some(3);
@@ -579,7 +1822,7 @@ impl MaxSeqErrors {
     /// let constant_errors_option = None;
     /// let constant_region_size = 30;
     /// let min_quality = 0.0;
-    /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option, barcode_sizes, constant_errors_option, constant_region_size, min_quality);
+    /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option.clone(), barcode_sizes, constant_errors_option, constant_region_size, min_quality);
     /// assert_eq!(max_sequence_errors.max_sample_errors(), 2);
     /// let barcode_sizes = vec![8,8,8];
     /// let sample_errors_option = Some(3);
@@ -606,9 +1849,14 @@ impl MaxSeqErrors {
     /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option, barcode_sizes, constant_errors_option, constant_region_size, min_quality);
     /// assert_eq!(max_sequence_errors.max_barcode_errors(), vec![1,1,1]);
     /// let barcode_sizes = vec![8,8,8];
-    /// let barcode_errors_option = Some(2);
+    /// let barcode_errors_option = Some(vec![2]);
     /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option, barcode_sizes, constant_errors_option, constant_region_size, min_quality);
     /// assert_eq!(max_sequence_errors.max_barcode_errors(), vec![2,2,2]);
+    /// // Each counted barcode position can also have its own maximum
+    /// let barcode_sizes = vec![6,8,10];
+    /// let barcode_errors_option = Some(vec![1,2,2]);
+    /// let mut max_sequence_errors = MaxSeqErrors::new(sample_errors_option, sample_barcode_size_option, barcode_errors_option, barcode_sizes, constant_errors_option, constant_region_size, min_quality);
+    /// assert_eq!(max_sequence_errors.max_barcode_errors(), vec![1,2,2]);
     /// ```
     pub fn max_barcode_errors(&self) -> &[u16] {
         &self.barcode
@@ -658,19 +1906,337 @@ impl fmt::Display for MaxSeqErrors {
     }
 }
 
-#[derive(Debug)]
+/// The random barcodes found for one counted barcode, which is used to count each random barcode only once.  Either every random barcode
+/// is stored, or with --umi-bloom, only a Bloom filter and a count are stored, or with --umi-hll, only a HyperLogLog sketch is stored.
+/// The last two use a set amount of memory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RandomBarcodes {
+    Exact(AHashSet<String>),
+    Bloom(UmiBloom),
+    Hll(UmiHll),
+}
+
+impl RandomBarcodes {
+    /// Creates an empty set of random barcodes.  Uses a HyperLogLog sketch if hll is true, otherwise a Bloom filter with the given number of
+    /// bits if bloom_bits is some
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::RandomBarcodes;
+    ///
+    /// let mut exact = RandomBarcodes::new(None, false);
+    /// let mut bloom = RandomBarcodes::new(Some(1024), false);
+    /// let mut hll = RandomBarcodes::new(None, true);
+    /// for random_barcodes in [&mut exact, &mut bloom, &mut hll] {
+    ///     assert!(random_barcodes.insert("AAAAAAAA"));
+    ///     assert!(random_barcodes.insert("CCCCCCCC"));
+    ///     assert!(!random_barcodes.insert("AAAAAAAA"));
+    ///     assert_eq!(random_barcodes.len(), 2);
+    /// }
+    /// ```
+    pub fn new(bloom_bits: Option<usize>, hll: bool) -> Self {
+        match bloom_bits {
+            _ if hll => RandomBarcodes::Hll(UmiHll::new()),
+            Some(bits) => RandomBarcodes::Bloom(UmiBloom::new(bits)),
+            None => RandomBarcodes::Exact(AHashSet::new()),
+        }
+    }
+
+    /// Adds the random barcode.  Returns false if it was already added, which for a Bloom filter can also be a false positive.  For a
+    /// HyperLogLog sketch, false only means that the estimate did not change, which is often the case for new random barcodes
+    pub fn insert(&mut self, random_barcode: &str) -> bool {
+        match self {
+            RandomBarcodes::Exact(random_set) => random_set.insert(random_barcode.to_string()),
+            RandomBarcodes::Bloom(umi_bloom) => umi_bloom.insert(random_barcode),
+            RandomBarcodes::Hll(umi_hll) => umi_hll.insert(random_barcode),
+        }
+    }
+
+    /// Adds each of the random barcodes
+    pub fn extend<'a, I>(&mut self, random_barcodes: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for random_barcode in random_barcodes {
+            self.insert(random_barcode);
+        }
+    }
+
+    /// Adds the random barcodes of other.  Two Bloom filters are combined, and the count is estimated from the bits of both, since the
+    /// random barcodes they share are unknown.  Exact random barcodes merged with a Bloom filter or HyperLogLog sketch are added to one of
+    /// the same type
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::RandomBarcodes;
+    ///
+    /// let mut first_bloom = RandomBarcodes::new(Some(1024), false);
+    /// first_bloom.extend(["AAAAAAAA", "CCCCCCCC"]);
+    /// let mut second_bloom = RandomBarcodes::new(Some(1024), false);
+    /// second_bloom.extend(["CCCCCCCC", "GGGGGGGG"]);
+    /// first_bloom.merge(second_bloom.clone());
+    /// // CCCCCCCC is within both, so it is only counted once
+    /// assert_eq!(first_bloom.len(), 3);
+    ///
+    /// let mut exact = RandomBarcodes::new(None, false);
+    /// exact.insert("TTTTTTTT");
+    /// exact.merge(second_bloom);
+    /// assert!(exact.exact().is_none());
+    /// assert_eq!(exact.len(), 3);
+    /// ```
+    pub fn merge(&mut self, other: RandomBarcodes) {
+        match (self, other) {
+            (RandomBarcodes::Exact(random_set), RandomBarcodes::Exact(other_set)) => {
+                random_set.extend(other_set)
+            }
+            (RandomBarcodes::Bloom(umi_bloom), RandomBarcodes::Bloom(other_bloom)) => {
+                umi_bloom.merge(&other_bloom)
+            }
+            (RandomBarcodes::Hll(umi_hll), RandomBarcodes::Hll(other_hll)) => umi_hll.merge(&other_hll),
+            (random_barcodes, RandomBarcodes::Exact(other_set)) => {
+                random_barcodes.extend(other_set.iter().map(|random_barcode| random_barcode.as_str()))
+            }
+            // Such as a partial result created before the sketch type was set, so its random barcodes are added to a sketch like other
+            (random_barcodes @ RandomBarcodes::Exact(_), other_sketch) => {
+                let mut sketch = match &other_sketch {
+                    RandomBarcodes::Bloom(other_bloom) => RandomBarcodes::Bloom(UmiBloom::new(other_bloom.bit_num)),
+                    _ => RandomBarcodes::Hll(UmiHll::new()),
+                };
+                if let Some(random_set) = random_barcodes.exact() {
+                    sketch.extend(random_set.iter().map(|random_barcode| random_barcode.as_str()));
+                }
+                sketch.merge(other_sketch);
+                *random_barcodes = sketch;
+            }
+            (_, _) => panic!("Random barcodes within a Bloom filter and a HyperLogLog sketch can not be merged"),
+        }
+    }
+
+    /// Returns the number of unique random barcodes added, which is an estimate for a HyperLogLog sketch
+    pub fn len(&self) -> usize {
+        match self {
+            RandomBarcodes::Exact(random_set) => random_set.len(),
+            RandomBarcodes::Bloom(umi_bloom) => umi_bloom.count,
+            RandomBarcodes::Hll(umi_hll) => umi_hll.estimate(),
+        }
+    }
+
+    /// Returns whether or not any random barcodes were added
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the random barcodes if every one is stored.  None for a Bloom filter or HyperLogLog sketch
+    pub fn exact(&self) -> Option<&AHashSet<String>> {
+        match self {
+            RandomBarcodes::Exact(random_set) => Some(random_set),
+            RandomBarcodes::Bloom(_) | RandomBarcodes::Hll(_) => None,
+        }
+    }
+}
+
+/// A Bloom filter of random barcodes along with the number of random barcodes added that were not already within the filter.  A new
+/// random barcode is miscounted as a duplicate when all of its bits were already set by others.  With m bits, k = 3 hashes, and n random
+/// barcodes already added, this false duplicate rate is about (1 - e^(-3n/m))^3.  For example, with 1,024 bits this is about 0.1% for the 35th
+/// random barcode, 1% for the 80th, and 5% for the 150th, so the bits need to be several times more than the expected random barcodes per
+/// counted barcode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UmiBloom {
+    bits: Vec<u64>, // the filter bits packed into words
+    bit_num: usize, // number of bits within the filter
+    count: usize,   // random barcodes added that were not already within the filter
+}
+
+impl UmiBloom {
+    const HASH_NUM: usize = 3; // number of bits set for each random barcode
+
+    /// Creates an empty Bloom filter with at least 64 bits
+    pub fn new(bit_num: usize) -> Self {
+        let words = bit_num.max(64).div_ceil(64);
+        UmiBloom {
+            bits: vec![0; words],
+            bit_num: words * 64,
+            count: 0,
+        }
+    }
+
+    /// Sets the bits of the random barcode.  Returns true and counts the random barcode if any of its bits were not already set
+    pub fn insert(&mut self, random_barcode: &str) -> bool {
+        // Double hashing of a single fixed seed hash, so that the positions are the same across threads and runs
+        let hash = ahash::RandomState::with_seeds(1, 2, 3, 4).hash_one(random_barcode);
+        let first_hash = hash & 0xFFFF_FFFF;
+        let second_hash = (hash >> 32) | 1;
+        let mut new = false;
+        for hash_index in 0..Self::HASH_NUM as u64 {
+            let bit = (first_hash.wrapping_add(hash_index.wrapping_mul(second_hash)) % self.bit_num as u64) as usize;
+            let mask = 1 << (bit % 64);
+            if self.bits[bit / 64] & mask == 0 {
+                self.bits[bit / 64] |= mask;
+                new = true;
+            }
+        }
+        if new {
+            self.count += 1;
+        }
+        new
+    }
+
+    /// Combines the bits of other into this filter.  The random barcodes within both are unknown, so the count is estimated from the set
+    /// bits of the combined filter as -(m / k) * ln(1 - set bits / m), and kept between the larger count and the summed counts
+    fn merge(&mut self, other: &UmiBloom) {
+        for (word, other_word) in self.bits.iter_mut().zip(&other.bits) {
+            *word |= other_word;
+        }
+        let set_bits = self.bits.iter().map(|word| word.count_ones() as f64).sum::<f64>();
+        let bit_num = self.bit_num as f64;
+        let estimate = (-(bit_num / Self::HASH_NUM as f64) * (1.0 - set_bits / bit_num).ln()).round() as usize;
+        self.count = estimate.clamp(self.count.max(other.count), self.count + other.count);
+    }
+}
+
+/// A HyperLogLog sketch that estimates the number of unique random barcodes added, using 4,096 registers for a relative standard error
+/// of about 1.04 / sqrt(4,096), or 1.6%.  Exact duplicates are never counted twice, but the count is an estimate, so the duplicate tally
+/// is not exact.  The registers that are set are kept in a sorted list until there are more than a quarter of the full 4KB of registers,
+/// so counted barcodes with few random barcodes use little memory
+///
+/// # Example
+/// ```
+/// use barcode_count::info::UmiHll;
+///
+/// let mut umi_hll = UmiHll::new();
+/// for random_index in 0..10_000 {
+///     umi_hll.insert(&format!("{:016b}", random_index));
+///     umi_hll.insert(&format!("{:016b}", random_index));
+/// }
+/// assert!((umi_hll.estimate() as f64 - 10_000.0).abs() < 500.0);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UmiHll {
+    sparse: Vec<u32>, // (register index << 8) | rank of each set register, sorted by register index.  Used until dense is needed
+    dense: Vec<u8>,   // rank of every register.  Empty while the sparse registers are used
+}
+
+impl UmiHll {
+    const PRECISION: u32 = 12; // bits of the hash used for the register index
+    const REGISTER_NUM: usize = 1 << Self::PRECISION;
+    const SPARSE_MAX: usize = Self::REGISTER_NUM / 16; // sparse registers, at 4 bytes each, before switching to the dense registers
+
+    /// Creates an empty sketch
+    pub fn new() -> Self {
+        UmiHll::default()
+    }
+
+    /// Adds the random barcode.  Returns true if the estimate could have changed
+    pub fn insert(&mut self, random_barcode: &str) -> bool {
+        // A single fixed seed hash, so that the registers are the same across threads and runs
+        let hash = ahash::RandomState::with_seeds(1, 2, 3, 4).hash_one(random_barcode);
+        let register_index = (hash >> (64 - Self::PRECISION)) as usize;
+        // The position of the first set bit of the rest of the hash.  The added bit limits the rank when the rest are all 0
+        let rank = ((hash << Self::PRECISION) | (1 << (Self::PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.set_register(register_index, rank)
+    }
+
+    /// Raises the register to the rank if it is lower.  Returns true if the register was raised
+    fn set_register(&mut self, register_index: usize, rank: u8) -> bool {
+        if !self.dense.is_empty() {
+            if self.dense[register_index] >= rank {
+                return false;
+            }
+            self.dense[register_index] = rank;
+            return true;
+        }
+        let sparse_register = ((register_index as u32) << 8) | rank as u32;
+        match self
+            .sparse
+            .binary_search_by_key(&(register_index as u32), |sparse_register| sparse_register >> 8)
+        {
+            Ok(position) => {
+                if (self.sparse[position] & 0xFF) as u8 >= rank {
+                    return false;
+                }
+                self.sparse[position] = sparse_register;
+            }
+            Err(position) => {
+                self.sparse.insert(position, sparse_register);
+                if self.sparse.len() > Self::SPARSE_MAX {
+                    self.dense = vec![0; Self::REGISTER_NUM];
+                    for sparse_register in self.sparse.drain(..) {
+                        self.dense[(sparse_register >> 8) as usize] = (sparse_register & 0xFF) as u8;
+                    }
+                    self.sparse.shrink_to_fit();
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the (register index, rank) of every set register
+    fn registers(&self) -> Vec<(usize, u8)> {
+        if self.dense.is_empty() {
+            self.sparse
+                .iter()
+                .map(|sparse_register| ((sparse_register >> 8) as usize, (sparse_register & 0xFF) as u8))
+                .collect()
+        } else {
+            self.dense
+                .iter()
+                .enumerate()
+                .filter(|(_, rank)| **rank > 0)
+                .map(|(register_index, rank)| (register_index, *rank))
+                .collect()
+        }
+    }
+
+    /// Returns the estimated number of unique random barcodes added.  Uses linear counting of the empty registers for small numbers,
+    /// where it is more accurate
+    pub fn estimate(&self) -> usize {
+        let register_num = Self::REGISTER_NUM as f64;
+        let registers = self.registers();
+        let empty_registers = (Self::REGISTER_NUM - registers.len()) as f64;
+        let inverse_sum = empty_registers
+            + registers
+                .iter()
+                .map(|(_, rank)| 2_f64.powi(-(*rank as i32)))
+                .sum::<f64>();
+        let alpha = 0.7213 / (1.0 + 1.079 / register_num);
+        let raw_estimate = alpha * register_num * register_num / inverse_sum;
+        if raw_estimate <= 2.5 * register_num && empty_registers > 0.0 {
+            (register_num * (register_num / empty_registers).ln()).round() as usize
+        } else {
+            raw_estimate.round() as usize
+        }
+    }
+
+    /// Combines other into this sketch, which then estimates the unique random barcodes of both
+    fn merge(&mut self, other: &UmiHll) {
+        for (register_index, rank) in other.registers() {
+            self.set_register(register_index, rank);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ResultsHashmap {
-    RandomBarcode(HashMap<String, HashMap<String, AHashSet<String>>>),
+    RandomBarcode(HashMap<String, HashMap<String, RandomBarcodes>>),
     NoRandomBarcode(HashMap<String, HashMap<String, usize>>),
 }
 
 // A struct which holds the count results, whether that is for a scheme which contains a random barcode or not
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Results {
     pub results_hashmap: ResultsHashmap, // holds the counted results
     empty_count_hash: HashMap<String, usize>, // An empty hashmap that is used a few times and therefor stored within the struct
-    empty_random_hash: HashMap<String, AHashSet<String>>,
+    empty_random_hash: HashMap<String, RandomBarcodes>,
+    umi_bloom_bits: Option<usize>, // bits of the Bloom filter used for each counted barcode's random barcodes.  None stores every random barcode
+    umi_hll: bool, // whether each counted barcode's random barcodes are estimated with a HyperLogLog sketch
     sample_conversion_omited: bool,
+    pub sample_matches: HashMap<String, u32>, // matched reads per sample barcode, including random barcode duplicates.  Used for run progress
+    pub uncorrected_barcodes: HashMap<(usize, String), u32>, // (counted barcode position, sequenced barcode) that could not be fixed -> number of reads
+    pub unknown_samples: HashMap<String, u32>, // sequenced sample barcode that could not be fixed -> number of reads
+    pub sample_groups: HashMap<String, String>, // sample barcode -> group the counts are added to.  Empty without technical replicate groups
+    pooled_sample: Option<String>, // sample name every read is counted under when the sample barcodes are not converted.  None keeps each sample barcode
+    pub weighted_counts: Option<HashMap<String, HashMap<String, f64>>>, // sample -> barcodes -> summed read weights.  Only kept with quality weighted counts
+    pub read_counts: Option<HashMap<String, HashMap<String, usize>>>, // sample -> barcodes -> reads, including random barcode duplicates.  Only kept with --report-reads
 }
 
 impl Results {
@@ -679,6 +2245,7 @@ impl Results {
         samples_barcode_hash: &HashMap<String, String>,
         random_barcode: bool,
         sample_barcode: bool,
+        umi_bloom_bits: Option<usize>,
     ) -> Self {
         let mut results_hashmap;
         // Create an empty hashmap into the enum depending on whether or not a random barcode is
@@ -692,7 +2259,7 @@ impl Results {
         // If sample name conversion was included, add all sample names to the hashmaps used to count
         let mut sample_conversion_omited = false;
         // create empty hashmaps to insert and have the sample name included.  This is so sample name doesn't need to be searched each time
-        let empty_random_hash: HashMap<String, AHashSet<String>> = HashMap::new();
+        let empty_random_hash: HashMap<String, RandomBarcodes> = HashMap::new();
         let empty_count_hash: HashMap<String, usize> = HashMap::new();
         // If there is a sample barcode file included, add these as keys in the relevant count hashmap
         if !samples_barcode_hash.is_empty() {
@@ -727,72 +2294,313 @@ impl Results {
             results_hashmap,
             empty_count_hash,
             empty_random_hash,
+            umi_bloom_bits,
+            umi_hll: false,
             sample_conversion_omited,
+            sample_matches: HashMap::new(),
+            uncorrected_barcodes: HashMap::new(),
+            unknown_samples: HashMap::new(),
+            sample_groups: HashMap::new(),
+            pooled_sample: None,
+            weighted_counts: None,
+            read_counts: None,
         }
     }
 
-    /// Adds the count to results hashmap
-    pub fn add_count(
-        &mut self,
-        sample_barcode: &str,
-        random_barcode: Option<&String>,
-        barcode_string: String,
-    ) -> bool {
-        // If conversion file does not exist, add the barcode as a key value
-        if self.sample_conversion_omited {
-            match self.results_hashmap {
-                ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
-                    if !count_hashmap.contains_key(sample_barcode) {
-                        count_hashmap
-                            .insert(sample_barcode.to_string(), self.empty_count_hash.clone());
-                    };
-                }
-                ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
-                    if !random_hashmap.contains_key(sample_barcode) {
-                        random_hashmap
-                            .insert(sample_barcode.to_string(), self.empty_random_hash.clone());
-                    };
-                }
-            }
-        };
+    /// Keeps the summed weight of the reads for each counted barcode, along with the read counts.  The weight of each read is given to
+    /// add_count.  Only used without a random barcode, where each read is counted
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::Results;
+    ///
+    /// let mut results = Results::new(&HashMap::default(), false, false, None);
+    /// results.weight_counts();
+    /// results.add_count("barcode", None, "AAAA".to_string(), 0.9);
+    /// results.add_count("barcode", None, "AAAA".to_string(), 0.5);
+    ///
+    /// let weighted_counts = results.weighted_counts.unwrap();
+    /// assert!((weighted_counts["barcode"]["AAAA"] - 1.4).abs() < 1e-9);
+    /// ```
+    pub fn weight_counts(&mut self) {
+        self.weighted_counts = Some(HashMap::new());
+    }
+
+    /// Keeps the number of reads of each counted barcode, including the random barcode duplicates, along with the unique random barcodes.
+    /// Only used with a random barcode, where the counts are otherwise the unique random barcodes
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::Results;
+    ///
+    /// let mut results = Results::new(&HashMap::default(), true, false, None);
+    /// results.count_reads();
+    /// results.add_count("barcode", Some(&"AAAAAAAA".to_string()), "AAAA".to_string(), 1.0);
+    /// results.add_count("barcode", Some(&"AAAAAAAA".to_string()), "AAAA".to_string(), 1.0);
+    /// results.add_count("barcode", Some(&"CCCCCCCC".to_string()), "AAAA".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.read_counts.unwrap()["barcode"]["AAAA"], 3);
+    /// ```
+    pub fn count_reads(&mut self) {
+        self.read_counts = Some(HashMap::new());
+    }
+
+    /// Estimates the unique random barcodes of each counted barcode with a HyperLogLog sketch, which uses a set amount of memory, instead
+    /// of storing every random barcode.  Used with --umi-hll
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    ///
+    /// let mut results = Results::new(&HashMap::default(), true, false, None);
+    /// results.estimate_random_barcodes();
+    /// results.add_count("barcode", Some(&"AAAAAAAA".to_string()), "AAAA".to_string(), 1.0);
+    /// results.add_count("barcode", Some(&"AAAAAAAA".to_string()), "AAAA".to_string(), 1.0);
+    /// results.add_count("barcode", Some(&"CCCCCCCC".to_string()), "AAAA".to_string(), 1.0);
+    ///
+    /// if let ResultsHashmap::RandomBarcode(random_hashmap) = results.results_hashmap {
+    ///     assert_eq!(random_hashmap["barcode"]["AAAA"].len(), 2);
+    /// }
+    /// ```
+    pub fn estimate_random_barcodes(&mut self) {
+        self.umi_hll = true;
+    }
 
+    /// Counts every read under one sample name instead of making each sequenced sample barcode into a sample, when there is a sample
+    /// barcode within the format but no sample barcode file.  Without a sample barcode file, every sequencing error within the sample
+    /// barcode becomes a new sample, which can be thousands of samples.  Does nothing if the sample barcodes are converted
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    ///
+    /// let mut results = Results::new(&HashMap::default(), false, true, None);
+    /// results.pool_samples("unknown");
+    /// results.add_count("AGCT", None, "a1".to_string(), 1.0);
+    /// results.add_count("TTGA", None, "a1".to_string(), 1.0);
+    /// if let ResultsHashmap::NoRandomBarcode(count_hashmap) = &results.results_hashmap {
+    ///     assert_eq!(count_hashmap.len(), 1);
+    ///     assert_eq!(count_hashmap["unknown"]["a1"], 2);
+    /// }
+    /// ```
+    pub fn pool_samples(&mut self, sample_name: &str) {
+        if !self.sample_conversion_omited {
+            return;
+        }
+        self.sample_conversion_omited = false;
         match self.results_hashmap {
-            // If random barcode is not included, add the count to this hashmap
+            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                random_hashmap.insert(sample_name.to_string(), self.empty_random_hash.clone());
+            }
             ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
-                *count_hashmap
-                    .get_mut(sample_barcode)
-                    .unwrap_or(&mut self.empty_count_hash.clone())
-                    .entry(barcode_string)
-                    .or_insert(0) += 1;
+                count_hashmap.insert(sample_name.to_string(), self.empty_count_hash.clone());
             }
-            // If a random barcode is included, add the random barcode and later use the number of
-            // random barcodes as the count
-            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
-                // Get the hashmap for the sample
-                let barcodes_hashmap_option = if sample_barcode.is_empty() {
-                    random_hashmap.get_mut("barcode")
-                } else {
-                    random_hashmap.get_mut(sample_barcode)
-                };
-                if let Some(barcodes_hashmap) = barcodes_hashmap_option {
-                    // If the barcodes_hashmap is not empty
-                    // but doesn't contain the barcode
-                    if let std::collections::hash_map::Entry::Vacant(e) = barcodes_hashmap.entry(barcode_string.clone()) {
-                        // insert the hashmap<barcode_id, Set<random_barcodes>>
-                        let mut intermediate_set = AHashSet::new();
-                        intermediate_set
-                            .insert(random_barcode.unwrap_or(&"".to_string()).to_string());
+        }
+        self.pooled_sample = Some(sample_name.to_string());
+    }
+
+    /// Returns the samples without any counted barcodes, sorted.  Only the samples known before counting, from the sample barcode file, can
+    /// be without counts
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::{HashMap, HashMapExt};
+    /// use barcode_count::info::Results;
+    ///
+    /// let mut samples_barcode_hash = HashMap::new();
+    /// samples_barcode_hash.insert("AGCT".to_string(), "S1".to_string());
+    /// samples_barcode_hash.insert("TTGA".to_string(), "S2".to_string());
+    /// let mut results = Results::new(&samples_barcode_hash, false, true, None);
+    /// results.add_count("AGCT", None, "a1".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.empty_samples(), vec!["TTGA".to_string()]);
+    /// ```
+    pub fn empty_samples(&self) -> Vec<String> {
+        let mut empty_samples = match self.results_hashmap {
+            ResultsHashmap::RandomBarcode(ref random_hashmap) => random_hashmap
+                .iter()
+                .filter(|(_, barcodes)| barcodes.is_empty())
+                .map(|(sample_barcode, _)| sample_barcode.to_string())
+                .collect::<Vec<String>>(),
+            ResultsHashmap::NoRandomBarcode(ref count_hashmap) => count_hashmap
+                .iter()
+                .filter(|(_, barcodes)| barcodes.is_empty())
+                .map(|(sample_barcode, _)| sample_barcode.to_string())
+                .collect::<Vec<String>>(),
+        };
+        empty_samples.sort();
+        empty_samples
+    }
+
+    /// Returns each sample barcode along with its number of distinct counted barcodes, sorted by the sample barcode.  This is the same with
+    /// or without a random barcode, since each counted barcode is counted once however many random barcodes it has
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::Results;
+    ///
+    /// let mut results = Results::new(&HashMap::default(), true, true, None);
+    /// results.add_count("AGCT", Some(&"AAAA".to_string()), "a1".to_string(), 1.0);
+    /// results.add_count("AGCT", Some(&"CCCC".to_string()), "a1".to_string(), 1.0);
+    /// results.add_count("AGCT", Some(&"AAAA".to_string()), "a2".to_string(), 1.0);
+    /// results.add_count("TTGA", Some(&"AAAA".to_string()), "a1".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.distinct_barcodes(), vec![("AGCT".to_string(), 2), ("TTGA".to_string(), 1)]);
+    /// ```
+    pub fn distinct_barcodes(&self) -> Vec<(String, usize)> {
+        let mut distinct_barcodes = match self.results_hashmap {
+            ResultsHashmap::RandomBarcode(ref random_hashmap) => random_hashmap
+                .iter()
+                .map(|(sample_barcode, barcodes)| (sample_barcode.to_string(), barcodes.len()))
+                .collect::<Vec<(String, usize)>>(),
+            ResultsHashmap::NoRandomBarcode(ref count_hashmap) => count_hashmap
+                .iter()
+                .map(|(sample_barcode, barcodes)| (sample_barcode.to_string(), barcodes.len()))
+                .collect::<Vec<(String, usize)>>(),
+        };
+        distinct_barcodes.sort();
+        distinct_barcodes
+    }
+
+    /// Adds each sample barcode that is not already within the results as a sample without counts, so that it is written like the
+    /// samples that were counted.  Returns the number of samples added
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::Results;
+    ///
+    /// let mut results = Results::new(&HashMap::default(), false, true, None);
+    /// results.add_count("AGCT", None, "a1".to_string(), 1.0);
+    ///
+    /// let added = results.add_empty_samples(["AGCT".to_string(), "TTGA".to_string()].iter());
+    /// assert_eq!(added, 1);
+    /// assert_eq!(results.empty_samples(), vec!["TTGA".to_string()]);
+    /// ```
+    pub fn add_empty_samples<'a, I>(&mut self, sample_barcodes: I) -> usize
+    where
+        I: Iterator<Item = &'a String>,
+    {
+        let mut added = 0;
+        for sample_barcode in sample_barcodes {
+            let inserted = match self.results_hashmap {
+                ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                    if random_hashmap.contains_key(sample_barcode) {
+                        false
+                    } else {
+                        random_hashmap.insert(sample_barcode.to_string(), self.empty_random_hash.clone());
+                        true
+                    }
+                }
+                ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                    if count_hashmap.contains_key(sample_barcode) {
+                        false
+                    } else {
+                        count_hashmap.insert(sample_barcode.to_string(), self.empty_count_hash.clone());
+                        true
+                    }
+                }
+            };
+            if inserted {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Adds the count to results hashmap.  The weight is added to the weighted counts when they are kept
+    pub fn add_count(
+        &mut self,
+        sample_barcode: &str,
+        random_barcode: Option<&String>,
+        barcode_string: String,
+        weight: f64,
+    ) -> bool {
+        // Technical replicates are counted under their group, and every read is counted under the pooled sample if there is one
+        let sample_barcode = match (&self.pooled_sample, self.sample_groups.get(sample_barcode)) {
+            (Some(pooled_sample), _) => pooled_sample.as_str(),
+            (None, Some(group)) => group.as_str(),
+            (None, None) => sample_barcode,
+        };
+        // Add to the sample's matched reads
+        if let Some(sample_matched) = self.sample_matches.get_mut(sample_barcode) {
+            *sample_matched += 1;
+        } else {
+            self.sample_matches.insert(sample_barcode.to_string(), 1);
+        }
+        // If conversion file does not exist, add the barcode as a key value
+        if self.sample_conversion_omited {
+            match self.results_hashmap {
+                ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                    if !count_hashmap.contains_key(sample_barcode) {
+                        count_hashmap
+                            .insert(sample_barcode.to_string(), self.empty_count_hash.clone());
+                    };
+                }
+                ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                    if !random_hashmap.contains_key(sample_barcode) {
+                        random_hashmap
+                            .insert(sample_barcode.to_string(), self.empty_random_hash.clone());
+                    };
+                }
+            }
+        };
+
+        match self.results_hashmap {
+            // If random barcode is not included, add the count to this hashmap
+            ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                if let Some(ref mut weighted_counts) = self.weighted_counts {
+                    *weighted_counts
+                        .entry(sample_barcode.to_string())
+                        .or_default()
+                        .entry(barcode_string.clone())
+                        .or_insert(0.0) += weight;
+                }
+                *count_hashmap
+                    .get_mut(sample_barcode)
+                    .unwrap_or(&mut self.empty_count_hash.clone())
+                    .entry(barcode_string)
+                    .or_insert(0) += 1;
+            }
+            // If a random barcode is included, add the random barcode and later use the number of
+            // random barcodes as the count
+            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                // Every read is counted, including the duplicates of random barcodes already within the results
+                if let Some(ref mut read_counts) = self.read_counts {
+                    *read_counts
+                        .entry(sample_barcode.to_string())
+                        .or_default()
+                        .entry(barcode_string.clone())
+                        .or_insert(0) += 1;
+                }
+                // Get the hashmap for the sample
+                let barcodes_hashmap_option = if sample_barcode.is_empty() {
+                    random_hashmap.get_mut("barcode")
+                } else {
+                    random_hashmap.get_mut(sample_barcode)
+                };
+                if let Some(barcodes_hashmap) = barcodes_hashmap_option {
+                    // If the barcodes_hashmap is not empty
+                    // but doesn't contain the barcode
+                    if let std::collections::hash_map::Entry::Vacant(e) = barcodes_hashmap.entry(barcode_string.clone()) {
+                        // insert the hashmap<barcode_id, Set<random_barcodes>>
+                        let mut intermediate_set = RandomBarcodes::new(self.umi_bloom_bits, self.umi_hll);
+                        intermediate_set.insert(random_barcode.map_or("", |random| random.as_str()));
                         e.insert(intermediate_set);
                     } else {
                         // if the hashmap<sample_id, hashmap<barcode_id, Set<>> exists, check to see if the random barcode already was inserted
                         let random_set = barcodes_hashmap.get_mut(&barcode_string).unwrap();
-                        return random_set
-                            .insert(random_barcode.unwrap_or(&"".to_string()).to_string());
+                        return random_set.insert(random_barcode.map_or("", |random| random.as_str()));
                     }
                 } else {
                     // create the Set<RandomBarcode>
-                    let mut intermediate_set = AHashSet::new();
-                    intermediate_set.insert(random_barcode.unwrap_or(&"".to_string()).to_string());
+                    let mut intermediate_set = RandomBarcodes::new(self.umi_bloom_bits, self.umi_hll);
+                    intermediate_set.insert(random_barcode.map_or("", |random| random.as_str()));
                     let mut intermediate_hash = HashMap::new();
                     // create the HashMap<barcode_id, Set<RandomBarcodes>>
                     intermediate_hash.insert(barcode_string.to_string(), intermediate_set);
@@ -806,6 +2614,510 @@ impl Results {
         // barcode is already within the results
         true
     }
+
+    /// Adds a sequenced counted barcode that could not be fixed to the tally of uncorrected barcodes.  The barcode position starts at 0
+    pub fn add_uncorrected(&mut self, barcode_position: usize, barcode: &str) {
+        *self
+            .uncorrected_barcodes
+            .entry((barcode_position, barcode.to_string()))
+            .or_insert(0) += 1;
+    }
+
+    /// Adds a sequenced sample barcode that could not be fixed to the tally of unknown sample barcodes
+    pub fn add_unknown_sample(&mut self, sample_barcode: &str) {
+        *self
+            .unknown_samples
+            .entry(sample_barcode.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Creates an empty Results with the same samples, sample groups and kept counts, which a processing thread adds its reads to before
+    /// merging them into the shared Results with merge.  Every random barcode is stored, even with --umi-bloom or --umi-hll, so that the
+    /// merge can find the random barcodes that other threads already counted
+    pub fn partial(&self) -> Results {
+        let results_hashmap = match self.results_hashmap {
+            ResultsHashmap::RandomBarcode(ref random_hashmap) => ResultsHashmap::RandomBarcode(
+                random_hashmap
+                    .keys()
+                    .map(|sample_barcode| (sample_barcode.to_string(), self.empty_random_hash.clone()))
+                    .collect(),
+            ),
+            ResultsHashmap::NoRandomBarcode(ref count_hashmap) => ResultsHashmap::NoRandomBarcode(
+                count_hashmap
+                    .keys()
+                    .map(|sample_barcode| (sample_barcode.to_string(), self.empty_count_hash.clone()))
+                    .collect(),
+            ),
+        };
+        Results {
+            results_hashmap,
+            empty_count_hash: HashMap::new(),
+            empty_random_hash: HashMap::new(),
+            umi_bloom_bits: None,
+            umi_hll: false,
+            sample_conversion_omited: self.sample_conversion_omited,
+            sample_matches: HashMap::new(),
+            uncorrected_barcodes: HashMap::new(),
+            unknown_samples: HashMap::new(),
+            sample_groups: self.sample_groups.clone(),
+            pooled_sample: self.pooled_sample.clone(),
+            weighted_counts: self.weighted_counts.as_ref().map(|_| HashMap::new()),
+            read_counts: self.read_counts.as_ref().map(|_| HashMap::new()),
+        }
+    }
+
+    /// Adds the counts of a Results created with partial.  The random barcodes of each counted barcode are combined, so a random barcode
+    /// found by more than one thread is counted once.  Returns the number of random barcodes that were already counted for each sample with
+    /// any, which are duplicates that the thread counted as new
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), true, false, None);
+    /// let mut first_thread = results.partial();
+    /// first_thread.add_count("barcode", Some(&"AAAA".to_string()), "ACGT".to_string(), 1.0);
+    /// let mut second_thread = results.partial();
+    /// second_thread.add_count("barcode", Some(&"AAAA".to_string()), "ACGT".to_string(), 1.0);
+    /// second_thread.add_count("barcode", Some(&"CCCC".to_string()), "ACGT".to_string(), 1.0);
+    ///
+    /// assert!(results.merge(first_thread).is_empty());
+    /// assert_eq!(results.merge(second_thread)["barcode"], 1);
+    /// if let ResultsHashmap::RandomBarcode(random_hashmap) = &results.results_hashmap {
+    ///     assert_eq!(random_hashmap["barcode"]["ACGT"].len(), 2);
+    /// }
+    /// assert_eq!(results.sample_matches["barcode"], 3);
+    /// ```
+    pub fn merge(&mut self, other: Results) -> HashMap<String, usize> {
+        let mut duplicates = HashMap::new();
+        let umi_bloom_bits = self.umi_bloom_bits;
+        let umi_hll = self.umi_hll;
+        match (&mut self.results_hashmap, other.results_hashmap) {
+            (ResultsHashmap::NoRandomBarcode(count_hashmap), ResultsHashmap::NoRandomBarcode(other_hashmap)) => {
+                for (sample_barcode, other_barcodes) in other_hashmap {
+                    let barcodes_hashmap = count_hashmap.entry(sample_barcode).or_default();
+                    for (barcode_string, count) in other_barcodes {
+                        *barcodes_hashmap.entry(barcode_string).or_insert(0) += count;
+                    }
+                }
+            }
+            (ResultsHashmap::RandomBarcode(random_hashmap), ResultsHashmap::RandomBarcode(other_hashmap)) => {
+                for (sample_barcode, other_barcodes) in other_hashmap {
+                    let mut sample_duplicates = 0;
+                    let barcodes_hashmap = random_hashmap.entry(sample_barcode.clone()).or_default();
+                    for (barcode_string, other_random) in other_barcodes {
+                        match barcodes_hashmap.entry(barcode_string) {
+                            // A counted barcode new to the results takes the thread's random barcodes as they are, when they are stored the same way
+                            std::collections::hash_map::Entry::Vacant(entry) if umi_bloom_bits.is_none() && !umi_hll => {
+                                entry.insert(other_random);
+                            }
+                            entry => {
+                                let random_barcodes =
+                                    entry.or_insert_with(|| RandomBarcodes::new(umi_bloom_bits, umi_hll));
+                                match other_random {
+                                    RandomBarcodes::Exact(other_set) => {
+                                        for random_barcode in &other_set {
+                                            if !random_barcodes.insert(random_barcode) {
+                                                sample_duplicates += 1;
+                                            }
+                                        }
+                                    }
+                                    other_random => random_barcodes.merge(other_random),
+                                }
+                            }
+                        }
+                    }
+                    if sample_duplicates > 0 {
+                        duplicates.insert(sample_barcode, sample_duplicates);
+                    }
+                }
+            }
+            _ => panic!("Results with and without a random barcode can not be merged"),
+        }
+        for (sample_barcode, matched) in other.sample_matches {
+            *self.sample_matches.entry(sample_barcode).or_insert(0) += matched;
+        }
+        for (uncorrected_barcode, reads) in other.uncorrected_barcodes {
+            *self.uncorrected_barcodes.entry(uncorrected_barcode).or_insert(0) += reads;
+        }
+        for (sample_barcode, reads) in other.unknown_samples {
+            *self.unknown_samples.entry(sample_barcode).or_insert(0) += reads;
+        }
+        if let (Some(weighted_counts), Some(other_weighted_counts)) = (&mut self.weighted_counts, other.weighted_counts) {
+            for (sample_barcode, other_barcodes) in other_weighted_counts {
+                let barcodes_hashmap = weighted_counts.entry(sample_barcode).or_default();
+                for (barcode_string, weight) in other_barcodes {
+                    *barcodes_hashmap.entry(barcode_string).or_insert(0.0) += weight;
+                }
+            }
+        }
+        if let (Some(read_counts), Some(other_read_counts)) = (&mut self.read_counts, other.read_counts) {
+            for (sample_barcode, other_barcodes) in other_read_counts {
+                let barcodes_hashmap = read_counts.entry(sample_barcode).or_default();
+                for (barcode_string, reads) in other_barcodes {
+                    *barcodes_hashmap.entry(barcode_string).or_insert(0) += reads;
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// Packs the results into a string with one `sample\tbarcodes\tcount` line per counted barcode, or with the comma separated
+    /// random barcodes in place of the count when a random barcode is included.  Used to save checkpoints.  Random barcodes kept
+    /// within Bloom filters cannot be listed, so checkpoints are not used with --umi-bloom
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::Results;
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), false, false, None);
+    /// results.add_count("barcode", None, "AAAA,CCCC".to_string(), 1.0);
+    /// results.add_count("barcode", None, "AAAA,CCCC".to_string(), 1.0);
+    /// assert_eq!(results.pack(), "barcode\tAAAA,CCCC\t2\n");
+    ///
+    /// let mut resumed_results = Results::new(&HashMap::new(), false, false, None);
+    /// for line in results.pack().lines() {
+    ///     resumed_results.unpack(line).unwrap();
+    /// }
+    /// assert_eq!(resumed_results.pack(), results.pack());
+    /// ```
+    pub fn pack(&self) -> String {
+        let mut packed = String::new();
+        match &self.results_hashmap {
+            ResultsHashmap::NoRandomBarcode(count_hashmap) => {
+                for (sample_barcode, barcodes_hashmap) in count_hashmap {
+                    for (barcode_string, count) in barcodes_hashmap {
+                        packed.push_str(&format!(
+                            "{}\t{}\t{}\n",
+                            sample_barcode, barcode_string, count
+                        ));
+                    }
+                }
+            }
+            ResultsHashmap::RandomBarcode(random_hashmap) => {
+                for (sample_barcode, barcodes_hashmap) in random_hashmap {
+                    for (barcode_string, random_set) in barcodes_hashmap {
+                        packed.push_str(&format!(
+                            "{}\t{}\t{}\n",
+                            sample_barcode,
+                            barcode_string,
+                            random_set.exact().map(|set| set.iter().join(",")).unwrap_or_default()
+                        ));
+                    }
+                }
+            }
+        }
+        packed
+    }
+
+    /// Adds a line created with pack back into the results.  Used to resume from checkpoints
+    pub fn unpack(&mut self, packed_line: &str) -> Result<()> {
+        let (sample_barcode, barcode_string, value) = packed_line
+            .split('\t')
+            .collect_tuple()
+            .ok_or_else(|| anyhow!("Checkpoint results line is malformed: {}", packed_line))?;
+        let umi_bloom_bits = self.umi_bloom_bits;
+        let umi_hll = self.umi_hll;
+        match self.results_hashmap {
+            ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                let count = value.parse::<usize>().context(format!(
+                    "Checkpoint count is not an integer: {}",
+                    packed_line
+                ))?;
+                *count_hashmap
+                    .entry(sample_barcode.to_string())
+                    .or_insert_with(|| self.empty_count_hash.clone())
+                    .entry(barcode_string.to_string())
+                    .or_insert(0) += count;
+            }
+            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                random_hashmap
+                    .entry(sample_barcode.to_string())
+                    .or_insert_with(|| self.empty_random_hash.clone())
+                    .entry(barcode_string.to_string())
+                    .or_insert_with(|| RandomBarcodes::new(umi_bloom_bits, umi_hll))
+                    .extend(value.split(','));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes, per sample, the counted barcodes with fewer than min_random_barcodes unique random barcodes.  Used to call a barcode present
+    /// only when it has enough random barcodes, which filters singletons from index hopping.  Returns the number of barcodes removed, which is
+    /// always 0 without a random barcode
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), true, false, None);
+    /// for random_barcode in ["AAAA", "CCCC", "GGGG"] {
+    ///     results.add_count("barcode", Some(&random_barcode.to_string()), "ACGT".to_string(), 1.0);
+    /// }
+    /// results.add_count("barcode", Some(&"AAAA".to_string()), "TTTT".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.filter_min_random_barcodes(2), 1);
+    /// if let ResultsHashmap::RandomBarcode(random_hashmap) = &results.results_hashmap {
+    ///     assert!(random_hashmap["barcode"].contains_key("ACGT"));
+    ///     assert!(!random_hashmap["barcode"].contains_key("TTTT"));
+    /// }
+    /// ```
+    pub fn filter_min_random_barcodes(&mut self, min_random_barcodes: usize) -> usize {
+        let mut removed = 0;
+        if let ResultsHashmap::RandomBarcode(ref mut random_hashmap) = self.results_hashmap {
+            for barcodes_hashmap in random_hashmap.values_mut() {
+                let barcodes_before = barcodes_hashmap.len();
+                barcodes_hashmap.retain(|_, random_barcodes| random_barcodes.len() >= min_random_barcodes);
+                removed += barcodes_before - barcodes_hashmap.len();
+            }
+        }
+        removed
+    }
+
+    /// Removes the samples within excluded_samples, which are sample barcodes or the sample names used in their place, so that they are
+    /// not written.  Returns the number of samples removed and their counts, which are the unique random barcodes with a random barcode
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::Results;
+    /// use ahash::{AHashSet, HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), false, true, None);
+    /// results.add_count("AGCT", None, "ACGT".to_string(), 1.0);
+    /// results.add_count("TTGA", None, "ACGT".to_string(), 1.0);
+    /// results.add_count("TTGA", None, "CCCC".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.exclude_samples(&AHashSet::from_iter(["TTGA".to_string()])), (1, 2));
+    /// ```
+    pub fn exclude_samples(&mut self, excluded_samples: &AHashSet<String>) -> (usize, usize) {
+        let mut removed = (0, 0);
+        for sample_barcode in excluded_samples {
+            let removed_counts = match self.results_hashmap {
+                ResultsHashmap::RandomBarcode(ref mut random_hashmap) => random_hashmap
+                    .remove(sample_barcode)
+                    .map(|barcodes_hashmap| barcodes_hashmap.values().map(|random_barcodes| random_barcodes.len()).sum::<usize>()),
+                ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => count_hashmap
+                    .remove(sample_barcode)
+                    .map(|barcodes_hashmap| barcodes_hashmap.values().sum::<usize>()),
+            };
+            if let Some(removed_counts) = removed_counts {
+                removed.0 += 1;
+                removed.1 += removed_counts;
+            }
+        }
+        removed
+    }
+
+    /// Removes, within every sample, the counted barcodes that have any barcode within excluded_barcodes, such as a spike-in control, so
+    /// that they are not written.  Returns the number of counted barcodes removed, summed over the samples, and their counts, which are
+    /// the unique random barcodes with a random barcode
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::Results;
+    /// use ahash::{AHashSet, HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), false, false, None);
+    /// results.add_count("barcode", None, "ACGT,TTTT".to_string(), 1.0);
+    /// results.add_count("barcode", None, "ACGT,TTTT".to_string(), 1.0);
+    /// results.add_count("barcode", None, "ACGT,GGGG".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.exclude_barcodes(&AHashSet::from_iter(["TTTT".to_string()])), (1, 2));
+    /// ```
+    pub fn exclude_barcodes(&mut self, excluded_barcodes: &AHashSet<String>) -> (usize, usize) {
+        let excluded = |code: &str| code.split(',').any(|barcode| excluded_barcodes.contains(barcode));
+        let mut removed = (0, 0);
+        match self.results_hashmap {
+            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                for barcodes_hashmap in random_hashmap.values_mut() {
+                    barcodes_hashmap.retain(|code, random_barcodes| {
+                        if excluded(code) {
+                            removed.0 += 1;
+                            removed.1 += random_barcodes.len();
+                            return false;
+                        }
+                        true
+                    });
+                }
+            }
+            ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                for barcodes_hashmap in count_hashmap.values_mut() {
+                    barcodes_hashmap.retain(|code, count| {
+                        if excluded(code) {
+                            removed.0 += 1;
+                            removed.1 += *count;
+                            return false;
+                        }
+                        true
+                    });
+                }
+            }
+        }
+        removed
+    }
+
+    /// Downsamples, per sample, the counts to a total of downsample_to reads with a multinomial draw, where each drawn read is a counted
+    /// barcode with the chance of its share of the sample's reads.  Used so that samples sequenced to different depths can be compared.
+    /// Counted barcodes that are not drawn are removed.  The samples are drawn in order with one generator from the seed, so the same seed
+    /// gives the same counts.  Returns the sample barcodes with fewer reads than downsample_to, which are not changed.  Only changes the
+    /// counts without a random barcode
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), false, false, None);
+    /// for _ in 0..90 {
+    ///     results.add_count("barcode", None, "AAAA".to_string(), 1.0);
+    /// }
+    /// for _ in 0..10 {
+    ///     results.add_count("barcode", None, "CCCC".to_string(), 1.0);
+    /// }
+    ///
+    /// assert!(results.downsample(20, 1).is_empty());
+    /// if let ResultsHashmap::NoRandomBarcode(count_hashmap) = &results.results_hashmap {
+    ///     assert_eq!(count_hashmap["barcode"].values().sum::<usize>(), 20);
+    /// }
+    /// assert_eq!(results.downsample(50, 1), vec!["barcode".to_string()]);
+    /// ```
+    pub fn downsample(&mut self, downsample_to: usize, seed: u64) -> Vec<String> {
+        let mut shallow_samples = Vec::new();
+        let mut rng = SeededRng::new(seed);
+        if let ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) = self.results_hashmap {
+            for (sample_barcode, barcodes_hashmap) in count_hashmap.iter_mut().sorted_by(|a, b| a.0.cmp(b.0)) {
+                let sample_total = barcodes_hashmap.values().sum::<usize>();
+                if sample_total < downsample_to {
+                    shallow_samples.push(sample_barcode.to_string());
+                    continue;
+                }
+                // Each drawn read is found by its position within the cumulative counts of the sorted barcodes
+                let barcodes = barcodes_hashmap.keys().sorted().cloned().collect::<Vec<String>>();
+                let cumulative_counts = barcodes
+                    .iter()
+                    .scan(0, |cumulative_count, barcode| {
+                        *cumulative_count += barcodes_hashmap[barcode];
+                        Some(*cumulative_count)
+                    })
+                    .collect::<Vec<usize>>();
+                let mut drawn_counts = vec![0; barcodes.len()];
+                for _ in 0..downsample_to {
+                    let read_position = rng.below(sample_total);
+                    drawn_counts[cumulative_counts.partition_point(|cumulative_count| *cumulative_count <= read_position)] += 1;
+                }
+                barcodes_hashmap.clear();
+                for (barcode, drawn_count) in barcodes.into_iter().zip(drawn_counts) {
+                    if drawn_count > 0 {
+                        barcodes_hashmap.insert(barcode, drawn_count);
+                    }
+                }
+            }
+        }
+        shallow_samples
+    }
+
+    /// Greedily merges, per sample, counted barcodes within one mismatch of a higher count barcode into that barcode.  Used when there is
+    /// no counted barcode conversion file so that sequencing errors do not fragment the counts.  Returns the number of barcodes merged
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::{Results, ResultsHashmap};
+    /// use ahash::{HashMap, HashMapExt};
+    ///
+    /// let mut results = Results::new(&HashMap::new(), false, false, None);
+    /// for _ in 0..10 {
+    ///     results.add_count("barcode", None, "AAAA,CCCC".to_string(), 1.0);
+    /// }
+    /// results.add_count("barcode", None, "AAAT,CCCC".to_string(), 1.0);
+    /// results.add_count("barcode", None, "GGGG,CCCC".to_string(), 1.0);
+    ///
+    /// assert_eq!(results.cluster_counted_barcodes(), 1);
+    /// if let ResultsHashmap::NoRandomBarcode(count_hashmap) = &results.results_hashmap {
+    ///     assert_eq!(count_hashmap["barcode"]["AAAA,CCCC"], 11);
+    ///     assert_eq!(count_hashmap["barcode"]["GGGG,CCCC"], 1);
+    /// }
+    /// ```
+    pub fn cluster_counted_barcodes(&mut self) -> usize {
+        let mut merged = 0;
+        match self.results_hashmap {
+            ResultsHashmap::NoRandomBarcode(ref mut count_hashmap) => {
+                for barcodes_hashmap in count_hashmap.values_mut() {
+                    merged += cluster_hashmap(
+                        barcodes_hashmap,
+                        |count| *count,
+                        |representative, count| *representative += count,
+                    );
+                }
+            }
+            ResultsHashmap::RandomBarcode(ref mut random_hashmap) => {
+                for barcodes_hashmap in random_hashmap.values_mut() {
+                    merged += cluster_hashmap(
+                        barcodes_hashmap,
+                        |random_set| random_set.len(),
+                        |representative, random_set| representative.merge(random_set),
+                    );
+                }
+            }
+        }
+        merged
+    }
+}
+
+/// Merges every barcode key that is within one mismatch of a higher count barcode key into the higher count barcode.  Barcodes are
+/// visited from highest to lowest count, and each visited barcode absorbs all of its unvisited single mismatch neighbors
+fn cluster_hashmap<V, C, M>(barcodes_hashmap: &mut HashMap<String, V>, count: C, merge: M) -> usize
+where
+    C: Fn(&V) -> usize,
+    M: Fn(&mut V, V),
+{
+    // Sort by count, then by barcode so that the result does not depend on the hashmap order
+    let mut ordered_barcodes = barcodes_hashmap
+        .iter()
+        .map(|(barcode, value)| (count(value), barcode.to_string()))
+        .collect::<Vec<(usize, String)>>();
+    ordered_barcodes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let mut merged = 0;
+    for (_, representative) in ordered_barcodes {
+        // Skip the barcode if it was already merged into a higher count barcode
+        if !barcodes_hashmap.contains_key(&representative) {
+            continue;
+        }
+        let representative_count = count(&barcodes_hashmap[&representative]);
+        let mut neighbor_chars = representative.chars().collect::<Vec<char>>();
+        for position in 0..neighbor_chars.len() {
+            let original_char = neighbor_chars[position];
+            // Commas separate the counted barcodes and are not changed
+            if original_char == ',' {
+                continue;
+            }
+            for nucleotide in ['A', 'C', 'G', 'T', 'N'] {
+                if nucleotide == original_char {
+                    continue;
+                }
+                neighbor_chars[position] = nucleotide;
+                let neighbor = neighbor_chars.iter().collect::<String>();
+                // Only merge lower or equal count neighbors.  Equal counts are merged into the barcode that sorts first
+                let mergeable = barcodes_hashmap
+                    .get(&neighbor)
+                    .map(|value| count(value) <= representative_count)
+                    .unwrap_or(false);
+                if mergeable {
+                    let neighbor_value = barcodes_hashmap.remove(&neighbor).unwrap();
+                    merge(
+                        barcodes_hashmap.get_mut(&representative).unwrap(),
+                        neighbor_value,
+                    );
+                    merged += 1;
+                }
+            }
+            neighbor_chars[position] = original_char;
+        }
+    }
+    merged
 }
 
 /// A struct which holds hte enriched single and double counted barcodes.  Useful for DEL.  This struct is used during output.
@@ -836,12 +3148,21 @@ impl ResultsEnrichment {
         }
     }
 
-    /// Adds the count the the single barcode enrichment hashmap
-    pub fn add_single(&mut self, sample_id: &str, barcode_string: &str, count: usize) {
+    /// Adds the count the the single barcode enrichment hashmap.  With unordered barcodes, the column a barcode was in does not matter,
+    /// so every single barcode is placed within the first column
+    pub fn add_single(
+        &mut self,
+        sample_id: &str,
+        barcode_string: &str,
+        count: usize,
+        delimiter: char,
+        unordered: bool,
+    ) {
         // get the number of barcodes to know homu much to iterate
-        let barcode_num = barcode_string.split(',').count();
-        // For each single barcode in the comma separate barcodes, create a new string with just one barcode and empty other columns
-        for (index, single_barcode) in barcode_string.split(',').enumerate() {
+        let barcode_num = barcode_string.split(delimiter).count();
+        // For each single barcode in the delimiter separate barcodes, create a new string with just one barcode and empty other columns
+        for (index, single_barcode) in barcode_string.split(delimiter).enumerate() {
+            let index = if unordered { 0 } else { index };
             let mut single_barcode_string = String::new();
             // Recreate the new comma separated barcode with only one barcode
             for x in 0..barcode_num {
@@ -851,7 +3172,7 @@ impl ResultsEnrichment {
                 }
                 // Don't add a comma at the end
                 if x != (barcode_num - 1) {
-                    single_barcode_string.push(',');
+                    single_barcode_string.push(delimiter);
                 }
             }
             // Insert 0 if the barcodes are not within the single_hashmap -> barcodes
@@ -865,30 +3186,44 @@ impl ResultsEnrichment {
         }
     }
 
-    /// Adds the count to the double barcode enrichment hashmap
-    pub fn add_double(&mut self, sample_id: &str, barcode_string: &str, count: usize) {
+    /// Adds the count to the double barcode enrichment hashmap.  With unordered barcodes, each pair is placed within the first two columns,
+    /// keeping the sorted order of the canonical barcode string
+    pub fn add_double(
+        &mut self,
+        sample_id: &str,
+        barcode_string: &str,
+        count: usize,
+        delimiter: char,
+        unordered: bool,
+    ) {
         // get the number of barcodes to know homu much to iterate
-        let barcode_num = barcode_string.split(',').count();
-        // split the barcodes into a vec from their comma separated form
-        let barcode_split = barcode_string.split(',').collect::<Vec<&str>>();
+        let barcode_num = barcode_string.split(delimiter).count();
+        // split the barcodes into a vec from their delimiter separated form
+        let barcode_split = barcode_string.split(delimiter).collect::<Vec<&str>>();
         // iterate through the number of barcode_num - 1, and take this index for the first barcode
         for first_barcode_index in 0..(barcode_num - 1) {
             // Get the amount needed to add to the first index in order to get the second index.  This is iterated to account for the second being the next barcode or two away etc. Eg from 1,2,3 = 1,2,, and 1,,3
             for next_barcode_add in 1..(barcode_num - first_barcode_index) {
                 // Initiate the new barcode string
                 let mut double_barcode_string = String::new();
+                // The columns the pair is placed within
+                let (first_column, second_column) = if unordered {
+                    (0, 1)
+                } else {
+                    (first_barcode_index, first_barcode_index + next_barcode_add)
+                };
                 // Iterate over each comma separated column and insert the barcode if needed
                 for column_index in 0..barcode_num {
                     // If it is either the first or second barcode, add comma separated to the new string
-                    if column_index == first_barcode_index {
+                    if column_index == first_column {
                         double_barcode_string.push_str(barcode_split[first_barcode_index])
-                    } else if column_index == (first_barcode_index + next_barcode_add) {
+                    } else if column_index == second_column {
                         double_barcode_string
                             .push_str(barcode_split[first_barcode_index + next_barcode_add])
                     }
                     // If we are not on the last barcode, add a comma
                     if column_index != (barcode_num - 1) {
-                        double_barcode_string.push(',')
+                        double_barcode_string.push(delimiter)
                     }
                 }
                 // Insert 0 if the barcodes are not within the double_hashmap -> barcodes
@@ -909,3 +3244,493 @@ impl Default for ResultsEnrichment {
         Self::new()
     }
 }
+
+/// A struct which holds the read length and format match start distributions of matched reads.  Useful for diagnosing adapter-dimers or staggered libraries.
+/// Also holds the base composition of the matched barcode regions for a GC content QC metric.  Each processing thread keeps its own and they are merged at the end
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InsertStats {
+    read_lengths: HashMap<usize, u64>, // read length -> number of matched reads
+    match_starts: HashMap<usize, u64>, // offset where the format match started -> number of matched reads
+    gc_bases: u64,                     // number of 'G' and 'C' bases within the matched barcode regions
+    at_bases: u64,                     // number of 'A' and 'T' bases within the matched barcode regions
+    merged_pairs: u64,                 // read pairs merged by their overlap with --merge-pairs
+    read_pairs: u64,                   // read pairs read with --merge-pairs
+    random_bases: Vec<[u64; 5]>,       // A, C, G, T and other base counts at each random barcode position.  Only added to with --umi-composition
+}
+
+impl InsertStats {
+    /// Creates an empty InsertStats struct
+    pub fn new() -> Self {
+        InsertStats {
+            read_lengths: HashMap::new(),
+            match_starts: HashMap::new(),
+            gc_bases: 0,
+            at_bases: 0,
+            merged_pairs: 0,
+            read_pairs: 0,
+            random_bases: Vec::new(),
+        }
+    }
+
+    /// Adds the number of read pairs merged by their overlap out of the read pairs read
+    pub fn add_read_pairs(&mut self, merged_pairs: u64, read_pairs: u64) {
+        self.merged_pairs += merged_pairs;
+        self.read_pairs += read_pairs;
+    }
+
+    /// Adds the read length and match start of a matched read
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::InsertStats;
+    ///
+    /// let mut insert_stats = InsertStats::new();
+    /// insert_stats.add(150, 2);
+    /// insert_stats.add(148, 0);
+    /// insert_stats.add(150, 2);
+    /// assert_eq!(insert_stats.total(), 3);
+    /// ```
+    pub fn add(&mut self, read_length: usize, match_start: usize) {
+        *self.read_lengths.entry(read_length).or_insert(0) += 1;
+        *self.match_starts.entry(match_start).or_insert(0) += 1;
+    }
+
+    /// Merges the counts of another InsertStats into this one
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::InsertStats;
+    ///
+    /// let mut insert_stats = InsertStats::new();
+    /// insert_stats.add(150, 2);
+    /// let mut thread_stats = InsertStats::new();
+    /// thread_stats.add(148, 0);
+    /// insert_stats.merge(&thread_stats);
+    /// assert_eq!(insert_stats.total(), 2);
+    /// ```
+    pub fn merge(&mut self, other: &InsertStats) {
+        self.gc_bases += other.gc_bases;
+        self.at_bases += other.at_bases;
+        self.merged_pairs += other.merged_pairs;
+        self.read_pairs += other.read_pairs;
+        if self.random_bases.len() < other.random_bases.len() {
+            self.random_bases.resize(other.random_bases.len(), [0; 5]);
+        }
+        for (position_bases, other_position_bases) in self.random_bases.iter_mut().zip(&other.random_bases) {
+            for (base_count, other_base_count) in position_bases.iter_mut().zip(other_position_bases) {
+                *base_count += other_base_count;
+            }
+        }
+        for (read_length, count) in &other.read_lengths {
+            *self.read_lengths.entry(*read_length).or_insert(0) += count;
+        }
+        for (match_start, count) in &other.match_starts {
+            *self.match_starts.entry(*match_start).or_insert(0) += count;
+        }
+    }
+
+    /// Returns the total number of matched reads recorded
+    pub fn total(&self) -> u64 {
+        self.read_lengths.values().sum()
+    }
+
+    /// Adds the base composition of a matched barcode region.  'N's and other characters are not counted
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::InsertStats;
+    ///
+    /// let mut insert_stats = InsertStats::new();
+    /// insert_stats.add_barcode_bases("GGCA");
+    /// insert_stats.add_barcode_bases("TANN");
+    /// assert_eq!(insert_stats.gc_percent(), Some(50.0));
+    /// ```
+    pub fn add_barcode_bases(&mut self, barcode: &str) {
+        for base in barcode.bytes() {
+            match base {
+                b'G' | b'C' => self.gc_bases += 1,
+                b'A' | b'T' => self.at_bases += 1,
+                _ => (),
+            }
+        }
+    }
+
+    /// Adds the base at each position of a matched read's random barcode to the per position base composition.  'N's and any other
+    /// characters are counted as other bases
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::InsertStats;
+    ///
+    /// let mut insert_stats = InsertStats::new();
+    /// insert_stats.add_random_bases("ACGN");
+    /// insert_stats.add_random_bases("AAGT");
+    /// let composition = insert_stats.random_composition_text(',');
+    ///
+    /// assert_eq!(composition.lines().next(), Some("Position,A,C,G,T,Other,A_Fraction,C_Fraction,G_Fraction,T_Fraction"));
+    /// assert_eq!(composition.lines().nth(1), Some("1,2,0,0,0,0,1.0000,0.0000,0.0000,0.0000"));
+    /// assert_eq!(composition.lines().nth(4), Some("4,0,0,0,1,1,0.0000,0.0000,0.0000,0.5000"));
+    /// ```
+    pub fn add_random_bases(&mut self, random_barcode: &str) {
+        if self.random_bases.len() < random_barcode.len() {
+            self.random_bases.resize(random_barcode.len(), [0; 5]);
+        }
+        for (position_bases, base) in self.random_bases.iter_mut().zip(random_barcode.bytes()) {
+            let base_index = match base {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            position_bases[base_index] += 1;
+        }
+    }
+
+    /// Creates the table of the base counts at each random barcode position, followed by the fraction of each position's bases that are
+    /// each nucleotide.  An unbiased random barcode has close to 0.25 for each
+    pub fn random_composition_text(&self, delimiter: char) -> String {
+        let mut composition_text = ["Position", "A", "C", "G", "T", "Other", "A_Fraction", "C_Fraction", "G_Fraction", "T_Fraction"]
+            .join(&delimiter.to_string());
+        composition_text.push('\n');
+        for (position, position_bases) in self.random_bases.iter().enumerate() {
+            let position_total = position_bases.iter().sum::<u64>().max(1) as f64;
+            let mut row = vec![(position + 1).to_string()];
+            row.extend(position_bases.iter().map(|base_count| base_count.to_string()));
+            row.extend(
+                position_bases
+                    .iter()
+                    .take(4)
+                    .map(|base_count| format!("{:.4}", *base_count as f64 / position_total)),
+            );
+            composition_text.push_str(&row.join(&delimiter.to_string()));
+            composition_text.push('\n');
+        }
+        composition_text
+    }
+
+    /// Returns the percent of the matched barcode bases that are 'G' or 'C'.  None if no bases have been added
+    pub fn gc_percent(&self) -> Option<f64> {
+        let total_bases = self.gc_bases + self.at_bases;
+        if total_bases == 0 {
+            None
+        } else {
+            Some(self.gc_bases as f64 / total_bases as f64 * 100.0)
+        }
+    }
+}
+
+/// Creates the min/median/max line and a small histogram of up to 10 equal width bins from a value -> count hashmap
+fn distribution_summary(distribution: &HashMap<usize, u64>) -> String {
+    let mut values = distribution.iter().collect::<Vec<(&usize, &u64)>>();
+    if values.is_empty() {
+        return "None".to_string();
+    }
+    values.sort();
+    let total: u64 = values.iter().map(|(_, count)| **count).sum();
+    let min = *values.first().unwrap().0;
+    let max = *values.last().unwrap().0;
+    // Find the median by walking the sorted values until half of the total counts have been passed
+    let mut median = min;
+    let mut running_total = 0;
+    for (value, count) in &values {
+        running_total += **count;
+        if running_total * 2 >= total {
+            median = **value;
+            break;
+        }
+    }
+    let mut summary = format!("min/median/max: {}/{}/{}", min, median, max);
+
+    // Group the values into bins for the histogram
+    let bin_width = (max - min) / 10 + 1;
+    let mut bins = vec![0u64; (max - min) / bin_width + 1];
+    for (value, count) in &values {
+        bins[(**value - min) / bin_width] += **count;
+    }
+    for (bin_index, bin_count) in bins.iter().enumerate() {
+        let bin_start = min + bin_index * bin_width;
+        let bin_end = bin_start + bin_width - 1;
+        let bin_name = if bin_width == 1 {
+            bin_start.to_string()
+        } else {
+            format!("{}-{}", bin_start, bin_end)
+        };
+        summary.push_str(&format!(
+            "\n  {}:\t{}",
+            bin_name,
+            bin_count.to_formatted_string(&Locale::en)
+        ));
+    }
+    summary
+}
+
+impl fmt::Display for InsertStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "-MATCHED READ INFO-\nRead length {}\nFormat match start {}\nBarcode GC content: {}\n",
+            distribution_summary(&self.read_lengths),
+            distribution_summary(&self.match_starts),
+            self.gc_percent()
+                .map_or("None".to_string(), |gc_percent| format!("{:.2}%", gc_percent))
+        )?;
+        if self.read_pairs > 0 {
+            writeln!(
+                f,
+                "Merged read pairs: {} of {} ({:.2}%)",
+                self.merged_pairs.to_formatted_string(&Locale::en),
+                self.read_pairs.to_formatted_string(&Locale::en),
+                self.merged_pairs as f64 / self.read_pairs as f64 * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An estimate of the barcodes within a sample that were not sequenced, from how many barcodes were seen once (singletons) and twice
+/// (doubletons).  Unseen uses the bias-corrected Chao1 estimator, f1(f1 - 1) / (2(f2 + 1)) * (n - 1) / n, and the Good-Turing estimate of
+/// the chance that one more read is a new barcode is f1 / n.  Many singletons compared with doubletons mean the sample is not sequenced
+/// deep enough to see most of its barcodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RichnessEstimate {
+    pub observed: usize,   // number of different barcodes counted
+    pub reads: usize,      // total count of all barcodes
+    pub singletons: usize, // barcodes counted once
+    pub doubletons: usize, // barcodes counted twice
+    pub unseen: f64,       // Chao1 estimate of the barcodes not yet sequenced
+    pub new_barcode_chance: f64, // Good-Turing estimate of the chance the next read is a barcode not yet sequenced
+}
+
+impl RichnessEstimate {
+    /// Creates the estimate from the count of each barcode within a sample
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::info::RichnessEstimate;
+    ///
+    /// let richness_estimate = RichnessEstimate::from_counts(vec![1, 1, 1, 2, 5]);
+    /// assert_eq!(richness_estimate.observed, 5);
+    /// assert_eq!(richness_estimate.singletons, 3);
+    /// assert_eq!(richness_estimate.doubletons, 1);
+    /// // 3 * 2 / (2 * 2) * 9 / 10
+    /// assert!((richness_estimate.unseen - 1.35).abs() < 1e-9);
+    /// assert!((richness_estimate.new_barcode_chance - 0.3).abs() < 1e-9);
+    /// ```
+    pub fn from_counts<I: IntoIterator<Item = usize>>(counts: I) -> Self {
+        let mut observed = 0;
+        let mut reads = 0;
+        let mut singletons = 0;
+        let mut doubletons = 0;
+        for count in counts {
+            observed += 1;
+            reads += count;
+            match count {
+                1 => singletons += 1,
+                2 => doubletons += 1,
+                _ => (),
+            }
+        }
+        let (unseen, new_barcode_chance) = if reads == 0 {
+            (0.0, 0.0)
+        } else {
+            let reads_f64 = reads as f64;
+            let singletons_f64 = singletons as f64;
+            (
+                (reads_f64 - 1.0) / reads_f64 * singletons_f64 * (singletons_f64 - 1.0).max(0.0)
+                    / (2.0 * (doubletons as f64 + 1.0)),
+                singletons_f64 / reads_f64,
+            )
+        };
+        RichnessEstimate {
+            observed,
+            reads,
+            singletons,
+            doubletons,
+            unseen,
+            new_barcode_chance,
+        }
+    }
+}
+
+impl fmt::Display for RichnessEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Observed barcodes: {}\nSingletons: {}\nDoubletons: {}\nEstimated unseen barcodes (Chao1): {:.1}\nEstimated total barcodes: {:.1}\nChance the next read is a new barcode (Good-Turing): {:.4}%",
+            self.observed.to_formatted_string(&Locale::en),
+            self.singletons.to_formatted_string(&Locale::en),
+            self.doubletons.to_formatted_string(&Locale::en),
+            self.unseen,
+            self.observed as f64 + self.unseen,
+            self.new_barcode_chance * 100.0
+        )
+    }
+}
+
+/// The counts and stats of a finished parse.  Saved right before the counts files are written, so that a failed write, such as from a full
+/// disk, can be run again with --write-from-checkpoint without reparsing the fastq files
+pub struct WriteCheckpoint {
+    pub results: Vec<Results>,    // counts of each sequence format, in order
+    pub sequence_errors: String,  // sequence errors created with SequenceErrors::pack
+    pub total_reads: u32,         // reads within the fastq files
+    pub insert_stats: InsertStats, // read length, match start and GC content of the matched reads
+    pub stage_times: StageTimes,   // reading and parsing spans of the parse
+}
+
+impl WriteCheckpoint {
+    /// Writes the counts and stats with bincode.  The file is written under a temporary name and then renamed, so that an interrupted
+    /// save does not leave a partial checkpoint
+    ///
+    /// # Example
+    /// ```
+    /// use ahash::HashMap;
+    /// use barcode_count::info::{InsertStats, Results, SequenceErrors, StageTimes, WriteCheckpoint};
+    ///
+    /// let mut results = Results::new(&HashMap::default(), false, false, None);
+    /// results.add_count("barcode", None, "AAAA".to_string(), 1.0);
+    /// let checkpoint_path = std::env::temp_dir().join("example_results.checkpoint");
+    /// WriteCheckpoint::save(&checkpoint_path, &[&results], &SequenceErrors::new(), 1, &InsertStats::new(), &StageTimes::default()).unwrap();
+    ///
+    /// let write_checkpoint = WriteCheckpoint::load(&checkpoint_path).unwrap();
+    /// assert_eq!(write_checkpoint.results.len(), 1);
+    /// assert_eq!(write_checkpoint.total_reads, 1);
+    /// ```
+    pub fn save(
+        checkpoint_path: &Path,
+        results: &[&Results],
+        sequence_errors: &SequenceErrors,
+        total_reads: u32,
+        insert_stats: &InsertStats,
+        stage_times: &StageTimes,
+    ) -> Result<()> {
+        let temp_path = checkpoint_path.with_extension("checkpoint.tmp");
+        let checkpoint_file = File::create(&temp_path)
+            .context(format!("Unable to create results checkpoint: {}", temp_path.display()))?;
+        let mut writer = BufWriter::new(checkpoint_file);
+        bincode::serialize_into(&mut writer, results)
+            .and_then(|_| bincode::serialize_into(&mut writer, &sequence_errors.pack()))
+            .and_then(|_| bincode::serialize_into(&mut writer, &total_reads))
+            .and_then(|_| bincode::serialize_into(&mut writer, insert_stats))
+            .and_then(|_| bincode::serialize_into(&mut writer, stage_times))
+            .context(format!("Unable to write results checkpoint: {}", temp_path.display()))?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .and_then(|checkpoint_file| checkpoint_file.sync_all())
+            .context(format!("Unable to write results checkpoint: {}", temp_path.display()))?;
+        fs::rename(&temp_path, checkpoint_path)
+            .context(format!("Unable to rename results checkpoint: {}", temp_path.display()))?;
+        Ok(())
+    }
+
+    /// Reads a checkpoint written by save
+    pub fn load(checkpoint_path: &Path) -> Result<Self> {
+        let checkpoint_file = File::open(checkpoint_path)
+            .context(format!("Unable to open results checkpoint: {}", checkpoint_path.display()))?;
+        let mut reader = BufReader::new(checkpoint_file);
+        let read_error = || format!("Unable to read results checkpoint: {}", checkpoint_path.display());
+        Ok(WriteCheckpoint {
+            results: bincode::deserialize_from(&mut reader).with_context(read_error)?,
+            sequence_errors: bincode::deserialize_from(&mut reader).with_context(read_error)?,
+            total_reads: bincode::deserialize_from(&mut reader).with_context(read_error)?,
+            insert_stats: bincode::deserialize_from(&mut reader).with_context(read_error)?,
+            stage_times: bincode::deserialize_from(&mut reader).with_context(read_error)?,
+        })
+    }
+}
+
+/// A small xorshift random number generator for the synthetic reads of the self-test and for downsampling, so that a seed always gives
+/// the same results
+pub struct SeededRng {
+    state: u64, // current state, which is never 0
+}
+
+impl SeededRng {
+    /// Creates the generator from the seed
+    pub fn new(seed: u64) -> Self {
+        SeededRng {
+            state: (seed ^ 0x9E37_79B9_7F4A_7C15).max(1),
+        }
+    }
+
+    /// The next random 64 bit number
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A random number from 0 up to, but not including, max
+    pub fn below(&mut self, max: usize) -> usize {
+        (self.next_u64() % max as u64) as usize
+    }
+
+    /// A random fraction from 0 up to, but not including, 1
+    pub fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random nucleotide
+    pub fn base(&mut self) -> char {
+        ['A', 'C', 'G', 'T'][self.below(4)]
+    }
+}
+
+/// Wall-clock spans of each stage of the run.  The reading and parsing threads run at the same time, so the reading span overlaps the parsing span
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StageTimes {
+    pub reading: Duration, // time within read_fastq, summed over all fastq files
+    pub parsing: Duration, // time from starting the reading and parsing threads until the last parsing thread finishes
+    pub writing: Duration, // time writing the counts files
+    pub peak_memory_kb: Option<u64>, // peak resident memory at the end of parsing, in kB.  None where /proc/self/status is not available
+}
+
+impl fmt::Display for StageTimes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "-STAGE TIMES-\nWall-clock spans.  Reading runs concurrently with parsing and is included within the parsing span\nReading: {:.3} seconds\nParsing: {:.3} seconds\nWriting: {:.3} seconds\n",
+            self.reading.as_secs_f64(),
+            self.parsing.as_secs_f64(),
+            self.writing.as_secs_f64()
+        )?;
+        match self.peak_memory_kb {
+            Some(peak_memory_kb) => writeln!(
+                f,
+                "Peak memory after parsing: {:.1} MB ({} kB)",
+                peak_memory_kb as f64 / 1024.0,
+                peak_memory_kb.to_formatted_string(&Locale::en)
+            ),
+            None => writeln!(f, "Peak memory after parsing: not available"),
+        }
+    }
+}
+
+/// Reads the peak resident memory of this process, in kB, from the VmHWM line of /proc/self/status.  Returns None on systems without
+/// /proc, such as macOS and Windows
+pub fn peak_resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    parse_peak_memory(&status)
+}
+
+/// Parses the peak resident memory, in kB, from the contents of /proc/self/status
+///
+/// # Example
+/// ```
+/// use barcode_count::info::parse_peak_memory;
+///
+/// let status = "Name:\tbarcode-count\nVmPeak:\t  204800 kB\nVmHWM:\t   51200 kB\nVmRSS:\t   40960 kB\n";
+/// assert_eq!(parse_peak_memory(status), Some(51200));
+/// assert_eq!(parse_peak_memory("Name:\tbarcode-count\n"), None);
+/// ```
+pub fn parse_peak_memory(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse::<u64>()
+        .ok()
+}
@@ -1,18 +1,31 @@
-use anyhow::{bail, Context, Result};
+use ahash::{AHashSet, HashMap, HashMapExt};
+use anyhow::{anyhow, bail, Context, Result};
 use num_format::{Locale, ToFormattedString};
 use std::{
-    collections::VecDeque,
     fmt,
-    fs::File,
-    io::{BufRead, BufReader, Write},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
     },
+    time::Instant,
 };
+use bzip2::read::MultiBzDecoder;
 use flate2::read::MultiGzDecoder;
+use itertools::Itertools;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
-use crate::parse::RawSequenceRead;
+use crate::{
+    arguments::{Args, SelfTestArgs},
+    info::{
+        parse_illumina_sample_sheet, BarcodeColumns, BarcodeConversions, Results, SeededRng, SequenceErrors, SequenceFormat,
+    },
+    info_println,
+    parse::{expand_iupac, reverse_complement, RawSequenceRead, ReadQueue, SharedMutData},
+    verbose_println,
+};
 
 /// Reads in the FASTQ file line by line, then pushes every 2 out of 4 lines, which corresponds to the sequence line, into a Vec that is passed to other threads
 ///
@@ -23,127 +36,1114 @@ use crate::parse::RawSequenceRead;
 /// Line 4: Quality score
 pub fn read_fastq(
     fastq: String,
-    seq_clone: Arc<Mutex<VecDeque<String>>>,
+    shared_mut: SharedMutData,
     exit_clone: Arc<AtomicBool>,
     total_reads_arc: Arc<AtomicU32>,
+    first_record_checks: FirstRecordChecks,
+    checkpoint: Checkpoint,
+    sequence_errors: SequenceErrors,
 ) -> Result<()> {
+    // Create a fastq line reader which keeps track of line number, reads, and sends the sequences in batches to the processing threads
+    let mut fastq_line_reader = FastqLineReader::with_checks(Arc::clone(&shared_mut.seq), Arc::clone(&exit_clone), first_record_checks);
+    // Start the read count from the checkpoint, which is 0 when not resuming
+    fastq_line_reader.total_reads = checkpoint.total_reads;
+    let mut fastq_file = File::open(&fastq).context(format!("Failed to open file: {}", fastq))?; // open file
+    // The size of the file as stored, which is compared with the compressed bytes read for the progress of compressed files
+    let file_size = fastq_file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let compressed_bytes_read = Arc::new(AtomicU64::new(0));
+    // If the file is not compressed use BufReader to read in lines
+    let mut reader: Box<dyn BufRead> = if fastq.ends_with("fastq") {
+        // If resuming, skip to the checkpoint offset
+        fastq_file.seek(SeekFrom::Start(checkpoint.offset))?;
+        Box::new(BufReader::new(fastq_file))
+    } else {
+        if fastq.ends_with("gz") {
+            info_println!("If this program stops reading before the expected number of sequencing reads, unzip the gzipped fastq and rerun.");
+            info_println!();
+        }
+        // stream in by decoding, then reading into buffer.  The uncompressed size is not known ahead of time, so the bytes read from the
+        // compressed file are counted instead
+        let counting_file = CountingReader {
+            inner: fastq_file,
+            bytes_read: Arc::clone(&compressed_bytes_read),
+        };
+        let mut decoded_reader = decompressed_reader(&fastq, counting_file)?;
+        // If resuming, the compressed file cannot be seeked, so the decompressed bytes before the checkpoint offset are discarded
+        io::copy(&mut decoded_reader.by_ref().take(checkpoint.offset), &mut io::sink())?;
+        decoded_reader
+    };
 
-    // Create a fastq line reader which keeps track of line number, reads, and posts the sequence to the shared vector
-    let mut fastq_line_reader = FastqLineReader::new(seq_clone, exit_clone);
-    let fastq_file = File::open(&fastq).context(format!("Failed to open file: {}", fastq))?; // open file
-    // If the file is not gzipped use BufReader to read in lines
-    if !fastq.ends_with("fastq.gz") {
-        // If the file does not end with fastq, return with an error
-        if !fastq.ends_with("fastq") {
-            bail!("This program only works with *.fastq files and *.fastq.gz files.  The latter is still experimental")
-        }
-
-        // go line by line
-        let mut stdout = std::io::stdout();
-        let mut lock = stdout.lock();
-        for line_result in BufReader::new(fastq_file).lines() {
-            let mut line =
-                line_result.context(format!("Bufread could not read line for file: {}", fastq))?;
-            line.push('\n');
-            // post the line to the shared vector and keep track of the number of sequences etc
-            fastq_line_reader.read(line);
-            if fastq_line_reader.line_num == 4 {
-                fastq_line_reader.post()?;
+    // Keep track of how many bytes have been read in order to record the offset within checkpoints
+    let mut bytes_read = checkpoint.offset;
+    // The bytes of the file as stored that have been read, which are the compressed bytes for compressed files
+    let is_compressed = !fastq.ends_with("fastq");
+    let progress_bytes = |bytes_read: u64| {
+        if is_compressed {
+            compressed_bytes_read.load(Ordering::Relaxed)
+        } else {
+            bytes_read
+        }
+    };
+    // The reading progress goes to stderr, so that it is still shown with --quiet
+    let mut stderr = std::io::stderr();
+    let mut lock = stderr.lock();
+    // Whether or not a compressed file ended partway through its compressed data
+    let mut truncated_stream = false;
+    // continue reading until there is a response of 0, which indicates the end of file.  This may be where some gzipped files abrupty end
+    loop {
+        let mut line = String::new();
+        let read_response = match reader.read_line(&mut line) {
+            Ok(read_response) => read_response,
+            // A compressed file that was cut off, such as by an interrupted transfer, is counted up to where it ends
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                truncated_stream = true;
+                0
             }
-            // Add to read count to print numnber of sequences read by this thread
-            if fastq_line_reader.total_reads % 10000 == 0 {
-                write!(lock, "{}", fastq_line_reader)?;
-                stdout.flush()?;
+            Err(error) => {
+                return Err(error).context(format!("Bufread could not read line for file: {}", fastq))
             }
+        };
+        // Also stop if a processing thread failed or the match rate check stopped the run
+        if read_response == 0 || exit_clone.load(Ordering::Relaxed) {
+            break;
         }
-    } else {
-        println!("If this program stops reading before the expected number of sequencing reads, unzip the gzipped fastq and rerun.");
-        println!();
-        // stream in first by decoding with GzDecoder, the reading into buffer
-        let mut reader = BufReader::new(MultiGzDecoder::new(fastq_file));
-
-        let mut stdout = std::io::stdout();
-        let mut lock = stdout.lock();
-        let mut read_response = 10;
-        // continue reading until there is a response of 0, which indicates the end of file.  This may be where some gzipped files abrupty end
-        while read_response != 0 {
-            let mut line = String::new();
-            read_response = reader.read_line(&mut line)?;
-            // post the line to the shared vector and keep track of the number of sequences etc
-            fastq_line_reader.read(line);
-            if fastq_line_reader.line_num == 4 {
-                fastq_line_reader.post()?;
-            }
-            // Add to read count to print numnber of sequences read by this thread
-            if fastq_line_reader.total_reads % 10000 == 0 {
-                write!(lock, "{}", fastq_line_reader)?;
-                stdout.flush()?;
+        bytes_read += read_response as u64;
+        // Make sure every line ends with a single '\n' so that '\r\n' line endings and a final line without a new line are handled the same
+        let line_length = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(line_length);
+        line.push('\n');
+        // post the line to the shared vector and keep track of the number of sequences etc
+        if fastq_line_reader.read(line)? {
+            fastq_line_reader.post()?;
+            // Periodically save the results along with where the fastq has been read to
+            if checkpoint.due(fastq_line_reader.total_reads) {
+                // The partial batch is sent first so that the processing threads can finish every read before the offset
+                fastq_line_reader.flush()?;
+                checkpoint.save(
+                    bytes_read,
+                    fastq_line_reader.total_reads,
+                    &shared_mut,
+                    &sequence_errors,
+                    &exit_clone,
+                )?;
             }
         }
+        // Add to read count to print numnber of sequences read by this thread
+        if fastq_line_reader.total_reads.is_multiple_of(10000) {
+            fastq_line_reader.set_file_progress(progress_bytes(bytes_read), file_size);
+            write!(lock, "{}", fastq_line_reader)?;
+            stderr.flush()?;
+        }
     }
+    fastq_line_reader.flush()?;
+    // A record that was started but not finished is not counted, which is the usual sign of a cut off file
+    let stopped_early = exit_clone.load(Ordering::Relaxed);
+    let incomplete_record = !stopped_early && fastq_line_reader.drop_incomplete_record();
     // Display the final total read count
-    print!("{}", fastq_line_reader);
+    fastq_line_reader.set_file_progress(progress_bytes(bytes_read), file_size);
+    eprint!("{}", fastq_line_reader);
     total_reads_arc.store(fastq_line_reader.total_reads, Ordering::Relaxed);
-    println!();
+    eprintln!();
+    if incomplete_record || (truncated_stream && !stopped_early) {
+        eprintln!(
+            "\nWARNING: {} ended partway through a FASTQ record and may be truncated, such as from an interrupted transfer.  Only the {} complete records were counted\n",
+            fastq,
+            fastq_line_reader.total_reads.to_formatted_string(&Locale::en)
+        );
+    }
+    Ok(())
+}
+
+/// Reads the fastq file along with its read 2 fastq file, merges each read pair by its overlap with merge_read_pair, then posts the
+/// merged read to the shared vector in place of the fastq read.  Pairs that do not overlap by at least min_overlap bases are posted as
+/// the fastq read alone.  The number of merged pairs is added to the insert stats for the stats file.  Checkpoints are not supported
+pub fn read_fastq_pairs(
+    fastq: String,
+    fastq2: String,
+    shared_mut: SharedMutData,
+    exit_clone: Arc<AtomicBool>,
+    total_reads_arc: Arc<AtomicU32>,
+    first_record_checks: FirstRecordChecks,
+    min_overlap: usize,
+) -> Result<()> {
+    let quality_offset = first_record_checks.quality_offset;
+    let mut read1_lines = fastq_lines(&fastq)?;
+    let mut read2_lines = fastq_lines(&fastq2)?;
+    let mut read1_reader = FastqLineReader::with_checks(Arc::clone(&shared_mut.seq), Arc::clone(&exit_clone), first_record_checks);
+    // The read 2 records are merged into the read 1 records instead of being posted
+    let mut read2_reader =
+        FastqLineReader::new(Arc::new(ReadQueue::new()), Arc::clone(&exit_clone), quality_offset);
+    if fastq.ends_with("gz") || fastq2.ends_with("gz") {
+        info_println!("If this program stops reading before the expected number of sequencing reads, unzip the gzipped fastq and rerun.");
+        info_println!();
+    }
+
+    let mut merged_pairs = 0;
+    // The reading progress goes to stderr, so that it is still shown with --quiet
+    let mut stderr = std::io::stderr();
+    let mut lock = stderr.lock();
+    while read1_reader.read_record(&mut read1_lines, &fastq)? {
+        if exit_clone.load(Ordering::Relaxed) {
+            break;
+        }
+        if !read2_reader.read_record(&mut read2_lines, &fastq2)? {
+            bail!("{} has fewer records than {}", fastq2, fastq)
+        }
+        // Paired files that are out of order would merge reads of different molecules
+        if pair_id(&read1_reader.description) != pair_id(&read2_reader.description) {
+            bail!(
+                "FASTQ record {} has different read IDs within the paired files: {} and {}",
+                read1_reader.total_reads,
+                read1_reader.description,
+                read2_reader.description
+            )
+        }
+        if let Some((merged_sequence, merged_quality)) = merge_read_pair(
+            &read1_reader.sequence,
+            &read1_reader.quality_values,
+            &read2_reader.sequence,
+            &read2_reader.quality_values,
+            quality_offset,
+            min_overlap,
+        ) {
+            read1_reader.sequence = merged_sequence;
+            read1_reader.quality_values = merged_quality;
+            merged_pairs += 1;
+        }
+        read1_reader.post()?;
+        if read1_reader.total_reads.is_multiple_of(10000) {
+            write!(lock, "{}", read1_reader)?;
+            stderr.flush()?;
+        }
+    }
+    read1_reader.flush()?;
+    if !exit_clone.load(Ordering::Relaxed) && read2_reader.read_record(&mut read2_lines, &fastq2)? {
+        bail!("{} has more records than {}", fastq2, fastq)
+    }
+    // Display the final total read count
+    eprint!("{}", read1_reader);
+    total_reads_arc.store(read1_reader.total_reads, Ordering::Relaxed);
+    eprintln!();
+    shared_mut
+        .insert_stats
+        .lock()
+        .unwrap()
+        .add_read_pairs(merged_pairs, read1_reader.total_reads as u64);
     Ok(())
 }
 
-/// A struct with functions for keeping track of read information and to post sequence lines to the shared vector
+/// Returns the read ID of a FASTQ description line without the '@', anything after the first space, or a trailing /1 or /2, which is
+/// the same for both reads of a pair
+fn pair_id(description: &str) -> &str {
+    let read_id = description
+        .trim_start_matches('@')
+        .split_whitespace()
+        .next()
+        .unwrap_or_default();
+    read_id
+        .strip_suffix("/1")
+        .or_else(|| read_id.strip_suffix("/2"))
+        .unwrap_or(read_id)
+}
+
+/// Merges a read pair by finding where the end of read 1 overlaps the start of the reverse complement of read 2.  The longest overlap
+/// of at least min_overlap bases with at most 10% mismatches is used, since barcode formats repeat constant regions that can give short
+/// overlaps without mismatches by chance.  Within the overlap, matching bases keep the higher quality score, and where the reads disagree the base
+/// with the higher quality is kept with the difference of the two scores as its quality.  Returns the merged sequence and quality
+/// scores, or None when no overlap is found
+///
+/// # Example
+/// ```
+/// use barcode_count::input::merge_read_pair;
+///
+/// // Read 2 is the reverse complement of the last 12 bases of read 1 followed by 4 more bases, with one lower quality disagreement
+/// let merged = merge_read_pair("AAAACCCCGGTTACGT", "IIIIIIIIIIIIIIII", "CCCCACGTAACAGGGG", "IIIIIIIIIII5IIII", 33, 6);
+/// assert_eq!(merged, Some(("AAAACCCCGGTTACGTGGGG".to_string(), "IIIIIIII5IIIIIIIIIII".to_string())));
+/// assert_eq!(merge_read_pair("AAAACCCC", "IIIIIIII", "TTACGTAC", "IIIIIIII", 33, 6), None);
+/// ```
+pub fn merge_read_pair(
+    sequence1: &str,
+    quality1: &str,
+    sequence2: &str,
+    quality2: &str,
+    quality_offset: u8,
+    min_overlap: usize,
+) -> Option<(String, String)> {
+    // Without a quality score for each base, the higher quality base cannot be chosen
+    if sequence1.len() != quality1.len() || sequence2.len() != quality2.len() {
+        return None;
+    }
+    let sequence1 = sequence1.as_bytes();
+    let quality1 = quality1.as_bytes();
+    let sequence2 = reverse_complement(sequence2).into_bytes();
+    let quality2 = quality2.bytes().rev().collect::<Vec<u8>>();
+
+    // Search from the longest overlap to the shortest
+    let overlap = (min_overlap..=sequence1.len().min(sequence2.len()))
+        .rev()
+        .find(|overlap| {
+            let mismatches = sequence1[sequence1.len() - overlap..]
+                .iter()
+                .zip(&sequence2[..*overlap])
+                .filter(|(base1, base2)| base1 != base2)
+                .count();
+            mismatches * 10 <= *overlap
+        })?;
+
+    let overlap_start = sequence1.len() - overlap;
+    let mut merged_sequence = sequence1[..overlap_start].to_vec();
+    let mut merged_quality = quality1[..overlap_start].to_vec();
+    for overlap_index in 0..overlap {
+        let (base1, score1) = (sequence1[overlap_start + overlap_index], quality1[overlap_start + overlap_index]);
+        let (base2, score2) = (sequence2[overlap_index], quality2[overlap_index]);
+        if base1 == base2 {
+            merged_sequence.push(base1);
+            merged_quality.push(score1.max(score2));
+        } else {
+            let (base, high_score, low_score) = if score1 >= score2 {
+                (base1, score1, score2)
+            } else {
+                (base2, score2, score1)
+            };
+            merged_sequence.push(base);
+            merged_quality.push(quality_offset + (high_score - low_score));
+        }
+    }
+    merged_sequence.extend_from_slice(&sequence2[overlap..]);
+    merged_quality.extend_from_slice(&quality2[overlap..]);
+    Some((
+        String::from_utf8_lossy(&merged_sequence).to_string(),
+        String::from_utf8_lossy(&merged_quality).to_string(),
+    ))
+}
+
+/// Opens the fastq file for reading lines, decompressing it if it is compressed
+fn fastq_lines(fastq: &str) -> Result<Box<dyn BufRead>> {
+    let fastq_file = File::open(fastq).context(format!("Failed to open file: {}", fastq))?;
+    if fastq.ends_with("fastq") {
+        Ok(Box::new(BufReader::new(fastq_file)))
+    } else {
+        decompressed_reader(fastq, fastq_file)
+    }
+}
+
+/// Counts the bytes read through it, so that the progress through a compressed file can be found from the compressed bytes read
+struct CountingReader<R> {
+    inner: R,                   // the file being read
+    bytes_read: Arc<AtomicU64>, // shared so that it can still be read once this is within the decoder
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(bytes)
+    }
+}
+
+/// Wraps the compressed fastq file with the decoder for its extension, which is either gzip (.gz), bzip2 (.bz2), or zstd (.zst)
+fn decompressed_reader<R: Read + 'static>(fastq: &str, fastq_file: R) -> Result<Box<dyn BufRead>> {
+    let reader: Box<dyn BufRead> = if fastq.ends_with("fastq.gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(fastq_file)))
+    } else if fastq.ends_with("fastq.bz2") {
+        Box::new(BufReader::new(MultiBzDecoder::new(fastq_file)))
+    } else if fastq.ends_with("fastq.zst") {
+        Box::new(BufReader::new(
+            ZstdDecoder::new(fastq_file).context(format!("Failed to start zstd decoding of file: {}", fastq))?,
+        ))
+    } else {
+        bail!("This program only works with *.fastq, *.fastq.gz, *.fastq.bz2, and *.fastq.zst files.  The gzipped files are still experimental")
+    };
+    Ok(reader)
+}
+
+/// Periodically saves the results, sequence errors, and the fastq byte offset that they correspond to.  This allows an interrupted
+/// run to be resumed with --resume without reparsing the reads already counted
+pub struct Checkpoint {
+    path: PathBuf,         // checkpoint file path within the output directory
+    interval: u32,         // number of reads between checkpoints.  0 turns off checkpointing
+    pub offset: u64,       // bytes of the fastq already counted within the results.  0 unless resuming
+    pub total_reads: u32,  // reads already counted within the results.  0 unless resuming
+}
+
+impl Checkpoint {
+    /// Creates a new Checkpoint struct from the output directory, prefix, and checkpoint interval arguments
+    pub fn new(args: &Args) -> Self {
+        let path = Path::new(&args.output_dir).join(format!("{}_checkpoint.txt", args.prefix));
+        Checkpoint {
+            path,
+            interval: args.checkpoint_interval,
+            offset: 0,
+            total_reads: 0,
+        }
+    }
+
+    /// Loads the checkpoint file, adding the saved counts into the results and sequence errors, and sets where to resume reading the fastq
+    pub fn load(&mut self, results: &mut Results, sequence_errors: &SequenceErrors) -> Result<()> {
+        let checkpoint_data = fs::read_to_string(&self.path).context(format!(
+            "Failed to open checkpoint file {} to resume from",
+            self.path.display()
+        ))?;
+        let mut lines = checkpoint_data.lines();
+        let mut header_value = |name: &str| -> Result<String> {
+            let line = lines.next().unwrap_or_default();
+            line.strip_prefix(&format!("{}\t", name))
+                .map(|value| value.to_string())
+                .ok_or_else(|| anyhow!("Checkpoint file is missing the {} line", name))
+        };
+        self.offset = header_value("offset")?
+            .parse::<u64>()
+            .context("Unable to convert checkpoint offset to an integer")?;
+        self.total_reads = header_value("total_reads")?
+            .parse::<u32>()
+            .context("Unable to convert checkpoint total reads to an integer")?;
+        sequence_errors.unpack(&header_value("sequence_errors")?)?;
+        for line in lines {
+            results.unpack(line)?;
+        }
+        info_println!(
+            "Resuming from checkpoint after {} reads\n",
+            self.total_reads.to_formatted_string(&Locale::en)
+        );
+        Ok(())
+    }
+
+    /// Returns whether a checkpoint should be saved at the current read count
+    fn due(&self, total_reads: u32) -> bool {
+        self.interval != 0 && total_reads.is_multiple_of(self.interval)
+    }
+
+    /// Waits for the parsing threads to finish and merge the reads that have already been posted, so that the results match the offset, then saves
+    /// the checkpoint.  The checkpoint is written to a temporary file then renamed so that an interruption does not leave a partial file
+    fn save(
+        &self,
+        offset: u64,
+        total_reads: u32,
+        shared_mut: &SharedMutData,
+        sequence_errors: &SequenceErrors,
+        exit: &AtomicBool,
+    ) -> Result<()> {
+        // if threads have failed exit out without saving
+        if !shared_mut.merged.wait_for(total_reads, exit)? {
+            return Ok(());
+        }
+        let mut checkpoint_text = format!(
+            "offset\t{}\ntotal_reads\t{}\nsequence_errors\t{}\n",
+            offset,
+            total_reads,
+            sequence_errors.pack()
+        );
+        checkpoint_text.push_str(&shared_mut.results.lock().unwrap().pack());
+        let temp_path = self.path.with_extension("txt.tmp");
+        fs::write(&temp_path, checkpoint_text)?;
+        fs::rename(&temp_path, &self.path)?;
+        verbose_println!(
+            "Checkpoint saved after {} reads",
+            total_reads.to_formatted_string(&Locale::en)
+        );
+        Ok(())
+    }
+
+    /// Removes the checkpoint file once the run has finished
+    pub fn remove(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Detects whether the quality scores are Phred+33 or Phred+64 encoded by scanning the quality characters of the first 100 reads.
+/// Phred+33 uses characters starting at '!' while Phred+64 starts at '@' (or ';' for Solexa), so any character below ';' means Phred+33.
+/// If all characters are at or above '@' and some are above 'J', the highest Phred+33 score, Phred+64 is returned.  Otherwise Phred+33
+pub fn detect_quality_offset(fastq: &str) -> Result<u8> {
+    let fastq_file = File::open(fastq).context(format!("Failed to open file: {}", fastq))?;
+    let reader: Box<dyn BufRead> = if fastq.ends_with("fastq") {
+        Box::new(BufReader::new(fastq_file))
+    } else {
+        decompressed_reader(fastq, fastq_file)?
+    };
+    let mut min_char = u8::MAX;
+    let mut max_char = u8::MIN;
+    // Use the same record reading as read_fastq so that wrapped quality lines are joined.  The records are not posted anywhere
+    let mut fastq_line_reader = FastqLineReader::new(
+        Arc::new(ReadQueue::new()),
+        Arc::new(AtomicBool::new(false)),
+        0,
+    );
+    let mut records = 0;
+    for line_result in reader.lines() {
+        let mut line = line_result.context(format!("Bufread could not read line for file: {}", fastq))?;
+        let line_length = line.trim_end().len();
+        line.truncate(line_length);
+        line.push('\n');
+        if fastq_line_reader.read(line)? {
+            for quality_char in fastq_line_reader.quality_values.bytes() {
+                min_char = min_char.min(quality_char);
+                max_char = max_char.max(quality_char);
+            }
+            records += 1;
+            if records == 100 {
+                break;
+            }
+        }
+    }
+    if min_char == u8::MAX {
+        bail!("No quality scores found within {} to detect the quality offset", fastq)
+    }
+    if min_char >= b'@' && max_char > b'J' {
+        Ok(64)
+    } else {
+        Ok(33)
+    }
+}
+
+/// Checks that the first record of the fastq file has the FASTQ structure, with a quality score for every base and quality characters
+/// at or above the quality offset.  These are the same checks as the start of read_fastq
+pub fn check_first_fastq_record(fastq: &str, first_record_checks: FirstRecordChecks) -> Result<()> {
+    let fastq_file = File::open(fastq).context(format!("Failed to open file: {}", fastq))?;
+    let reader: Box<dyn BufRead> = if fastq.ends_with("fastq") {
+        Box::new(BufReader::new(fastq_file))
+    } else {
+        decompressed_reader(fastq, fastq_file)?
+    };
+    let first_record = Arc::new(ReadQueue::new());
+    let mut fastq_line_reader =
+        FastqLineReader::with_checks(Arc::clone(&first_record), Arc::new(AtomicBool::new(false)), first_record_checks);
+    for line_result in reader.lines() {
+        let mut line = line_result.context(format!("Bufread could not read line for file: {}", fastq))?;
+        let line_length = line.trim_end().len();
+        line.truncate(line_length);
+        line.push('\n');
+        if fastq_line_reader.read(line)? {
+            // Posting the first record runs the format and quality offset checks
+            fastq_line_reader.post()?;
+            fastq_line_reader.flush()?;
+            let raw_sequence = first_record
+                .recv()?
+                .and_then(|batch| batch.into_iter().next())
+                .unwrap_or_default();
+            if !RawSequenceRead::unpack(raw_sequence)?.is_well_formed() {
+                bail!("The first record has a different number of quality scores than bases")
+            }
+            return Ok(());
+        }
+    }
+    bail!("No complete FASTQ record found within {}", fastq)
+}
+
+/// Checks the sequence formats, sample barcode file, counted barcode files and the first record of each fastq file without counting.
+/// Prints a pass or fail line for each check followed by a summary, and returns whether or not every check passed
+pub fn validate_inputs(args: &Args) -> bool {
+    println!("-VALIDATION-");
+    let mut checks = 0;
+    let mut passed = 0;
+    let mut report = |check_name: String, check_result: Result<()>| {
+        checks += 1;
+        match check_result {
+            Ok(()) => {
+                passed += 1;
+                println!("PASS  {}", check_name);
+            }
+            Err(err) => println!("FAIL  {}: {:#}", check_name, err),
+        }
+    };
+
+    let barcode_columns = BarcodeColumns {
+        seq_column_option: args.seq_column_option.clone(),
+        id_column_option: args.id_column_option.clone(),
+        index_column_option: args.index_column_option.clone(),
+    };
+    let mut sequence_formats = Vec::new();
+    for format in args.format.split(',') {
+        let sequence_format_result = if args.format_from_string {
+            SequenceFormat::parse_format_str(format)
+        } else {
+            SequenceFormat::parse_format_file(format)
+        };
+        match sequence_format_result {
+            Ok(sequence_format) => {
+                report(format!("Sequence format {}", format), Ok(()));
+                sequence_formats.push(sequence_format);
+            }
+            Err(err) => report(format!("Sequence format {}", format), Err(err)),
+        }
+    }
+
+    if let Some(ref samples) = args.sample_barcodes_option {
+        report(
+            format!("Sample barcodes {}", samples),
+            check_sample_barcodes(samples, args, &barcode_columns, &sequence_formats),
+        );
+    }
+
+    if let Some(ref counted_barcodes) = args.counted_barcodes_option {
+        let counted_barcode_files = counted_barcodes.split(',').collect::<Vec<&str>>();
+        if counted_barcode_files.len() > 1 && counted_barcode_files.len() != sequence_formats.len() {
+            report(
+                format!("Counted barcodes {}", counted_barcodes),
+                Err(anyhow!(
+                    "{} files but {} sequence formats.  Use one file for all or one for each",
+                    counted_barcode_files.len(),
+                    sequence_formats.len()
+                )),
+            );
+        } else {
+            for (format_index, sequence_format) in sequence_formats.iter().enumerate() {
+                let barcodes = counted_barcode_files
+                    .get(format_index)
+                    .unwrap_or(&counted_barcode_files[0]);
+                report(
+                    format!("Counted barcodes {} for sequence format {}", barcodes, format_index + 1),
+                    check_counted_barcodes(barcodes, args.delimiter, &barcode_columns, sequence_format),
+                );
+            }
+        }
+    }
+
+    let fastq_files = if args.fastq_samples.is_empty() {
+        vec![args.fastq.clone()]
+    } else {
+        args.fastq_samples
+            .iter()
+            .map(|(_, fastq_file)| fastq_file.to_string())
+            .collect::<Vec<String>>()
+    };
+    for fastq in fastq_files {
+        report(
+            format!("First record of {}", fastq),
+            check_first_fastq_record(&fastq, FirstRecordChecks::new(args)),
+        );
+    }
+
+    println!("\n{} of {} checks passed", passed, checks);
+    passed == checks
+}
+
+/// Checks that the sample barcode file is read, has no duplicate barcodes, and that every barcode has the sample barcode length of the
+/// sequence formats
+fn check_sample_barcodes(
+    samples: &str,
+    args: &Args,
+    barcode_columns: &BarcodeColumns,
+    sequence_formats: &[SequenceFormat],
+) -> Result<()> {
+    let mut barcode_conversions = BarcodeConversions::new();
+    let sample_barcodes = if args.illumina_sample_sheet {
+        barcode_conversions.illumina_sample_sheet_conversion(samples)?;
+        parse_illumina_sample_sheet(&fs::read_to_string(samples)?)?
+            .into_iter()
+            .map(|(barcode, _)| barcode)
+            .collect::<Vec<String>>()
+    } else {
+        barcode_conversions.sample_barcode_file_conversion(samples, args.delimiter, barcode_columns)?;
+        let sample_file = fs::read_to_string(samples).context(format!("Failed to open {}", samples))?;
+        let mut lines = sample_file.lines();
+        let (seq_position, _, _) = barcode_columns.positions(lines.next().unwrap_or_default(), args.delimiter)?;
+        lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.split(args.delimiter)
+                    .nth(seq_position)
+                    .unwrap_or_default()
+                    .to_uppercase()
+            })
+            .collect::<Vec<String>>()
+    };
+    if sample_barcodes.is_empty() {
+        bail!("No sample barcodes found")
+    }
+    let duplicates = sample_barcodes.iter().duplicates().collect::<Vec<&String>>();
+    if !duplicates.is_empty() {
+        bail!("Duplicate sample barcodes: {}", duplicates.iter().join(", "))
+    }
+    check_nucleotides(&sample_barcodes)?;
+    for sequence_format in sequence_formats {
+        if let Some(sample_length) = sequence_format.sample_length_option {
+            if let Some(barcode) = sample_barcodes
+                .iter()
+                .find(|barcode| barcode.len() != sample_length as usize)
+            {
+                bail!(
+                    "Sample barcode {} is not the {} bases of the sequence format's sample barcode",
+                    barcode,
+                    sample_length
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the counted barcode file is read with every barcode number of the sequence format, and that every barcode has the
+/// length of its counted barcode within the sequence format
+fn check_counted_barcodes(
+    barcodes: &str,
+    delimiter: char,
+    barcode_columns: &BarcodeColumns,
+    sequence_format: &SequenceFormat,
+) -> Result<()> {
+    let mut barcode_conversions = BarcodeConversions::new();
+    barcode_conversions.barcode_file_conversion(barcodes, sequence_format.barcode_num, delimiter, barcode_columns)?;
+    for (barcode_index, (barcodes_hash, barcode_length)) in barcode_conversions
+        .counted_barcodes_hash
+        .iter()
+        .zip(&sequence_format.barcode_lengths)
+        .enumerate()
+    {
+        // Barcodes with IUPAC codes are checked as the sequences they expand into
+        let counted_barcodes = barcodes_hash
+            .keys()
+            .flat_map(|barcode| expand_iupac(barcode))
+            .collect::<Vec<String>>();
+        check_nucleotides(&counted_barcodes)?;
+        if let Some(barcode) = counted_barcodes
+            .iter()
+            .find(|barcode| barcode.len() != *barcode_length as usize)
+        {
+            bail!(
+                "Barcode {} for barcode number {} is not the {} bases of the sequence format",
+                barcode,
+                barcode_index + 1,
+                barcode_length
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error for the first barcode with a character other than A, C, G, T or N
+fn check_nucleotides(barcodes: &[String]) -> Result<()> {
+    if let Some(barcode) = barcodes
+        .iter()
+        .find(|barcode| barcode.is_empty() || !barcode.chars().all(|base| "ACGTN".contains(base)))
+    {
+        bail!("Barcode '{}' is not a DNA sequence", barcode)
+    }
+    Ok(())
+}
+
+/// Generates synthetic reads from the sequence format and barcode files with random substitutions at the error rate, counts them with
+/// this same executable, then compares the counts of every sample and barcode combination with the known truth.  Prints the counting
+/// speed and a pass or fail summary, and returns whether or not every count is within the tolerance
+pub fn self_test(self_test_args: &SelfTestArgs) -> Result<bool> {
+    println!("-SELF TEST-");
+    let sequence_format = SequenceFormat::parse_format_file(&self_test_args.format)?;
+    if sequence_format.barcode_num == 0 {
+        bail!("The sequence format needs at least one counted barcode for the self-test")
+    }
+    let mut barcode_conversions = BarcodeConversions::new();
+    barcode_conversions.barcode_file_conversion(
+        &self_test_args.counted_barcodes,
+        sequence_format.barcode_num,
+        ',',
+        &BarcodeColumns::default(),
+    )?;
+    // Sorted so that the same seed gives the same reads.  Barcodes with IUPAC codes are sequenced as any one of their expanded sequences
+    let counted_barcodes = barcode_conversions
+        .counted_barcodes_hash
+        .iter()
+        .map(|barcodes_hash| {
+            barcodes_hash
+                .iter()
+                .sorted()
+                .flat_map(|(barcode, barcode_id)| {
+                    expand_iupac(barcode)
+                        .into_iter()
+                        .map(move |expanded_barcode| (expanded_barcode, barcode_id))
+                })
+                .collect::<Vec<(String, &String)>>()
+        })
+        .collect::<Vec<Vec<(String, &String)>>>();
+    let mut sample_conversions = BarcodeConversions::new();
+    let sample_barcodes = match (sequence_format.sample_barcode, &self_test_args.sample_barcodes_option) {
+        (true, Some(samples)) => {
+            sample_conversions.sample_barcode_file_conversion(samples, ',', &BarcodeColumns::default())?;
+            sample_conversions.samples_barcode_hash.iter().sorted().collect::<Vec<(&String, &String)>>()
+        }
+        (true, None) => bail!("The sequence format has a sample barcode, so --sample-barcodes is needed"),
+        (false, Some(_)) => bail!("--sample-barcodes was given but the sequence format does not have a sample barcode"),
+        (false, None) => Vec::new(),
+    };
+
+    // Where each barcode region lands within the format, found the same way as the capture group check
+    let template_captures = sequence_format
+        .format_regex
+        .captures(&sequence_format.match_example)
+        .unwrap();
+    let region_range = |group_name: &str| template_captures.name(group_name).map(|group_match| group_match.range());
+
+    let output_dir = Path::new(&self_test_args.output_dir);
+    fs::create_dir_all(output_dir).context(format!("Failed to create {}", self_test_args.output_dir))?;
+    let fastq_path = output_dir.join("self_test.fastq");
+    let mut fastq = io::BufWriter::new(
+        File::create(&fastq_path).context(format!("Failed to create {}", fastq_path.display()))?,
+    );
+
+    // sample name: barcode IDs joined with ',': count, which is reads or, with a random barcode, unique random barcodes
+    let mut true_counts: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    let mut true_random_barcodes: HashMap<String, HashMap<String, AHashSet<String>>> = HashMap::new();
+    let mut rng = SeededRng::new(self_test_args.seed);
+    let quality_line = "I".repeat(sequence_format.length);
+    for read_index in 0..self_test_args.reads {
+        // 'N's, which are any nucleotide or barcode regions, start as random bases and are then replaced with the barcodes
+        let mut read = sequence_format
+            .format_string
+            .chars()
+            .map(|base| if base == 'N' { rng.base() } else { base })
+            .collect::<Vec<char>>();
+        let mut barcode_ids = Vec::new();
+        for (barcode_index, barcodes) in counted_barcodes.iter().enumerate() {
+            let (barcode_seq, barcode_id) = &barcodes[rng.below(barcodes.len())];
+            let range = region_range(&format!("barcode{}", barcode_index + 1)).unwrap();
+            read.splice(range, barcode_seq.chars());
+            barcode_ids.push(barcode_id.as_str());
+        }
+        for (variant_index, alternatives) in sequence_format.variant_alternatives.iter().enumerate() {
+            if !alternatives.is_empty() {
+                let range = region_range(&format!("variant{}", variant_index + 1)).unwrap();
+                read.splice(range, alternatives[rng.below(alternatives.len())].chars());
+            }
+        }
+        let sample_name = if let Some(range) = region_range("sample") {
+            let (sample_seq, sample_name) = sample_barcodes[rng.below(sample_barcodes.len())];
+            read.splice(range, sample_seq.chars());
+            sample_name.to_string()
+        } else {
+            "barcode".to_string()
+        };
+        let random_barcode = (1..=sequence_format.random_barcode_num)
+            .filter_map(|random_index| region_range(&format!("random{}", random_index)))
+            .map(|range| read[range].iter().collect::<String>())
+            .collect::<String>();
+        // The reported constant regions are counted as sequenced, after the counted barcodes
+        let variants = (1..=sequence_format.variant_num)
+            .filter_map(|variant_index| region_range(&format!("variant{}", variant_index)))
+            .map(|range| read[range].iter().collect::<String>())
+            .collect::<Vec<String>>();
+        barcode_ids.extend(variants.iter().map(|variant| variant.as_str()));
+        let barcode_key = barcode_ids.join(",");
+        if sequence_format.random_barcode {
+            true_random_barcodes
+                .entry(sample_name)
+                .or_default()
+                .entry(barcode_key)
+                .or_default()
+                .insert(random_barcode);
+        } else {
+            *true_counts.entry(sample_name).or_default().entry(barcode_key).or_insert(0) += 1;
+        }
+
+        // Substitute a different base at each position with the chance of the error rate
+        for base in read.iter_mut() {
+            if rng.fraction() < self_test_args.error_rate {
+                let original_base = *base;
+                while *base == original_base {
+                    *base = rng.base();
+                }
+            }
+        }
+        writeln!(
+            fastq,
+            "@synthetic{}\n{}\n+\n{}",
+            read_index,
+            read.iter().collect::<String>(),
+            quality_line
+        )?;
+    }
+    fastq.flush()?;
+    drop(fastq);
+    for (sample_name, random_barcodes) in true_random_barcodes {
+        true_counts.insert(
+            sample_name,
+            random_barcodes
+                .into_iter()
+                .map(|(barcode_key, random_barcodes)| (barcode_key, random_barcodes.len()))
+                .collect(),
+        );
+    }
+
+    // Count the synthetic reads with this same executable so that the whole pipeline, including the argument parsing, is tested
+    let mut count_command = std::process::Command::new(std::env::current_exe()?);
+    count_command
+        .arg("--fastq")
+        .arg(&fastq_path)
+        .args(["--sequence-format", &self_test_args.format])
+        .args(["--counted-barcodes", &self_test_args.counted_barcodes])
+        .args(["--output-dir", &self_test_args.output_dir])
+        .args(["--prefix", "self_test"])
+        .args(["--threads", &self_test_args.threads.to_string()]);
+    if let Some(ref samples) = self_test_args.sample_barcodes_option {
+        count_command.args(["--sample-barcodes", samples]);
+    }
+    let counting_start = Instant::now();
+    let count_output = count_command.output().context("Failed to run the counting for the self-test")?;
+    let counting_time = counting_start.elapsed();
+    if !count_output.status.success() {
+        bail!(
+            "Counting the synthetic reads failed: {}",
+            String::from_utf8_lossy(&count_output.stderr)
+        )
+    }
+
+    let mut checked = 0;
+    let mut outside_tolerance = Vec::new();
+    let mut true_total = 0;
+    let mut counted_total = 0;
+    for (sample_name, sample_true_counts) in true_counts.iter().sorted_by_key(|(sample_name, _)| *sample_name) {
+        let counts_path = output_dir.join(format!("self_test_{}_counts.csv", sample_name));
+        let counts_data =
+            fs::read_to_string(&counts_path).context(format!("Failed to open {}", counts_path.display()))?;
+        // The counts rows are the barcode IDs followed by the count
+        let mut sample_counts = HashMap::new();
+        for line in counts_data.lines().skip(1).filter(|line| !line.is_empty()) {
+            let (barcode_key, count) = line
+                .rsplit_once(',')
+                .ok_or_else(|| anyhow!("Counts row without a count: {}", line))?;
+            sample_counts.insert(barcode_key.to_string(), count.parse::<usize>()?);
+        }
+        for barcode_key in sample_true_counts.keys().chain(sample_counts.keys()).unique().sorted() {
+            let true_count = *sample_true_counts.get(barcode_key).unwrap_or(&0);
+            let count = *sample_counts.get(barcode_key).unwrap_or(&0);
+            checked += 1;
+            true_total += true_count;
+            counted_total += count;
+            if (count as f64 - true_count as f64).abs() > self_test_args.tolerance * true_count as f64 {
+                outside_tolerance.push(format!("{} {}: {} counted, {} true", sample_name, barcode_key, count, true_count));
+            }
+        }
+    }
+
+    println!("Synthetic reads:      {}", self_test_args.reads.to_formatted_string(&Locale::en));
+    println!("Error rate:           {}", self_test_args.error_rate);
+    println!("Synthetic fastq:      {}", fastq_path.display());
+    println!(
+        "Counting time:        {:.3} seconds, {} reads per second",
+        counting_time.as_secs_f64(),
+        ((self_test_args.reads as f64 / counting_time.as_secs_f64()) as u64).to_formatted_string(&Locale::en)
+    );
+    println!(
+        "Counted:              {} of {} ({:.2}%)",
+        counted_total.to_formatted_string(&Locale::en),
+        true_total.to_formatted_string(&Locale::en),
+        counted_total as f64 / true_total.max(1) as f64 * 100.0
+    );
+    println!(
+        "Barcode combinations: {} checked, {} outside the {}% tolerance",
+        checked.to_formatted_string(&Locale::en),
+        outside_tolerance.len().to_formatted_string(&Locale::en),
+        self_test_args.tolerance * 100.0
+    );
+    for outside_count in outside_tolerance.iter().take(10) {
+        println!("FAIL  {}", outside_count);
+    }
+    let passed = outside_tolerance.is_empty();
+    println!("\n{}", if passed { "PASS" } else { "FAIL" });
+    Ok(passed)
+}
+
+/// The part of a FASTQ record that the next line belongs to
+#[derive(PartialEq)]
+enum FastqStage {
+    Description, // line 1, the sequence ID
+    Sequence,    // the DNA sequence, which can be wrapped over multiple lines until the '+' line
+    Quality,     // the quality scores, which can be wrapped over multiple lines until they are as long as the sequence
+}
+
+/// The checks run on the first record of each fastq file before it is posted
+#[derive(Clone, Copy)]
+pub struct FirstRecordChecks {
+    pub quality_offset: u8, // the quality score offset, which the quality scores of the first record need to be at or above
+    pub check_format: bool, // whether or not to check the FASTQ format of the first record.  Turned off with --skip-format-check
+}
+
+impl FirstRecordChecks {
+    /// Creates the first record checks from the quality offset and --skip-format-check arguments
+    pub fn new(args: &Args) -> Self {
+        FirstRecordChecks {
+            quality_offset: args.quality_offset,
+            check_format: !args.skip_format_check,
+        }
+    }
+}
+
+/// A struct with functions for keeping track of read information and to send the records in batches to the processing threads
 struct FastqLineReader {
     test: bool,   // whether or not to test the fastq format. Only does this for the first read
-    line_num: u8, // the current line number 1-4.  Resets back to 1
+    check_format: bool, // whether or not the test includes the FASTQ format check, or only the quality offset check
+    stage: FastqStage, // the part of the record that the next line belongs to
     total_reads: u32, // total sequences read within the fastq file
-    raw_sequence_read_string: String,
-    seq_clone: Arc<Mutex<VecDeque<String>>>, // the vector that is passed between threads which containst the sequences
+    description: String, // the sequence ID line of the current record
+    sequence: String, // the sequence of the current record, with any wrapped lines joined
+    add_description: String, // the '+' line of the current record
+    quality_values: String, // the quality scores of the current record, with any wrapped lines joined
+    seq_clone: Arc<ReadQueue>, // the queue that passes the batches of records to the processing threads
+    batch: Vec<String>, // the records posted since the last batch was sent
     exit_clone: Arc<AtomicBool>, // a bool which is set to true when one of the other threads panic.  This is the prevent hanging and is used to exit this thread
+    quality_offset: u8, // the quality score offset, which is tested with the first read
+    file_progress_option: Option<(u64, u64)>, // bytes of the file as stored that have been read, and the file size, for the progress percentage
 }
 
 impl FastqLineReader {
     /// Creates a new FastqLineReader struct
-    pub fn new(seq_clone: Arc<Mutex<VecDeque<String>>>, exit_clone: Arc<AtomicBool>) -> Self {
+    pub fn new(
+        seq_clone: Arc<ReadQueue>,
+        exit_clone: Arc<AtomicBool>,
+        quality_offset: u8,
+    ) -> Self {
         FastqLineReader {
             test: true,
-            line_num: 0,
+            check_format: true,
+            stage: FastqStage::Description,
             total_reads: 0,
-            raw_sequence_read_string: String::new(),
+            description: String::new(),
+            sequence: String::new(),
+            add_description: String::new(),
+            quality_values: String::new(),
             seq_clone,
+            batch: Vec::with_capacity(ReadQueue::BATCH_SIZE),
             exit_clone,
+            quality_offset,
+            file_progress_option: None,
         }
     }
 
-    /// Reads in the line and either passes to the vec or discards it, depending if it is a sequence line.  Also increments on line count, sequence count etc.
-    pub fn read(&mut self, line: String) {
-        // Pause if there are already 10000 sequences in the vec so memory is not overloaded
-        while self.seq_clone.lock().unwrap().len() >= 10000 {
-            // if threads have failed exit out of this thread
-            if self.exit_clone.load(Ordering::Relaxed) {
-                break;
-            }
+    /// Creates a new FastqLineReader struct which runs the first record checks when posting the first record
+    fn with_checks(
+        seq_clone: Arc<ReadQueue>,
+        exit_clone: Arc<AtomicBool>,
+        first_record_checks: FirstRecordChecks,
+    ) -> Self {
+        let mut fastq_line_reader = FastqLineReader::new(seq_clone, exit_clone, first_record_checks.quality_offset);
+        fastq_line_reader.check_format = first_record_checks.check_format;
+        fastq_line_reader
+    }
+
+    /// Sets the bytes of the file read so far, which are the compressed bytes for compressed files, and the file size so that the
+    /// progress line shows the percentage of the file read.  Nothing is shown without a file size
+    fn set_file_progress(&mut self, bytes_read: u64, file_size: u64) {
+        if file_size > 0 {
+            self.file_progress_option = Some((bytes_read.min(file_size), file_size));
         }
-        // increase line number and if it has passed line 4, reset to 1
-        self.line_num += 1;
-        if self.line_num == 5 {
-            self.line_num = 1
+    }
+
+    /// Reads in the line and adds it to the current record.  The sequence ends at the '+' line instead of after a set number of lines, and the
+    /// quality scores end once they are as long as the sequence, so that records with wrapped lines are joined back together.  Returns true
+    /// when the record is complete and ready to post
+    pub fn read(&mut self, mut line: String) -> Result<bool> {
+        line.pop(); // removes the \n
+        let complete = match self.stage {
+            // Blank lines between records, such as at the end of the file, are skipped
+            FastqStage::Description if line.is_empty() => false,
+            FastqStage::Description => {
+                // Without the '@' the records are out of sync or this is not a FASTQ file
+                if !line.starts_with('@') {
+                    bail!(
+                        "FASTQ record {} does not start with '@': {}\nCheck the FASTQ format",
+                        self.total_reads + 1,
+                        line
+                    )
+                }
+                self.total_reads += 1;
+                self.description = line;
+                self.sequence.clear();
+                self.quality_values.clear();
+                self.stage = FastqStage::Sequence;
+                false
+            }
+            FastqStage::Sequence if line.starts_with('+') => {
+                self.add_description = line;
+                self.stage = FastqStage::Quality;
+                false
+            }
+            // Sequence lines never start with '@', so this is the next record or the quality scores and the '+' line is missing
+            FastqStage::Sequence if line.starts_with('@') => {
+                bail!(
+                    "FASTQ record {} does not have a '+' line after its sequence: {}\nCheck the FASTQ format",
+                    self.total_reads,
+                    self.description
+                )
+            }
+            FastqStage::Sequence => {
+                self.sequence.push_str(&line);
+                false
+            }
+            FastqStage::Quality => {
+                self.quality_values.push_str(&line);
+                let complete = self.quality_values.len() >= self.sequence.len();
+                if complete {
+                    self.stage = FastqStage::Description;
+                }
+                complete
+            }
+        };
+        Ok(complete)
+    }
+
+    /// Reads lines from the reader until the next record is complete.  Returns false at the end of the file, warning if the file ended
+    /// partway through a record
+    fn read_record(&mut self, reader: &mut Box<dyn BufRead>, fastq: &str) -> Result<bool> {
+        loop {
+            let mut line = String::new();
+            let read_response = reader
+                .read_line(&mut line)
+                .context(format!("Bufread could not read line for file: {}", fastq))?;
+            if read_response == 0 {
+                if self.drop_incomplete_record() {
+                    eprintln!(
+                        "\nWARNING: {} ended partway through a FASTQ record and may be truncated, such as from an interrupted transfer\n",
+                        fastq
+                    );
+                }
+                return Ok(false);
+            }
+            let line_length = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(line_length);
+            line.push('\n');
+            if self.read(line)? {
+                return Ok(true);
+            }
         }
-        if self.line_num == 1 {
-            self.total_reads += 1;
-            self.raw_sequence_read_string = line;
-        } else {
-            self.raw_sequence_read_string.push_str(&line);
+    }
+
+    /// Checks whether the lines read stopped partway through a record, and if so removes that record from the total reads since it was
+    /// never posted.  Returns true when there was an incomplete record
+    fn drop_incomplete_record(&mut self) -> bool {
+        if self.stage == FastqStage::Description {
+            return false;
         }
+        self.total_reads -= 1;
+        self.stage = FastqStage::Description;
+        true
     }
 
     pub fn post(&mut self) -> Result<()> {
-        self.raw_sequence_read_string.pop(); // removes the last \n
-                                             // Insert the sequence into the vec.  This will be popped out by other threads
+        let raw_sequence_read_string = format!(
+            "{}\n{}\n{}\n{}",
+            self.description, self.sequence, self.add_description, self.quality_values
+        );
+        // Add the sequence to the batch.  This will be sent to the other threads once the batch is full
         if self.test {
-            RawSequenceRead::unpack(self.raw_sequence_read_string.clone())?.check_fastq_format()?;
+            let first_read = RawSequenceRead::unpack(raw_sequence_read_string.clone())?;
+            if self.check_format {
+                first_read.check_fastq_format()?;
+            }
+            first_read.check_quality_offset(self.quality_offset)?;
             self.test = false;
         }
-        self.seq_clone
-            .lock()
-            .unwrap()
-            .push_front(self.raw_sequence_read_string.clone());
+        self.batch.push(raw_sequence_read_string);
+        if self.batch.len() >= ReadQueue::BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the records posted since the last batch to the processing threads, waiting if the queue is full so memory is not overloaded
+    fn flush(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(ReadQueue::BATCH_SIZE));
+            self.seq_clone.send(batch, &self.exit_clone)?;
+        }
         Ok(())
     }
 }
@@ -152,8 +1152,18 @@ impl fmt::Display for FastqLineReader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Total sequences:             {}\r",
+            "Total sequences:             {}",
             self.total_reads.to_formatted_string(&Locale::en)
-        )
+        )?;
+        if let Some((bytes_read, file_size)) = self.file_progress_option {
+            write!(
+                f,
+                "  ({:.1}% of the file, {} of {} bytes)",
+                bytes_read as f64 / file_size as f64 * 100.0,
+                bytes_read.to_formatted_string(&Locale::en),
+                file_size.to_formatted_string(&Locale::en)
+            )?;
+        }
+        write!(f, "\r")
     }
 }
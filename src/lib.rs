@@ -3,3 +3,23 @@ pub mod info;
 pub mod input;
 pub mod output;
 pub mod parse;
+
+/// Prints a line of routine run information to stdout, unless --quiet is used
+#[macro_export]
+macro_rules! info_println {
+    ($($arg:tt)*) => {
+        if $crate::arguments::Verbosity::Normal.enabled() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a line of detailed run information to stdout, only when --verbose is used
+#[macro_export]
+macro_rules! verbose_println {
+    ($($arg:tt)*) => {
+        if $crate::arguments::Verbosity::Verbose.enabled() {
+            println!($($arg)*);
+        }
+    };
+}
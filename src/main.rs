@@ -1,166 +1,781 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use chrono::Local;
+use itertools::Itertools;
+use num_format::{Locale, ToFormattedString};
 use std::{
-    collections::VecDeque,
+    path::Path,
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
-fn main() -> Result<()> {
+fn main() {
+    // Problems found with a finished run have their own exit codes so that pipelines can branch on them.  All other errors exit with 1
+    if let Err(err) = run() {
+        eprintln!("Error: {:?}", err);
+        let exit_code = err
+            .downcast_ref::<barcode_count::info::RunCheckFailure>()
+            .map_or(1, |run_check_failure| run_check_failure.exit_code());
+        std::process::exit(exit_code);
+    }
+}
+
+fn run() -> Result<()> {
     // Start a clock to measure how long the algorithm takes
     let start_time = Local::now();
 
     // get the argument inputs
-    let mut args = barcode_count::arguments::Args::new()?;
+    let args = match barcode_count::arguments::Command::new()? {
+        barcode_count::arguments::Command::Count(args) => *args,
+        // Merging completed counts files does not need any fastq parsing
+        barcode_count::arguments::Command::MergeCounts(merge_args) => {
+            return barcode_count::output::merge_counts_files(&merge_args)
+        }
+        // Validating the input files stops before any counting
+        barcode_count::arguments::Command::Validate(validate_args) => {
+            if barcode_count::input::validate_inputs(&validate_args) {
+                return Ok(());
+            }
+            bail!("Validation failed")
+        }
+        // The self-test generates its own reads and counts them with a separate run of this executable
+        barcode_count::arguments::Command::SelfTest(self_test_args) => {
+            if barcode_count::input::self_test(&self_test_args)? {
+                return Ok(());
+            }
+            bail!("Self-test failed")
+        }
+    };
+
+    // The routine run information is only printed at the verbosity given
+    args.verbosity.set();
 
-    let sequence_format = barcode_count::info::SequenceFormat::parse_format_file(&args.format)?;
-    println!("{}\n", sequence_format);
+    // Create the output directory, along with any missing parents, before counting so that a bad path fails now and not after parsing
+    std::fs::create_dir_all(&args.output_dir).context(format!("Failed to create output directory {}", args.output_dir))?;
 
-    // Check how many barcodes occur if either single or double barcode enrichment is callsed.  If there are too few, ignore the argument flag
-    if args.enrich && sequence_format.barcode_num < 2 {
-        eprintln!("Fewer than 2 counted barcodes.  Too few for barcode enrichment.  Argument flag is ignored");
-        args.enrich = false;
+    // Several comma separated sequence formats can be given for a pool of library designs.  Each read is counted under the first that matches
+    let sequence_formats = args
+        .format
+        .split(',')
+        .map(|format| {
+            if args.format_from_string {
+                barcode_count::info::SequenceFormat::parse_format_str(format)
+            } else {
+                barcode_count::info::SequenceFormat::parse_format_file(format)
+            }
+        })
+        .collect::<Result<Vec<barcode_count::info::SequenceFormat>>>()?;
+    for sequence_format in &sequence_formats {
+        barcode_count::info_println!("{}\n", sequence_format);
+    }
+    let multiple_formats = sequence_formats.len() > 1;
+    // Each sequence format's output files are named after its file, or its order when the formats are given directly
+    let format_names = args
+        .format
+        .split(',')
+        .enumerate()
+        .map(|(format_index, format)| match Path::new(format).file_stem() {
+            Some(file_stem) if !args.format_from_string => file_stem.to_string_lossy().to_string(),
+            _ => format!("format{}", format_index + 1),
+        })
+        .collect::<Vec<String>>();
+    if format_names.iter().unique().count() != format_names.len() {
+        bail!("Each sequence format file needs a different name to separate their output files")
+    }
+    // The checkpoint and correction index files, along with the sample progress, only hold one set of counts
+    if multiple_formats
+        && (args.resume
+            || args.checkpoint_interval > 0
+            || args.load_index_option.is_some()
+            || args.dump_index_option.is_some()
+            || args.sample_progress_seconds > 0)
+    {
+        bail!("Checkpoints, --resume, --load-index, --dump-index and --sample-progress are not supported with more than one sequence format")
+    }
+    // Each sequence format has its own counts files, so one basename for a sample would be used by every format
+    if multiple_formats && args.name_map_option.is_some() {
+        bail!("--name-map is not supported with more than one sequence format")
+    }
+    let name_map = match args.name_map_option {
+        Some(ref name_map_file) => barcode_count::info::parse_name_map(
+            &std::fs::read_to_string(name_map_file).context(format!("Failed to open {}", name_map_file))?,
+            args.delimiter,
+        )?,
+        None => ahash::HashMap::default(),
+    };
+    // Barcodes and samples, such as spike-in controls, that are left out of the counts files
+    let exclusion_list = |exclusion_file: &Option<String>| -> Result<ahash::AHashSet<String>> {
+        match exclusion_file {
+            Some(exclusion_file) => Ok(barcode_count::info::parse_exclusion_list(
+                &std::fs::read_to_string(exclusion_file).context(format!("Failed to open {}", exclusion_file))?,
+                args.delimiter,
+            )),
+            None => Ok(ahash::AHashSet::new()),
+        }
+    };
+    let excluded_barcodes = exclusion_list(&args.exclude_barcodes_option)?;
+    let excluded_samples = exclusion_list(&args.exclude_samples_option)?;
+    // The expected combinations of counted barcodes, such as the enumerated compounds of a library design
+    let valid_combinations_option = match args.valid_combinations_option {
+        Some(ref combinations_file) => Some(barcode_count::info::parse_combinations_list(
+            &std::fs::read_to_string(combinations_file).context(format!("Failed to open {}", combinations_file))?,
+            args.delimiter,
+            args.unordered_barcodes,
+        )),
+        None => None,
+    };
+    // Either one counted barcodes file for every sequence format or one for each
+    let counted_barcode_files = args
+        .counted_barcodes_option
+        .as_ref()
+        .map(|barcodes| barcodes.split(',').map(|barcodes| barcodes.to_string()).collect::<Vec<String>>())
+        .unwrap_or_default();
+    if counted_barcode_files.len() > 1 && counted_barcode_files.len() != sequence_formats.len() {
+        bail!(
+            "--counted-barcodes has {} files but there are {} sequence formats.  Use one file for all or one for each",
+            counted_barcode_files.len(),
+            sequence_formats.len()
+        )
     }
 
+    // The sample and counted barcode file columns are found by their header names when given, otherwise by their position
+    let barcode_columns = barcode_count::info::BarcodeColumns {
+        seq_column_option: args.seq_column_option.clone(),
+        id_column_option: args.id_column_option.clone(),
+        index_column_option: args.index_column_option.clone(),
+    };
     // Start getting the barcode conversion with the BarcodeConversions struct
     let mut barcode_conversions = barcode_count::info::BarcodeConversions::new();
     // Create a hashmap of the sample barcodes in order to convert sequence to sample ID
     if let Some(ref samples) = args.sample_barcodes_option {
-        barcode_conversions.sample_barcode_file_conversion(samples)?;
+        if args.illumina_sample_sheet {
+            barcode_conversions.illumina_sample_sheet_conversion(samples)?;
+        } else {
+            barcode_conversions.sample_barcode_file_conversion(samples, args.delimiter, &barcode_columns)?;
+        }
         barcode_conversions.get_sample_seqs();
+        // Sample barcodes with the same sample name are either pooled like a group or written under names with their barcode added
+        barcode_conversions.resolve_sample_name_collisions(args.pool_sample_names);
+        // Technical replicate sample barcodes with the same group are counted together after their sequencing errors are fixed
+        barcode_conversions.group_samples()?;
+        // Reads with sample barcodes that could not be fixed are counted under their own sample.  It is not a known sample barcode, so
+        // sample barcodes are not fixed to it
+        if args.match_unknown_samples {
+            if barcode_conversions
+                .samples_barcode_hash
+                .iter()
+                .any(|(sample_barcode, sample_name)| sample_barcode == "unknown" || sample_name == "unknown")
+            {
+                bail!("--match-unknown-samples counts reads under the 'unknown' sample, which is already within the sample barcode file")
+            }
+            barcode_conversions
+                .samples_barcode_hash
+                .insert("unknown".to_string(), "unknown".to_string());
+        }
     }
-
-    // Create a results struct that will contain the counts.  This is passed between threads
-    let results = Arc::new(Mutex::new(barcode_count::info::Results::new(
-        &barcode_conversions.samples_barcode_hash,
-        sequence_format.random_barcode,
-        sequence_format.sample_barcode,
-    )));
-
-    // Create a hashmap of the building block barcodes in order to convert sequence to building block
-    if let Some(ref barcodes) = args.counted_barcodes_option {
-        barcode_conversions.barcode_file_conversion(barcodes, sequence_format.barcode_num)?;
-        barcode_conversions.get_barcode_seqs();
+    // If each sample has its own fastq file, the sample names are used in place of sample barcodes
+    if !args.fastq_samples.is_empty() {
+        if sequence_formats.iter().any(|sequence_format| sequence_format.sample_barcode)
+            || args.sample_barcodes_option.is_some()
+        {
+            bail!("Sample barcodes cannot be used when each sample has its own fastq file")
+        }
+        for (sample_name, _) in &args.fastq_samples {
+            barcode_conversions
+                .samples_barcode_hash
+                .insert(sample_name.to_string(), sample_name.to_string());
+        }
     }
 
     // Create a sequencing errors Struct to track errors.  This is passed between threads
     let sequence_errors = barcode_count::info::SequenceErrors::new();
 
+    // Create a checkpoint struct to periodically save the counts.  If resuming, add the saved counts and get where to start reading
+    let mut checkpoint = barcode_count::input::Checkpoint::new(&args);
+
+    // Fixes the sample and counted barcodes with sequencing errors for every design
+    let barcode_corrector = barcode_count::parse::barcode_corrector(&args.corrector)?;
+    // The correction indexes find the fewest mismatches, so they are neither created nor used by other correctors
+    if !barcode_corrector.is_hamming() && (args.load_index_option.is_some() || args.dump_index_option.is_some()) {
+        bail!("--load-index and --dump-index are only used with --corrector hamming")
+    }
+    // The counts, conversions and allowed errors of each sequence format
+    let mut designs = Vec::new();
+    let mut design_conversions = Vec::new();
+    for (format_index, sequence_format) in sequence_formats.into_iter().enumerate() {
+        // The molecule key from the read names takes the place of a random barcode, so both can not be used
+        if sequence_format.random_barcode && args.dedup_name_regex_option.is_some() {
+            bail!("--dedup-by-name-regex cannot be used with a random barcode within the sequence format")
+        }
+        // Counts with a random barcode are the unique random barcodes, which do not have a read weight
+        if sequence_format.random_barcode && args.quality_weighted {
+            bail!("--quality-weighted cannot be used with a random barcode within the sequence format")
+        }
+        // Create a results struct that will contain the counts.  This is passed between threads
+        let mut results_start = barcode_count::info::Results::new(
+            &barcode_conversions.samples_barcode_hash,
+            sequence_format.random_barcode || args.dedup_name_regex_option.is_some(),
+            sequence_format.sample_barcode,
+            args.umi_bloom_bits,
+        );
+        results_start.sample_groups = barcode_conversions.sample_groups.clone();
+        if args.umi_hll {
+            results_start.estimate_random_barcodes();
+        }
+        if args.quality_weighted {
+            results_start.weight_counts();
+        }
+        if args.report_reads {
+            if matches!(
+                results_start.results_hashmap,
+                barcode_count::info::ResultsHashmap::RandomBarcode(_)
+            ) {
+                results_start.count_reads();
+            } else {
+                eprintln!("No random barcode within the sequence format.  --report-reads is ignored\n");
+            }
+        }
+        if args.pool_unknown_samples {
+            results_start.pool_samples("unknown");
+        }
+        // The unknown sample is only added with its first read, so that it is not an empty sample without index hopping
+        if args.match_unknown_samples {
+            results_start.exclude_samples(&std::iter::once("unknown".to_string()).collect());
+        }
+
+        // Create a hashmap of the building block barcodes in order to convert sequence to building block
+        let mut format_conversions = barcode_conversions.clone();
+        if let Some(barcodes) = counted_barcode_files
+            .get(format_index)
+            .or_else(|| counted_barcode_files.first())
+        {
+            format_conversions.barcode_file_conversion(
+                barcodes,
+                sequence_format.barcode_num,
+                args.delimiter,
+                &barcode_columns,
+            )?;
+            format_conversions.get_barcode_seqs()?;
+            // A barcode and its reverse complement are counted as one, so both are fixed and converted to the same ID
+            if args.fold_revcomp {
+                // The reverse complement of an expanded sequence would not be counted as its IUPAC barcode
+                if format_conversions
+                    .counted_barcode_seqs
+                    .iter()
+                    .any(|counted_barcode_seqs| !counted_barcode_seqs.iupac_barcodes.is_empty())
+                {
+                    bail!("--fold-revcomp is not supported with IUPAC codes within the counted barcodes file")
+                }
+                format_conversions.fold_reverse_complements();
+            }
+            if args.unordered_barcodes {
+                format_conversions.check_unordered_ids()?;
+            }
+        }
+
+        if args.resume {
+            checkpoint.load(&mut results_start, &sequence_errors)?;
+        }
+
+        // The counted barcode max errors are either one value for all or one for each counted barcode
+        if let Some(ref barcodes_errors) = args.barcodes_errors_option {
+            if barcodes_errors.len() != 1 && barcodes_errors.len() != sequence_format.barcode_num {
+                bail!(
+                    "--max-errors-counted-barcode has {} values but the sequence format has {} counted barcodes.  Use one value for all or one for each",
+                    barcodes_errors.len(),
+                    sequence_format.barcode_num
+                )
+            }
+        }
+        if let Some((row_barcode, column_barcode)) = args.pairwise_matrix_option {
+            if row_barcode.max(column_barcode) > sequence_format.barcode_num {
+                bail!(
+                    "--pairwise-matrix uses barcode number {} but the sequence format has {} counted barcodes",
+                    row_barcode.max(column_barcode),
+                    sequence_format.barcode_num
+                )
+            }
+        }
+        // Create a MaxSeqErrors struct which holds how many sequencing errors are allowed for each sequencing region
+        // With an error fraction, the barcodes without set max errors allow that fraction of their length instead of 20%
+        let (sample_errors_option, barcodes_errors_option) = match args.max_error_fraction_option {
+            Some(max_error_fraction) => (
+                args.sample_errors_option.or(sequence_format.sample_length_option.map(|sample_length| {
+                    barcode_count::info::MaxSeqErrors::fraction_errors(sample_length, max_error_fraction)
+                })),
+                args.barcodes_errors_option.clone().or_else(|| {
+                    Some(
+                        sequence_format
+                            .barcode_lengths
+                            .iter()
+                            .map(|barcode_length| {
+                                barcode_count::info::MaxSeqErrors::fraction_errors(*barcode_length, max_error_fraction)
+                            })
+                            .collect(),
+                    )
+                }),
+            ),
+            None => (args.sample_errors_option, args.barcodes_errors_option.clone()),
+        };
+        let max_errors = barcode_count::info::MaxSeqErrors::new(
+            sample_errors_option,
+            sequence_format.sample_length_option,
+            barcodes_errors_option,
+            sequence_format.barcode_lengths.clone(),
+            args.constant_errors_option,
+            sequence_format.constant_region_length,
+            args.min_average_quality_score,
+        );
+        // Display region sizes and errors allowed
+        barcode_count::info_println!("{}\n", max_errors);
+        // Precompute the single mismatch index for counted barcodes that allow only one mismatch, or load it from an earlier run.  The
+        // indexes find the fewest mismatches, so they are not used by other correctors
+        if barcode_corrector.is_hamming() {
+            if let Some(ref index_path) = args.load_index_option {
+                format_conversions.load_barcode_indexes(index_path, max_errors.max_barcode_errors())?;
+            } else {
+                format_conversions.get_barcode_indexes(max_errors.max_barcode_errors());
+            }
+            format_conversions.get_kmer_indexes(max_errors.max_barcode_errors(), args.min_correction_margin);
+        }
+        if let Some(ref index_path) = args.dump_index_option {
+            format_conversions.dump_barcode_indexes(index_path)?;
+        }
+        // Warn if barcodes are close enough to each other that reads with errors between them cannot be fixed
+        format_conversions.check_correctable(&max_errors, args.check_barcode_distances);
+
+        designs.push(barcode_count::parse::FormatDesign {
+            sequence_format,
+            max_errors,
+            counted_barcode_seqs: format_conversions.counted_barcode_seqs.clone(),
+            results: Arc::new(Mutex::new(results_start)),
+            corrector: Arc::clone(&barcode_corrector),
+        });
+        design_conversions.push(format_conversions);
+    }
+    // Keeps track of how many reads the processing threads have finished, which starts after any resumed reads
+    let processed = Arc::new(AtomicU32::new(checkpoint.total_reads));
+
     // Create a passed exit passed variable to stop reading when a thread has panicked
     let exit = Arc::new(AtomicBool::new(false));
 
-    // Create a MaxSeqErrors struct which holds how many sequencing errors are allowed for each sequencing region
-    let max_errors = barcode_count::info::MaxSeqErrors::new(
-        args.sample_errors_option,
-        sequence_format.sample_length_option,
-        args.barcodes_errors_option,
-        sequence_format.barcode_lengths.clone(),
-        args.constant_errors_option,
-        sequence_format.constant_region_length,
-        args.min_average_quality_score,
-    );
-    // Display region sizes and errors allowed
-    println!("{}\n", max_errors);
-
     let total_reads_arc = Arc::new(AtomicU32::new(0));
-    // Start the multithreading scope
-    rayon::scope(|s| {
-        // Create a sequence vec which will have sequences entered by the reading thread, and sequences removed by the processing threads
-        let seq = Arc::new(Mutex::new(VecDeque::new()));
-        // Create a passed variable to let the processing threads know the reading thread is done
-        let finished = Arc::new(AtomicBool::new(false));
-
-        // Clone variables that are needed to be passed into the reading thread and create the reading thread
-        let seq_clone = Arc::clone(&seq);
-        let finished_clone = Arc::clone(&finished);
-        let exit_clone = Arc::clone(&exit);
-        let fastq = args.fastq.clone();
-        let total_reads_arc_clone = Arc::clone(&total_reads_arc);
-        s.spawn(move |_| {
-            barcode_count::input::read_fastq(fastq, seq_clone, exit_clone, total_reads_arc_clone)
-                .unwrap_or_else(|err| {
-                    finished_clone.store(true, Ordering::Relaxed);
-                    panic!("Read Fastq error: {}", err)
-                });
-            finished_clone.store(true, Ordering::Relaxed);
+    // Create a shared struct which each processing thread merges its read length and match start information into
+    let insert_stats = Arc::new(Mutex::new(barcode_count::info::InsertStats::new()));
+    // Create a separate thread, outside of the processing threads, to print the matched reads per sample while parsing
+    let parsing_done = Arc::new(AtomicBool::new(false));
+    let sample_progress_thread = if args.sample_progress_seconds > 0 {
+        // The sample progress uses the counts of the first sequence format, which is the only one when it is used
+        let results_clone = Arc::clone(&designs[0].results);
+        let processed_clone = Arc::clone(&processed);
+        let parsing_done_clone = Arc::clone(&parsing_done);
+        let samples_barcode_hash_clone = barcode_conversions.samples_barcode_hash.clone();
+        let interval_seconds = args.sample_progress_seconds;
+        Some(std::thread::spawn(move || {
+            barcode_count::output::print_sample_matches(
+                results_clone,
+                processed_clone,
+                parsing_done_clone,
+                samples_barcode_hash_clone,
+                interval_seconds,
+            )
+        }))
+    } else {
+        None
+    };
+    // Each fastq file is read in turn.  Without per sample fastq files, there is one file without a sample label.  Writing from a results
+    // checkpoint does not read any
+    let fastq_files = if args.write_from_checkpoint_option.is_some() {
+        Vec::new()
+    } else if args.fastq_samples.is_empty() {
+        vec![(None, args.fastq.clone())]
+    } else {
+        args.fastq_samples
+            .iter()
+            .map(|(sample_name, fastq_file)| (Some(sample_name.to_string()), fastq_file.to_string()))
+            .collect::<Vec<(Option<String>, String)>>()
+    };
+    let mut checkpoint_option = Some(checkpoint);
+    // Keep track of the wall-clock time of each stage for the stats file
+    let mut stage_times = barcode_count::info::StageTimes::default();
+    let reading_time = Arc::new(Mutex::new(Duration::ZERO));
+    let parsing_start = Instant::now();
+    // Aborts the run if too few of the first reads match, which is shared by every fastq file
+    let match_rate_check = barcode_count::parse::MatchRateCheck::new(
+        args.min_match_rate_option,
+        args.check_after_reads,
+        Arc::clone(&exit),
+    );
+    // Prints the barcodes of the first matched reads of the run, which is also shared by every fastq file
+    let match_preview = barcode_count::parse::MatchPreview::new(args.preview);
+    // A thread pool of only the requested threads, in place of rayon's global pool of every core, so that shared cluster nodes are not
+    // oversubscribed.  The reading thread and each processing thread run within it
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads as usize)
+        .build()
+        .context("Unable to create the thread pool")?;
+    // The first error from the reading or processing threads.  The threads stop and the error is returned after the scope instead of panicking
+    let thread_error = Mutex::new(None::<anyhow::Error>);
+    // A separate thread writes the outcome of each read, sent from the processing threads, so that the parsing does not wait on the file
+    let (per_read_sender_option, per_read_thread) = if let Some(ref per_read_output) = args.per_read_output_option {
+        let (per_read_sender, per_read_receiver) = std::sync::mpsc::sync_channel(10_000);
+        let per_read_path = Path::new(per_read_output).to_path_buf();
+        let samples_barcode_hash_clone = barcode_conversions.samples_barcode_hash.clone();
+        let counted_barcodes_hashes = design_conversions
+            .iter()
+            .map(|format_conversions| format_conversions.counted_barcodes_hash.clone())
+            .collect::<Vec<_>>();
+        let unordered_barcodes = args.unordered_barcodes;
+        let per_read_thread = std::thread::spawn(move || {
+            barcode_count::output::write_per_read_file(
+                &per_read_path,
+                per_read_receiver,
+                samples_barcode_hash_clone,
+                counted_barcodes_hashes,
+                unordered_barcodes,
+            )
         });
+        (Some(per_read_sender), Some(per_read_thread))
+    } else {
+        (None, None)
+    };
+    for (sample_label, fastq) in fastq_files {
+        // Only the first file uses the loaded checkpoint.  Later files continue the read count from the previous files
+        let mut checkpoint = checkpoint_option
+            .take()
+            .unwrap_or_else(|| barcode_count::input::Checkpoint::new(&args));
+        checkpoint.total_reads = checkpoint
+            .total_reads
+            .max(total_reads_arc.load(Ordering::Relaxed));
+        // Start the multithreading scope
+        thread_pool.scope(|s| {
+            // Create a queue which will have batches of sequences sent by the reading thread, and received by the processing threads
+            let seq = Arc::new(barcode_count::parse::ReadQueue::new());
 
-        let shared_mut =
-            barcode_count::parse::SharedMutData::new(seq, finished, Arc::clone(&results));
-        // Create processing threads.  One less than the total threads because of the single reading thread
-        for _ in 1..args.threads {
-            // Clone all variables needed to pass into each thread
-            let shared_mut_clone = shared_mut.arc_clone();
-            let sequence_errors_clone = sequence_errors.arc_clone();
-            let sequence_format_clone = sequence_format.clone();
-            let exit_clone = &exit;
-            let max_errors_clone = max_errors.clone();
-            let sample_seqs_clone = barcode_conversions.sample_seqs.clone();
-            let counted_barcode_seqs_clone = barcode_conversions.counted_barcode_seqs.clone();
-            let min_quality_score = args.min_average_quality_score;
-
-            // Create a processing thread
+            let shared_mut = barcode_count::parse::SharedMutData::new(
+                seq,
+                // The checkpoints use the counts of the first sequence format, which is the only one when they are used
+                Arc::clone(&designs[0].results),
+                Arc::clone(&insert_stats),
+                Arc::clone(&processed),
+                sample_label,
+                match_rate_check.clone(),
+                match_preview.clone(),
+            );
+
+            // Clone variables that are needed to be passed into the reading thread and create the reading thread
+            let shared_mut_reader = shared_mut.arc_clone();
+            let read_queue_clone = Arc::clone(&shared_mut.seq);
+            let exit_clone = Arc::clone(&exit);
+            let total_reads_arc_clone = Arc::clone(&total_reads_arc);
+            let first_record_checks = barcode_count::input::FirstRecordChecks::new(&args);
+            let sequence_errors_reader = sequence_errors.arc_clone();
+            let reading_time_clone = Arc::clone(&reading_time);
+            let thread_error_reader = &thread_error;
+            let merge_pairs_option = args.merge_pairs_option.clone();
+            let min_overlap = args.min_overlap;
             s.spawn(move |_| {
-                let mut parser = barcode_count::parse::SequenceParser::new(
-                    shared_mut_clone,
-                    sequence_errors_clone,
-                    sequence_format_clone,
-                    max_errors_clone,
-                    sample_seqs_clone,
-                    counted_barcode_seqs_clone,
-                    min_quality_score,
-                );
-                parser.parse().unwrap_or_else(|err| {
-                    exit_clone.store(true, Ordering::Relaxed);
-                    panic!("Compute thread panic error: {}", err)
-                });
-            })
+                let reading_start = Instant::now();
+                // Paired reads are merged by their overlap before being posted, which does not use checkpoints
+                let read_result = if let Some(fastq2) = merge_pairs_option {
+                    barcode_count::input::read_fastq_pairs(
+                        fastq,
+                        fastq2,
+                        shared_mut_reader,
+                        Arc::clone(&exit_clone),
+                        total_reads_arc_clone,
+                        first_record_checks,
+                        min_overlap,
+                    )
+                } else {
+                    barcode_count::input::read_fastq(
+                        fastq,
+                        shared_mut_reader,
+                        Arc::clone(&exit_clone),
+                        total_reads_arc_clone,
+                        first_record_checks,
+                        checkpoint,
+                        sequence_errors_reader,
+                    )
+                };
+                read_result
+                    .unwrap_or_else(|err| {
+                        exit_clone.store(true, Ordering::Relaxed);
+                        thread_error_reader
+                            .lock()
+                            .unwrap()
+                            .get_or_insert(err.context("Read Fastq error"));
+                    });
+                *reading_time_clone.lock().unwrap() += reading_start.elapsed();
+                // Let the processing threads know the reading thread is done once they have parsed the batches left in the queue
+                read_queue_clone.close();
+            });
+
+            // Only the first processing thread prints the matched reads, so that they are not interleaved
+            let mut match_printer_option = if args.show_matches > 0 {
+                Some(barcode_count::parse::MatchPrinter::new(
+                    args.show_matches,
+                    barcode_conversions.samples_barcode_hash.clone(),
+                    design_conversions
+                        .iter()
+                        .map(|format_conversions| format_conversions.counted_barcodes_hash.clone())
+                        .collect(),
+                ))
+            } else {
+                None
+            };
+            // Create processing threads.  One less than the total threads because of the single reading thread
+            for _ in 1..args.threads {
+                // Clone all variables needed to pass into each thread
+                let shared_mut_clone = shared_mut.arc_clone();
+                let sequence_errors_clone = sequence_errors.arc_clone();
+                let designs_clone = designs.clone();
+                let exit_clone = &exit;
+                let sample_seqs_clone = barcode_conversions.sample_seqs.clone();
+                let args_clone = &args;
+                let thread_error_clone = &thread_error;
+                let per_read_sender_clone = per_read_sender_option.clone();
+                let match_printer_clone = match_printer_option.take();
+
+                // Create a processing thread
+                s.spawn(move |_| {
+                    let mut parser = barcode_count::parse::SequenceParser::new(
+                        shared_mut_clone,
+                        sequence_errors_clone,
+                        designs_clone,
+                        sample_seqs_clone,
+                        per_read_sender_clone,
+                        match_printer_clone,
+                        args_clone,
+                    );
+                    parser.parse().unwrap_or_else(|err| {
+                        exit_clone.store(true, Ordering::Relaxed);
+                        thread_error_clone
+                            .lock()
+                            .unwrap()
+                            .get_or_insert(err.context("Compute thread error"));
+                    });
+                })
+            }
+        });
+        if let Some(err) = thread_error.lock().unwrap().take() {
+            // A failed per read writer stops the processing threads, and its error is the cause
+            if let Some(per_read_thread) = per_read_thread {
+                drop(per_read_sender_option);
+                per_read_thread.join().unwrap()?;
+            }
+            return Err(err);
+        }
+        match_rate_check.result()?;
+    }
+    // Every processing thread has dropped its sender, so dropping this last one lets the per read writer finish the file
+    drop(per_read_sender_option);
+    if let Some(per_read_thread) = per_read_thread {
+        per_read_thread
+            .join()
+            .unwrap()
+            .context("Per read output error")?;
+    }
+    stage_times.parsing = parsing_start.elapsed();
+    stage_times.reading = *reading_time.lock().unwrap();
+    // Every count is held in memory once the parsing is finished, so the peak so far is close to the peak of the run
+    stage_times.peak_memory_kb = barcode_count::info::peak_resident_memory();
+
+    // The counts and stats of a finished parse are either loaded to write again, or saved before writing in case the writing fails
+    let results_checkpoint_path =
+        Path::new(&args.output_dir).join(format!("{}_results.checkpoint", args.prefix));
+    if let Some(ref write_from_checkpoint) = args.write_from_checkpoint_option {
+        let write_checkpoint = barcode_count::info::WriteCheckpoint::load(Path::new(write_from_checkpoint))?;
+        if write_checkpoint.results.len() != designs.len() {
+            bail!(
+                "Results checkpoint {} has {} sequence formats but there are {}",
+                write_from_checkpoint,
+                write_checkpoint.results.len(),
+                designs.len()
+            )
+        }
+        for (design, results) in designs.iter().zip(write_checkpoint.results) {
+            *design.results.lock().unwrap() = results;
         }
-    });
+        sequence_errors.unpack(&write_checkpoint.sequence_errors)?;
+        total_reads_arc.store(write_checkpoint.total_reads, Ordering::Relaxed);
+        *insert_stats.lock().unwrap() = write_checkpoint.insert_stats;
+        stage_times = write_checkpoint.stage_times;
+        barcode_count::info_println!("Loaded results checkpoint {}\n", write_from_checkpoint);
+    } else {
+        let results_guards = designs
+            .iter()
+            .map(|design| design.results.lock().unwrap())
+            .collect::<Vec<_>>();
+        // A checkpoint that can not be saved, such as on a full disk, does not stop the writing
+        if let Err(err) = barcode_count::info::WriteCheckpoint::save(
+            &results_checkpoint_path,
+            &results_guards.iter().map(|results| &**results).collect::<Vec<_>>(),
+            &sequence_errors,
+            total_reads_arc.load(Ordering::Relaxed),
+            &insert_stats.lock().unwrap(),
+            &stage_times,
+        ) {
+            eprintln!("Results checkpoint not saved: {:#}", err);
+        }
+    }
+
+    // Stop the sample progress thread now that parsing is done
+    parsing_done.store(true, Ordering::Relaxed);
+    if let Some(progress_thread) = sample_progress_thread {
+        progress_thread.join().unwrap();
+    }
 
     // Print sequencing error counts to stdout
-    println!("{}\n", sequence_errors);
+    barcode_count::info_println!("{}\n", sequence_errors);
+    // Show which of the sequence formats the reads matched
+    if multiple_formats {
+        for (design, format_name) in designs.iter().zip(&format_names) {
+            let format_matches = design.results.lock().unwrap().sample_matches.values().sum::<u32>();
+            barcode_count::info_println!(
+                "{} matches: {}",
+                format_name,
+                format_matches.to_formatted_string(&Locale::en)
+            );
+        }
+        barcode_count::info_println!();
+    }
 
     // Get the end time and print compute time for the algorithm
     let elapsed_time = Local::now() - start_time;
-    println!(
+    barcode_count::info_println!(
         "Compute time: {} hours, {} minutes, {}.{} seconds",
         elapsed_time.num_hours(),
         elapsed_time.num_minutes() % 60,
         elapsed_time.num_seconds() % 60,
         barcode_count::output::millisecond_decimal(elapsed_time)
     );
-    println!();
-
-    println!("-WRITING COUNTS-");
-    let mut output = barcode_count::output::WriteFiles::new(
-        results,
-        sequence_format.clone(),
-        barcode_conversions.counted_barcodes_hash,
-        barcode_conversions.samples_barcode_hash,
-        args,
-    )
-    .unwrap_or_else(|err| panic!("Output error: {}", err));
-    output.write_counts_files()?;
-    // Get the end time and print total time for the algorithm
-    output.write_stats_file(
-        start_time,
-        max_errors,
-        sequence_errors,
-        total_reads_arc,
-        sequence_format,
-    )?;
+    barcode_count::info_println!();
+
+    if args.umi_composition {
+        if designs.iter().any(|design| design.sequence_format.random_barcode) {
+            barcode_count::output::write_umi_composition_file(&args, &insert_stats.lock().unwrap())?;
+        } else {
+            eprintln!("No random barcode within the sequence format.  --umi-composition is ignored\n");
+        }
+    }
+
+    // The samples without counts within any of the sequence formats, found before the counts are moved into the writers
+    let empty_samples = if args.fail_on_empty {
+        designs
+            .iter()
+            .map(|design| design.results.lock().unwrap().empty_samples())
+            .reduce(|empty_samples, format_empty_samples| {
+                empty_samples
+                    .into_iter()
+                    .filter(|sample_barcode| format_empty_samples.contains(sample_barcode))
+                    .collect()
+            })
+            .unwrap_or_default()
+            .iter()
+            .map(|sample_barcode| {
+                barcode_count::output::convert_sample_barcode(sample_barcode, &barcode_conversions.samples_barcode_hash)
+            })
+            .collect::<Vec<String>>()
+    } else {
+        Vec::new()
+    };
+
+    barcode_count::info_println!("-WRITING COUNTS-");
+    // The checkpoint was moved into the reading thread, so recreate it to remove the file after writing
+    let checkpoint = barcode_count::input::Checkpoint::new(&args);
+    for ((design, format_conversions), format_name) in designs
+        .into_iter()
+        .zip(design_conversions)
+        .zip(&format_names)
+    {
+        // With more than one sequence format, each has its own output files named after the format
+        let mut format_args = args.clone();
+        if multiple_formats {
+            format_args.prefix = format!("{}_{}", args.prefix, format_name);
+            barcode_count::info_println!("{}", format_name);
+        }
+        // Check how many barcodes occur if either single or double barcode enrichment is callsed.  If there are too few, ignore the argument flag
+        if format_args.enrich && design.sequence_format.barcode_num < 2 {
+            eprintln!("Fewer than 2 counted barcodes.  Too few for barcode enrichment.  Argument flag is ignored");
+            format_args.enrich = false;
+        }
+        let mut output = barcode_count::output::WriteFiles::new(
+            design.results,
+            design.sequence_format.clone(),
+            format_conversions.counted_barcodes_hash,
+            format_conversions.samples_barcode_hash,
+            format_args,
+            stage_times.clone(),
+        )
+        .context("Output error")?;
+        // Let a wrapper start downstream processing of each sample as soon as its counts file is written
+        if let Some(ref command) = args.sample_written_command_option {
+            output.on_sample_written(barcode_count::output::sample_written_command(command.to_string()));
+        }
+        output.set_name_map(name_map.clone());
+        output.set_exclusions(excluded_barcodes.clone(), excluded_samples.clone());
+        if let Some(ref valid_combinations) = valid_combinations_option {
+            output.set_valid_combinations(valid_combinations.clone());
+        }
+        output.write_counts_files()?;
+        // Read back the written files to check them before the run is recorded as finished
+        if args.verify {
+            output.verify_output_files()?;
+        }
+        // Get the end time and print total time for the algorithm
+        output.write_stats_file(
+            start_time,
+            design.max_errors,
+            sequence_errors.arc_clone(),
+            Arc::clone(&total_reads_arc),
+            design.sequence_format,
+            Arc::clone(&insert_stats),
+        )?;
+    }
+    // The run finished, so the checkpoints are no longer needed.  A results checkpoint given with --write-from-checkpoint is kept
+    checkpoint.remove()?;
+    if args.write_from_checkpoint_option.is_none() && results_checkpoint_path.exists() {
+        std::fs::remove_file(&results_checkpoint_path).context(format!(
+            "Unable to remove results checkpoint {}",
+            results_checkpoint_path.display()
+        ))?;
+    }
     // Get the end time and print total time for the algorithm
     let elapsed_time = Local::now() - start_time;
-    println!();
-    println!(
+    barcode_count::info_println!();
+    barcode_count::info_println!(
         "Total time: {} hours, {} minutes, {}.{} seconds",
         elapsed_time.num_hours(),
         elapsed_time.num_minutes() % 60,
         elapsed_time.num_seconds() % 60,
         barcode_count::output::millisecond_decimal(elapsed_time)
     );
+
+    // Check the finished run, now that the counts files are written for any inspection
+    let matched_reads = sequence_errors.matched_reads();
+    if args.fail_on_empty && matched_reads == 0 {
+        return Err(barcode_count::info::RunCheckFailure::NoMatchedReads.into());
+    }
+    if let Some(min_match_rate) = args.min_match_rate_exit_option {
+        let total_reads = total_reads_arc.load(Ordering::Relaxed);
+        let match_rate = if total_reads == 0 {
+            0.0
+        } else {
+            matched_reads as f64 / total_reads as f64
+        };
+        if match_rate < min_match_rate as f64 {
+            return Err(barcode_count::info::RunCheckFailure::LowMatchRate {
+                match_rate,
+                min_match_rate,
+            }
+            .into());
+        }
+    }
+    if !empty_samples.is_empty() {
+        return Err(barcode_count::info::RunCheckFailure::EmptySamples(empty_samples).into());
+    }
     Ok(())
 }
@@ -1,14 +1,18 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use chrono::{DateTime, Local};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
 use num_format::{Locale, ToFormattedString};
 use std::{
-    fs::{File, OpenOptions},
-    io::{stdout, Write},
+    fs::{self, File, OpenOptions},
+    io::{stderr, BufReader, BufWriter, Read, Write},
     path::Path,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::Receiver,
         Arc, Mutex,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use ahash::{AHashSet, HashMap, HashMapExt};
@@ -16,10 +20,13 @@ use ahash::{AHashSet, HashMap, HashMapExt};
 use itertools::Itertools;
 
 use crate::{
-    arguments::Args,
+    arguments::{Args, MergeCountsArgs, SortBy},
     info::{
-        MaxSeqErrors, Results, ResultsEnrichment, ResultsHashmap, SequenceErrors, SequenceFormat,
+        combination_key, InsertStats, MaxSeqErrors, Results, ResultsEnrichment, ResultsHashmap, RichnessEstimate,
+        SequenceErrors, SequenceFormat, StageTimes,
     },
+    info_println,
+    parse::ReadAssignment,
 };
 
 #[derive(PartialEq, Clone)]
@@ -40,11 +47,27 @@ pub struct WriteFiles {
     args: Args,
     output_files: Vec<String>,
     output_counts: Vec<usize>,
+    output_totals: Vec<Option<u64>>, // sum of the Count column of each output file, for --verify.  None for files without a Count column
     merged_count: usize,
     merge_text: String,
     sample_text: String,
+    unconvertible_barcodes: AHashSet<String>, // DNA barcodes that were counted but were not found within the conversion file
+    stage_times: StageTimes, // wall-clock spans of each stage.  The writing span is recorded by write_counts_files
+    write_progress: WriteProgress, // prints the number of barcodes written so far for each file
+    cycle_counts: Vec<HashMap<String, usize>>, // counts of each barcode for each counted barcode position within the current sample.  Used with --per-cycle-counts
+    sample_written_callback: Option<SampleWrittenCallback>, // called with the sample name and file path after each sample's counts file is written
+    name_map: HashMap<String, String>, // sample name -> basename of its counts file.  Samples without one use the default file name
+    excluded_barcodes: AHashSet<String>, // counted barcode sequences or IDs that are left out of the counts files
+    excluded_samples: AHashSet<String>, // sample barcodes or names that are left out of the counts files
+    excluded_text: String, // the counts that were excluded, and those of unexpected combinations, for the stats file.  Empty without either
+    valid_combinations_option: Option<AHashSet<String>>, // combination_key of each expected counted barcode combination.  None counts every combination as expected
+    unexpected_text: String, // the rows of the current sample with combinations outside the valid combinations
+    unexpected_total: (usize, usize), // rows and counts of every sample with combinations outside the valid combinations
 }
 
+/// Called with the sample name and the path of its counts file as soon as the file is written.  An error stops the writing
+pub type SampleWrittenCallback = Box<dyn FnMut(&str, &Path) -> Result<()>>;
+
 impl WriteFiles {
     pub fn new(
         results_arc: Arc<Mutex<Results>>,
@@ -52,6 +75,7 @@ impl WriteFiles {
         counted_barcodes_hash: Vec<HashMap<String, String>>,
         samples_barcode_hash: HashMap<String, String>,
         args: Args,
+        stage_times: StageTimes,
     ) -> Result<Self> {
         let results = Arc::try_unwrap(results_arc).unwrap().into_inner().unwrap();
         Ok(WriteFiles {
@@ -61,18 +85,169 @@ impl WriteFiles {
             counted_barcodes_hash,
             samples_barcode_hash,
             compounds_written: AHashSet::new(),
+            write_progress: WriteProgress::new(args.progress_interval),
+            cycle_counts: Vec::new(),
+            sample_written_callback: None,
+            name_map: HashMap::new(),
+            excluded_barcodes: AHashSet::new(),
+            excluded_samples: AHashSet::new(),
+            excluded_text: String::new(),
+            valid_combinations_option: None,
+            unexpected_text: String::new(),
+            unexpected_total: (0, 0),
             args,
             output_files: Vec::new(),
             output_counts: Vec::new(),
+            output_totals: Vec::new(),
             merged_count: 0,
             merge_text: String::new(),
             sample_text: String::new(),
+            unconvertible_barcodes: AHashSet::new(),
+            stage_times,
         })
     }
 
+    /// Sets a callback that is called with the sample name and file path as soon as each sample's counts file is written, so that
+    /// downstream processing can start before the other samples are written
+    pub fn on_sample_written<F>(&mut self, callback: F)
+    where
+        F: FnMut(&str, &Path) -> Result<()> + 'static,
+    {
+        self.sample_written_callback = Some(Box::new(callback));
+    }
+
+    /// Names the counts file of each sample within the name map as its basename, in place of <prefix>_<sample>_counts, so that the files
+    /// match what downstream tools expect.  Samples that are not within the name map keep the default name
+    pub fn set_name_map(&mut self, name_map: HashMap<String, String>) {
+        self.name_map = name_map;
+    }
+
+    /// Sets the counted barcodes and the samples that are left out of the counts files.  The counted barcodes are either sequences or the
+    /// IDs of the counted barcodes file, and the samples are either sample barcodes or sample names
+    pub fn set_exclusions(&mut self, excluded_barcodes: AHashSet<String>, excluded_samples: AHashSet<String>) {
+        self.excluded_barcodes = excluded_barcodes;
+        self.excluded_samples = excluded_samples;
+    }
+
+    /// Sets the expected combinations of counted barcodes, created with parse_combinations_list.  The rows of each sample with any other
+    /// combination are written to a separate unexpected counts file instead of the counts file
+    pub fn set_valid_combinations(&mut self, valid_combinations: AHashSet<String>) {
+        self.valid_combinations_option = Some(valid_combinations);
+    }
+
+    /// Returns whether the counted barcodes of the code, as sequences or as the written IDs, are one of the valid combinations.  Every
+    /// combination is expected without valid combinations.  Reported constant regions after the counted barcodes are not compared
+    fn expected_combination(&self, code: &str, written_barcodes: &str) -> bool {
+        let valid_combinations = match self.valid_combinations_option {
+            Some(ref valid_combinations) => valid_combinations,
+            None => return true,
+        };
+        let barcode_num = self.sequence_format.barcode_num;
+        let unordered = self.args.unordered_barcodes;
+        valid_combinations.contains(&combination_key(code.split(',').take(barcode_num), unordered))
+            || valid_combinations.contains(&combination_key(
+                written_barcodes.split(self.args.delimiter).take(barcode_num),
+                unordered,
+            ))
+    }
+
+    /// Removes the excluded samples, then the excluded counted barcodes within the rest of the samples, from the results and records their
+    /// counts for the stats file
+    fn exclude_results(&mut self) {
+        if !self.excluded_samples.is_empty() {
+            let excluded_sample_barcodes = self
+                .samples_barcode_hash
+                .iter()
+                .filter(|(_, sample_name)| self.excluded_samples.contains(*sample_name))
+                .map(|(sample_barcode, _)| sample_barcode.to_string())
+                .chain(self.excluded_samples.iter().cloned())
+                .collect::<AHashSet<String>>();
+            let (samples, counts) = self.results.exclude_samples(&excluded_sample_barcodes);
+            let excluded_line = format!(
+                "Excluded samples:            {} samples, {} counts\n",
+                samples.to_formatted_string(&Locale::en),
+                counts.to_formatted_string(&Locale::en)
+            );
+            info_println!("{}", excluded_line.trim_end());
+            self.excluded_text.push_str(&excluded_line);
+        }
+        if !self.excluded_barcodes.is_empty() {
+            // The IDs are converted to their sequences, which are what the results are counted under
+            let excluded_sequences = self
+                .counted_barcodes_hash
+                .iter()
+                .flatten()
+                .filter(|(_, barcode_id)| self.excluded_barcodes.contains(*barcode_id))
+                .map(|(barcode, _)| barcode.to_string())
+                .chain(self.excluded_barcodes.iter().map(|barcode| barcode.to_uppercase()))
+                .collect::<AHashSet<String>>();
+            let (barcodes, counts) = self.results.exclude_barcodes(&excluded_sequences);
+            let excluded_line = format!(
+                "Excluded barcodes:           {} barcodes, {} counts\n",
+                barcodes.to_formatted_string(&Locale::en),
+                counts.to_formatted_string(&Locale::en)
+            );
+            info_println!("{}", excluded_line.trim_end());
+            self.excluded_text.push_str(&excluded_line);
+        }
+        if !self.excluded_text.is_empty() {
+            info_println!();
+        }
+    }
+
     /// Sets up and writes the results file.  Works for either with or without a random barcode
     pub fn write_counts_files(&mut self) -> Result<()> {
+        let writing_start = Instant::now();
         let unknown_sample = "barcode".to_string();
+        // Merge counted barcodes with sequencing errors if there is no conversion file to correct them
+        if self.args.cluster_unknown {
+            if self.counted_barcodes_hash.is_empty() {
+                let merged = self.results.cluster_counted_barcodes();
+                info_println!(
+                    "Barcodes merged by clustering: {}\n",
+                    merged.to_formatted_string(&Locale::en)
+                );
+            } else {
+                eprintln!("Counted barcodes file included.  --cluster-unknown is ignored\n");
+            }
+        }
+        // Excluded reads are removed first so that they do not count toward --min-umi or the --downsample-to depth
+        self.exclude_results();
+        // Drop the counted barcodes without enough random barcodes to be called present
+        if let Some(min_umi) = self.args.min_umi_option {
+            if let ResultsHashmap::RandomBarcode(_) = self.results.results_hashmap {
+                let removed = self.results.filter_min_random_barcodes(min_umi);
+                info_println!(
+                    "Barcodes dropped with fewer than {} random barcodes: {}\n",
+                    min_umi,
+                    removed.to_formatted_string(&Locale::en)
+                );
+            } else {
+                eprintln!("No random barcode within the sequence format.  --min-umi is ignored\n");
+            }
+        }
+        // Bring every sample to the same depth so that they can be compared
+        if let Some(downsample_to) = self.args.downsample_to_option {
+            if let ResultsHashmap::NoRandomBarcode(_) = self.results.results_hashmap {
+                let shallow_samples = self.results.downsample(downsample_to, self.args.downsample_seed);
+                info_println!(
+                    "Samples downsampled to {} reads\n",
+                    downsample_to.to_formatted_string(&Locale::en)
+                );
+                if !shallow_samples.is_empty() {
+                    eprintln!(
+                        "Samples with fewer than {} reads, which are not downsampled: {}\n",
+                        downsample_to.to_formatted_string(&Locale::en),
+                        shallow_samples
+                            .iter()
+                            .map(|sample_barcode| convert_sample_barcode(sample_barcode, &self.samples_barcode_hash))
+                            .join(", ")
+                    );
+                }
+            } else {
+                eprintln!("Random barcode within the sequence format.  --downsample-to is ignored\n");
+            }
+        }
         // Pull all sample IDs from either random hashmap or counts hashmap
         let mut sample_barcodes = match &self.results.results_hashmap {
             ResultsHashmap::RandomBarcode(random_hashmap) => {
@@ -105,7 +280,7 @@ impl WriteFiles {
         if self.args.merge_output {
             if sample_barcodes.len() == 1 {
                 eprintln!("Merged file cannot be created without multiple sample barcodes");
-                println!();
+                info_println!();
                 self.args.merge_output = false;
             } else {
                 // Create the merge file and push the header
@@ -120,7 +295,7 @@ impl WriteFiles {
                             .get(sample_barcode)
                             .unwrap_or(&unknown_sample)
                     };
-                    merged_header.push(',');
+                    merged_header.push(self.args.delimiter);
                     merged_header.push_str(sample_name);
                 }
                 merged_header.push('\n');
@@ -128,9 +303,24 @@ impl WriteFiles {
             }
         }
 
-        // Crate the header to be used with each sample file.  This is just Barcode_1..Barcode_n and Count
-        header.push_str(",Count\n");
+        // Crate the header to be used with each sample file.  This is just Barcode_1..Barcode_n and Count, or the reads and unique random
+        // barcodes when both are reported
+        if self.results.read_counts.is_some() {
+            header.push_str(&format!("{}Reads{}UMIs\n", self.args.delimiter, self.args.delimiter));
+        } else {
+            header.push_str(&format!("{}Count\n", self.args.delimiter));
+        }
 
+        // With --merge-only, the sample counts are still added to the merged file, but the individual sample files are not written
+        let write_sample_files = !(self.args.merge_only && self.args.merge_output);
+        // The results start with every sample from the sample barcode file, so the samples without counts get a header only file unless
+        // --emit-empty-samples is 'no'.  They are still columns within the merged file
+        let empty_samples = if self.args.emit_empty_samples {
+            Vec::new()
+        } else {
+            self.results.empty_samples()
+        };
+        let mut unwritten_samples = Vec::new();
         // For each sample, write the counts file
         for sample_barcode in &sample_barcodes {
             let sample_name = if !self.samples_barcode_hash.is_empty() {
@@ -141,57 +331,628 @@ impl WriteFiles {
             } else {
                 sample_barcode
             };
-            let file_name = format!("{}_{}_counts.csv", self.args.prefix, sample_name);
-            println!("{}", file_name);
-            self.output_files.push(file_name.clone());
+            // An empty sample adds no rows to the merged file, so there is nothing else to write for it
+            if empty_samples.contains(sample_barcode) {
+                unwritten_samples.push(sample_name.to_string());
+                continue;
+            }
+            let file_name = match self.name_map.get(sample_name) {
+                Some(basename) => format!("{}.{}", basename, self.file_extension()),
+                None => format!(
+                    "{}_{}_counts.{}",
+                    self.args.prefix,
+                    sample_name,
+                    self.file_extension()
+                ),
+            };
+            if write_sample_files {
+                info_println!("{}", file_name);
+            }
             // join the filename with the directory to create the full path
-            let output_path = directory.join(file_name);
+            let output_path = directory.join(&file_name);
+            let cycle_file_start = format!("{}_{}", self.args.prefix, sample_name);
+            let written_sample = sample_name.to_string();
 
             self.sample_text.push_str(&header);
             let count =
                 self.add_counts_string(sample_barcode, &sample_barcodes, EnrichedType::Full)?;
+            if self.args.per_cycle_counts && write_sample_files {
+                self.write_cycle_files(&cycle_file_start)?;
+            }
+            if let Some(barcode_pair) = self.args.pairwise_matrix_option {
+                self.write_pairwise_matrix(sample_barcode, &cycle_file_start, barcode_pair)?;
+            }
 
-            let mut output = File::create(output_path)?; // Create the output file
-            output.write_all(self.sample_text.as_bytes())?;
+            if write_sample_files {
+                write_output_file(&output_path, &self.sample_text, self.args.output_gzip)?;
+                let count_total = count_column_total(&self.sample_text, self.args.delimiter);
+                self.record_output_file(file_name, count, count_total);
+                if let Some(callback) = self.sample_written_callback.as_mut() {
+                    callback(&written_sample, &output_path)?;
+                }
+                if self.valid_combinations_option.is_some() {
+                    let unexpected_file_name = match self.name_map.get(&written_sample) {
+                        Some(basename) => format!("{}_unexpected.{}", basename, self.file_extension()),
+                        None => format!(
+                            "{}_{}_unexpected_counts.{}",
+                            self.args.prefix,
+                            written_sample,
+                            self.file_extension()
+                        ),
+                    };
+                    let unexpected_text = format!("{}{}", header, self.unexpected_text);
+                    write_output_file(&directory.join(&unexpected_file_name), &unexpected_text, self.args.output_gzip)?;
+                    let unexpected_rows = self.unexpected_text.lines().count();
+                    let unexpected_count_total = count_column_total(&unexpected_text, self.args.delimiter);
+                    self.record_output_file(unexpected_file_name, unexpected_rows, unexpected_count_total);
+                }
+            }
             self.sample_text.clear();
-            self.output_counts.push(count);
+            self.unexpected_text.clear();
+        }
+        if !unwritten_samples.is_empty() {
+            eprintln!("Samples without counts, which have no counts file: {}\n", unwritten_samples.join(", "));
         }
         if self.args.merge_output {
-            let merged_file_name = format!("{}{}", self.args.prefix, "_counts.all.csv");
-            println!("{}", merged_file_name);
-            println!(
-                "Barcodes counted: {}",
-                self.merged_count.to_formatted_string(&Locale::en)
-            );
-            self.output_files.push(merged_file_name.clone());
-            let merged_output_path = directory.join(merged_file_name);
-            let mut merged_output_file = File::create(merged_output_path)?;
-            merged_output_file.write_all(self.merge_text.as_bytes())?;
+            if let Some(chunk_rows) = self.args.merge_chunk_rows_option {
+                // Each part has the header and at most chunk_rows rows, for tools that can not open files above a size limit
+                for (part_index, part_text) in split_rows(&self.merge_text, chunk_rows).into_iter().enumerate() {
+                    let part_file_name = format!(
+                        "{}_counts.all.part{}.{}",
+                        self.args.prefix,
+                        part_index + 1,
+                        self.file_extension()
+                    );
+                    info_println!("{}", part_file_name);
+                    write_output_file(&directory.join(&part_file_name), &part_text, self.args.output_gzip)?;
+                    let part_rows = part_text.lines().count() - 1;
+                    self.record_output_file(part_file_name, part_rows, None);
+                }
+                info_println!(
+                    "Barcodes counted: {}",
+                    self.merged_count.to_formatted_string(&Locale::en)
+                );
+            } else {
+                let merged_file_name =
+                    format!("{}_counts.all.{}", self.args.prefix, self.file_extension());
+                info_println!("{}", merged_file_name);
+                info_println!(
+                    "Barcodes counted: {}",
+                    self.merged_count.to_formatted_string(&Locale::en)
+                );
+                let merged_output_path = directory.join(&merged_file_name);
+                write_output_file(&merged_output_path, &self.merge_text, self.args.output_gzip)?;
+                // The merged file has a count column for each sample instead of a single Count column, so only its rows are checked
+                self.record_output_file(merged_file_name, self.merged_count, None);
+            }
             self.merge_text.clear();
-            self.output_counts.insert(0, self.merged_count);
             self.merged_count = 0;
         }
+        if self.valid_combinations_option.is_some() {
+            let unexpected_line = format!(
+                "Unexpected combinations:     {} barcodes, {} counts\n",
+                self.unexpected_total.0.to_formatted_string(&Locale::en),
+                self.unexpected_total.1.to_formatted_string(&Locale::en)
+            );
+            info_println!("{}", unexpected_line);
+            self.excluded_text.push_str(&unexpected_line);
+        }
+        if let Some(reference_sample) = self.args.reference_sample_option.clone() {
+            self.write_enrichment_ratios(&sample_barcodes, &reference_sample)?;
+        }
         if self.args.enrich {
             self.write_enriched_files(EnrichedType::Single)?;
             if self.sequence_format.barcode_num > 2 {
                 self.write_enriched_files(EnrichedType::Double)?;
             }
         }
+        if self.args.write_uncorrected {
+            self.write_uncorrected_file()?;
+        }
+        if self.args.count_unmatched_sample {
+            self.write_unknown_samples_file()?;
+        }
+        if let Some(top) = self.args.top_option {
+            self.print_top_barcodes(&sample_barcodes, top);
+        }
+        // Warn if any counted barcodes could not be converted to their ID.  These are written as the DNA sequence instead
+        if !self.unconvertible_barcodes.is_empty() {
+            eprintln!(
+                "WARNING: {} counted barcode sequences were not found within the barcode conversion file and were written as DNA sequences",
+                self.unconvertible_barcodes
+                    .len()
+                    .to_formatted_string(&Locale::en)
+            );
+            info_println!();
+        }
+        self.stage_times.writing = writing_start.elapsed();
+        Ok(())
+    }
+
+    /// Writes the counted barcodes that could not be fixed, with the number of reads for each, sorted by barcode position then by the most reads
+    fn write_uncorrected_file(&mut self) -> Result<()> {
+        let mut uncorrected_barcodes = self
+            .results
+            .uncorrected_barcodes
+            .iter()
+            .collect::<Vec<(&(usize, String), &u32)>>();
+        uncorrected_barcodes.sort_by(
+            |((position_a, barcode_a), count_a), ((position_b, barcode_b), count_b)| {
+                position_a
+                    .cmp(position_b)
+                    .then(count_b.cmp(count_a))
+                    .then(barcode_a.cmp(barcode_b))
+            },
+        );
+        let delimiter = self.args.delimiter;
+        let mut uncorrected_text = format!(
+            "Barcode_Position{}Sequence{}Count\n",
+            delimiter, delimiter
+        );
+        for ((barcode_position, barcode), count) in &uncorrected_barcodes {
+            uncorrected_text.push_str(&format!(
+                "{}{}{}{}{}\n",
+                barcode_position + 1,
+                delimiter,
+                barcode,
+                delimiter,
+                count
+            ));
+        }
+        let file_name = format!("{}_uncorrected.{}", self.args.prefix, self.file_extension());
+        info_println!("{}", file_name);
+        info_println!(
+            "Uncorrected barcodes: {}",
+            uncorrected_barcodes.len().to_formatted_string(&Locale::en)
+        );
+        write_output_file(
+            &Path::new(&self.args.output_dir).join(&file_name),
+            &uncorrected_text,
+            self.args.output_gzip,
+        )?;
+        let count_total = count_column_total(&uncorrected_text, self.args.delimiter);
+        self.record_output_file(file_name, uncorrected_barcodes.len(), count_total);
         Ok(())
     }
 
+    /// Prints the top barcodes with the highest counts for each sample, along with their counts, to stdout.  Only the top barcodes are
+    /// sorted, using a partial sort of the sample's counts
+    fn print_top_barcodes(&mut self, sample_barcodes: &[String], top: usize) {
+        if top == 0 {
+            return;
+        }
+        println!("-TOP {} BARCODES-", top);
+        for sample_barcode in sample_barcodes {
+            let mut counts = match &self.results.results_hashmap {
+                ResultsHashmap::NoRandomBarcode(count_hashmap) => count_hashmap[sample_barcode]
+                    .iter()
+                    .map(|(code, count)| (code.to_string(), *count))
+                    .collect::<Vec<(String, usize)>>(),
+                ResultsHashmap::RandomBarcode(random_hashmap) => random_hashmap[sample_barcode]
+                    .iter()
+                    .map(|(code, random_barcodes)| (code.to_string(), random_barcodes.len()))
+                    .collect::<Vec<(String, usize)>>(),
+            };
+            let by_count = |(code_a, count_a): &(String, usize), (code_b, count_b): &(String, usize)| {
+                count_b.cmp(count_a).then(code_a.cmp(code_b))
+            };
+            if counts.len() > top {
+                counts.select_nth_unstable_by(top - 1, by_count);
+                counts.truncate(top);
+            }
+            counts.sort_unstable_by(by_count);
+
+            println!("{}", convert_sample_barcode(sample_barcode, &self.samples_barcode_hash));
+            for (code, count) in counts {
+                let written_barcodes = if self.converts_barcodes() {
+                    convert_code(
+                        &code,
+                        &self.counted_barcodes_hash,
+                        &mut self.unconvertible_barcodes,
+                        self.args.delimiter,
+                        self.args.unordered_barcodes,
+                    )
+                } else {
+                    code.replace(',', &self.args.delimiter.to_string())
+                };
+                println!("  {}\t{}", written_barcodes, count.to_formatted_string(&Locale::en));
+            }
+        }
+        println!();
+    }
+
+    /// Creates the stats file section with the estimate of unseen barcodes for each sample, sorted by sample name
+    fn richness_text(&self) -> String {
+        let mut sample_estimates = match &self.results.results_hashmap {
+            ResultsHashmap::NoRandomBarcode(count_hashmap) => count_hashmap
+                .iter()
+                .map(|(sample_barcode, counts)| {
+                    (sample_barcode, RichnessEstimate::from_counts(counts.values().copied()))
+                })
+                .collect::<Vec<(&String, RichnessEstimate)>>(),
+            ResultsHashmap::RandomBarcode(random_hashmap) => random_hashmap
+                .iter()
+                .map(|(sample_barcode, random_barcodes)| {
+                    (
+                        sample_barcode,
+                        RichnessEstimate::from_counts(
+                            random_barcodes.values().map(|random_barcodes| random_barcodes.len()),
+                        ),
+                    )
+                })
+                .collect::<Vec<(&String, RichnessEstimate)>>(),
+        }
+        .into_iter()
+        .map(|(sample_barcode, estimate)| {
+            (convert_sample_barcode(sample_barcode, &self.samples_barcode_hash), estimate)
+        })
+        .collect::<Vec<(String, RichnessEstimate)>>();
+        sample_estimates.sort_by(|(sample_a, _), (sample_b, _)| sample_a.cmp(sample_b));
+
+        let mut richness_text = "-RAREFACTION-\n".to_string();
+        for (sample_name, estimate) in sample_estimates {
+            richness_text.push_str(&format!("{}\n{}\n\n", sample_name, estimate));
+        }
+        richness_text
+    }
+
+    /// Returns the number of distinct counted barcodes within each sample, sorted by the sample name, for the stats file
+    fn distinct_barcodes_text(&self) -> String {
+        let mut sample_distinct_barcodes = self
+            .results
+            .distinct_barcodes()
+            .into_iter()
+            .map(|(sample_barcode, distinct_barcodes)| {
+                (convert_sample_barcode(&sample_barcode, &self.samples_barcode_hash), distinct_barcodes)
+            })
+            .collect::<Vec<(String, usize)>>();
+        sample_distinct_barcodes.sort();
+
+        let mut distinct_text = "-DISTINCT BARCODES-\n".to_string();
+        for (sample_name, distinct_barcodes) in sample_distinct_barcodes {
+            distinct_text.push_str(&format!(
+                "Sample & distinct barcodes: {}\t{}\n",
+                sample_name,
+                distinct_barcodes.to_formatted_string(&Locale::en)
+            ));
+        }
+        distinct_text.push('\n');
+        distinct_text
+    }
+
+    /// Writes the log ratio of each barcode's count within every other sample against its count within the reference sample, with the
+    /// log base from the arguments.  Each count has the pseudocount added, so that barcodes missing from a sample still have a ratio, then
+    /// is divided by the sample's total count so that the ratios are not skewed by sequencing depth
+    fn write_enrichment_ratios(&mut self, sample_barcodes: &[String], reference_sample: &str) -> Result<()> {
+        let pseudocount = self.args.pseudocount;
+        let log_base = self.args.log_base;
+        // The sample name and the counts of each sample
+        let sample_counts = sample_barcodes
+            .iter()
+            .map(|sample_barcode| {
+                let counts = match &self.results.results_hashmap {
+                    ResultsHashmap::NoRandomBarcode(count_hashmap) => count_hashmap[sample_barcode]
+                        .iter()
+                        .map(|(code, count)| (code.as_str(), *count))
+                        .collect::<HashMap<&str, usize>>(),
+                    ResultsHashmap::RandomBarcode(random_hashmap) => random_hashmap[sample_barcode]
+                        .iter()
+                        .map(|(code, random_barcodes)| (code.as_str(), random_barcodes.len()))
+                        .collect::<HashMap<&str, usize>>(),
+                };
+                (
+                    convert_sample_barcode(sample_barcode, &self.samples_barcode_hash),
+                    counts,
+                )
+            })
+            .collect::<Vec<(String, HashMap<&str, usize>)>>();
+        let reference_index = sample_counts
+            .iter()
+            .position(|(sample_name, _)| sample_name == reference_sample)
+            .ok_or_else(|| anyhow!("Reference sample {} was not found within the counted samples", reference_sample))?;
+        // Total count of each sample, which is at least 1 so that an empty sample does not divide by 0
+        let totals = sample_counts
+            .iter()
+            .map(|(_, counts)| counts.values().sum::<usize>().max(1) as f64)
+            .collect::<Vec<f64>>();
+
+        // Every barcode counted within any sample, along with its written form
+        let codes = sample_counts
+            .iter()
+            .flat_map(|(_, counts)| counts.keys().copied())
+            .collect::<AHashSet<&str>>();
+        let mut rows = Vec::with_capacity(codes.len());
+        for code in codes {
+            let written_barcodes = if !self.converts_barcodes() {
+                code.replace(',', &self.args.delimiter.to_string())
+            } else {
+                convert_code(
+                    code,
+                    &self.counted_barcodes_hash,
+                    &mut self.unconvertible_barcodes,
+                    self.args.delimiter,
+                    self.args.unordered_barcodes,
+                )
+            };
+            rows.push((code.to_string(), written_barcodes, 0));
+        }
+        sort_rows(&mut rows, SortBy::Barcode, self.args.delimiter);
+
+        let delimiter = self.args.delimiter;
+        // The ratio column names keep the usual names of the common log bases
+        let log_name = if log_base == 2.0 {
+            "log2".to_string()
+        } else if log_base == 10.0 {
+            "log10".to_string()
+        } else if log_base == std::f64::consts::E {
+            "ln".to_string()
+        } else {
+            format!("log{}", log_base)
+        };
+        let mut ratio_text = format!("{}{}{}_Count", self.create_header(), delimiter, reference_sample);
+        for (sample_index, (sample_name, _)) in sample_counts.iter().enumerate() {
+            if sample_index != reference_index {
+                ratio_text.push_str(&format!("{}{}_{}_ratio", delimiter, sample_name, log_name));
+            }
+        }
+        ratio_text.push('\n');
+        let normalized_count = |sample_index: usize, code: &str| {
+            (*sample_counts[sample_index].1.get(code).unwrap_or(&0) as f64 + pseudocount)
+                / totals[sample_index]
+        };
+        for (code, written_barcodes, _) in &rows {
+            ratio_text.push_str(&format!(
+                "{}{}{}",
+                written_barcodes,
+                delimiter,
+                sample_counts[reference_index].1.get(code.as_str()).unwrap_or(&0)
+            ));
+            let reference_normalized = normalized_count(reference_index, code);
+            for sample_index in 0..sample_counts.len() {
+                if sample_index != reference_index {
+                    let ratio = normalized_count(sample_index, code) / reference_normalized;
+                    ratio_text.push_str(&format!("{}{:.4}", delimiter, ratio.log(log_base)));
+                }
+            }
+            ratio_text.push('\n');
+        }
+
+        let file_name = format!("{}_enrichment_ratios.{}", self.args.prefix, self.file_extension());
+        info_println!("{}", file_name);
+        write_output_file(
+            &Path::new(&self.args.output_dir).join(&file_name),
+            &ratio_text,
+            self.args.output_gzip,
+        )?;
+        self.record_output_file(file_name, rows.len(), None);
+        Ok(())
+    }
+
+    /// Writes a file for each counted barcode position, or cycle, with the total count of each barcode at that position for the sample.
+    /// Unlike the single barcode enrichment, the other positions are ignored instead of being blank columns.  The counts are cleared for
+    /// the next sample
+    fn write_cycle_files(&mut self, file_start: &str) -> Result<()> {
+        let delimiter = self.args.delimiter;
+        for (cycle_index, cycle_counts) in std::mem::take(&mut self.cycle_counts).into_iter().enumerate() {
+            let mut barcode_counts = cycle_counts.into_iter().collect::<Vec<(String, usize)>>();
+            barcode_counts.sort_by(|(barcode_a, count_a), (barcode_b, count_b)| {
+                count_b.cmp(count_a).then(barcode_a.cmp(barcode_b))
+            });
+            let mut cycle_text = format!("Barcode_{}{}Count\n", cycle_index + 1, delimiter);
+            for (barcode, count) in &barcode_counts {
+                cycle_text.push_str(&format!("{}{}{}\n", barcode, delimiter, count));
+            }
+            let file_name = format!(
+                "{}_cycle{}_counts.{}",
+                file_start,
+                cycle_index + 1,
+                self.file_extension()
+            );
+            info_println!("{}", file_name);
+            write_output_file(
+                &Path::new(&self.args.output_dir).join(&file_name),
+                &cycle_text,
+                self.args.output_gzip,
+            )?;
+            let count_total = count_column_total(&cycle_text, self.args.delimiter);
+            self.record_output_file(file_name, barcode_counts.len(), count_total);
+        }
+        Ok(())
+    }
+
+    /// Writes a matrix of the sample's counts with the first counted barcode of the pair as rows and the second as columns, adding together
+    /// the counts over every other counted barcode.  With a counted barcodes file, every listed barcode at both positions is included so
+    /// that the matrices of each sample have the same rows and columns
+    fn write_pairwise_matrix(
+        &mut self,
+        sample_barcode: &str,
+        file_start: &str,
+        (row_barcode, column_barcode): (usize, usize),
+    ) -> Result<()> {
+        let codes_counts = match &self.results.results_hashmap {
+            ResultsHashmap::NoRandomBarcode(count_hashmap) => count_hashmap[sample_barcode]
+                .iter()
+                .map(|(code, count)| (code.as_str(), *count))
+                .collect::<Vec<(&str, usize)>>(),
+            ResultsHashmap::RandomBarcode(random_hashmap) => random_hashmap[sample_barcode]
+                .iter()
+                .map(|(code, random_barcodes)| (code.as_str(), random_barcodes.len()))
+                .collect::<Vec<(&str, usize)>>(),
+        };
+        let converts_barcodes = self.converts_barcodes();
+        let written_barcode = |code: &str, barcode_number: usize| {
+            let barcode = code.split(',').nth(barcode_number - 1).unwrap_or_default();
+            if converts_barcodes {
+                self.counted_barcodes_hash[barcode_number - 1]
+                    .get(barcode)
+                    .cloned()
+                    .unwrap_or_else(|| barcode.to_string())
+            } else {
+                barcode.to_string()
+            }
+        };
+        // (row barcode, column barcode): count
+        let mut matrix_counts = HashMap::new();
+        for (code, count) in codes_counts {
+            *matrix_counts
+                .entry((written_barcode(code, row_barcode), written_barcode(code, column_barcode)))
+                .or_insert(0) += count;
+        }
+        let mut row_names = matrix_counts.keys().map(|(row_name, _)| row_name.clone()).collect::<AHashSet<String>>();
+        let mut column_names = matrix_counts
+            .keys()
+            .map(|(_, column_name)| column_name.clone())
+            .collect::<AHashSet<String>>();
+        if converts_barcodes {
+            row_names.extend(self.counted_barcodes_hash[row_barcode - 1].values().cloned());
+            column_names.extend(self.counted_barcodes_hash[column_barcode - 1].values().cloned());
+        }
+        let row_names = row_names.into_iter().sorted().collect::<Vec<String>>();
+        let column_names = column_names.into_iter().sorted().collect::<Vec<String>>();
+
+        let delimiter = self.args.delimiter;
+        let mut matrix_text = format!("Barcode_{}/Barcode_{}", row_barcode, column_barcode);
+        for column_name in &column_names {
+            matrix_text.push(delimiter);
+            matrix_text.push_str(column_name);
+        }
+        matrix_text.push('\n');
+        for row_name in &row_names {
+            matrix_text.push_str(row_name);
+            for column_name in &column_names {
+                let count = matrix_counts
+                    .get(&(row_name.clone(), column_name.clone()))
+                    .unwrap_or(&0);
+                matrix_text.push_str(&format!("{}{}", delimiter, count));
+            }
+            matrix_text.push('\n');
+        }
+        let file_name = format!(
+            "{}_BC{}_vs_BC{}.{}",
+            file_start,
+            row_barcode,
+            column_barcode,
+            self.file_extension()
+        );
+        info_println!("{}", file_name);
+        write_output_file(
+            &Path::new(&self.args.output_dir).join(&file_name),
+            &matrix_text,
+            self.args.output_gzip,
+        )?;
+        self.record_output_file(file_name, row_names.len(), None);
+        Ok(())
+    }
+
+    /// Writes the sample barcodes that could not be fixed, with the number of reads for each, sorted by the most reads
+    fn write_unknown_samples_file(&mut self) -> Result<()> {
+        let mut unknown_samples = self
+            .results
+            .unknown_samples
+            .iter()
+            .collect::<Vec<(&String, &u32)>>();
+        unknown_samples.sort_by(|(barcode_a, count_a), (barcode_b, count_b)| {
+            count_b.cmp(count_a).then(barcode_a.cmp(barcode_b))
+        });
+        let delimiter = self.args.delimiter;
+        let mut unknown_text = format!("Sample_Barcode{}Count\n", delimiter);
+        for (sample_barcode, count) in &unknown_samples {
+            unknown_text.push_str(&format!("{}{}{}\n", sample_barcode, delimiter, count));
+        }
+        let file_name = format!("{}_unknown_samples.{}", self.args.prefix, self.file_extension());
+        info_println!("{}", file_name);
+        info_println!(
+            "Unknown sample barcodes: {}",
+            unknown_samples.len().to_formatted_string(&Locale::en)
+        );
+        write_output_file(
+            &Path::new(&self.args.output_dir).join(&file_name),
+            &unknown_text,
+            self.args.output_gzip,
+        )?;
+        let count_total = count_column_total(&unknown_text, self.args.delimiter);
+        self.record_output_file(file_name, unknown_samples.len(), count_total);
+        Ok(())
+    }
+
+    /// Records the written file for the stats file, along with its rows and Count column total for --verify
+    fn record_output_file(&mut self, file_name: String, rows: usize, count_total: Option<u64>) {
+        self.output_files.push(file_name);
+        self.output_counts.push(rows);
+        self.output_totals.push(count_total);
+    }
+
+    /// Reads back each written file and checks that its rows, and the sum of its Count column, are the same as what was written.  Returns
+    /// an error for any difference, which catches silent write corruption
+    pub fn verify_output_files(&self) -> Result<()> {
+        let directory = Path::new(&self.args.output_dir);
+        for ((file_name, rows), count_total) in self
+            .output_files
+            .iter()
+            .zip(&self.output_counts)
+            .zip(&self.output_totals)
+        {
+            let output_path = directory.join(file_name);
+            let output_file = File::open(&output_path)
+                .context(format!("Unable to open {} to verify", output_path.display()))?;
+            let mut file_text = String::new();
+            if file_name.ends_with(".gz") {
+                MultiGzDecoder::new(output_file).read_to_string(&mut file_text)
+            } else {
+                BufReader::new(output_file).read_to_string(&mut file_text)
+            }
+            .context(format!("Unable to read {} to verify", output_path.display()))?;
+
+            let file_rows = file_text.lines().count().saturating_sub(1);
+            if file_rows != *rows {
+                bail!(
+                    "Verification failed for {}.  Found {} rows but {} were written",
+                    file_name,
+                    file_rows,
+                    rows
+                )
+            }
+            let file_total = count_column_total(&file_text, self.args.delimiter);
+            if file_total != *count_total {
+                bail!(
+                    "Verification failed for {}.  Found a Count total of {} but {} was written",
+                    file_name,
+                    file_total.unwrap_or_default(),
+                    count_total.unwrap_or_default()
+                )
+            }
+        }
+        info_println!("Verified {} output files", self.output_files.len());
+        Ok(())
+    }
+
+    /// Returns the counts file extension, which is 'tsv' for tab delimited files and 'csv' otherwise.  '.gz' is added when the
+    /// output is gzipped
+    fn file_extension(&self) -> String {
+        output_extension(&self.args)
+    }
+
+    /// Whether or not the counted DNA barcodes are written as their IDs from the conversion file
+    fn converts_barcodes(&self) -> bool {
+        !self.counted_barcodes_hash.is_empty() && !self.args.output_dna
+    }
+
     /// Creates the file header string for column headers
     fn create_header(&self) -> String {
-        // Create a comma separated header.  First columns are the barcodes, 'Barcode_#'.  The last header is 'Count'
+        // Create a delimiter separated header.  First columns are the barcodes, 'Barcode_#'.  The last header is 'Count'
         let mut header = String::new();
         if self.sequence_format.barcode_num > 1 {
             header = "Barcode_1".to_string();
             for num in 1..self.sequence_format.barcode_num {
-                header.push_str(&format!(",Barcode_{}", num + 1))
+                header.push_str(&format!("{}Barcode_{}", self.args.delimiter, num + 1))
             }
         } else {
             header.push_str("Barcode")
         }
+        // The reported constant regions are columns after the counted barcodes
+        for variant_index in 1..=self.sequence_format.variant_num {
+            header.push_str(&format!("{}Barcode_Variant_{}", self.args.delimiter, variant_index));
+        }
         header
     }
 
@@ -239,52 +1000,76 @@ impl WriteFiles {
             },
         };
 
-        let mut barcode_num = 0;
-        for (line_num, code) in codes.iter().enumerate() {
+        // Find the count and written barcodes of each code, then sort the rows so that the output is the same between runs
+        let mut rows = Vec::with_capacity(codes.len());
+        for code in codes {
             let count = match enrichment {
                 EnrichedType::Single => *self
                     .results_enriched
                     .single_hashmap
                     .get(sample_barcode)
                     .unwrap()
-                    .get(code)
+                    .get(&code)
                     .unwrap(),
                 EnrichedType::Double => *self
                     .results_enriched
                     .double_hashmap
                     .get(sample_barcode)
                     .unwrap()
-                    .get(code)
+                    .get(&code)
                     .unwrap(),
                 EnrichedType::Full => match &self.results.results_hashmap {
                     ResultsHashmap::NoRandomBarcode(count_hashmap) => *count_hashmap
                         .get(sample_barcode)
                         .unwrap()
-                        .get(code)
+                        .get(&code)
                         .unwrap(),
                     ResultsHashmap::RandomBarcode(random_hashmap) => random_hashmap
                         .get(sample_barcode)
                         .unwrap()
-                        .get(code)
+                        .get(&code)
                         .unwrap()
                         .len(),
                 },
             };
-            barcode_num = line_num + 1;
-            // Print the number counted so far ever 50,000 writes
-            if barcode_num % 50000 == 0 {
-                print!(
-                    "Barcodes counted: {}\r",
-                    barcode_num.to_formatted_string(&Locale::en)
-                );
-                stdout().flush()?;
-            }
-            let written_barcodes = if enrichment == EnrichedType::Full && !self.counted_barcodes_hash.is_empty() {
-                // Convert the building block DNA barcodes and join them back to comma separated
-                convert_code(code, &self.counted_barcodes_hash)
+            let written_barcodes = if enrichment == EnrichedType::Full && self.converts_barcodes() {
+                // Convert the building block DNA barcodes and join them back to delimiter separated
+                convert_code(
+                    &code,
+                    &self.counted_barcodes_hash,
+                    &mut self.unconvertible_barcodes,
+                    self.args.delimiter,
+                    self.args.unordered_barcodes,
+                )
+            } else if enrichment == EnrichedType::Full {
+                // The counted DNA barcodes are comma separated within the results
+                code.replace(',', &self.args.delimiter.to_string())
             } else {
+                // The single and double barcodes are already delimiter separated
                 code.to_string()
             };
+            rows.push((code, written_barcodes, count));
+        }
+        sort_rows(&mut rows, self.args.sort_by, self.args.delimiter);
+
+        let mut barcode_num = 0;
+        for (code, written_barcodes, count) in rows.iter() {
+            let count = *count;
+            // Print the number counted so far every progress interval writes
+            self.write_progress.increment()?;
+            // Combinations outside the valid combinations are only written to the unexpected counts file
+            if enrichment == EnrichedType::Full && !self.expected_combination(code, written_barcodes) {
+                let count_text = self
+                    .weighted_count_text(sample_barcode, code, &enrichment)
+                    .or_else(|| self.reads_count_text(sample_barcode, code, &enrichment, count))
+                    .unwrap_or_else(|| count.to_string());
+                self.unexpected_text
+                    .push_str(&format!("{}{}{}\n", written_barcodes, self.args.delimiter, count_text));
+                self.unexpected_total.0 += 1;
+                self.unexpected_total.1 += count;
+                continue;
+            }
+            barcode_num += 1;
 
             // If merge output argument is called, pull data for the compound and write to merged file
             if self.args.merge_output {
@@ -296,7 +1081,11 @@ impl WriteFiles {
                     let mut merged_row = written_barcodes.clone();
                     // For every sample, retrieve the count and add to the row with a comma
                     for sample_barcode in sample_barcodes {
-                        merged_row.push(',');
+                        merged_row.push(self.args.delimiter);
+                        if let Some(count_text) = self.weighted_count_text(sample_barcode, code, &enrichment) {
+                            merged_row.push_str(&count_text);
+                            continue;
+                        }
                         // Get teh sample count from the hashmap that corresponds to the EnrichedType.  For single and double, it is the holding hashmap created earlier
                         let sample_count = match enrichment {
                             EnrichedType::Single => hash_holder
@@ -318,8 +1107,7 @@ impl WriteFiles {
                                     .get(sample_barcode)
                                     .unwrap()
                                     .get(code)
-                                    .unwrap_or(&AHashSet::new())
-                                    .len()
+                                    .map_or(0, |random_barcodes| random_barcodes.len())
                                     .to_string(),
                                 ResultsHashmap::NoRandomBarcode(count_hashmap) => count_hashmap
                                     .get(sample_barcode)
@@ -337,29 +1125,82 @@ impl WriteFiles {
                 }
             }
             // Create the row for the sample file and write
-            let row = format!("{},{}\n", written_barcodes, count);
+            let count_text = self
+                .weighted_count_text(sample_barcode, code, &enrichment)
+                .or_else(|| self.reads_count_text(sample_barcode, code, &enrichment, count))
+                .unwrap_or_else(|| count.to_string());
+            let row = format!("{}{}{}\n", written_barcodes, self.args.delimiter, count_text);
             self.sample_text.push_str(&row);
+            // Add the count to each barcode's position, ignoring the other positions
+            if enrichment == EnrichedType::Full && self.args.per_cycle_counts {
+                for (cycle, barcode) in written_barcodes.split(self.args.delimiter).enumerate() {
+                    if self.cycle_counts.len() <= cycle {
+                        self.cycle_counts.push(HashMap::new());
+                    }
+                    *self.cycle_counts[cycle].entry(barcode.to_string()).or_insert(0) += count;
+                }
+            }
             // If enrichment type is Full, which is neither single nor double for adding string,
             // and enrich is called.  Add 1 and 2 synthon enrichment.  This is becuase this smae
             // method is called to create the 1 and 2 synthon strings, and therefore should only
             // run when Full is used
             if enrichment == EnrichedType::Full && self.args.enrich {
-                self.results_enriched
-                    .add_single(sample_barcode, &written_barcodes, count);
+                self.results_enriched.add_single(
+                    sample_barcode,
+                    written_barcodes,
+                    count,
+                    self.args.delimiter,
+                    self.args.unordered_barcodes,
+                );
                 if self.sequence_format.barcode_num > 2 {
-                    self.results_enriched
-                        .add_double(sample_barcode, &written_barcodes, count);
+                    self.results_enriched.add_double(
+                        sample_barcode,
+                        written_barcodes,
+                        count,
+                        self.args.delimiter,
+                        self.args.unordered_barcodes,
+                    );
                 }
             }
         }
-        print!(
-            "Barcodes counted: {}\r",
-            barcode_num.to_formatted_string(&Locale::en)
-        );
-        println!();
+        self.write_progress.finish();
         Ok(barcode_num)
     }
 
+    /// Returns the reads of the code within the sample followed by its unique random barcodes, when both are reported.  Only the full
+    /// barcode counts keep the reads, so the single and double barcode counts return None
+    fn reads_count_text(&self, sample_barcode: &str, code: &str, enrichment: &EnrichedType, count: usize) -> Option<String> {
+        if *enrichment != EnrichedType::Full {
+            return None;
+        }
+        let reads = self
+            .results
+            .read_counts
+            .as_ref()?
+            .get(sample_barcode)
+            .and_then(|read_counts| read_counts.get(code))
+            .copied()
+            .unwrap_or_default();
+        Some(format!("{}{}{}", reads, self.args.delimiter, count))
+    }
+
+    /// Returns the summed read weights of the code within the sample, written with 4 decimals, when the counts are quality weighted.  Only
+    /// the full barcode counts are weighted, so the single and double barcode counts return None
+    fn weighted_count_text(&self, sample_barcode: &str, code: &str, enrichment: &EnrichedType) -> Option<String> {
+        if *enrichment != EnrichedType::Full {
+            return None;
+        }
+        let weight = self
+            .results
+            .weighted_counts
+            .as_ref()?
+            .get(sample_barcode)
+            .and_then(|weighted_counts| weighted_counts.get(code))
+            .copied()
+            .unwrap_or_default();
+        Some(format!("{:.4}", weight))
+    }
+
     /// Write enriched files for either single or double barcodes if either flag is called
     fn write_enriched_files(&mut self, enrichment: EnrichedType) -> Result<()> {
         let unknown_sample = "barcode".to_string();
@@ -422,7 +1263,7 @@ impl WriteFiles {
                         .get(sample_barcode)
                         .unwrap_or(&unknown_sample)
                 };
-                merged_header.push(',');
+                merged_header.push(self.args.delimiter);
                 merged_header.push_str(sample_name);
             }
             merged_header.push('\n');
@@ -430,8 +1271,10 @@ impl WriteFiles {
         }
 
         // Crate the header to be used with each sample file.  This is just Barcode_1..Barcode_n and Count
-        header.push_str(",Count\n");
+        header.push_str(&format!("{}Count\n", self.args.delimiter));
 
+        // With --merge-only, only the merged enriched file is written
+        let write_sample_files = !(self.args.merge_only && self.args.merge_output);
         // For each sample, write the enriched file
         for sample_barcode in &sample_barcodes {
             // Create the file_name with the single or double descriptor
@@ -444,41 +1287,48 @@ impl WriteFiles {
                 sample_barcode
             };
             let file_name = format!(
-                "{}_{}_counts.{}.csv",
-                self.args.prefix, sample_name, descriptor
+                "{}_{}_counts.{}.{}",
+                self.args.prefix,
+                sample_name,
+                descriptor,
+                self.file_extension()
             );
-            println!("{}", file_name);
-            self.output_files.push(file_name.clone());
+            if write_sample_files {
+                info_println!("{}", file_name);
+            }
             // join the filename with the directory to create the full path
-            let output_path = directory.join(file_name);
+            let output_path = directory.join(&file_name);
 
             self.sample_text.push_str(&header);
             let count =
                 self.add_counts_string(sample_barcode, &sample_barcodes, enrichment.clone())?;
-            let mut output = File::create(output_path)?; // Create the output file
-            output.write_all(self.sample_text.as_bytes())?;
+            if write_sample_files {
+                write_output_file(&output_path, &self.sample_text, self.args.output_gzip)?;
+                // add the counts to output to stats later
+                let count_total = count_column_total(&self.sample_text, self.args.delimiter);
+                self.record_output_file(file_name, count, count_total);
+            }
             self.sample_text.clear();
-            // add the counts to output to stats later
-            self.output_counts.push(count);
         }
         // Add the count of merged barcodes if the flag is called
         if self.args.merge_output {
             // Create the merge file and push the header, if merged called within arguments
-            let merged_file_name = format!("{}_counts.all.{}.csv", self.args.prefix, descriptor);
-            println!("{}", merged_file_name);
-            self.output_files.push(merged_file_name.clone());
-            let merged_output_path = directory.join(merged_file_name);
-            let mut merged_output_file = File::create(merged_output_path)?;
-            merged_output_file.write_all(self.merge_text.as_bytes())?;
-            println!(
+            let merged_file_name = format!(
+                "{}_counts.all.{}.{}",
+                self.args.prefix,
+                descriptor,
+                self.file_extension()
+            );
+            info_println!("{}", merged_file_name);
+            let merged_output_path = directory.join(&merged_file_name);
+            write_output_file(&merged_output_path, &self.merge_text, self.args.output_gzip)?;
+            info_println!(
                 "Barcodes counted: {}",
                 self.merged_count.to_formatted_string(&Locale::en)
             );
             self.merge_text.clear();
-            self.output_counts.insert(
-                self.output_counts.len() - sample_barcodes.len(),
-                self.merged_count,
-            );
+            // The merged file has a count column for each sample instead of a single Count column, so only its rows are checked
+            self.record_output_file(merged_file_name, self.merged_count, None);
             self.merged_count = 0;
         }
         Ok(())
@@ -492,6 +1342,7 @@ impl WriteFiles {
         seq_errors: SequenceErrors,
         total_reads: Arc<AtomicU32>,
         sequence_format: SequenceFormat,
+        insert_stats: Arc<Mutex<InsertStats>>,
     ) -> Result<()> {
         // Create the stat file name
         let output_dir = self.args.output_dir.clone();
@@ -499,7 +1350,6 @@ impl WriteFiles {
         let stat_filename = directory.join(format!("{}_barcode_stats.txt", self.args.prefix));
         // Make the stat file and make it an appending function
         let mut stat_file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(stat_filename)?;
@@ -520,6 +1370,8 @@ impl WriteFiles {
             )
             .as_bytes(),
         )?;
+        // Write the wall-clock time of each stage
+        stat_file.write_all(format!("{}\n", self.stage_times).as_bytes())?;
         // Write the input file information
         stat_file.write_all(
             format!(
@@ -541,17 +1393,36 @@ impl WriteFiles {
         stat_file.write_all(format!("{}\n\n", sequence_format).as_bytes())?;
         // Record the barcode information
         stat_file.write_all(format!("{}\n", max_sequence_errors).as_bytes())?;
-        // Record the total reads and errors
+        // Record the total reads and errors.  These are tallied before a read is matched to a sequence format, so with more than one format
+        // they are of the whole run and are the same within the stats file of every format
+        let run_wide_text = if self.args.format.split(',').count() > 1 {
+            "Total sequences and sequence errors are of the whole run, across every sequence format\n"
+        } else {
+            ""
+        };
         stat_file.write_all(
             format!(
-                "-RESULTS-\nTotal sequences:             {}\n{}\n\n",
+                "-RESULTS-\n{}Total sequences:             {}\n{}\nUnconvertible barcodes:      {}\n{}\n",
+                run_wide_text,
                 total_reads
                     .load(Ordering::Relaxed)
                     .to_formatted_string(&Locale::en),
-                seq_errors
+                seq_errors,
+                self.unconvertible_barcodes
+                    .len()
+                    .to_formatted_string(&Locale::en),
+                self.excluded_text
             )
             .as_bytes(),
         )?;
+        // Record the read length and match start distributions of the matched reads
+        stat_file.write_all(format!("{}\n", insert_stats.lock().unwrap()).as_bytes())?;
+        // Record the estimates of the barcodes that were not sequenced within each sample
+        if self.args.rarefaction {
+            stat_file.write_all(self.richness_text().as_bytes())?;
+        }
+        // Record the number of distinct counted barcodes within each sample, which is how complex the sample is
+        stat_file.write_all(self.distinct_barcodes_text().as_bytes())?;
         // Record the files that were created
         stat_file.write_all("-OUTPUT FILES-\n".as_bytes())?;
         for (file_name, counts) in self.output_files.iter().zip(self.output_counts.iter()) {
@@ -576,6 +1447,399 @@ impl WriteFiles {
     }
 }
 
+/// Returns the output file extension, which is 'tsv' for tab delimited files and 'csv' otherwise.  '.gz' is added when the output is
+/// gzipped
+fn output_extension(args: &Args) -> String {
+    let extension = if args.delimiter == '\t' {
+        "tsv"
+    } else {
+        "csv"
+    };
+    if args.output_gzip {
+        format!("{}.gz", extension)
+    } else {
+        extension.to_string()
+    }
+}
+
+/// Writes the base composition at each random barcode position, over the random barcodes of every matched read, to
+/// <prefix>_umi_composition.csv.  Uneven fractions at a position show biased synthesis of the random barcodes
+pub fn write_umi_composition_file(args: &Args, insert_stats: &InsertStats) -> Result<()> {
+    let file_name = format!("{}_umi_composition.{}", args.prefix, output_extension(args));
+    info_println!("{}\n", file_name);
+    write_output_file(
+        &Path::new(&args.output_dir).join(file_name),
+        &insert_stats.random_composition_text(args.delimiter),
+        args.output_gzip,
+    )
+}
+
+/// Sums the counts files from separate runs into a single counts file.  Rows are aligned by the leading 'Barcode' columns, which need
+/// to be the same within each file.  The remaining columns are counts, either 'Count' or the sample columns of a merged file.  Barcodes
+/// or count columns missing from a file are counted as 0 for that file
+pub fn merge_counts_files(merge_args: &MergeCountsArgs) -> Result<()> {
+    let mut barcode_header: Option<Vec<String>> = None;
+    let mut count_columns: Vec<String> = Vec::new(); // count column names in the order they were first found
+    let mut barcode_rows: Vec<String> = Vec::new(); // barcode column values in the order they were first found
+    let mut merged_counts: HashMap<String, HashMap<String, usize>> = HashMap::new(); // barcode values: count column: count
+
+    for counts_file in &merge_args.counts_files {
+        let counts_data = fs::read_to_string(counts_file)
+            .context(format!("Failed to open counts file {}", counts_file))?;
+        let mut lines = counts_data.lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| anyhow!("Counts file {} is empty", counts_file))?
+            .split(merge_args.delimiter)
+            .map(|column| column.to_string())
+            .collect::<Vec<String>>();
+        // The barcode columns come first and all start with 'Barcode'
+        let barcode_columns_num = header
+            .iter()
+            .take_while(|column| column.starts_with("Barcode"))
+            .count();
+        if barcode_columns_num == 0 || barcode_columns_num == header.len() {
+            bail!(
+                "Counts file {} needs to have 'Barcode' columns followed by count columns",
+                counts_file
+            )
+        }
+        let file_barcode_header = header[..barcode_columns_num].to_vec();
+        if let Some(ref first_barcode_header) = barcode_header {
+            if first_barcode_header != &file_barcode_header {
+                bail!(
+                    "Counts file {} has barcode columns {} that do not match the previous files' barcode columns {}",
+                    counts_file,
+                    file_barcode_header.join(","),
+                    first_barcode_header.join(",")
+                )
+            }
+        } else {
+            barcode_header = Some(file_barcode_header);
+        }
+        let file_count_columns = header[barcode_columns_num..].to_vec();
+        for count_column in &file_count_columns {
+            if !count_columns.contains(count_column) {
+                count_columns.push(count_column.to_string());
+            }
+        }
+
+        for line in lines.filter(|line| !line.is_empty()) {
+            let values = line.split(merge_args.delimiter).collect::<Vec<&str>>();
+            if values.len() != header.len() {
+                bail!(
+                    "Counts file {} has a row with {} columns instead of {}: {}",
+                    counts_file,
+                    values.len(),
+                    header.len(),
+                    line
+                )
+            }
+            let barcodes = values[..barcode_columns_num].join(&merge_args.delimiter.to_string());
+            if !merged_counts.contains_key(&barcodes) {
+                barcode_rows.push(barcodes.clone());
+            }
+            let barcode_counts = merged_counts.entry(barcodes).or_default();
+            for (count_column, count) in file_count_columns
+                .iter()
+                .zip(values[barcode_columns_num..].iter())
+            {
+                let count = count.parse::<usize>().context(format!(
+                    "Count within {} is not an integer: {}",
+                    counts_file, count
+                ))?;
+                *barcode_counts.entry(count_column.to_string()).or_insert(0) += count;
+            }
+        }
+    }
+
+    // Write the header followed by each barcode row's summed counts, with 0 for any count column the barcodes were not found in
+    let delimiter = merge_args.delimiter.to_string();
+    let mut merged_text = format!(
+        "{}{}{}\n",
+        barcode_header.unwrap_or_default().join(&delimiter),
+        delimiter,
+        count_columns.join(&delimiter)
+    );
+    for barcodes in &barcode_rows {
+        let barcode_counts = &merged_counts[barcodes];
+        let counts = count_columns
+            .iter()
+            .map(|count_column| barcode_counts.get(count_column).unwrap_or(&0).to_string())
+            .join(&delimiter);
+        merged_text.push_str(&format!("{}{}{}\n", barcodes, delimiter, counts));
+    }
+    write_output_file(Path::new(&merge_args.output), &merged_text, false)?;
+    info_println!(
+        "Merged {} counts files with {} barcode rows into {}",
+        merge_args.counts_files.len(),
+        barcode_rows.len().to_formatted_string(&Locale::en),
+        merge_args.output
+    );
+    Ok(())
+}
+
+/// Creates the output file and writes the text, compressing it with gzip when gzip is true.  All counts and diagnostic files are
+/// written through here so that they are compressed the same way.  The text is written to a temporary file within the same directory,
+/// which is renamed to the output file only after the full write, so that a crash never leaves a partial file that looks complete
+fn write_output_file(output_path: &Path, text: &str, gzip: bool) -> Result<()> {
+    let mut temp_path = output_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = Path::new(&temp_path);
+    let mut output_file = File::create(temp_path)
+        .context(format!("Unable to create output file: {}", temp_path.display()))?;
+    if gzip {
+        let mut encoder = GzEncoder::new(output_file, Compression::default());
+        encoder.write_all(text.as_bytes())?;
+        output_file = encoder.finish()?;
+    } else {
+        output_file.write_all(text.as_bytes())?;
+    }
+    output_file.sync_all()?;
+    fs::rename(temp_path, output_path)
+        .context(format!("Unable to move {} to {}", temp_path.display(), output_path.display()))?;
+    Ok(())
+}
+
+/// Creates a sample written callback that runs the command with the sample name and the counts file path as its two arguments.  The
+/// command finishes before the next sample is written, so long running processing should be started in the background by the command
+pub fn sample_written_command(command: String) -> impl FnMut(&str, &Path) -> Result<()> {
+    move |sample_name, file_path| {
+        let status = std::process::Command::new(&command)
+            .arg(sample_name)
+            .arg(file_path)
+            .status()
+            .context(format!("Unable to run the sample written command: {}", command))?;
+        if !status.success() {
+            bail!(
+                "The sample written command {} failed for {} with {}",
+                command,
+                file_path.display(),
+                status
+            )
+        }
+        Ok(())
+    }
+}
+
+/// Writes a tab separated line for each read received from the parsing threads, until every thread has finished and dropped its sender.
+/// Each line has the read ID, sample, comma separated counted barcode IDs, random barcode and whether the read was counted or why not.
+/// Barcodes without a conversion are written as their DNA sequence.  The file is compressed with gzip when its path ends with .gz, and is
+/// written to a temporary file that is renamed once every read is written.  With a line for every read, the file can be larger than the fastq
+pub fn write_per_read_file(
+    output_path: &Path,
+    receiver: Receiver<ReadAssignment>,
+    samples_barcode_hash: HashMap<String, String>,
+    counted_barcodes_hashes: Vec<Vec<HashMap<String, String>>>,
+    unordered: bool,
+) -> Result<()> {
+    let mut temp_path = output_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = Path::new(&temp_path);
+    let output_file = File::create(temp_path)
+        .context(format!("Unable to create per read output file: {}", temp_path.display()))?;
+    let write_lines = |writer: &mut dyn Write| -> Result<()> {
+        writeln!(writer, "Read_ID\tSample\tCounted_Barcodes\tRandom_Barcode\tStatus")?;
+        // Barcodes without a conversion are kept as DNA, which does not need to be reported for this file
+        let mut unconvertible = AHashSet::new();
+        for read_assignment in receiver {
+            let (sample, counted_barcodes) = if read_assignment.sample_barcode.is_empty() {
+                (String::new(), String::new())
+            } else {
+                let counted_barcodes_hash = &counted_barcodes_hashes[read_assignment.design_index];
+                let counted_barcodes = if counted_barcodes_hash.is_empty() {
+                    read_assignment.counted_barcodes.join(",")
+                } else {
+                    convert_code(
+                        &read_assignment.counted_barcodes.join(","),
+                        counted_barcodes_hash,
+                        &mut unconvertible,
+                        ',',
+                        unordered,
+                    )
+                };
+                (
+                    convert_sample_barcode(&read_assignment.sample_barcode, &samples_barcode_hash),
+                    counted_barcodes,
+                )
+            };
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                read_assignment.read_id,
+                sample,
+                counted_barcodes,
+                read_assignment.random_barcode.unwrap_or_default(),
+                read_assignment.status
+            )?;
+        }
+        Ok(())
+    };
+    let output_file = if output_path.extension().is_some_and(|extension| extension == "gz") {
+        let mut writer = BufWriter::new(GzEncoder::new(output_file, Compression::default()));
+        write_lines(&mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?.finish()?
+    } else {
+        let mut writer = BufWriter::new(output_file);
+        write_lines(&mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?
+    };
+    output_file.sync_all()?;
+    fs::rename(temp_path, output_path)
+        .context(format!("Unable to move {} to {}", temp_path.display(), output_path.display()))?;
+    Ok(())
+}
+
+/// Splits the delimited text into parts of at most chunk_rows rows each, with the header as the first line of every part.  Text without any
+/// rows is one part with only the header
+///
+/// # Example
+/// ```
+/// use barcode_count::output::split_rows;
+///
+/// let text = "Barcode_1,S1,S2\na1,1,2\na2,3,4\na3,5,6\n";
+/// let parts = split_rows(text, 2);
+/// assert_eq!(parts, vec!["Barcode_1,S1,S2\na1,1,2\na2,3,4\n".to_string(), "Barcode_1,S1,S2\na3,5,6\n".to_string()]);
+/// ```
+pub fn split_rows(text: &str, chunk_rows: usize) -> Vec<String> {
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut parts = Vec::new();
+    for chunk in &lines.chunks(chunk_rows.max(1)) {
+        let mut part = format!("{}\n", header);
+        for line in chunk {
+            part.push_str(line);
+            part.push('\n');
+        }
+        parts.push(part);
+    }
+    if parts.is_empty() {
+        parts.push(format!("{}\n", header));
+    }
+    parts
+}
+
+/// Returns the sum of the last column of the delimited text when the header's last column is Count.  Otherwise returns None
+fn count_column_total(text: &str, delimiter: char) -> Option<u64> {
+    let mut lines = text.lines();
+    if lines.next()?.rsplit(delimiter).next()? != "Count" {
+        return None;
+    }
+    Some(
+        lines
+            .map(|line| {
+                line.rsplit(delimiter)
+                    .next()
+                    .and_then(|count| count.parse::<u64>().ok())
+                    .unwrap_or_default()
+            })
+            .sum(),
+    )
+}
+
+/// Sorts the counts file rows of (results code, written barcodes, count).  By barcode compares the written barcode columns in order.  By count
+/// puts the highest counts first, with ties sorted by barcode
+fn sort_rows(rows: &mut [(String, String, usize)], sort_by: SortBy, delimiter: char) {
+    match sort_by {
+        SortBy::Barcode => rows.sort_by(|(_, barcodes_a, _), (_, barcodes_b, _)| {
+            barcodes_a.split(delimiter).cmp(barcodes_b.split(delimiter))
+        }),
+        SortBy::Count => rows.sort_by(|(_, barcodes_a, count_a), (_, barcodes_b, count_b)| {
+            count_b
+                .cmp(count_a)
+                .then_with(|| barcodes_a.split(delimiter).cmp(barcodes_b.split(delimiter)))
+        }),
+    }
+}
+
+/// Prints the number of barcodes written so far every interval barcodes
+pub struct WriteProgress {
+    interval: usize, // barcodes written between progress prints.  0 turns off the progress prints
+    counted: usize,  // barcodes written to the current file
+}
+
+impl WriteProgress {
+    /// Creates a new WriteProgress struct that prints every interval barcodes
+    pub fn new(interval: usize) -> Self {
+        WriteProgress { interval, counted: 0 }
+    }
+
+    /// Adds a written barcode to the count and prints the count when it reaches a multiple of the interval
+    pub fn increment(&mut self) -> Result<()> {
+        self.counted += 1;
+        if self.interval != 0 && self.counted.is_multiple_of(self.interval) {
+            eprint!(
+                "Barcodes counted: {}\r",
+                self.counted.to_formatted_string(&Locale::en)
+            );
+            stderr().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Prints the final count of the current file, then resets the count for the next file
+    pub fn finish(&mut self) {
+        eprintln!(
+            "Barcodes counted: {}",
+            self.counted.to_formatted_string(&Locale::en)
+        );
+        self.counted = 0;
+    }
+}
+
+/// Prints a table of the matched reads per sample to stderr every interval_seconds until the parsing is finished, so that a failing
+/// sample can be seen early within a long run
+pub fn print_sample_matches(
+    results: Arc<Mutex<Results>>,
+    processed: Arc<AtomicU32>,
+    finished: Arc<AtomicBool>,
+    samples_barcode_hash: HashMap<String, String>,
+    interval_seconds: u64,
+) {
+    let interval = Duration::from_secs(interval_seconds);
+    let mut last_print = Instant::now();
+    while !finished.load(Ordering::Relaxed) {
+        // Sleep in short steps so that the thread ends soon after parsing finishes
+        thread::sleep(Duration::from_millis(100));
+        if last_print.elapsed() < interval {
+            continue;
+        }
+        last_print = Instant::now();
+        let total_processed = processed.load(Ordering::Relaxed);
+        let mut sample_matches = results
+            .lock()
+            .unwrap()
+            .sample_matches
+            .iter()
+            .map(|(sample_barcode, matched)| {
+                (
+                    convert_sample_barcode(sample_barcode, &samples_barcode_hash),
+                    *matched,
+                )
+            })
+            .collect::<Vec<(String, u32)>>();
+        sample_matches.sort();
+        let mut table = format!(
+            "\n-SAMPLE MATCHES-\nReads processed: {}\n",
+            total_processed.to_formatted_string(&Locale::en)
+        );
+        for (sample_name, matched) in sample_matches {
+            let percent = if total_processed == 0 {
+                0.0
+            } else {
+                matched as f64 / total_processed as f64 * 100.0
+            };
+            table.push_str(&format!(
+                "{}\t{}\t{:.1}%\n",
+                sample_name,
+                matched.to_formatted_string(&Locale::en),
+                percent
+            ));
+        }
+        eprint!("{}", table);
+    }
+}
+
 pub fn millisecond_decimal(elapsed_time: chrono::Duration) -> String {
     let milliseconds =
         (elapsed_time.num_milliseconds() - (elapsed_time.num_seconds() * 1000)).to_string();
@@ -587,15 +1851,40 @@ pub fn millisecond_decimal(elapsed_time: chrono::Duration) -> String {
     final_string
 }
 
-/// Converst the DNA sequence from counted barcodes to the ID
-fn convert_code(code: &str, barcodes_hashmap: &[HashMap<String, String>]) -> String {
+/// Converst the DNA sequence from counted barcodes to the ID, joined by the delimiter.  If the DNA sequence is not within the conversion hashmap,
+/// the DNA sequence is kept and added to the unconvertible set so that it can be reported instead of panicking
+fn convert_code(
+    code: &str,
+    barcodes_hashmap: &[HashMap<String, String>],
+    unconvertible: &mut AHashSet<String>,
+    delimiter: char,
+    unordered: bool,
+) -> String {
     code.split(',')
         .enumerate()
         .map(|(barcode_index, barcode)| {
-            let barcode_hash = &barcodes_hashmap[barcode_index];
-            return barcode_hash.get(barcode).unwrap().to_string();
+            // The reported constant regions come after the counted barcodes and are written as sequenced
+            if barcode_index >= barcodes_hashmap.len() {
+                return barcode.to_string();
+            }
+            // Unordered barcodes are sorted, so the barcode may have been sequenced at, and listed for, any position
+            let barcode_id_option = barcodes_hashmap[barcode_index].get(barcode).or_else(|| {
+                if unordered {
+                    barcodes_hashmap
+                        .iter()
+                        .find_map(|barcode_hash| barcode_hash.get(barcode))
+                } else {
+                    None
+                }
+            });
+            if let Some(barcode_id) = barcode_id_option {
+                barcode_id.to_string()
+            } else {
+                unconvertible.insert(barcode.to_string());
+                barcode.to_string()
+            }
         })
-        .join(",")
+        .join(&delimiter.to_string())
 }
 
 pub fn convert_sample_barcode(
@@ -604,6 +1893,9 @@ pub fn convert_sample_barcode(
 ) -> String {
     if let Some(sample_results) = sample_barcodes_hash.get(sample_barcode) {
         sample_results.to_string()
+    } else if sample_barcodes_hash.is_empty() {
+        // Without a sample conversion file, the DNA sample barcode is the sample name
+        sample_barcode.to_string()
     } else {
         "barcode".to_string()
     }
@@ -1,196 +1,925 @@
-use anyhow::{anyhow, Result};
-use regex::Captures;
+use anyhow::{anyhow, bail, Result};
+use regex::{Captures, Regex};
 use std::{
     collections::VecDeque,
     fmt,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        mpsc::SyncSender,
+        Arc, Condvar, Mutex, MutexGuard,
     },
+    time::Duration,
 };
 
-use crate::info::{MaxSeqErrors, Results, SequenceErrors, SequenceFormat};
-use ahash::AHashSet;
+use crate::{
+    arguments::Args,
+    info::{CountedBarcodeSeqs, InsertStats, MaxSeqErrors, Results, SequenceErrors, SequenceFormat},
+};
+use ahash::{AHashMap, AHashSet, HashMap};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+/// The sequence format of one library design, along with its allowed errors, known counted barcodes and counts.  A pool of library designs
+/// has one for each design, and each read is counted under the first design that matches
+#[derive(Clone)]
+pub struct FormatDesign {
+    pub sequence_format: SequenceFormat,
+    pub max_errors: MaxSeqErrors,
+    pub counted_barcode_seqs: Vec<CountedBarcodeSeqs>,
+    pub results: Arc<Mutex<Results>>, // the counts of the reads that matched this design.  Shared between the threads
+    pub corrector: Arc<dyn BarcodeCorrector>, // fixes the sample and counted barcodes that are not known
+}
 
 pub struct SequenceParser {
     shared_mut_clone: SharedMutData,
     sequence_errors_clone: SequenceErrors,
-    sequence_format_clone: SequenceFormat,
-    max_errors_clone: MaxSeqErrors,
+    designs: Vec<FormatDesign>, // the sequence format of each library design, tried in order
     sample_seqs: AHashSet<String>,
-    counted_barcode_seqs: Vec<AHashSet<String>>,
     raw_sequence: RawSequenceRead,
-    barcode_groups: Vec<String>,
+    barcode_groups: Vec<Vec<String>>, // the counted barcode capture group names of each design
+    min_format_length: usize, // length of the shortest design's format.  Shorter reads can not match any design
     min_quality_score: f32,
+    max_expected_errors_option: Option<f32>, // maximum summed error probability allowed within each barcode
+    quality_offset: u8,
+    strict_n: bool,
+    anchor_end: bool, // whether or not to prefer the format match closest to the end of the read
+    trim_start: usize, // bases removed from the start of each read before matching
+    trim_end: usize, // bases removed from the end of each read before matching
+    trim_adapter_option: Option<String>, // adapter removed, along with everything after it, from each read before matching
+    adapter_errors: usize, // mismatches allowed when finding the adapter
+    max_read_length_option: Option<usize>, // reads longer than this are truncated to it, or skipped, before matching
+    skip_long_reads: bool, // whether or not reads longer than the maximum read length are skipped instead of truncated
+    unordered_barcodes: bool, // whether or not to sort the counted barcodes so that their order within the read does not matter
+    write_uncorrected: bool, // whether or not to tally the counted barcodes that could not be fixed
+    count_unmatched_sample: bool, // whether or not to tally the sample barcodes that could not be fixed
+    match_unknown_samples: bool, // whether or not reads with a sample barcode that could not be fixed are counted under the unknown sample
+    umi_composition: bool, // whether or not to add the bases of each matched random barcode to the per position base composition
+    min_correction_margin: u16, // how many more mismatches the second closest known barcode needs than the closest for a fix to be kept
+    tie_resolution: TieResolution, // whether barcodes tied between known barcodes are discarded or fixed to one of them
+    check_reverse_complement: bool, // whether or not to try the reverse complement of reads where the constant region is not found
+    fold_revcomp: bool, // whether or not to count each counted barcode together with its reverse complement
+    dedup_name_regex_option: Option<Regex>, // finds the molecule key within the read name that is used in place of a random barcode
+    insert_stats: InsertStats, // read lengths and match starts for this thread.  Merged into the shared stats when parsing is finished
+    read_status: ReadStatus, // whether the current read was counted, or why it was not
+    quality_weighted: bool, // whether or not each read is counted with the probability that its barcode bases are correct
+    read_weight: f64, // the weight the current read adds to the counts.  1 unless the counts are quality weighted
+    per_read_sender: Option<SyncSender<ReadAssignment>>, // sends the outcome of each read to the per read output writer when it is used
+    match_printer_option: Option<MatchPrinter>, // prints the first matched reads with their region codes.  Only one thread has it
+    match_alignment: Option<String>, // the current read with the region codes beneath it, kept while the match printer has reads left
+    read_batch: std::vec::IntoIter<String>, // the reads left of the last batch received from the reader
+    partial_results: Vec<Results>, // the counts of each design since the last merge into the shared results, so the lock is taken once per batch
+    unmerged_reads: u32, // reads finished since the last merge into the shared results
 }
 
 impl SequenceParser {
     pub fn new(
         shared_mut_clone: SharedMutData,
         sequence_errors_clone: SequenceErrors,
-        sequence_format_clone: SequenceFormat,
-        max_errors_clone: MaxSeqErrors,
+        designs: Vec<FormatDesign>,
         sample_seqs: AHashSet<String>,
-        counted_barcode_seqs: Vec<AHashSet<String>>,
-        min_quality_score: f32,
+        per_read_sender: Option<SyncSender<ReadAssignment>>,
+        match_printer_option: Option<MatchPrinter>,
+        args: &Args,
     ) -> Self {
-        let mut barcode_groups = Vec::new();
-        for x in 0..sequence_format_clone.barcode_num {
-            barcode_groups.push(format!("barcode{}", x + 1))
-        }
+        let barcode_groups = designs
+            .iter()
+            .map(|design| {
+                (0..design.sequence_format.barcode_num)
+                    .map(|x| format!("barcode{}", x + 1))
+                    .collect::<Vec<String>>()
+            })
+            .collect::<Vec<Vec<String>>>();
+        let min_format_length = designs
+            .iter()
+            .map(|design| design.sequence_format.length)
+            .min()
+            .unwrap_or(0);
+        let partial_results = designs
+            .iter()
+            .map(|design| design.results.lock().unwrap().partial())
+            .collect();
         SequenceParser {
             shared_mut_clone,
             sequence_errors_clone,
-            sequence_format_clone,
-            max_errors_clone,
+            designs,
             sample_seqs,
-            counted_barcode_seqs,
             raw_sequence: RawSequenceRead::new(),
             barcode_groups,
-            min_quality_score,
+            min_format_length,
+            min_quality_score: args.min_average_quality_score,
+            max_expected_errors_option: args.max_expected_errors_option,
+            quality_offset: args.quality_offset,
+            strict_n: args.strict_n,
+            anchor_end: args.anchor_end,
+            trim_start: args.trim_start,
+            trim_end: args.trim_end,
+            trim_adapter_option: args.trim_adapter_option.clone(),
+            adapter_errors: args.adapter_errors,
+            max_read_length_option: args.max_read_length_option,
+            skip_long_reads: args.skip_long_reads,
+            unordered_barcodes: args.unordered_barcodes,
+            write_uncorrected: args.write_uncorrected,
+            count_unmatched_sample: args.count_unmatched_sample,
+            match_unknown_samples: args.match_unknown_samples,
+            umi_composition: args.umi_composition,
+            min_correction_margin: args.min_correction_margin,
+            tie_resolution: args.tie_resolution,
+            check_reverse_complement: args.check_reverse_complement,
+            fold_revcomp: args.fold_revcomp,
+            dedup_name_regex_option: args.dedup_name_regex_option.clone(),
+            insert_stats: InsertStats::new(),
+            read_status: ReadStatus::Counted,
+            quality_weighted: args.quality_weighted,
+            read_weight: 1.0,
+            per_read_sender,
+            match_printer_option,
+            match_alignment: None,
+            read_batch: Vec::new().into_iter(),
+            partial_results,
+            unmerged_reads: 0,
         }
     }
     pub fn parse(&mut self) -> Result<()> {
-        // Loop until there are no sequences left to parse.  These are fed into seq vec by the reader thread
-        loop {
-            if self.get_seqeunce()? {
-                if let Some(seq_match_result) = self.match_seq()? {
-                    let barcode_string = seq_match_result.barcode_string();
-                    // If there is a random barcode included
-                    let added = self.shared_mut_clone.results.lock().unwrap().add_count(
-                        &seq_match_result.sample_barcode,
-                        seq_match_result.random_barcode.as_ref(),
-                        barcode_string,
-                    );
+        // Loop until there are no sequences left to parse.  These are sent in batches by the reader thread
+        while let Some(matchable) = self.get_seqeunce()? {
+            // Keep the read before matching, which can change the sequence, in case it does not match and is shown by the match rate check
+            let unmatched_read = if matchable
+                && self
+                    .shared_mut_clone
+                    .match_rate_check
+                    .collecting(self.shared_mut_clone.processed.load(Ordering::Relaxed))
+            {
+                Some(self.raw_sequence.sequence.clone())
+            } else {
+                None
+            };
+            // Malformed and skipped long reads are already tallied and are skipped instead of stopping the run
+            let match_result = if matchable { self.match_seq()? } else { None };
+            // The outcome of the read for the per read output.  Reads that are not counted only have the reason why
+            let mut read_assignment_option = self.per_read_sender.as_ref().map(|_| ReadAssignment {
+                read_id: self.raw_sequence.read_id(),
+                design_index: 0,
+                sample_barcode: String::new(),
+                counted_barcodes: Vec::new(),
+                random_barcode: None,
+                status: self.read_status,
+            });
+            if match_result.is_none() {
+                if let Some(unmatched_read) = unmatched_read {
+                    self.shared_mut_clone.match_rate_check.add_unmatched(unmatched_read);
+                }
+            }
+            if let Some((design_index, mut seq_match_result)) = match_result {
+                if self.fold_revcomp {
+                    seq_match_result.fold_reverse_complements();
+                }
+                let barcode_string = seq_match_result.barcode_string(self.unordered_barcodes);
+                // If the sample is given by the fastq file, use its name in place of the sample barcode
+                let sample_barcode = self
+                    .shared_mut_clone
+                    .sample_label
+                    .as_deref()
+                    .unwrap_or(&seq_match_result.sample_barcode);
+                // The molecule key from the read name is used in place of the random barcode when given
+                let name_key = self
+                    .dedup_name_regex_option
+                    .as_ref()
+                    .map(|name_regex| self.raw_sequence.name_key(name_regex));
+                // If there is a random barcode included
+                let mut added = self.partial_results[design_index].add_count(
+                    sample_barcode,
+                    name_key.as_ref().or(seq_match_result.random_barcode.as_ref()),
+                    barcode_string,
+                    self.read_weight,
+                );
+                // The per read output has the status of each read, so the count is merged right away to find the random barcodes other
+                // threads already counted
+                if self.per_read_sender.is_some() {
+                    added &= Self::merge_partial_results(
+                        &self.designs,
+                        &mut self.partial_results,
+                        &self.shared_mut_clone.merged,
+                        &mut self.unmerged_reads,
+                        None,
+                        self.match_unknown_samples,
+                    )?
+                    .is_empty();
+                }
+                // Reads counted under the unknown sample are already tallied as sample barcode mismatches
+                if !seq_match_result.sample_barcode_error {
                     if added {
                         self.sequence_errors_clone.correct_match()
                     } else {
                         self.sequence_errors_clone.duplicated();
                     }
                 }
-            } else if self.shared_mut_clone.finished.load(Ordering::Relaxed) {
-                break;
+                if !self.shared_mut_clone.match_preview.is_finished() {
+                    if let Some(preview_line) = self
+                        .shared_mut_clone
+                        .match_preview
+                        .line(&self.raw_sequence.read_id(), &seq_match_result)
+                    {
+                        eprintln!("{}", preview_line);
+                    }
+                }
+                if let (Some(match_printer), Some(match_alignment)) =
+                    (&mut self.match_printer_option, self.match_alignment.take())
+                {
+                    match_printer.print(
+                        &self.raw_sequence.read_id(),
+                        &match_alignment,
+                        design_index,
+                        sample_barcode,
+                        &seq_match_result,
+                    );
+                }
+                if let Some(ref mut read_assignment) = read_assignment_option {
+                    read_assignment.design_index = design_index;
+                    read_assignment.sample_barcode = sample_barcode.to_string();
+                    read_assignment.counted_barcodes = seq_match_result.counted_barcodes;
+                    read_assignment.counted_barcodes.extend(seq_match_result.constant_variants);
+                    read_assignment.random_barcode = name_key.or(seq_match_result.random_barcode);
+                    read_assignment.status = if added {
+                        ReadStatus::Counted
+                    } else {
+                        ReadStatus::Duplicate
+                    };
+                }
+            }
+            if let (Some(per_read_sender), Some(read_assignment)) =
+                (&self.per_read_sender, read_assignment_option)
+            {
+                // The writer only stops early when it fails, and its error is returned once the parsing is finished
+                if per_read_sender.send(read_assignment).is_err() {
+                    bail!("The per read output writer stopped")
+                }
             }
+            self.unmerged_reads += 1;
+            // Record that this read is finished.  Used for the match rate check and progress
+            let processed = self
+                .shared_mut_clone
+                .processed
+                .fetch_add(1, Ordering::Relaxed)
+                + 1;
+            self.shared_mut_clone
+                .match_rate_check
+                .check(processed, self.sequence_errors_clone.matched_reads());
         }
+        // Add this thread's read length and match start information to the shared stats
+        lock_shared(&self.shared_mut_clone.insert_stats)?.merge(&self.insert_stats);
         Ok(())
     }
 
-    fn get_seqeunce(&mut self) -> Result<bool> {
-        // Pop off the last sequence from the seq vec
-        if let Some(new_raw_sequence) = self.shared_mut_clone.seq.lock().unwrap().pop_back() {
-            self.raw_sequence = RawSequenceRead::unpack(new_raw_sequence)?;
-            Ok(true)
+    /// Takes the next read from this thread's batch, receiving the next batch from the reader once it is used up.  Returns None once the
+    /// reader has finished and every batch is taken, otherwise whether or not the read is matched.  Malformed reads, such as those with a
+    /// different number of quality scores than bases, and long reads that are skipped are tallied here
+    fn get_seqeunce(&mut self) -> Result<Option<bool>> {
+        while self.read_batch.as_slice().is_empty() {
+            // Merge the counts before waiting for the next batch, so that they are within the shared results whenever the reader waits
+            Self::merge_partial_results(
+                &self.designs,
+                &mut self.partial_results,
+                &self.shared_mut_clone.merged,
+                &mut self.unmerged_reads,
+                Some(&mut self.sequence_errors_clone),
+                self.match_unknown_samples,
+            )?;
+            match self.shared_mut_clone.seq.recv()? {
+                Some(batch) => self.read_batch = batch.into_iter(),
+                None => return Ok(None),
+            }
+        }
+        if let Some(new_raw_sequence) = self.read_batch.next() {
+            match RawSequenceRead::unpack(new_raw_sequence) {
+                Ok(raw_sequence) if raw_sequence.is_well_formed() => self.raw_sequence = raw_sequence,
+                malformed => {
+                    // Keep the read, if it could be unpacked, so that its name is within the per read output
+                    self.raw_sequence = malformed.unwrap_or_else(|_| RawSequenceRead::new());
+                    self.read_status = ReadStatus::Malformed;
+                    self.sequence_errors_clone.malformed_read();
+                    return Ok(Some(false));
+                }
+            }
+            if let Some(ref adapter) = self.trim_adapter_option {
+                if self.raw_sequence.trim_adapter(adapter, self.adapter_errors) {
+                    self.sequence_errors_clone.adapter_trimmed_read();
+                }
+            }
+            if self.trim_start != 0 || self.trim_end != 0 {
+                self.raw_sequence.trim(self.trim_start, self.trim_end);
+            }
+            // Bounds the cost of finding the constant region within reads much longer than the format
+            if let Some(max_read_length) = self.max_read_length_option {
+                let read_length = self.raw_sequence.sequence.len();
+                if read_length > max_read_length {
+                    if self.skip_long_reads {
+                        self.read_status = ReadStatus::Long;
+                        self.sequence_errors_clone.long_read();
+                        return Ok(Some(false));
+                    }
+                    self.raw_sequence.trim(0, read_length - max_read_length);
+                }
+            }
+            Ok(Some(true))
         } else {
-            Ok(false)
+            Ok(None)
+        }
+    }
+
+    /// Adds the counts of each design since the last merge into the shared results, and the finished reads to the merged reads used by the
+    /// checkpoints.  Returns the random barcodes per sample that other threads already counted.  When the merged reads are already tallied,
+    /// their sequence errors are given so that these duplicates are moved from the matches before the reads are added to the merged
+    /// reads, which keeps the tallies saved by a checkpoint correct.  Takes the fields instead of self so that it can be called while the
+    /// current read's sample is borrowed
+    fn merge_partial_results(
+        designs: &[FormatDesign],
+        partial_results: &mut [Results],
+        merged: &MergedReads,
+        unmerged_reads: &mut u32,
+        sequence_errors_option: Option<&mut SequenceErrors>,
+        match_unknown_samples: bool,
+    ) -> Result<HashMap<String, usize>> {
+        let mut duplicates = HashMap::default();
+        for (design, partial_results) in designs.iter().zip(partial_results.iter_mut()) {
+            let new_partial_results = partial_results.partial();
+            let counts = std::mem::replace(partial_results, new_partial_results);
+            for (sample_barcode, sample_duplicates) in lock_shared(&design.results)?.merge(counts) {
+                *duplicates.entry(sample_barcode).or_insert(0) += sample_duplicates;
+            }
+        }
+        if let Some(sequence_errors) = sequence_errors_option {
+            // Reads counted under the unknown sample are tallied as sample barcode mismatches instead of matches or duplicates
+            let matched_duplicates = duplicates
+                .iter()
+                .filter(|(sample_barcode, _)| !(match_unknown_samples && sample_barcode.as_str() == "unknown"))
+                .map(|(_, sample_duplicates)| *sample_duplicates as u32)
+                .sum();
+            sequence_errors.recount_duplicates(matched_duplicates);
         }
+        merged.add(*unmerged_reads);
+        *unmerged_reads = 0;
+        Ok(duplicates)
     }
 
-    /// Does a regex search and captures the barcodes.  Returns a struct of the results.  
-    fn match_seq(&mut self) -> Result<Option<SequenceMatchResult>> {
-        self.check_and_fix_consant_region();
+    /// Does a regex search and captures the barcodes.  Returns the index of the design the read matched along with a struct of the results
+    fn match_seq(&mut self) -> Result<Option<(usize, SequenceMatchResult)>> {
+        // Keep the read length before the constant region fix, which shortens the sequence to the format length
+        let read_length = self.raw_sequence.sequence.len();
+        // Reads shorter than the format can not contain the barcodes, so they are tallied separately from constant region mismatches
+        if read_length < self.min_format_length {
+            self.read_status = ReadStatus::Short;
+            self.sequence_errors_clone.short_read();
+            return Ok(None);
+        }
+        let (design_index, fix_offset, reverse_complement) = self.choose_design();
+        let design = &self.designs[design_index];
         // if the barcodes are found continue, else return None and record a constant region error
-        if let Some(barcodes) = self
-            .sequence_format_clone
-            .format_regex
-            .captures(&self.raw_sequence.sequence)
-        {
+        if let Some(barcodes) = anchored_captures(
+            &design.sequence_format.format_regex,
+            &self.raw_sequence.sequence,
+            self.anchor_end,
+        ) {
+            // Where the format match started within the original read
+            let match_start = fix_offset + barcodes.get(0).unwrap().start();
+            // With variable spacers the barcodes are not at fixed positions, so the region codes come from where this read matched
+            let read_regions_option = if design.sequence_format.spacer_num > 0 {
+                Some(design.sequence_format.match_regions(&barcodes))
+            } else {
+                None
+            };
+            let regions_string = read_regions_option
+                .as_deref()
+                .unwrap_or(&design.sequence_format.regions_string);
             // If there was a minimum set for quality, check each barcode's quality
-            if self.min_quality_score > 0.0 {
-                if let Some(format_match) = self
-                    .sequence_format_clone
-                    .format_regex
-                    .find(&self.raw_sequence.sequence)
-                {
-                    let start = format_match.start();
-                    if self.raw_sequence.low_quality(
-                        self.min_quality_score,
-                        &self.sequence_format_clone.regions_string,
-                        start,
-                    ) {
-                        // If any are low qualty, add to the low quality count and return
-                        self.sequence_errors_clone.low_quality_barcode();
-                        return Ok(None);
-                    }
-                } else {
-                    return Err(anyhow!(
-                        "Regex find failed after regex captures was successful"
-                    ));
+            if self.min_quality_score > 0.0
+                && self.raw_sequence.low_quality(
+                    self.min_quality_score,
+                    self.quality_offset,
+                    regions_string,
+                    match_start,
+                )
+            {
+                // If any are low qualty, add to the low quality count and return
+                self.read_status = ReadStatus::LowQuality;
+                self.sequence_errors_clone.low_quality_barcode();
+                return Ok(None);
+            }
+            // If there was a maximum set for expected errors, check each barcode's expected errors
+            if let Some(max_expected_errors) = self.max_expected_errors_option {
+                if self.raw_sequence.high_expected_errors(
+                    max_expected_errors,
+                    self.quality_offset,
+                    regions_string,
+                    match_start,
+                ) {
+                    self.read_status = ReadStatus::LowQuality;
+                    self.sequence_errors_clone.low_quality_barcode();
+                    return Ok(None);
                 }
             }
 
+            // Keep the matched barcode regions, which are all of the capture groups except the spacers and reported constant regions, for
+            // the GC content.  Only added if the barcodes are found
+            let barcode_regions = barcodes
+                .iter()
+                .zip(design.sequence_format.format_regex.capture_names())
+                .skip(1)
+                .filter(|(_, group_name)| {
+                    !group_name.is_some_and(|group_name| group_name.starts_with("spacer") || group_name.starts_with("variant"))
+                })
+                .filter_map(|(barcode_match, _)| barcode_match)
+                .map(|barcode_match| barcode_match.as_str())
+                .collect::<Vec<&str>>();
+            // Keep the read with its region codes for the match printer, which prints it once the barcodes are found
+            if self
+                .match_printer_option
+                .as_ref()
+                .is_some_and(|match_printer| match_printer.matches_left > 0)
+            {
+                self.match_alignment = Some(MatchPrinter::alignment(
+                    &self.raw_sequence.sequence,
+                    &design.sequence_format,
+                    &barcodes,
+                ));
+            }
             // Create a match results struct which tests the regex regions
-            let match_results = SequenceMatchResult::new(
-                barcodes,
-                &self.barcode_groups,
-                &self.counted_barcode_seqs,
-                self.max_errors_clone.max_barcode_errors(),
+            let mut match_results = SequenceMatchResult::new(
+                &barcodes,
+                &self.barcode_groups[design_index],
+                design,
                 &self.sample_seqs,
-                self.max_errors_clone.max_sample_errors(),
+                self.min_correction_margin,
+                self.strict_n,
+                self.tie_resolution,
             );
 
+            // Ties are tallied whether or not they are kept
+            self.sequence_errors_clone.tied_barcodes(match_results.tied_barcodes);
             // If the sample barcode was not found, record the error and return none so that the algorithm stops for this sequence
             if match_results.sample_barcode_error {
+                if self.count_unmatched_sample {
+                    self.partial_results[design_index].add_unknown_sample(&match_results.sample_barcode);
+                }
                 self.sequence_errors_clone.sample_barcode_error();
-                return Ok(None);
+                // The counted barcodes of reads with an unknown sample barcode can show index hopping, so they are still found when asked
+                if self.match_unknown_samples {
+                    let sample_ties = match_results.tied_barcodes;
+                    match_results.match_counted_barcodes(
+                        &barcodes,
+                        &self.barcode_groups[design_index],
+                        design,
+                        self.min_correction_margin,
+                        self.strict_n,
+                        self.tie_resolution,
+                    );
+                    self.sequence_errors_clone.tied_barcodes(match_results.tied_barcodes - sample_ties);
+                }
+                if !self.match_unknown_samples || match_results.counted_barcode_error {
+                    self.read_status = ReadStatus::SampleBarcode;
+                    return Ok(None);
+                }
+                match_results.sample_barcode = "unknown".to_string();
+                self.partial_results[design_index].add_empty_samples(std::iter::once(&match_results.sample_barcode));
             }
             // If any of the counted barcodes were not found, even with error handling, record the error and return none so that the algorithm stops for this sequence
             if match_results.counted_barcode_error {
+                if self.write_uncorrected {
+                    if let Some((barcode_position, uncorrected_barcode)) =
+                        &match_results.uncorrected_barcode
+                    {
+                        self.partial_results[design_index]
+                            .add_uncorrected(*barcode_position, uncorrected_barcode);
+                    }
+                }
+                self.read_status = ReadStatus::CountedBarcode;
                 self.sequence_errors_clone.barcode_error();
                 return Ok(None);
             }
-            // If all went well, return the match results struct
-            Ok(Some(match_results))
+            // If all went well, record the read length, match start and orientation then return the match results struct
+            if self.quality_weighted {
+                self.read_weight = self.raw_sequence.correct_probability(
+                    self.quality_offset,
+                    regions_string,
+                    match_start,
+                );
+            }
+            self.insert_stats.add(read_length, match_start);
+            // Only reads tallied as matches, not those counted under the unknown sample, are split by orientation
+            if reverse_complement && !match_results.sample_barcode_error {
+                self.sequence_errors_clone.reverse_complement_match();
+            }
+            for barcode_region in &barcode_regions {
+                self.insert_stats.add_barcode_bases(barcode_region);
+            }
+            if self.umi_composition {
+                if let Some(ref random_barcode) = match_results.random_barcode {
+                    self.insert_stats.add_random_bases(random_barcode);
+                }
+            }
+            Ok(Some((design_index, match_results)))
         } else {
             // If the constant region was not found, record the error and return None
+            self.read_status = ReadStatus::ConstantRegion;
             self.sequence_errors_clone.constant_region_error();
             Ok(None)
         }
     }
 
-    /// Checks the constant region of the sequence then finds the best fix if it is not found.  Basically whether or not the regex search worked
-    fn check_and_fix_consant_region(&mut self) {
+    /// Checks and fixes the constant region of the read.  If the constant region can not be found and the reverse complement is checked,
+    /// the reverse complement of the read is checked and fixed instead.  Returns the offset where the fixed sequence starts, and whether
+    /// or not the reverse complement was used
+    fn check_and_fix_either_orientation(&mut self, design_index: usize) -> (usize, bool) {
+        let format_regex = &self.designs[design_index].sequence_format.format_regex;
+        if !self.check_reverse_complement || format_regex.is_match(&self.raw_sequence.sequence) {
+            return (self.check_and_fix_consant_region(design_index), false);
+        }
+        // Keep the read as sequenced, since a failed constant region fix clears the sequence
+        let forward_read = self.raw_sequence.clone();
+        let fix_offset = self.check_and_fix_consant_region(design_index);
+        if self.designs[design_index]
+            .sequence_format
+            .format_regex
+            .is_match(&self.raw_sequence.sequence)
+        {
+            return (fix_offset, false);
+        }
+        self.raw_sequence = forward_read;
+        self.raw_sequence.reverse_complement();
+        (self.check_and_fix_consant_region(design_index), true)
+    }
+
+    /// Chooses the design to capture the barcodes with.  An exact match to any design's format is kept first, then each design's constant
+    /// region is fixed in order until one is found.  Returns the design index, the offset where the fixed sequence starts, and whether or
+    /// not the reverse complement was used.  When no design is found, the last design is returned and its barcodes will not be captured
+    fn choose_design(&mut self) -> (usize, usize, bool) {
+        if self.designs.len() == 1 {
+            let (fix_offset, reverse_complement) = self.check_and_fix_either_orientation(0);
+            return (0, fix_offset, reverse_complement);
+        }
+        if let Some(design_index) = self.designs.iter().position(|design| {
+            design
+                .sequence_format
+                .format_regex
+                .is_match(&self.raw_sequence.sequence)
+        }) {
+            return (design_index, 0, false);
+        }
+        // Keep the read as sequenced to try each design, since fixing the constant region changes the sequence
+        let sequenced_read = self.raw_sequence.clone();
+        for design_index in 0..self.designs.len() {
+            self.raw_sequence = sequenced_read.clone();
+            let (fix_offset, reverse_complement) = self.check_and_fix_either_orientation(design_index);
+            if self.designs[design_index]
+                .sequence_format
+                .format_regex
+                .is_match(&self.raw_sequence.sequence)
+            {
+                return (design_index, fix_offset, reverse_complement);
+            }
+        }
+        (self.designs.len() - 1, 0, false)
+    }
+
+    /// Checks the constant region of the sequence then finds the best fix if it is not found.  Basically whether or not the regex search worked.
+    /// Returns the offset within the read where the fixed sequence starts, which is 0 if no fix was needed
+    fn check_and_fix_consant_region(&mut self, design_index: usize) -> usize {
+        let design = &self.designs[design_index];
         // If the regex search does not work, try to fix the constant region
-        if !self
-            .sequence_format_clone
+        if !design
+            .sequence_format
             .format_regex
             .is_match(&self.raw_sequence.sequence)
         {
-            self.raw_sequence.fix_constant_region(
-                &self.sequence_format_clone.format_string,
-                self.max_errors_clone.max_constant_errors(),
+            let fix_offset = self
+                .raw_sequence
+                .fix_constant_region(
+                    &design.sequence_format.format_string,
+                    design.max_errors.max_constant_errors(),
+                    self.strict_n,
+                    self.anchor_end,
+                    self.quality_offset,
+                )
+                .unwrap_or(0);
+            // Alternative constant regions are 'N's within the format string, so they are fixed against their own alternatives
+            self.raw_sequence.fix_alternative_constants(
+                &design.sequence_format.variant_starts,
+                &design.sequence_format.variant_alternatives,
+                design.max_errors.max_constant_errors(),
+                self.strict_n,
             );
+            fix_offset
+        } else {
+            0
+        }
+    }
+}
+
+/// Locks the data shared between the threads.  A poisoned lock means another thread panicked while holding it, so the shared data can not be
+/// trusted and an error is returned to stop the run
+fn lock_shared<T>(shared: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    shared
+        .lock()
+        .map_err(|_| anyhow!("A thread stopped while holding the shared data.  The counts are incomplete"))
+}
+
+/// Runs the format regex on the sequence.  The regex finds the leftmost match, so when anchor_end is true the search is repeated
+/// one position past each match start to find the rightmost match instead
+fn anchored_captures<'a>(
+    format_regex: &Regex,
+    sequence: &'a str,
+    anchor_end: bool,
+) -> Option<Captures<'a>> {
+    let mut captures = format_regex.captures(sequence)?;
+    if anchor_end {
+        while let Some(next_captures) =
+            format_regex.captures_at(sequence, captures.get(0).unwrap().start() + 1)
+        {
+            captures = next_captures;
+        }
+    }
+    Some(captures)
+}
+
+/// A bounded queue of read batches passed from the reading thread to the processing threads.  The reader blocks while the queue is
+/// full and the processing threads block while it is empty, so neither spins on a lock.  Reads are sent in batches so that the queue's
+/// lock is taken once per batch instead of once per read
+pub struct ReadQueue {
+    state: Mutex<ReadQueueState>,
+    not_empty: Condvar, // notified when a batch is added or the queue is closed
+    not_full: Condvar, // notified when a batch is removed or the queue is closed
+}
+
+struct ReadQueueState {
+    batches: VecDeque<Vec<String>>, // the batches waiting to be parsed, oldest first
+    closed: bool, // set once the reader has finished, after which the processing threads stop when the queue is empty
+}
+
+impl Default for ReadQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadQueue {
+    /// The number of reads the reader collects before sending them as one batch
+    pub const BATCH_SIZE: usize = 500;
+    /// The number of batches held before the reader waits, so that at most 10000 reads are held in memory
+    const MAX_BATCHES: usize = 20;
+
+    pub fn new() -> Self {
+        ReadQueue {
+            state: Mutex::new(ReadQueueState {
+                batches: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Adds the batch to the end of the queue, waiting while the queue is full.  The wait stops without adding the batch if the queue is
+    /// closed or exit is set, since then the processing threads may have stopped and the reads would never be parsed
+    pub fn send(&self, batch: Vec<String>, exit: &AtomicBool) -> Result<()> {
+        let mut state = lock_shared(&self.state)?;
+        while state.batches.len() >= Self::MAX_BATCHES {
+            if state.closed || exit.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            // The exit flag is not tied to the condvar, so it is checked again after a short wait
+            state = self
+                .not_full
+                .wait_timeout(state, Duration::from_millis(100))
+                .map_err(|_| anyhow!("A thread stopped while holding the read queue"))?
+                .0;
+        }
+        if !state.closed {
+            state.batches.push_back(batch);
+            self.not_empty.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Removes the oldest batch, waiting while the queue is empty.  Returns None once the queue is closed and every batch is taken
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::parse::ReadQueue;
+    /// use std::sync::atomic::AtomicBool;
+    ///
+    /// let read_queue = ReadQueue::new();
+    /// read_queue.send(vec!["read_1".to_string()], &AtomicBool::new(false)).unwrap();
+    /// read_queue.close();
+    /// assert_eq!(read_queue.recv().unwrap(), Some(vec!["read_1".to_string()]));
+    /// assert_eq!(read_queue.recv().unwrap(), None);
+    /// ```
+    pub fn recv(&self) -> Result<Option<Vec<String>>> {
+        let mut state = lock_shared(&self.state)?;
+        loop {
+            if let Some(batch) = state.batches.pop_front() {
+                self.not_full.notify_one();
+                return Ok(Some(batch));
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = self
+                .not_empty
+                .wait(state)
+                .map_err(|_| anyhow!("A thread stopped while holding the read queue"))?;
+        }
+    }
+
+    /// Marks that no more batches are coming and wakes every waiting thread.  Batches already in the queue are still received
+    pub fn close(&self) {
+        // A poisoned lock still holds a usable flag, and the waiting threads need to be woken either way
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+/// The number of finished reads whose counts are merged into the shared results.  The checkpoints wait on it, so each merge wakes them
+/// instead of them checking the count in a loop
+///
+/// # Example
+/// ```
+/// use barcode_count::parse::MergedReads;
+/// use std::sync::atomic::AtomicBool;
+///
+/// let merged_reads = MergedReads::new(10);
+/// merged_reads.add(5);
+/// assert_eq!(merged_reads.reads(), 15);
+/// assert!(merged_reads.wait_for(15, &AtomicBool::new(false)).unwrap());
+/// assert!(!merged_reads.wait_for(20, &AtomicBool::new(true)).unwrap());
+/// ```
+pub struct MergedReads {
+    reads: Mutex<u32>, // finished reads merged so far
+    merged: Condvar,   // notified whenever reads are merged
+}
+
+impl MergedReads {
+    /// Starts the count at the reads already within the results
+    pub fn new(reads: u32) -> Self {
+        MergedReads {
+            reads: Mutex::new(reads),
+            merged: Condvar::new(),
+        }
+    }
+
+    /// Adds the newly merged reads and wakes the waiting threads
+    pub fn add(&self, reads: u32) {
+        // A poisoned lock still holds a usable count.  Whichever thread panicked has already stopped the run
+        let mut merged_reads = self.reads.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *merged_reads += reads;
+        self.merged.notify_all();
+    }
+
+    /// Returns the reads merged so far
+    pub fn reads(&self) -> u32 {
+        *self.reads.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Waits until at least total_reads are merged.  Returns false without waiting further once exit is set, since the parsing threads may
+    /// have stopped
+    pub fn wait_for(&self, total_reads: u32, exit: &AtomicBool) -> Result<bool> {
+        let mut merged_reads = lock_shared(&self.reads)?;
+        while *merged_reads < total_reads {
+            if exit.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            // The exit flag is not tied to the condvar, so it is checked again after a short wait
+            merged_reads = self
+                .merged
+                .wait_timeout(merged_reads, Duration::from_millis(100))
+                .map_err(|_| anyhow!("A thread stopped while holding the merged reads"))?
+                .0;
         }
+        Ok(true)
     }
 }
 
 pub struct SharedMutData {
-    pub seq: Arc<Mutex<VecDeque<String>>>,
-    pub finished: Arc<AtomicBool>,
+    pub seq: Arc<ReadQueue>, // the batches of reads sent by the reading thread to the processing threads
     pub results: Arc<Mutex<Results>>,
+    pub insert_stats: Arc<Mutex<InsertStats>>,
+    pub processed: Arc<AtomicU32>, // number of reads the parsing threads have finished with
+    pub merged: Arc<MergedReads>, // number of finished reads whose counts are merged into the shared results.  Used for checkpoints
+    pub sample_label: Option<String>, // sample name for all reads of the fastq file when each sample has its own fastq file
+    pub match_rate_check: MatchRateCheck, // aborts the run when too few of the first reads match
+    pub match_preview: MatchPreview, // prints the barcodes of the first matched reads of the run to stderr
 }
 
 impl SharedMutData {
     pub fn new(
-        seq: Arc<Mutex<VecDeque<String>>>,
-        finished: Arc<AtomicBool>,
+        seq: Arc<ReadQueue>,
         results: Arc<Mutex<Results>>,
+        insert_stats: Arc<Mutex<InsertStats>>,
+        processed: Arc<AtomicU32>,
+        sample_label: Option<String>,
+        match_rate_check: MatchRateCheck,
+        match_preview: MatchPreview,
     ) -> Self {
+        // Every read already processed, such as from earlier fastq files or before resuming, is within the results
+        let merged = Arc::new(MergedReads::new(processed.load(Ordering::Relaxed)));
         SharedMutData {
             seq,
-            finished,
             results,
+            insert_stats,
+            processed,
+            merged,
+            sample_label,
+            match_rate_check,
+            match_preview,
         }
     }
 
     pub fn arc_clone(&self) -> SharedMutData {
         let seq = Arc::clone(&self.seq);
-        let finished = Arc::clone(&self.finished);
         let results = Arc::clone(&self.results);
+        let insert_stats = Arc::clone(&self.insert_stats);
+        let processed = Arc::clone(&self.processed);
+        let merged = Arc::clone(&self.merged);
+        let sample_label = self.sample_label.clone();
+        let match_rate_check = self.match_rate_check.clone();
+        let match_preview = self.match_preview.clone();
         SharedMutData {
             seq,
-            finished,
             results,
+            insert_stats,
+            processed,
+            merged,
+            sample_label,
+            match_rate_check,
+            match_preview,
+        }
+    }
+}
+
+/// Checks the fraction of matched reads once a set number of reads are processed, and stops the run if it is below the minimum.  A few of
+/// the unmatched reads before the check are kept to show with the error, since a low match rate is usually a misconfigured run
+#[derive(Clone)]
+pub struct MatchRateCheck {
+    min_match_rate_option: Option<f32>, // the minimum fraction of matched reads.  None turns off the check
+    check_after_reads: u32, // the number of processed reads when the match rate is checked
+    unmatched_reads: Arc<Mutex<Vec<String>>>, // the first few unmatched reads, shown when the check fails
+    failure: Arc<Mutex<Option<String>>>, // the error message once the check fails
+    exit: Arc<AtomicBool>, // set when the check fails so that the reading thread stops
+}
+
+impl MatchRateCheck {
+    const KEPT_UNMATCHED_READS: usize = 5;
+
+    pub fn new(min_match_rate_option: Option<f32>, check_after_reads: u32, exit: Arc<AtomicBool>) -> Self {
+        MatchRateCheck {
+            min_match_rate_option,
+            check_after_reads,
+            unmatched_reads: Arc::new(Mutex::new(Vec::new())),
+            failure: Arc::new(Mutex::new(None)),
+            exit,
+        }
+    }
+
+    /// Whether or not unmatched reads are still kept, which is only before the check and when the check is used
+    pub fn collecting(&self, processed: u32) -> bool {
+        self.min_match_rate_option.is_some() && processed < self.check_after_reads
+    }
+
+    /// Keeps the unmatched read if fewer than the kept number are already held
+    pub fn add_unmatched(&self, read: String) {
+        let mut unmatched_reads = self.unmatched_reads.lock().unwrap();
+        if unmatched_reads.len() < Self::KEPT_UNMATCHED_READS {
+            unmatched_reads.push(read);
+        }
+    }
+
+    /// Checks the match rate when processed reaches the number of reads to check after.  Only the thread that processed that read checks
+    pub fn check(&self, processed: u32, matched: u32) {
+        if let Some(min_match_rate) = self.min_match_rate_option {
+            if processed != self.check_after_reads {
+                return;
+            }
+            let match_rate = matched as f32 / processed as f32;
+            if match_rate < min_match_rate {
+                let unmatched_reads = self.unmatched_reads.lock().unwrap();
+                *self.failure.lock().unwrap() = Some(format!(
+                    "Only {:.2}% of the first {} reads matched, which is below the minimum match rate of {:.2}%.  Check the sequence format and barcode files\nUnmatched reads:\n{}",
+                    match_rate * 100.0,
+                    processed,
+                    min_match_rate * 100.0,
+                    unmatched_reads.join("\n")
+                ));
+                self.exit.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns an error if the match rate check failed
+    pub fn result(&self) -> Result<()> {
+        if let Some(failure) = self.failure.lock().unwrap().as_ref() {
+            bail!("{}", failure)
         }
+        Ok(())
     }
 }
 
@@ -236,7 +965,8 @@ impl RawSequenceRead {
     pub fn add_line(&mut self, line_num: u16, line: String) -> Result<()> {
         match line_num {
             1 => self.description = line,
-            2 => self.sequence = line,
+            // Soft-masked reads contain lowercase bases, so the sequence is uppercased to match the barcodes and constant regions
+            2 => self.sequence = line.to_ascii_uppercase(),
             3 => self.add_description = line,
             4 => self.quality_values = line,
             _ => {
@@ -250,6 +980,71 @@ impl RawSequenceRead {
         Ok(())
     }
 
+    /// Returns whether or not the read has a quality score for each base.  The other reads are malformed and are skipped
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let read = RawSequenceRead::unpack("@read1\nAGCT\n+\nIIII".to_string()).unwrap();
+    /// let malformed_read = RawSequenceRead::unpack("@read2\nAGCT\n+\nIIIIII".to_string()).unwrap();
+    ///
+    /// assert!(read.is_well_formed());
+    /// assert!(!malformed_read.is_well_formed());
+    /// ```
+    pub fn is_well_formed(&self) -> bool {
+        self.sequence.len() == self.quality_values.len()
+    }
+
+    /// Returns the molecule key found by the regex within the read name, the first line of the FASTQ record.  The first capture group is
+    /// used if the regex has one, otherwise the whole match.  A read name without a match returns the whole name so that the read is still
+    /// counted
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    /// use regex::Regex;
+    ///
+    /// let read = RawSequenceRead::unpack("@read1 UMI:ACGTAC\nAGCT\n+\nIIII".to_string()).unwrap();
+    /// let other_read = RawSequenceRead::unpack("@read2\nAGCT\n+\nIIII".to_string()).unwrap();
+    /// let name_regex = Regex::new("UMI:([ACGTN]+)").unwrap();
+    ///
+    /// assert_eq!(read.name_key(&name_regex), "ACGTAC");
+    /// assert_eq!(other_read.name_key(&name_regex), "@read2");
+    /// ```
+    pub fn name_key(&self, name_regex: &Regex) -> String {
+        match name_regex.captures(&self.description) {
+            Some(captures) => captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .map_or("", |key| key.as_str())
+                .to_string(),
+            None => self.description.clone(),
+        }
+    }
+
+    /// Returns the read ID, which is the read name up to the first whitespace without the leading '@'
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let read = RawSequenceRead::unpack("@read1 1:N:0:1\nAGCT\n+\nIIII".to_string()).unwrap();
+    ///
+    /// assert_eq!(read.read_id(), "read1");
+    /// ```
+    pub fn read_id(&self) -> String {
+        self.description
+            .trim_start_matches('@')
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_string()
+    }
+
     pub fn pack(&self) -> String {
         format!(
             "{}\n{}\n{}\n{}",
@@ -257,6 +1052,20 @@ impl RawSequenceRead {
         )
     }
 
+    /// Creates a RawSequenceRead from the four new line separated lines of a FASTQ read.  The sequence is uppercased so that
+    /// soft-masked, lowercase, bases are counted the same as uppercase bases
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let raw_read = "@read1\nagcTACgaN\n+\nIIIIIIIII".to_string();
+    /// let read = RawSequenceRead::unpack(raw_read).unwrap();
+    ///
+    /// assert_eq!(read.sequence, "AGCTACGAN");
+    /// assert_eq!(read.pack(), "@read1\nAGCTACGAN\n+\nIIIIIIIII");
+    /// ```
     pub fn unpack(raw_string: String) -> Result<Self> {
         let mut raw_sequence_read = RawSequenceRead::new();
         for (line_num, line) in raw_string.split('\n').enumerate() {
@@ -266,6 +1075,87 @@ impl RawSequenceRead {
         Ok(raw_sequence_read)
     }
 
+    /// Removes trim_start bases from the start and trim_end bases from the end of both the sequence and the quality scores, so that the
+    /// quality scores stay aligned with the bases.  A read shorter than the trimmed bases is left empty
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let mut read = RawSequenceRead::unpack("@read1\nAAAGCTACGT\n+\n!!!IIIIII#".to_string()).unwrap();
+    /// read.trim(3, 1);
+    ///
+    /// assert_eq!(read.pack(), "@read1\nGCTACG\n+\nIIIIII");
+    /// ```
+    pub fn trim(&mut self, trim_start: usize, trim_end: usize) {
+        for line in [&mut self.sequence, &mut self.quality_values] {
+            let end = line.len().saturating_sub(trim_end).max(trim_start.min(line.len()));
+            *line = line[trim_start.min(line.len())..end].to_string();
+        }
+    }
+
+    /// Finds the adapter within the sequence, with up to max_errors mismatches, and removes it along with everything after it from both the
+    /// sequence and the quality scores.  Adapter read-through at the end of the read that is shorter than the adapter is also found, down to
+    /// 3 bases, with the mismatches allowed scaled to the length of the overlap.  Returns whether or not the read was trimmed
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let mut read = RawSequenceRead::unpack("@read1\nACGTAGATCGGTAGCC\n+\nIIIIIIIIIIIIIIII".to_string()).unwrap();
+    /// let mut read_through = RawSequenceRead::unpack("@read2\nACGTACGTAGAT\n+\nIIIIIIIIIIII".to_string()).unwrap();
+    /// let mut insert_only = RawSequenceRead::unpack("@read3\nACGTACGTAC\n+\nIIIIIIIIII".to_string()).unwrap();
+    ///
+    /// assert!(read.trim_adapter("AGATCGGAAG", 1));
+    /// assert_eq!(read.pack(), "@read1\nACGT\n+\nIIII");
+    /// assert!(read_through.trim_adapter("AGATCGGAAG", 1));
+    /// assert_eq!(read_through.pack(), "@read2\nACGTACGT\n+\nIIIIIIII");
+    /// assert!(!insert_only.trim_adapter("AGATCGGAAG", 1));
+    /// ```
+    pub fn trim_adapter(&mut self, adapter: &str, max_errors: usize) -> bool {
+        const MIN_ADAPTER_OVERLAP: usize = 3;
+        let sequence_bytes = self.sequence.as_bytes();
+        let adapter_bytes = adapter.as_bytes();
+        let read_length = sequence_bytes.len();
+        let adapter_start_option = (0..(read_length + 1).saturating_sub(MIN_ADAPTER_OVERLAP)).find(|start| {
+            let overlap = adapter_bytes.len().min(read_length - start);
+            let allowed_errors = max_errors * overlap / adapter_bytes.len();
+            sequence_bytes[*start..start + overlap]
+                .iter()
+                .zip(&adapter_bytes[..overlap])
+                .filter(|(base, adapter_base)| base != adapter_base)
+                .nth(allowed_errors)
+                .is_none()
+        });
+        if let Some(adapter_start) = adapter_start_option {
+            self.sequence.truncate(adapter_start);
+            self.quality_values.truncate(adapter_start);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reverse complements the sequence and reverses the quality scores, so that a read sequenced from the opposite strand is in the
+    /// same orientation as the format.  Any base other than A, C, G, T is kept as is
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let mut read = RawSequenceRead::unpack("@read1\nAAGCTN\n+\n#!IIII".to_string()).unwrap();
+    /// read.reverse_complement();
+    ///
+    /// assert_eq!(read.pack(), "@read1\nNAGCTT\n+\nIIII!#");
+    /// ```
+    pub fn reverse_complement(&mut self) {
+        self.sequence = reverse_complement(&self.sequence);
+        self.quality_values = self.quality_values.chars().rev().collect();
+    }
+
     /// Replaces the 'N's in the sequencing format with the barcodes to fix any sequencing errrors that would cause the regex search not to work
     pub fn insert_barcodes_constant_region(&mut self, format_string: &str, best_sequence: String) {
         // Start a new string to push to
@@ -274,63 +1164,249 @@ impl RawSequenceRead {
         // sequence corresponding to the barcodes
         for (old_char, new_char) in best_sequence.chars().zip(format_string.chars()) {
             if new_char == 'N' {
-                fixed_sequence.push_str(&old_char.to_string());
+                fixed_sequence.push(old_char);
             } else {
-                fixed_sequence.push_str(&new_char.to_string());
+                fixed_sequence.push(new_char);
             }
         }
         self.sequence = fixed_sequence
     }
 
     /// Fixes the constant region by finding the closest match within the full seqeuence that has fewer than the max errors allowed,
-    /// then uses the format string to flip the barcodes into the 'N's and have a fixed constant region string
-    pub fn fix_constant_region(&mut self, format_string: &str, max_constant_errors: u16) {
+    /// then uses the format string to flip the barcodes into the 'N's and have a fixed constant region string.  If strict_n is true,
+    /// 'N's sequenced within the constant region count as mismatches.  A tie for the fewest mismatches is broken by the quality scores
+    /// of the constant region, keeping the match with the highest total quality in the constant positions since its mismatches are the
+    /// least likely to be real.  A near tie, with one more mismatch that is still within the max errors, is kept instead when its
+    /// average quality over the constant positions is more than 10 higher.  If there is still a tie and anchor_end is true, the offsets
+    /// are searched from the end of the read and the rightmost is kept, otherwise no fix is made.  Returns the offset within the read
+    /// where the best match starts
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// // The constant region ACGT is found with one mismatch at both the start and the middle of the read
+    /// let mut read = RawSequenceRead::unpack("@read1\nACGATTACCTAA\n+\n######IIIIII".to_string()).unwrap();
+    /// assert_eq!(read.fix_constant_region("ACGTNN", 1, false, false, 33), Some(6));
+    /// assert_eq!(read.sequence, "ACGTAA");
+    ///
+    /// // Without a difference in quality, the tie is not fixed
+    /// let mut even_read = RawSequenceRead::unpack("@read2\nACGATTACCTAA\n+\nIIIIIIIIIIII".to_string()).unwrap();
+    /// assert_eq!(even_read.fix_constant_region("ACGTNN", 1, false, false, 33), None);
+    ///
+    /// // AGCT at the end of the read has one more mismatch than ACGA at the start, but is kept for its much higher quality
+    /// let mut near_tie_read = RawSequenceRead::unpack("@read3\nACGATTAGCTAA\n+\n######IIIIII".to_string()).unwrap();
+    /// assert_eq!(near_tie_read.fix_constant_region("ACGTNN", 2, false, false, 33), Some(6));
+    /// assert_eq!(near_tie_read.sequence, "ACGTAA");
+    ///
+    /// // Unless the extra mismatch is past the max errors
+    /// let mut near_tie_read = RawSequenceRead::unpack("@read3\nACGATTAGCTAA\n+\n######IIIIII".to_string()).unwrap();
+    /// assert_eq!(near_tie_read.fix_constant_region("ACGTNN", 1, false, false, 33), Some(0));
+    /// assert_eq!(near_tie_read.sequence, "ACGTTT");
+    /// ```
+    pub fn fix_constant_region(
+        &mut self,
+        format_string: &str,
+        max_constant_errors: u16,
+        strict_n: bool,
+        anchor_end: bool,
+        quality_offset: u8,
+    ) -> Option<usize> {
         // Find the region of the sequence that best matches the constant region.  This is doen by iterating through the sequence
         // Get the length difference between what was sequenced and the barcode region with constant regions
-        // This is to stop the iteration in the next step
-        let length_diff = self.sequence.len() - format_string.len();
-
-        // Create a vector of sequences the length of the constant region + barcodes to check for where the best match is located
-        let mut possible_seqs = Vec::new();
-        for index in 0..length_diff {
-            let possible_seq = self
-                .sequence
-                .chars()
-                .skip(index) // skip to where the current index is and take the next amount equal to the length of the constant region + barcodes
-                .take(format_string.len())
-                .collect::<String>();
-            // Add the new sequence to the vector of all possible matches
-            possible_seqs.push(possible_seq);
+        // This is to stop the iteration in the next step.  Reads shorter than the format have no possible matches
+        let length_diff = self.sequence.len().checked_sub(format_string.len());
+
+        // Create a vector of offsets, and the sequences at those offsets the length of the constant region + barcodes, to check for where the best match is located
+        let mut offsets = (0..length_diff.map_or(0, |diff| diff + 1)).collect::<Vec<usize>>();
+        if anchor_end {
+            offsets.reverse();
         }
+        let possible_seqs = offsets
+            .iter()
+            .map(|offset| {
+                self.sequence
+                    .chars()
+                    .skip(*offset) // skip to where the current offset is and take the next amount equal to the length of the constant region + barcodes
+                    .take(format_string.len())
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>();
         // Find the closest match within what was sequenced to the constant region
-        let best_sequence_option = fix_error(format_string, &possible_seqs, max_constant_errors);
+        // The format string is the known sequence, so only its 'N's stay as wildcards when strict_n is used
+        let n_matching = if strict_n {
+            NMatching::StrictPossibleSeqs
+        } else {
+            NMatching::Wildcard
+        };
+        // When anchored to the end, the offsets are in reverse so the first tied match is the rightmost
+        let tie_resolution = if anchor_end {
+            TieResolution::KeepFirst
+        } else {
+            TieResolution::Discard
+        };
+        // Ties are kept here so that they can be broken by the quality scores
+        let best_index_option = fix_error_with_margin(
+            format_string,
+            &possible_seqs,
+            max_constant_errors,
+            0,
+            n_matching,
+            TieResolution::KeepFirst,
+        )
+        .and_then(|(best_sequence, margin)| {
+            let best_index = possible_seqs
+                .iter()
+                .position(|possible_seq| possible_seq == &best_sequence)?;
+            // Without a quality score for each base, only a tie is looked at further, and only with the tie resolution
+            if margin > 1 || (margin == 1 && !self.is_well_formed()) {
+                return Some(best_index);
+            }
+            let tied_index_option = if self.is_well_formed() {
+                // How much higher the average quality of the constant positions of a near tie has to be to be kept over the fewest mismatches
+                const NEAR_TIE_QUALITY_MARGIN: i64 = 10;
+                let quality_scores = self.quality_scores(quality_offset);
+                let best_mismatches =
+                    count_mismatches(&best_sequence, format_string, n_matching, u16::MAX);
+                let constant_positions = format_string.chars().filter(|format_char| *format_char != 'N').count() as i64;
+                // The total quality of the constant positions of each tied and near tied match, less the margin for each extra mismatch.
+                // Every match has the same number of constant positions
+                let constant_qualities = possible_seqs
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, possible_seq)| {
+                        let mismatches = count_mismatches(possible_seq, format_string, n_matching, u16::MAX);
+                        if mismatches > best_mismatches.saturating_add(1) || mismatches > max_constant_errors {
+                            return None;
+                        }
+                        let constant_quality = format_string
+                            .chars()
+                            .zip(&quality_scores[offsets[index]..])
+                            .filter(|(format_char, _)| *format_char != 'N')
+                            .map(|(_, score)| *score as i64)
+                            .sum::<i64>();
+                        let extra_mismatches = (mismatches - best_mismatches) as i64;
+                        Some((index, constant_quality - extra_mismatches * NEAR_TIE_QUALITY_MARGIN * constant_positions))
+                    })
+                    .collect::<Vec<(usize, i64)>>();
+                let highest_quality = constant_qualities.iter().map(|(_, quality)| *quality).max()?;
+                let mut highest_indexes = constant_qualities
+                    .iter()
+                    .filter(|(_, quality)| *quality == highest_quality);
+                match (highest_indexes.next(), highest_indexes.next()) {
+                    (Some((index, _)), None) => return Some(*index),
+                    (Some((index, _)), Some(_)) => Some(*index),
+                    _ => None,
+                }
+            } else {
+                Some(best_index)
+            };
+            // The remaining tie keeps the first in the order of the offsets only when anchored to the end
+            match tie_resolution {
+                TieResolution::Discard => None,
+                _ => tied_index_option,
+            }
+        });
 
-        if let Some(best_sequence) = best_sequence_option {
+        if let Some(best_index) = best_index_option {
+            let best_sequence = possible_seqs[best_index].clone();
             self.insert_barcodes_constant_region(format_string, best_sequence);
+            Some(offsets[best_index])
         } else {
             self.sequence = "".to_string();
+            None
+        }
+    }
+
+    /// Fixes each alternative constant region within a sequence that has already had its constant region fixed to the format length.
+    /// Each region is changed to the closest of its alternatives with at most the max errors, and is left as sequenced when there is a
+    /// tie, so that the read is not counted as the wrong alternative
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// let mut read = RawSequenceRead::unpack("@read1\nAATGGTGG\n+\nIIIIIIII".to_string()).unwrap();
+    /// let alternatives = vec![vec!["TGGA".to_string(), "TCCA".to_string()]];
+    /// read.fix_alternative_constants(&[2], &alternatives, 1, false);
+    /// assert_eq!(read.sequence, "AATGGAGG");
+    /// ```
+    pub fn fix_alternative_constants(
+        &mut self,
+        variant_starts: &[usize],
+        variant_alternatives: &[Vec<String>],
+        max_constant_errors: u16,
+        strict_n: bool,
+    ) {
+        let n_matching = if strict_n {
+            NMatching::StrictMismatchSeq
+        } else {
+            NMatching::Wildcard
+        };
+        for (variant_start, alternatives) in variant_starts.iter().zip(variant_alternatives) {
+            // Reported constant regions with 'N's have no alternatives
+            if alternatives.is_empty() {
+                continue;
+            }
+            let variant_range = *variant_start..variant_start + alternatives[0].len();
+            let sequenced = match self.sequence.get(variant_range.clone()) {
+                Some(sequenced) => sequenced,
+                None => continue,
+            };
+            if let Some((best_alternative, _)) = fix_error_with_margin(
+                sequenced,
+                alternatives,
+                max_constant_errors,
+                0,
+                n_matching,
+                TieResolution::Discard,
+            ) {
+                self.sequence.replace_range(variant_range, &best_alternative);
+            }
         }
     }
 
-    /// Each DNA base read score within FASTQ is the ascii number - 33.
-    /// This returns the number scores associated with the ascii values
+    /// Each DNA base read score within FASTQ is the ascii number - the quality offset, which is 33 for modern
+    /// data and 64 for legacy Illumina 1.3-1.5 data.  This returns the number scores associated with the ascii values.
+    /// Any ascii value below the offset is clamped to a score of 0
     ///
     /// Score    Error Probability
     /// 40       0.0001
     /// 30       0.001
     /// 20       0.01
     /// 10       0.1
-    pub fn quality_scores(&self) -> Vec<u8> {
+    pub fn quality_scores(&self, quality_offset: u8) -> Vec<u8> {
         self.quality_values
             .chars()
-            .map(|ch| ch as u8 - 33)
+            .map(|ch| (ch as u8).saturating_sub(quality_offset))
             .collect::<Vec<u8>>()
     }
 
+    /// Checks that none of the quality characters are below the quality offset, which would be a negative score and means the
+    /// wrong offset is being used
+    pub fn check_quality_offset(&self, quality_offset: u8) -> Result<()> {
+        if let Some(low_char) = self
+            .quality_values
+            .chars()
+            .find(|ch| (*ch as u8) < quality_offset)
+        {
+            println!("{}", self);
+            return Err(anyhow!(
+                "Quality character '{}' is below the quality offset of {} and would give a negative score.  Check the --quality-offset argument",
+                low_char,
+                quality_offset
+            ));
+        }
+        Ok(())
+    }
+
     /// Test for if any of the barcode average quality score falls below the min_average cutoff
     pub fn low_quality(
         &self,
         min_average: f32,
+        quality_offset: u8,
         barcode_indicator_string: &str,
         start: usize,
     ) -> bool {
@@ -338,7 +1414,7 @@ impl RawSequenceRead {
         let mut previous_type = '\0'; // setup previoius barcode inidator type for the first comparison
 
         for (score, seq_type) in self
-            .quality_scores()
+            .quality_scores(quality_offset)
             .iter()
             .skip(start)
             .zip(barcode_indicator_string.chars())
@@ -359,13 +1435,13 @@ impl RawSequenceRead {
                 }
                 // set previous indicator type to the current type to check next
                 previous_type = seq_type;
-                // if indicator is not for a constant region, create a new vec with the new score
-                if seq_type != 'C' {
+                // if indicator is not for a constant region or spacer, create a new vec with the new score
+                if !matches!(seq_type, 'C' | 'V') {
                     scores = vec![*score as f32];
                 }
             } else {
-                // If indicator type is not for a constant region, add the score to the vec
-                if seq_type != 'C' {
+                // If indicator type is not for a constant region or spacer, add the score to the vec
+                if !matches!(seq_type, 'C' | 'V') {
                     scores.push(*score as f32);
                 }
             }
@@ -374,20 +1450,81 @@ impl RawSequenceRead {
         false
     }
 
-    pub fn check_fastq_format(&self) -> Result<()> {
-        // Test to see if the first line is not a sequence and the second is a sequence, which is typical fastq format
-        match test_sequence(&self.description) {
-            LineType::Sequence => {
-                println!("{}", self);
-                return Err(anyhow!("The first line within the FASTQ contains DNA sequences.  Check the FASTQ format"));
+    /// Test for if any of the barcodes have more expected errors than max_expected_errors.  The expected errors of a barcode are the sum
+    /// of the error probability, 10^(-Q/10), of each of its bases
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// // Scores of 10, an error probability of 0.1, for the first barcode and 40 for the second
+    /// let read = RawSequenceRead::new_fill("@read1".to_string(), "AAAACCCCAAAA".to_string(), "+".to_string(), "++++IIIIIIII".to_string());
+    ///
+    /// assert!(read.high_expected_errors(0.3, 33, "BBBBCCCCBBBB", 0));
+    /// assert!(!read.high_expected_errors(0.5, 33, "BBBBCCCCBBBB", 0));
+    /// ```
+    pub fn high_expected_errors(
+        &self,
+        max_expected_errors: f32,
+        quality_offset: u8,
+        barcode_indicator_string: &str,
+        start: usize,
+    ) -> bool {
+        let mut expected_errors = 0.0; // summed error probability of the current barcode
+        let mut previous_type = '\0';
+        for (score, seq_type) in self
+            .quality_scores(quality_offset)
+            .iter()
+            .skip(start)
+            .zip(barcode_indicator_string.chars())
+        {
+            // A change in region type starts a new barcode
+            if seq_type != previous_type {
+                expected_errors = 0.0;
+                previous_type = seq_type;
             }
-            LineType::Metadata => (),
-        }
-        match test_sequence(&self.sequence) {
-            LineType::Sequence => (),
-            LineType::Metadata => {
-                println!("{}", self);
-                return Err(anyhow!("The second line within the FASTQ file is not a sequence. Check the FASTQ format"));
+            if !matches!(seq_type, 'C' | 'V') {
+                expected_errors += 10_f32.powf(-(*score as f32) / 10.0);
+                if expected_errors > max_expected_errors {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the probability that every barcode base is correct, which is the product of 1 - 10^(-Q/10) over the bases that are not
+    /// within a constant region or variable spacer.  Used as the weight of the read for quality weighted counts
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::RawSequenceRead;
+    ///
+    /// // Scores of 10, a correct probability of 0.9, for two barcode bases.  The constant region scores are ignored
+    /// let read = RawSequenceRead::new_fill("@read1".to_string(), "ACGT".to_string(), "+".to_string(), "++!!".to_string());
+    ///
+    /// assert!((read.correct_probability(33, "BBCC", 0) - 0.81).abs() < 1e-9);
+    /// ```
+    pub fn correct_probability(&self, quality_offset: u8, barcode_indicator_string: &str, start: usize) -> f64 {
+        self.quality_scores(quality_offset)
+            .iter()
+            .skip(start)
+            .zip(barcode_indicator_string.chars())
+            .filter(|(_, seq_type)| !matches!(*seq_type, 'C' | 'V'))
+            .map(|(score, _)| 1.0 - 10_f64.powf(-(*score as f64) / 10.0))
+            .product()
+    }
+
+    pub fn check_fastq_format(&self) -> Result<()> {
+        // Test to see if the second line is a sequence, which is typical fastq format.  The '@' and '+' lines around it are already checked
+        // while the record is read
+        match test_sequence(&self.sequence) {
+            LineType::Sequence => (),
+            LineType::Metadata => {
+                println!("{}", self);
+                return Err(anyhow!("The second line within the FASTQ file is not a sequence. Check the FASTQ format"));
             }
         }
         Ok(())
@@ -426,26 +1563,232 @@ fn test_sequence(sequence: &str) -> LineType {
     LineType::Sequence
 }
 
+/// Whether a read was counted, or the reason it was not.  Written as the status of each read within the per read output
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReadStatus {
+    Counted,
+    Duplicate,
+    Short,
+    Long,
+    Malformed,
+    ConstantRegion,
+    LowQuality,
+    SampleBarcode,
+    CountedBarcode,
+}
+
+impl fmt::Display for ReadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            ReadStatus::Counted => "counted",
+            ReadStatus::Duplicate => "duplicate",
+            ReadStatus::Short => "short_read",
+            ReadStatus::Long => "long_read",
+            ReadStatus::Malformed => "malformed",
+            ReadStatus::ConstantRegion => "constant_region_error",
+            ReadStatus::LowQuality => "low_quality",
+            ReadStatus::SampleBarcode => "sample_barcode_error",
+            ReadStatus::CountedBarcode => "counted_barcode_error",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Prints the first matched reads with the region codes of the sequence format beneath them, along with the IDs of the barcodes, so that
+/// the positions the format finds the barcodes at can be checked.  Used by one processing thread with --show-matches
+pub struct MatchPrinter {
+    matches_left: usize, // matched reads left to print
+    shown: usize,        // matched reads printed so far
+    samples_barcode_hash: HashMap<String, String>, // sample barcode -> sample ID
+    counted_barcodes_hashes: Vec<Vec<HashMap<String, String>>>, // counted barcode -> ID for each counted barcode of each design
+}
+
+impl MatchPrinter {
+    /// Creates a MatchPrinter which prints the first matches matched reads
+    pub fn new(
+        matches: usize,
+        samples_barcode_hash: HashMap<String, String>,
+        counted_barcodes_hashes: Vec<Vec<HashMap<String, String>>>,
+    ) -> Self {
+        MatchPrinter {
+            matches_left: matches,
+            shown: 0,
+            samples_barcode_hash,
+            counted_barcodes_hashes,
+        }
+    }
+
+    /// Lines up the region codes beneath the read, starting at where the format matched.  The width of each region is taken from the
+    /// match, so the codes stay lined up past any variable spacer
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::{info::SequenceFormat, parse::MatchPrinter};
+    ///
+    /// let sequence_format = SequenceFormat::parse_format_str("[2]AG~3{2}").unwrap();
+    ///
+    /// let barcodes = sequence_format.format_regex.captures("TTAGCCAC").unwrap();
+    /// let alignment = MatchPrinter::alignment("TTAGCCAC", &sequence_format, &barcodes);
+    /// assert_eq!(alignment, "TTAGCCAC\nSSCCVVBB");
+    ///
+    /// // A shorter spacer moves the counted barcode codes with it
+    /// let barcodes = sequence_format.format_regex.captures("TTAGCAC").unwrap();
+    /// let alignment = MatchPrinter::alignment("TTAGCAC", &sequence_format, &barcodes);
+    /// assert_eq!(alignment, "TTAGCAC\nSSCCVBB");
+    /// ```
+    pub fn alignment(sequence: &str, sequence_format: &SequenceFormat, barcodes: &Captures) -> String {
+        format!(
+            "{}\n{}{}",
+            sequence,
+            " ".repeat(barcodes.get(0).unwrap().start()),
+            sequence_format.match_regions(barcodes)
+        )
+    }
+
+    /// Prints the read with its region codes, then each barcode found with the ID it is counted under
+    fn print(
+        &mut self,
+        read_id: &str,
+        alignment: &str,
+        design_index: usize,
+        sample_barcode: &str,
+        seq_match_result: &SequenceMatchResult,
+    ) {
+        if self.matches_left == 0 {
+            return;
+        }
+        self.matches_left -= 1;
+        self.shown += 1;
+        let mut match_text = format!("\nMatch {}: {}\n{}\n", self.shown, read_id, alignment);
+        if !sample_barcode.is_empty() {
+            match_text.push_str(&format!(
+                "Sample barcode: {} -> {}\n",
+                sample_barcode,
+                crate::output::convert_sample_barcode(sample_barcode, &self.samples_barcode_hash)
+            ));
+        }
+        for (barcode_index, counted_barcode) in seq_match_result.counted_barcodes.iter().enumerate() {
+            let barcode_id = self.counted_barcodes_hashes[design_index]
+                .get(barcode_index)
+                .and_then(|counted_barcodes_hash| counted_barcodes_hash.get(counted_barcode))
+                .unwrap_or(counted_barcode);
+            match_text.push_str(&format!("Barcode {}: {} -> {}\n", barcode_index + 1, counted_barcode, barcode_id));
+        }
+        if let Some(ref random_barcode) = seq_match_result.random_barcode {
+            match_text.push_str(&format!("Random barcode: {}\n", random_barcode));
+        }
+        print!("{}", match_text);
+    }
+}
+
+/// Prints the barcodes found within the first matched reads of the run to stderr as they are parsed, for quick feedback on a format file.
+/// Shared by every processing thread, so the reads are numbered by when they were printed.  Used with --preview
+#[derive(Clone)]
+pub struct MatchPreview {
+    reads_left: Arc<AtomicUsize>, // matched reads left to print across every thread
+    total: usize, // matched reads printed once the preview is finished
+}
+
+impl MatchPreview {
+    /// Creates a MatchPreview which prints the first reads matched reads.  0 turns it off
+    pub fn new(reads: usize) -> Self {
+        MatchPreview {
+            reads_left: Arc::new(AtomicUsize::new(reads)),
+            total: reads,
+        }
+    }
+
+    /// Returns the preview line of a matched read, or None once the preview is finished.  Each read left is only taken by one thread
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::{MatchPreview, SequenceMatchResult};
+    ///
+    /// let match_preview = MatchPreview::new(1);
+    /// let match_result = SequenceMatchResult {
+    ///     sample_barcode: "AGCT".to_string(),
+    ///     counted_barcodes: vec!["CAGAGA".to_string(), "TTGACC".to_string()],
+    ///     constant_variants: Vec::new(),
+    ///     counted_barcode_error: false,
+    ///     sample_barcode_error: false,
+    ///     random_barcode: Some("ACGTACGT".to_string()),
+    ///     uncorrected_barcode: None,
+    ///     tied_barcodes: 0,
+    /// };
+    ///
+    /// assert_eq!(
+    ///     match_preview.line("read_1", &match_result),
+    ///     Some("Preview 1: read_1\tsample: AGCT\tcounted: CAGAGA,TTGACC\trandom: ACGTACGT".to_string())
+    /// );
+    /// assert_eq!(match_preview.line("read_2", &match_result), None);
+    /// assert!(match_preview.is_finished());
+    /// ```
+    pub fn line(&self, read_id: &str, seq_match_result: &SequenceMatchResult) -> Option<String> {
+        let reads_left = self
+            .reads_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reads_left| reads_left.checked_sub(1))
+            .ok()?;
+        let mut line = format!("Preview {}: {}", self.total - reads_left + 1, read_id);
+        if !seq_match_result.sample_barcode.is_empty() {
+            line.push_str(&format!("\tsample: {}", seq_match_result.sample_barcode));
+        }
+        line.push_str(&format!("\tcounted: {}", seq_match_result.counted_barcodes.join(",")));
+        if let Some(ref random_barcode) = seq_match_result.random_barcode {
+            line.push_str(&format!("\trandom: {}", random_barcode));
+        }
+        Some(line)
+    }
+
+    /// Returns whether every read of the preview was printed, which is checked before the read ID is made for line
+    pub fn is_finished(&self) -> bool {
+        self.reads_left.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// The outcome of one read, sent from the parsing threads to the per read output writer.  The barcodes are DNA sequences, which the
+/// writer converts to IDs, and are empty for reads that were not counted
+pub struct ReadAssignment {
+    pub read_id: String,
+    pub design_index: usize, // the sequence format the read matched, which has the counted barcode conversions
+    pub sample_barcode: String,
+    pub counted_barcodes: Vec<String>,
+    pub random_barcode: Option<String>, // the random barcode, or the molecule key from the read name
+    pub status: ReadStatus,
+}
+
 /// A struct to hold the results of the regex search on the sequence along with perform the functions to fix and find
 pub struct SequenceMatchResult {
     pub sample_barcode: String,
     pub counted_barcodes: Vec<String>,
+    pub constant_variants: Vec<String>, // sequences of the reported constant regions, in format order
     pub counted_barcode_error: bool,
     pub sample_barcode_error: bool,
     pub random_barcode: Option<String>,
+    pub uncorrected_barcode: Option<(usize, String)>, // the position and sequence of the counted barcode that could not be fixed
+    pub tied_barcodes: u32, // sample and counted barcodes with more than one closest known barcode, whether or not the tie was kept
 }
 
 impl SequenceMatchResult {
     pub fn new(
-        barcodes: Captures, // The regex result on the sequence
+        barcodes: &Captures, // The regex result on the sequence
         barcode_groups: &[String],
-        counted_barcode_seqs: &[AHashSet<String>], // The vec of known counted barcode sequences in order to fix sequencing errors.  Will be empty if none are known or included
-        counted_barcode_max_errors: &[u16], // The maximum errors allowed for each counted barcode
+        design: &FormatDesign, // The design the read matched, with the known counted barcodes, the maximum errors and the corrector that fixes sequencing errors
         sample_seqs: &AHashSet<String>, // A hashset of all known sample barcodes. Will be empty if none are known or included
-        sample_seqs_max_errors: u16,    // Maximum allowed sample barcode sequencing errors
+        min_correction_margin: u16, // How many more mismatches the second closest known barcode needs than the closest for a fix to be kept
+        strict_n: bool, // Whether or not sequenced 'N's count as mismatches when fixing barcodes
+        tie_resolution: TieResolution, // Whether barcodes tied between known barcodes are discarded or fixed to one of them
     ) -> SequenceMatchResult {
+        // The sequenced barcode is what is being fixed, so its 'N's are mismatches when strict_n is used
+        let n_matching = if strict_n {
+            NMatching::StrictMismatchSeq
+        } else {
+            NMatching::Wildcard
+        };
         // Check for sample barcode and start with setting error to false
         let mut sample_barcode_error = false;
+        let mut tied_barcodes = 0;
         let sample_barcode;
         // If 'sample' is within the regex returned search continue with checking and fixing
         if let Some(sample_barcode_match) = barcodes.name("sample") {
@@ -458,12 +1801,24 @@ impl SequenceMatchResult {
                     sample_barcode = sample_barcode_str.to_string();
                 } else {
                     // Otherwise try and fix it.  If the fix returns none, then save the error and an empty string
-                    let sample_barcode_fix_option =
-                        fix_error(sample_barcode_str, sample_seqs, sample_seqs_max_errors);
+                    let sample_barcode_fix_option = Self::keep_fix(
+                        design.corrector.correct(
+                            sample_barcode_str,
+                            sample_seqs,
+                            design.max_errors.max_sample_errors(),
+                            min_correction_margin,
+                            n_matching,
+                            tie_resolution.keeping(),
+                        ),
+                        tie_resolution,
+                        min_correction_margin,
+                        &mut tied_barcodes,
+                    );
                     if let Some(fixed_barcode) = sample_barcode_fix_option {
                         sample_barcode = fixed_barcode;
                     } else {
-                        sample_barcode = String::new();
+                        // Keep the sequenced sample barcode so that unknown sample barcodes can be tallied
+                        sample_barcode = sample_barcode_str.to_string();
                         sample_barcode_error = true;
                     }
                 }
@@ -473,64 +1828,649 @@ impl SequenceMatchResult {
             sample_barcode = "barcode".to_string();
         }
 
-        // Check the counted barcodes and start with setting the error to false
-        let mut counted_barcode_error = false;
-        // Create an empty vec to hold the barcodes
-        let mut counted_barcodes = Vec::new();
+        // Chceck for a random barcode.  A random barcode split into more than one region is joined in order into one random barcode
+        let random_barcode;
+        // If a random barcode exists, add it.  Otherwise set it to None
+        if let Some(random_barcode_match) = barcodes.name("random1") {
+            let mut joined_random_barcode = random_barcode_match.as_str().to_string();
+            let mut random_index = 2;
+            while let Some(random_barcode_match) = barcodes.name(&format!("random{}", random_index)) {
+                joined_random_barcode.push_str(random_barcode_match.as_str());
+                random_index += 1;
+            }
+            random_barcode = Some(joined_random_barcode)
+        } else {
+            random_barcode = None
+        }
+        // The sequences of any reported constant regions, which are counted after the counted barcodes
+        let mut constant_variants = Vec::new();
+        while let Some(variant_match) = barcodes.name(&format!("variant{}", constant_variants.len() + 1)) {
+            constant_variants.push(variant_match.as_str().to_string());
+        }
+        let mut sequence_match_result = SequenceMatchResult {
+            sample_barcode,
+            counted_barcodes: Vec::new(),
+            constant_variants,
+            counted_barcode_error: false,
+            sample_barcode_error,
+            random_barcode,
+            uncorrected_barcode: None,
+            tied_barcodes,
+        };
         // Only continue if the sample barcode was found
         if !sample_barcode_error {
-            // Iterate through the counted barcocdes.  Fix if they are not within the known barcodes
-            for (index, barcode_group) in barcode_groups.iter().enumerate() {
-                let mut counted_barcode =
-                    barcodes.name(barcode_group).unwrap().as_str().to_string();
-                // If a barcode conversion file was included and there are known barcodes, check for sequencing errors
-                if !counted_barcode_seqs.is_empty() {
-                    // If the barcode is not known, try and fix
-                    if !counted_barcode_seqs[index].contains(&counted_barcode) {
-                        let barcode_seq_fix_option = fix_error(
+            sequence_match_result.match_counted_barcodes(
+                barcodes,
+                barcode_groups,
+                design,
+                min_correction_margin,
+                strict_n,
+                tie_resolution,
+            );
+        }
+        sequence_match_result
+    }
+
+    /// Returns the fixed barcode of a correction that keeps ties, or None if there is not one with at least the margin.  A tie, which has
+    /// a margin of 0, is added to the tied barcodes whether or not the tie resolution keeps it
+    fn keep_fix(
+        fix_option: Option<(String, u16)>,
+        tie_resolution: TieResolution,
+        min_correction_margin: u16,
+        tied_barcodes: &mut u32,
+    ) -> Option<String> {
+        let (fixed_barcode, margin) = fix_option?;
+        if margin == 0 {
+            *tied_barcodes += 1;
+            if tie_resolution == TieResolution::Discard {
+                return None;
+            }
+        }
+        if margin >= min_correction_margin {
+            Some(fixed_barcode)
+        } else {
+            None
+        }
+    }
+
+    /// Adds the counted barcodes of the regex result, fixing any that are not within the known counted barcodes.  If one can not be fixed,
+    /// the error is recorded along with the uncorrected barcode and the rest are not checked.  Called by new once the sample barcode is
+    /// found, or afterwards for reads with an unknown sample barcode when these are counted
+    pub fn match_counted_barcodes(
+        &mut self,
+        barcodes: &Captures,
+        barcode_groups: &[String],
+        design: &FormatDesign,
+        min_correction_margin: u16,
+        strict_n: bool,
+        tie_resolution: TieResolution,
+    ) {
+        let counted_barcode_seqs = &design.counted_barcode_seqs;
+        // The indexes give the same fixes as fix_error_with_margin, so they are only used in place of the Hamming corrector
+        let hamming = design.corrector.is_hamming();
+        let n_matching = if strict_n {
+            NMatching::StrictMismatchSeq
+        } else {
+            NMatching::Wildcard
+        };
+        // Iterate through the counted barcocdes.  Fix if they are not within the known barcodes
+        for (index, barcode_group) in barcode_groups.iter().enumerate() {
+            let mut counted_barcode =
+                barcodes.name(barcode_group).unwrap().as_str().to_string();
+            // If a barcode conversion file was included and there are known barcodes, check for sequencing errors
+            if !counted_barcode_seqs.is_empty() {
+                // If the barcode is not known, try and fix
+                if !counted_barcode_seqs[index].seqs.contains(&counted_barcode) {
+                    let kmer_index_option = counted_barcode_seqs[index]
+                        .kmer_index
+                        .as_ref()
+                        .filter(|kmer_index| hamming && kmer_index.covers(&counted_barcode, n_matching));
+                    // Ties are kept so that they are tallied, then discarded by keep_fix if the tie resolution discards them
+                    let correct = || {
+                        design.corrector.correct(
                             &counted_barcode,
-                            &counted_barcode_seqs[index],
-                            counted_barcode_max_errors[index],
-                        );
-                        if let Some(fixed_barcode) = barcode_seq_fix_option {
-                            counted_barcode = fixed_barcode;
-                        } else {
-                            // If a fix was not found, return the error and stop going through more barcodes
-                            counted_barcode_error = true;
-                            break;
+                            &counted_barcode_seqs[index].seqs,
+                            design.max_errors.max_barcode_errors()[index],
+                            min_correction_margin,
+                            n_matching,
+                            tie_resolution.keeping(),
+                        )
+                    };
+                    let correction = match (&counted_barcode_seqs[index].mismatch_index, kmer_index_option) {
+                        // The index gives the same fix as fix_error_with_margin, except for sequenced 'N's which fix_error_with_margin treats as wildcards without strict_n.
+                        // It does not find the margin, so fix_error_with_margin is used when a margin is required
+                        (Some(mismatch_index), _)
+                            if hamming && min_correction_margin <= 1 && (strict_n || !counted_barcode.contains('N')) =>
+                        {
+                            match mismatch_index.fix_or_tie(&counted_barcode) {
+                                // The margin is at least 1, which is all that is needed here
+                                (Some(fixed_barcode), _) => Some((fixed_barcode, 1)),
+                                // A discarded tie is only tallied, so which known barcodes are tied is not needed
+                                (None, true) if tie_resolution == TieResolution::Discard => {
+                                    self.tied_barcodes += 1;
+                                    None
+                                }
+                                // The index does not keep which known barcodes are tied, so the few kept ties are compared with all of them
+                                (None, true) => correct(),
+                                (None, false) => None,
+                            }
                         }
+                        // The k-mer index gives the same fix as fix_error_with_margin, comparing only the known barcodes that share enough k-mers
+                        (_, Some(kmer_index)) => {
+                            kmer_index.fix(&counted_barcode, min_correction_margin, n_matching, tie_resolution.keeping())
+                        }
+                        _ => correct(),
+                    };
+                    let barcode_seq_fix_option =
+                        Self::keep_fix(correction, tie_resolution, min_correction_margin, &mut self.tied_barcodes);
+                    if let Some(fixed_barcode) = barcode_seq_fix_option {
+                        counted_barcode = fixed_barcode;
+                    } else {
+                        // If a fix was not found, return the error and stop going through more barcodes
+                        self.counted_barcode_error = true;
+                        self.uncorrected_barcode = Some((index, counted_barcode));
+                        return;
                     }
                 }
-                // If all is well, add the counted barcode to the vec
-                counted_barcodes.push(counted_barcode);
+                // Sequences expanded from an IUPAC barcode are counted as the barcode listed within the barcode file
+                if let Some(iupac_barcode) = counted_barcode_seqs[index].iupac_barcodes.get(&counted_barcode) {
+                    counted_barcode = iupac_barcode.clone();
+                }
             }
+            // If all is well, add the counted barcode to the vec
+            self.counted_barcodes.push(counted_barcode);
         }
+    }
 
-        // Chceck for a random barcode
-        let random_barcode;
-        // If a random barcode exists, add it.  Otherwise set it to an empty string
-        if let Some(random_barcode_match) = barcodes.name("random") {
-            random_barcode = Some(random_barcode_match.as_str().to_string())
+    /// Replaces each counted barcode with the lexicographically smaller of itself and its reverse complement, so that a barcode and its
+    /// reverse complement are counted together
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::parse::SequenceMatchResult;
+    ///
+    /// let mut match_result = SequenceMatchResult {
+    ///     sample_barcode: String::new(),
+    ///     counted_barcodes: vec!["TTGC".to_string(), "AACG".to_string()],
+    ///     constant_variants: Vec::new(),
+    ///     counted_barcode_error: false,
+    ///     sample_barcode_error: false,
+    ///     random_barcode: None,
+    ///     uncorrected_barcode: None,
+    ///     tied_barcodes: 0,
+    /// };
+    /// match_result.fold_reverse_complements();
+    /// assert_eq!(match_result.barcode_string(false), "GCAA,AACG");
+    /// ```
+    pub fn fold_reverse_complements(&mut self) {
+        for counted_barcode in self.counted_barcodes.iter_mut() {
+            *counted_barcode = canonical_barcode(counted_barcode);
+        }
+    }
+
+    /// Returns a comma separated counted barcodes string.  Perfect for CSV file writing.  If unordered, the barcodes are sorted first so
+    /// that every order of the same barcodes is counted together.  The sequences of any reported constant regions are added at the end
+    pub fn barcode_string(&self, unordered: bool) -> String {
+        let mut barcode_string = if unordered {
+            self.counted_barcodes.iter().sorted().join(",")
         } else {
-            random_barcode = None
+            self.counted_barcodes.join(",")
+        };
+        for constant_variant in &self.constant_variants {
+            barcode_string.push(',');
+            barcode_string.push_str(constant_variant);
         }
-        SequenceMatchResult {
-            sample_barcode,
-            counted_barcodes,
-            counted_barcode_error,
-            sample_barcode_error,
-            random_barcode,
+        barcode_string
+    }
+}
+
+/// A precomputed index for fixing barcodes with a single mismatch.  Each known barcode is stored with each of its positions blanked, so
+/// a sequenced barcode is fixed with one hash lookup per position instead of comparing it with every known barcode
+#[derive(Serialize, Deserialize)]
+pub struct MismatchIndex {
+    barcodes: Vec<String>,
+    blanked_barcodes: AHashMap<Vec<u8>, Option<u32>>, // barcode with one position blanked -> index within barcodes.  None if more than one barcode has the blanked sequence
+}
+
+impl MismatchIndex {
+    /// Creates the index from the known barcodes.  Returns None if the barcodes are not all the same length or if any contain 'N's,
+    /// which fix_error_with_margin treats as wildcards
+    pub fn new(barcode_seqs: &AHashSet<String>) -> Option<Self> {
+        let barcode_length = barcode_seqs.iter().next()?.len();
+        if barcode_seqs
+            .iter()
+            .any(|barcode| barcode.len() != barcode_length || barcode.contains('N'))
+        {
+            return None;
+        }
+        let barcodes = barcode_seqs.iter().cloned().collect::<Vec<String>>();
+        let mut blanked_barcodes = AHashMap::new();
+        for (barcode_index, barcode) in barcodes.iter().enumerate() {
+            for position in 0..barcode_length {
+                let mut blanked_barcode = barcode.as_bytes().to_vec();
+                blanked_barcode[position] = b'.';
+                blanked_barcodes
+                    .entry(blanked_barcode)
+                    .and_modify(|index_option| *index_option = None)
+                    .or_insert(Some(barcode_index as u32));
+            }
+        }
+        Some(MismatchIndex {
+            barcodes,
+            blanked_barcodes,
+        })
+    }
+
+    /// Whether or not the index was created from exactly these barcodes.  Used to check a loaded index against the counted barcodes file
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::MismatchIndex;
+    ///
+    /// let barcodes: ahash::AHashSet<String> = ["AGCAG".to_string(), "TTCAA".to_string()].iter().cloned().collect();
+    /// let other_barcodes: ahash::AHashSet<String> = ["AGCAG".to_string(), "GGCAA".to_string()].iter().cloned().collect();
+    /// let mismatch_index = MismatchIndex::new(&barcodes).unwrap();
+    ///
+    /// assert!(mismatch_index.matches_barcodes(&barcodes));
+    /// assert!(!mismatch_index.matches_barcodes(&other_barcodes));
+    /// ```
+    pub fn matches_barcodes(&self, barcode_seqs: &AHashSet<String>) -> bool {
+        self.barcodes.len() == barcode_seqs.len()
+            && self.barcodes.iter().all(|barcode| barcode_seqs.contains(barcode))
+    }
+
+    /// Returns the known barcode within one mismatch of the sequenced barcode.  None is returned if there is none or there are more than one,
+    /// the same as fix_error_with_margin.  The sequenced barcode should already be checked to not be a known barcode
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::MismatchIndex;
+    ///
+    /// let barcodes: ahash::AHashSet<String> = ["AGCAG".to_string(), "AGAAG".to_string(), "TTCAA".to_string()].iter().cloned().collect();
+    /// let mismatch_index = MismatchIndex::new(&barcodes).unwrap();
+    ///
+    /// assert_eq!(mismatch_index.fix("TTCAT"), Some("TTCAA".to_string()));
+    /// assert_eq!(mismatch_index.fix("AGTAG"), None); // one mismatch from both AGCAG and AGAAG
+    /// assert_eq!(mismatch_index.fix("GGGGG"), None);
+    /// ```
+    pub fn fix(&self, mismatch_seq: &str) -> Option<String> {
+        self.fix_or_tie(mismatch_seq).0
+    }
+
+    /// Returns the same fix as fix, along with whether more than one known barcode is within one mismatch of the sequenced barcode,
+    /// which is why there is no fix for it, instead of there being none.  Both are found with the one set of lookups
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::MismatchIndex;
+    ///
+    /// let barcodes: ahash::AHashSet<String> = ["AGCAG".to_string(), "AGAAG".to_string(), "TTCAA".to_string()].iter().cloned().collect();
+    /// let mismatch_index = MismatchIndex::new(&barcodes).unwrap();
+    ///
+    /// assert_eq!(mismatch_index.fix_or_tie("TTCAT"), (Some("TTCAA".to_string()), false));
+    /// assert_eq!(mismatch_index.fix_or_tie("AGTAG"), (None, true));
+    /// assert_eq!(mismatch_index.fix_or_tie("GGGGG"), (None, false));
+    /// ```
+    pub fn fix_or_tie(&self, mismatch_seq: &str) -> (Option<String>, bool) {
+        match self.find(mismatch_seq) {
+            (Some(barcode_index), false) => (Some(self.barcodes[barcode_index as usize].to_string()), false),
+            (_, tied) => (None, tied),
+        }
+    }
+
+    /// Returns the index of a known barcode within one mismatch, if there is one, and whether there is more than one
+    fn find(&self, mismatch_seq: &str) -> (Option<u32>, bool) {
+        let mut blanked_seq = mismatch_seq.as_bytes().to_vec();
+        let mut best_match = None;
+        for position in 0..blanked_seq.len() {
+            let original_base = blanked_seq[position];
+            blanked_seq[position] = b'.';
+            match self.blanked_barcodes.get(&blanked_seq) {
+                // More than one barcode is within one mismatch
+                Some(None) => return (best_match, true),
+                Some(Some(barcode_index)) => {
+                    if best_match.is_some() && best_match != Some(*barcode_index) {
+                        return (best_match, true);
+                    }
+                    best_match = Some(*barcode_index);
+                }
+                None => (),
+            }
+            blanked_seq[position] = original_base;
+        }
+        (best_match, false)
+    }
+}
+
+/// An index of the known barcodes by the k-mer at each of their positions, so that a sequenced barcode is only compared with the known
+/// barcodes that share enough k-mers with it.  A barcode with d mismatches changes at most d * k of its L - k + 1 positional k-mers, so
+/// every known barcode within d mismatches shares at least L - k + 1 - d * k of them.  d covers the allowed mismatches plus the margin,
+/// so the fix is the same as fix_error_with_margin over every known barcode
+pub struct KmerIndex {
+    barcodes: Vec<String>,
+    barcode_length: usize,
+    kmer_length: usize,
+    min_shared: usize, // positional k-mers shared with the sequenced barcode by every known barcode close enough to change the fix
+    max_mismatches: u16,
+    kmer_barcodes: AHashMap<u64, Vec<u32>>, // position and packed k-mer -> indexes within barcodes of each barcode with the k-mer there
+}
+
+impl KmerIndex {
+    const MIN_BARCODES: usize = 500; // fewer known barcodes are quicker to compare one by one
+    const MIN_KMER_LENGTH: usize = 4; // shorter k-mers are shared by too many barcodes to leave out many
+
+    /// Creates the index for fixing up to max_mismatches with at least min_correction_margin, the same as fix_error_with_margin.  Returns None if there
+    /// are too few barcodes for the index to be quicker, if the barcodes are not all the same length or have bases other than A, C, G and
+    /// T, or if the barcodes are too short for k-mers of at least 4 bases to find every known barcode close enough
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use barcode_count::parse::{fix_error_with_margin, KmerIndex, NMatching, TieResolution};
+    ///
+    /// // 1,000 pseudorandom 12 base barcodes
+    /// let barcodes: ahash::AHashSet<String> = (1..=1000_u64)
+    ///     .map(|barcode_num| {
+    ///         let mut hash = barcode_num;
+    ///         (0..12)
+    ///             .map(|_| {
+    ///                 hash = hash.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    ///                 ['A', 'C', 'G', 'T'][(hash >> 62) as usize]
+    ///             })
+    ///             .collect()
+    ///     })
+    ///     .collect();
+    /// let kmer_index = KmerIndex::new(&barcodes, 2, 1).unwrap();
+    ///
+    /// // The fixes are the same as comparing with every known barcode, for sequenced barcodes with one and two mismatches
+    /// for barcode in barcodes.iter().take(100) {
+    ///     for mismatch_positions in [vec![3], vec![0, 7]] {
+    ///         let mut sequenced_barcode = barcode.clone().into_bytes();
+    ///         for position in mismatch_positions {
+    ///             sequenced_barcode[position] = if sequenced_barcode[position] == b'A' { b'C' } else { b'A' };
+    ///         }
+    ///         let sequenced_barcode = String::from_utf8(sequenced_barcode).unwrap();
+    ///         let full_fix = fix_error_with_margin(&sequenced_barcode, &barcodes, 2, 0, NMatching::Wildcard, TieResolution::Discard)
+    ///             .map(|(fixed_barcode, _)| fixed_barcode);
+    ///         let kmer_fix = kmer_index
+    ///             .fix(&sequenced_barcode, 0, NMatching::Wildcard, TieResolution::Discard)
+    ///             .map(|(fixed_barcode, _)| fixed_barcode);
+    ///         assert_eq!(kmer_fix, full_fix);
+    ///     }
+    /// }
+    /// ```
+    pub fn new(barcode_seqs: &AHashSet<String>, max_mismatches: u16, min_correction_margin: u16) -> Option<Self> {
+        if barcode_seqs.len() < Self::MIN_BARCODES {
+            return None;
+        }
+        let barcode_length = barcode_seqs.iter().next()?.len();
+        if barcode_length > u8::MAX as usize
+            || barcode_seqs.iter().any(|barcode| {
+                barcode.len() != barcode_length || !barcode.bytes().all(|base| matches!(base, b'A' | b'C' | b'G' | b'T'))
+            })
+        {
+            return None;
+        }
+        // A fix needs a margin of at least 1, so the second closest known barcode is needed up to this many mismatches
+        let searched_mismatches = max_mismatches as usize + min_correction_margin.max(1) as usize - 1;
+        // The longest k-mers where a known barcode with the searched mismatches still shares at least one.  Packed k-mers fit within 56 bits
+        let kmer_length = (barcode_length / (searched_mismatches + 1)).min(28);
+        if kmer_length < Self::MIN_KMER_LENGTH {
+            return None;
+        }
+        let barcodes = barcode_seqs.iter().cloned().collect::<Vec<String>>();
+        let mut kmer_barcodes: AHashMap<u64, Vec<u32>> = AHashMap::new();
+        for (barcode_index, barcode) in barcodes.iter().enumerate() {
+            for (position, kmer) in barcode.as_bytes().windows(kmer_length).enumerate() {
+                if let Some(kmer_key) = Self::kmer_key(position, kmer) {
+                    kmer_barcodes.entry(kmer_key).or_default().push(barcode_index as u32);
+                }
+            }
+        }
+        Some(KmerIndex {
+            barcodes,
+            barcode_length,
+            kmer_length,
+            min_shared: barcode_length - kmer_length + 1 - searched_mismatches * kmer_length,
+            max_mismatches,
+            kmer_barcodes,
+        })
+    }
+
+    /// Packs the position and the k-mer, with 2 bits per base, into one key.  None if the k-mer has a base other than A, C, G and T, which
+    /// is a mismatch with every known barcode
+    fn kmer_key(position: usize, kmer: &[u8]) -> Option<u64> {
+        let mut kmer_key = (position as u64) << 56;
+        for (base_index, base) in kmer.iter().enumerate() {
+            let base_bits = match base {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => return None,
+            };
+            kmer_key |= base_bits << (base_index * 2);
+        }
+        Some(kmer_key)
+    }
+
+    /// Whether or not the index gives the same fix as fix_error_with_margin for the sequenced barcode.  A sequenced 'N' that matches anything, which
+    /// k-mers can not find, or a different length, which fix_error_with_margin compares up to the shorter length, are left to fix_error_with_margin
+    pub fn covers(&self, mismatch_seq: &str, n_matching: NMatching) -> bool {
+        mismatch_seq.len() == self.barcode_length
+            && (n_matching == NMatching::StrictMismatchSeq || !mismatch_seq.contains('N'))
+    }
+
+    /// Returns the known barcode, along with the margin, that fix_error_with_margin would fix the sequenced barcode to.  Only the known barcodes
+    /// sharing enough positional k-mers with the sequenced barcode are compared, so a margin above the one the index was created with can
+    /// be larger than the one of fix_error_with_margin.  A tie is chosen the same as fix_error_with_margin whatever the order of the known barcodes, other than with
+    /// TieResolution::KeepFirst.  Mismatches are counted up to the same limit as fix_error_with_margin with min_margin.  Check covers first
+    pub fn fix(
+        &self,
+        mismatch_seq: &str,
+        min_margin: u16,
+        n_matching: NMatching,
+        tie_resolution: TieResolution,
+    ) -> Option<(String, u16)> {
+        let mut shared_kmers: AHashMap<u32, usize> = AHashMap::new();
+        for (position, kmer) in mismatch_seq.as_bytes().windows(self.kmer_length).enumerate() {
+            if let Some(kmer_barcodes) = Self::kmer_key(position, kmer).and_then(|kmer_key| self.kmer_barcodes.get(&kmer_key)) {
+                for barcode_index in kmer_barcodes {
+                    *shared_kmers.entry(*barcode_index).or_insert(0) += 1;
+                }
+            }
         }
+        let close_barcodes = shared_kmers
+            .into_iter()
+            .filter(|(_, shared)| *shared >= self.min_shared)
+            .map(|(barcode_index, _)| &self.barcodes[barcode_index as usize]);
+        fix_error_with_margin(mismatch_seq, close_barcodes, self.max_mismatches, min_margin, n_matching, tie_resolution)
     }
+}
+
+/// How 'N's are treated when counting mismatches within fix_error_with_margin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NMatching {
+    Wildcard,           // 'N' within either sequence matches any nucleotide
+    StrictMismatchSeq,  // 'N' within the sequence being fixed is a mismatch against a definite nucleotide
+    StrictPossibleSeqs, // 'N' within the possible sequences is a mismatch against a definite nucleotide
+}
 
-    /// Returns a comma separated counted barcodes string.  Perfect for CSV file writing
-    pub fn barcode_string(&self) -> String {
-        self.counted_barcodes.join(",")
+/// Returns the reverse complement of the DNA sequence.  Any base other than A, C, G, T is kept as is
+///
+/// # Example
+/// ```
+/// use barcode_count::parse::reverse_complement;
+///
+/// assert_eq!(reverse_complement("AAGCTN"), "NAGCTT");
+/// ```
+pub fn reverse_complement(sequence: &str) -> String {
+    sequence
+        .chars()
+        .rev()
+        .map(|base| match base {
+            'A' => 'T',
+            'T' => 'A',
+            'G' => 'C',
+            'C' => 'G',
+            other => other,
+        })
+        .collect()
+}
+
+/// Returns the lexicographically smaller of the barcode and its reverse complement, which is the same for both orientations
+pub fn canonical_barcode(barcode: &str) -> String {
+    let barcode_reverse_complement = reverse_complement(barcode);
+    if barcode_reverse_complement.as_str() < barcode {
+        barcode_reverse_complement
+    } else {
+        barcode.to_string()
+    }
+}
+
+/// Returns the bases that an IUPAC code stands for, or None for A, C, G, T, N and any other character, which are kept as is
+pub fn iupac_bases(code: char) -> Option<&'static str> {
+    match code {
+        'R' => Some("AG"),
+        'Y' => Some("CT"),
+        'S' => Some("CG"),
+        'W' => Some("AT"),
+        'K' => Some("GT"),
+        'M' => Some("AC"),
+        'B' => Some("CGT"),
+        'D' => Some("AGT"),
+        'H' => Some("ACT"),
+        'V' => Some("ACG"),
+        _ => None,
+    }
+}
+
+/// Expands a barcode with IUPAC codes, such as 'R' for A or G, into every DNA sequence that it stands for.  'N' is kept as is since it
+/// already matches any nucleotide when fixing errors.  A barcode without IUPAC codes is returned on its own
+///
+/// # Example
+/// ```
+/// use barcode_count::parse::expand_iupac;
+///
+/// assert_eq!(expand_iupac("ARTY"), vec!["AATC", "AATT", "AGTC", "AGTT"]);
+/// assert_eq!(expand_iupac("ACNT"), vec!["ACNT"]);
+/// ```
+pub fn expand_iupac(barcode: &str) -> Vec<String> {
+    barcode
+        .chars()
+        .map(|code| match iupac_bases(code) {
+            Some(bases) => bases.chars().collect::<Vec<char>>(),
+            None => vec![code],
+        })
+        .multi_cartesian_product()
+        .map(|bases| bases.into_iter().collect::<String>())
+        .collect()
+}
+
+/// Returns whether or not the barcode has an IUPAC code other than N
+pub fn has_iupac(barcode: &str) -> bool {
+    barcode.chars().any(|code| iupac_bases(code).is_some())
+}
+
+/// Hashes the seed, the sequence being fixed and a tied possible sequence with 64 bit FNV-1a, which has a fixed definition so that a seed
+/// chooses the same ties with every build.  Separators keep sequences of different lengths from hashing the same bytes
+fn tie_hash(seed: u64, mismatch_seq: &str, possible_seq: &str) -> u64 {
+    let mut hash = 0xCBF2_9CE4_8422_2325_u64;
+    for byte in seed
+        .to_le_bytes()
+        .iter()
+        .chain(mismatch_seq.as_bytes())
+        .chain(b"|")
+        .chain(possible_seq.as_bytes())
+    {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+/// Counts the mismatches between a possible sequence and the sequence to be fixed.  Depending on n_matching, an 'N' in one or both
+/// sequences is a wildcard and not counted as a mismatch.  Counting stops once stop_at mismatches are found
+fn count_mismatches(possible_seq: &str, mismatch_seq: &str, n_matching: NMatching, stop_at: u16) -> u16 {
+    let mut mismatches = 0;
+    for (possible_char, current_char) in possible_seq.chars().zip(mismatch_seq.chars()) {
+        if !bases_match(possible_char, current_char, n_matching) {
+            mismatches += 1;
+        }
+        if mismatches >= stop_at {
+            break;
+        }
+    }
+    mismatches
+}
+
+/// Returns whether the base of the possible sequence matches the sequenced base, either by being the same or as a wildcard 'N'
+fn bases_match(possible_char: char, current_char: char, n_matching: NMatching) -> bool {
+    possible_char == current_char
+        || match n_matching {
+            NMatching::Wildcard => current_char == 'N' || possible_char == 'N',
+            NMatching::StrictMismatchSeq => possible_char == 'N',
+            NMatching::StrictPossibleSeqs => current_char == 'N',
+        }
+}
+
+/// How fix_error_with_margin handles two or more possible sequences tied for the best match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieResolution {
+    Discard,         // return None since the correct sequence is ambiguous
+    KeepFirst,       // keep the first best match in the iteration order of the possible sequences
+    KeepLowest,      // keep the lexicographically first best match, whatever the iteration order
+    KeepRandom(u64), // keep a best match chosen by a hash of the sequence and each best match with the seed, whatever the iteration order
+}
+
+impl TieResolution {
+    /// Whether the tied possible sequence is kept in place of the best match found so far when fixing mismatch_seq.  Used by each
+    /// BarcodeCorrector so that ties are chosen the same way whatever the order the known barcodes are compared in
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::parse::TieResolution;
+    ///
+    /// assert!(TieResolution::KeepLowest.prefers("AGAAG", "AGCAG", "AGTAG"));
+    /// assert!(!TieResolution::KeepFirst.prefers("AGAAG", "AGCAG", "AGTAG"));
+    /// // A random choice keeps one of the two, whichever is compared first
+    /// let random = TieResolution::KeepRandom(7);
+    /// assert_ne!(random.prefers("AGAAG", "AGCAG", "AGTAG"), random.prefers("AGCAG", "AGAAG", "AGTAG"));
+    /// ```
+    pub fn prefers(&self, possible_seq: &str, best_match: &str, mismatch_seq: &str) -> bool {
+        match self {
+            TieResolution::Discard | TieResolution::KeepFirst => false,
+            TieResolution::KeepLowest => possible_seq < best_match,
+            TieResolution::KeepRandom(seed) => {
+                tie_hash(*seed, mismatch_seq, possible_seq) < tie_hash(*seed, mismatch_seq, best_match)
+            }
+        }
+    }
+
+    /// Returns the resolution that keeps a tied match, so that ties can be found from their margin of 0 and discarded afterwards.  Ties
+    /// that are discarded are kept as the first best match, which does not compare any further to choose between them
+    ///
+    /// # Example
+    /// ```
+    /// use barcode_count::parse::TieResolution;
+    ///
+    /// assert_eq!(TieResolution::Discard.keeping(), TieResolution::KeepFirst);
+    /// assert_eq!(TieResolution::KeepRandom(1).keeping(), TieResolution::KeepRandom(1));
+    /// ```
+    pub fn keeping(self) -> TieResolution {
+        match self {
+            TieResolution::Discard => TieResolution::KeepFirst,
+            other => other,
+        }
     }
 }
 
 /// Fix an error in a sequence by comparing it to all possible sequences.  If no sequence matches with fewer or equal to the number of mismatches 'None' is returned.
-/// 'None' is also returned if two or more sequences are best matches.  Will work with vec and hashset
+/// 'None' is also returned if two or more sequences are best matches.  Will work with vec and hashset.  'N's match any nucleotide.  Use
+/// fix_error_with_margin to choose the N matching, how ties are resolved and the margin of the fix
 ///
 /// # Example
 ///
@@ -539,8 +2479,8 @@ impl SequenceMatchResult {
 ///
 /// let barcode = "AGTAG";
 ///
-/// let possible_barcodes_one_match: std::collections::AHashSet<String> = ["AGCAG".to_string(), "ACAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // only the first has a single mismatch
-/// let possible_barcodes_two_match: std::collections::AHashSet<String> = ["AGCAG".to_string(), "AGAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // first and second have a single mismatch
+/// let possible_barcodes_one_match: ahash::AHashSet<String> = ["AGCAG".to_string(), "ACAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // only the first has a single mismatch
+/// let possible_barcodes_two_match: ahash::AHashSet<String> = ["AGCAG".to_string(), "AGAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // first and second have a single mismatch
 ///
 /// let max_mismatches = barcode.chars().count() as u16 / 5; // allow up to 20% mismatches
 ///
@@ -554,40 +2494,290 @@ pub fn fix_error<'a, I>(mismatch_seq: &str, possible_seqs: I, mismatches: u16) -
 where
     I: IntoIterator<Item = &'a String>,
 {
+    fix_error_with_margin(mismatch_seq, possible_seqs, mismatches, 0, NMatching::Wildcard, TieResolution::Discard)
+        .map(|(fixed_seq, _)| fixed_seq)
+}
+
+/// Fix an error in a sequence by comparing it to all possible sequences, the same as fix_error with the N matching, tie resolution and
+/// margin chosen.  If no sequence matches with fewer or equal to the number of mismatches 'None' is returned.
+/// 'None' is also returned if two or more sequences are best matches, unless TieResolution::KeepFirst is used.  Will work with vec and hashset.
+/// The fixed sequence is returned along with the margin, which is how many more mismatches the second best sequence has than the best.  The
+/// margin is u16::MAX when there is only one possible sequence, and 0 for a tie kept with TieResolution::KeepFirst.  Mismatches are only
+/// counted up to mismatches + min_margin + 1, so that each possible sequence is not compared past what could change the fix, and a margin
+/// above min_margin can be returned smaller than it is
+///
+/// # Example
+///
+/// ```
+/// use barcode_count::parse::{fix_error_with_margin, NMatching, TieResolution};
+///
+/// let barcode = "AGTAG";
+///
+/// let possible_barcodes_one_match: ahash::AHashSet<String> = ["AGCAG".to_string(), "ACAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // only the first has a single mismatch
+/// let possible_barcodes_two_match: ahash::AHashSet<String> = ["AGCAG".to_string(), "AGAAG".to_string(), "AGCAA".to_string()].iter().cloned().collect(); // first and second have a single mismatch
+///
+/// let max_mismatches = barcode.chars().count() as u16 / 5; // allow up to 20% mismatches
+///
+/// let fixed_error_one = fix_error_with_margin(barcode, &possible_barcodes_one_match, max_mismatches, 0, NMatching::Wildcard, TieResolution::Discard);
+/// let fixed_error_two = fix_error_with_margin(barcode, &possible_barcodes_two_match, max_mismatches, 0, NMatching::Wildcard, TieResolution::Discard);
+///
+/// // AGCAG has 1 mismatch and the next closest have 2, for a margin of 1
+/// assert_eq!(fixed_error_one, Some(("AGCAG".to_string(), 1)));
+/// assert_eq!(fixed_error_two, None);
+///
+/// // Keeping the first best match returns the earliest tied sequence within the order given
+/// let ordered_barcodes = vec!["AGCAG".to_string(), "AGAAG".to_string()];
+/// assert_eq!(fix_error_with_margin(barcode, &ordered_barcodes, max_mismatches, 0, NMatching::Wildcard, TieResolution::KeepFirst), Some(("AGCAG".to_string(), 0)));
+/// // Keeping the lowest returns the lexicographically first tied sequence
+/// assert_eq!(fix_error_with_margin(barcode, &ordered_barcodes, max_mismatches, 0, NMatching::Wildcard, TieResolution::KeepLowest), Some(("AGAAG".to_string(), 0)));
+/// // Keeping a random tied sequence gives the same one for the same seed
+/// let random_fix = fix_error_with_margin(barcode, &ordered_barcodes, max_mismatches, 0, NMatching::Wildcard, TieResolution::KeepRandom(7));
+/// assert_eq!(random_fix, fix_error_with_margin(barcode, ordered_barcodes.iter().rev(), max_mismatches, 0, NMatching::Wildcard, TieResolution::KeepRandom(7)));
+///
+/// // With strict N matching, a sequenced 'N' is counted as a mismatch instead of matching anything
+/// let barcode_with_n = "AGNAG";
+/// let possible_barcodes = vec!["AGCAG".to_string()];
+/// assert_eq!(fix_error_with_margin(barcode_with_n, &possible_barcodes, 0, 0, NMatching::Wildcard, TieResolution::Discard), Some(("AGCAG".to_string(), u16::MAX)));
+/// assert_eq!(fix_error_with_margin(barcode_with_n, &possible_barcodes, 0, 0, NMatching::StrictMismatchSeq, TieResolution::Discard), None);
+/// assert_eq!(fix_error_with_margin(barcode_with_n, &possible_barcodes, 1, 0, NMatching::StrictMismatchSeq, TieResolution::Discard).map(|(fixed, _)| fixed), Some("AGCAG".to_string()));
+/// ```
+pub fn fix_error_with_margin<'a, I>(
+    mismatch_seq: &str,
+    possible_seqs: I,
+    mismatches: u16,
+    min_margin: u16,
+    n_matching: NMatching,
+    tie_resolution: TieResolution,
+) -> Option<(String, u16)>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let max_mismatches = mismatches;
     let mut best_match = None; // start the best match with None
-    let mut best_mismatch_count = mismatches + 1; // Add 1 and start the best.  This allows a match with the same mismatches as required
-    let mut keep = true; // An initiated variable to check if there is more than one best match
+    let mut best_mismatch_count = u16::MAX; // The mismatches of the best match, which is only kept if it is within the max mismatches
+    let mut second_mismatch_count = u16::MAX; // The mismatches of the second best match, used for the margin.  A tie has the same count as the best
+    // Choosing between tied matches needs the exact count of a sequence tied with the best, which is also the second best
+    let choose_ties = matches!(tie_resolution, TieResolution::KeepLowest | TieResolution::KeepRandom(_));
+    // Past this many mismatches a sequence is too far to be the best match or to bring the second best within the margin
+    let max_counted = max_mismatches.saturating_add(min_margin).saturating_add(1);
 
     // Iterate through possible matches
     for true_seq in possible_seqs {
-        // Initiate the number of mismatches for the current iterated possible sequece match
-        let mut mismatches = 0;
+        // Once past the second best, this sequence cannot change the best match or the margin
+        let stop_at = if choose_ties {
+            second_mismatch_count.saturating_add(1)
+        } else {
+            second_mismatch_count
+        }
+        .min(max_counted);
+        let mismatches = count_mismatches(true_seq, mismatch_seq, n_matching, stop_at);
+        // If this is the best match, keep and move the previous best to the second best.  Otherwise it may be the second best
+        if mismatches < best_mismatch_count {
+            second_mismatch_count = best_mismatch_count;
+            best_mismatch_count = mismatches;
+            best_match = Some(true_seq.to_string());
+        } else {
+            if mismatches == best_mismatch_count
+                && best_match
+                    .as_ref()
+                    .is_some_and(|best_match| tie_resolution.prefers(true_seq, best_match, mismatch_seq))
+            {
+                best_match = Some(true_seq.to_string());
+            }
+            if mismatches < second_mismatch_count {
+                second_mismatch_count = mismatches;
+            }
+        }
+    }
+    let margin = second_mismatch_count - best_mismatch_count;
+    // If there is more than one best match, don't keep unless the first best match is kept
+    if best_mismatch_count > max_mismatches || (margin == 0 && tie_resolution == TieResolution::Discard) {
+        return None;
+    }
+    best_match.map(|best_match| (best_match, margin))
+}
+
+/// Fixes the sequenced sample and counted barcodes that are not known to the known barcode they came from.  The counted barcodes of each
+/// design, and the sample barcodes, are fixed with the corrector of the FormatDesign, so that lab specific correction rules can be used by
+/// implementing this trait without changing the parser.  The built-in correctors are selected by name with barcode_corrector
+pub trait BarcodeCorrector: Send + Sync {
+    /// Returns the closest known barcode within max_errors, along with the margin, which is how many more errors the second closest
+    /// known barcode has.  Returns None if no known barcode is within max_errors, or if the closest are tied and the tie resolution
+    /// discards them.  A tie that is kept has a margin of 0.  The fix is only kept with at least min_margin, so a larger margin does not
+    /// need to be exact
+    fn correct(
+        &self,
+        sequenced_barcode: &str,
+        known_barcodes: &AHashSet<String>,
+        max_errors: u16,
+        min_margin: u16,
+        n_matching: NMatching,
+        tie_resolution: TieResolution,
+    ) -> Option<(String, u16)>;
+
+    /// Whether the fixes are the same as those of fix_error_with_margin, so that the mismatch and k-mer indexes of the counted barcodes can be used
+    /// in its place
+    fn is_hamming(&self) -> bool {
+        false
+    }
+}
+
+/// Fixes barcodes with the fewest mismatches, using fix_error_with_margin.  Used by default
+pub struct HammingCorrector;
+
+impl BarcodeCorrector for HammingCorrector {
+    fn correct(
+        &self,
+        sequenced_barcode: &str,
+        known_barcodes: &AHashSet<String>,
+        max_errors: u16,
+        min_margin: u16,
+        n_matching: NMatching,
+        tie_resolution: TieResolution,
+    ) -> Option<(String, u16)> {
+        fix_error_with_margin(sequenced_barcode, known_barcodes, max_errors, min_margin, n_matching, tie_resolution)
+    }
+
+    fn is_hamming(&self) -> bool {
+        true
+    }
+}
+
+/// Fixes barcodes with the fewest insertions, deletions and substitutions, found with edit_distance, so that a barcode with an
+/// insertion or deletion is fixed with one or two errors instead of the mismatches of every base after it
+///
+/// # Example
+/// ```
+/// use ahash::AHashSet;
+/// use barcode_count::parse::{BarcodeCorrector, EditDistanceCorrector, NMatching, TieResolution};
+///
+/// let known_barcodes: AHashSet<String> = ["AGCTAG".to_string(), "TTGACC".to_string()].into_iter().collect();
+/// // A deleted C shifts every base after it, which is 4 mismatches but 2 edits
+/// let corrected = EditDistanceCorrector.correct("AGTAGA", &known_barcodes, 2, 0, NMatching::Wildcard, TieResolution::Discard);
+/// assert_eq!(corrected.map(|(fixed, _)| fixed), Some("AGCTAG".to_string()));
+/// ```
+pub struct EditDistanceCorrector;
 
-        // Iterate through the nucleotides of the possible match and the sequence to be fixed finding how many mismatches
-        // If the mismatches exceed the current best mismatched, end this early
-        for (possible_char, current_char) in true_seq.chars().zip(mismatch_seq.chars()) {
-            if possible_char != current_char && current_char != 'N' && possible_char != 'N' {
-                mismatches += 1;
+impl BarcodeCorrector for EditDistanceCorrector {
+    fn correct(
+        &self,
+        sequenced_barcode: &str,
+        known_barcodes: &AHashSet<String>,
+        max_errors: u16,
+        min_margin: u16,
+        n_matching: NMatching,
+        tie_resolution: TieResolution,
+    ) -> Option<(String, u16)> {
+        let mut best_match = None;
+        let mut best_distance = u16::MAX;
+        let mut second_distance = u16::MAX;
+        // The same limit as fix_error_with_margin, past which a known barcode can not change the fix
+        let max_counted = max_errors.saturating_add(min_margin).saturating_add(1);
+        let choose_ties = matches!(tie_resolution, TieResolution::KeepLowest | TieResolution::KeepRandom(_));
+        let sequenced_chars = sequenced_barcode.chars().collect::<Vec<char>>();
+        let mut rows = (Vec::new(), Vec::new());
+        for known_barcode in known_barcodes {
+            let stop_at = if choose_ties {
+                second_distance.saturating_add(1)
+            } else {
+                second_distance
             }
-            if mismatches > best_mismatch_count {
-                break;
+            .min(max_counted);
+            let distance = bounded_edit_distance(known_barcode, &sequenced_chars, n_matching, stop_at, &mut rows);
+            if distance < best_distance {
+                second_distance = best_distance;
+                best_distance = distance;
+                best_match = Some(known_barcode.to_string());
+            } else {
+                if distance == best_distance
+                    && best_match
+                        .as_ref()
+                        .is_some_and(|best_match| tie_resolution.prefers(known_barcode, best_match, sequenced_barcode))
+                {
+                    best_match = Some(known_barcode.to_string());
+                }
+                if distance < second_distance {
+                    second_distance = distance;
+                }
             }
         }
-        // If there are more than one best match, don't keep
-        if mismatches == best_mismatch_count {
-            keep = false
+        let margin = second_distance - best_distance;
+        if best_distance > max_errors || (margin == 0 && tie_resolution == TieResolution::Discard) {
+            return None;
         }
-        // If this is the best match, keep and reset best mismatches to this value
-        if mismatches < best_mismatch_count {
-            keep = true;
-            best_mismatch_count = mismatches;
-            best_match = Some(true_seq.to_string());
+        best_match.map(|best_match| (best_match, margin))
+    }
+}
+
+/// Returns the fewest insertions, deletions and substitutions that change the possible sequence into the sequenced one.  'N's are
+/// handled the same as by fix_error_with_margin, which is set by n_matching
+///
+/// # Example
+/// ```
+/// use barcode_count::parse::{edit_distance, NMatching};
+///
+/// assert_eq!(edit_distance("AGCTAG", "AGCTAG", NMatching::Wildcard), 0);
+/// assert_eq!(edit_distance("AGCTAG", "AGTTAG", NMatching::Wildcard), 1);
+/// assert_eq!(edit_distance("AGCTAG", "AGTAGA", NMatching::Wildcard), 2);
+/// assert_eq!(edit_distance("AGCTAG", "AGNTAG", NMatching::Wildcard), 0);
+/// assert_eq!(edit_distance("AGCTAG", "AGNTAG", NMatching::StrictMismatchSeq), 1);
+/// ```
+pub fn edit_distance(possible_seq: &str, mismatch_seq: &str, n_matching: NMatching) -> u16 {
+    let mismatch_chars = mismatch_seq.chars().collect::<Vec<char>>();
+    bounded_edit_distance(possible_seq, &mismatch_chars, n_matching, u16::MAX, &mut (Vec::new(), Vec::new()))
+}
+
+/// Returns the edit distance of edit_distance, or stop_at once every distance of a row is at least stop_at, since the distance can only
+/// grow from there.  The rows are reused between calls so that comparing with every known barcode does not allocate each time
+fn bounded_edit_distance(
+    possible_seq: &str,
+    mismatch_chars: &[char],
+    n_matching: NMatching,
+    stop_at: u16,
+    rows: &mut (Vec<u16>, Vec<u16>),
+) -> u16 {
+    let (previous_row, current_row) = rows;
+    // The distances from the start of the possible sequence so far to each start of the sequenced one, one row at a time
+    previous_row.clear();
+    previous_row.extend(0..=mismatch_chars.len() as u16);
+    current_row.clear();
+    current_row.resize(previous_row.len(), 0);
+    for (possible_index, possible_char) in possible_seq.chars().enumerate() {
+        current_row[0] = possible_index as u16 + 1;
+        let mut row_min = current_row[0];
+        for (mismatch_index, current_char) in mismatch_chars.iter().enumerate() {
+            let substitution = if bases_match(possible_char, *current_char, n_matching) {
+                previous_row[mismatch_index]
+            } else {
+                previous_row[mismatch_index] + 1
+            };
+            current_row[mismatch_index + 1] = substitution
+                .min(previous_row[mismatch_index + 1] + 1)
+                .min(current_row[mismatch_index] + 1);
+            row_min = row_min.min(current_row[mismatch_index + 1]);
+        }
+        std::mem::swap(previous_row, current_row);
+        if row_min >= stop_at {
+            return stop_at;
         }
     }
-    // If there is one best match and it is some, return it.  Otherwise return None
-    if keep && best_match.is_some() {
-        best_match
-    } else {
-        None
+    previous_row[mismatch_chars.len()].min(stop_at)
+}
+
+/// Returns the built-in barcode corrector with the name, either 'hamming' or 'edit'
+///
+/// # Example
+/// ```
+/// use barcode_count::parse::barcode_corrector;
+///
+/// assert!(barcode_corrector("hamming").unwrap().is_hamming());
+/// assert!(!barcode_corrector("edit").unwrap().is_hamming());
+/// assert!(barcode_corrector("levenshtein").is_err());
+/// ```
+pub fn barcode_corrector(name: &str) -> Result<Arc<dyn BarcodeCorrector>> {
+    match name {
+        "hamming" => Ok(Arc::new(HammingCorrector)),
+        "edit" => Ok(Arc::new(EditDistanceCorrector)),
+        other => bail!("The barcode corrector must be either 'hamming' or 'edit', not '{}'", other),
     }
 }